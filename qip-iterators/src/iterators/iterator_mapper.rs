@@ -60,6 +60,8 @@ where
             let n_op_indices = o_indices.len() - n_control_indices;
             act_on_control_iterator(row, op, *n_control_indices, n_op_indices, f)
         }
+        MatrixOp::Broadcast(_, data) => f(&mut BroadcastOpIterator::new(row, nindices, data)),
+        MatrixOp::Generated(_, g) => f(&mut GeneratedOpIterator::new(row, nindices, g.as_ref())),
     }
 }
 
@@ -109,5 +111,23 @@ where
             let n_op_indices = o_indices.len() - new_n_control_indices;
             act_on_control_iterator(row, op, n_control_indices, n_op_indices, f)
         }
+        MatrixOp::Broadcast(_, data) => {
+            let iter_builder = |row: usize| BroadcastOpIterator::new(row, n_op_indices, data);
+            f(&mut ControlledOpIterator::new(
+                row,
+                n_control_indices,
+                n_op_indices,
+                iter_builder,
+            ))
+        }
+        MatrixOp::Generated(_, g) => {
+            let iter_builder = |row: usize| GeneratedOpIterator::new(row, n_op_indices, g.as_ref());
+            f(&mut ControlledOpIterator::new(
+                row,
+                n_control_indices,
+                n_op_indices,
+                iter_builder,
+            ))
+        }
     }
 }
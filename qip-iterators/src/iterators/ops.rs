@@ -1,10 +1,15 @@
 use crate::iterators::{
-    ControlledOpIterator, MatrixOpIterator, SparseMatrixOpIterator, SwapOpIterator,
+    BroadcastOpIterator, ControlledOpIterator, GeneratedOpIterator, MatrixOpIterator,
+    SparseMatrixOpIterator, SwapOpIterator,
 };
 use num_traits::{One, Zero};
 use std::fmt;
 use std::iter::Sum;
 use std::ops::Mul;
+use std::sync::Arc;
+
+/// A function which computes the matrix entry at `(row, col)` on demand.
+pub type MatrixGenerator<P> = Arc<dyn Fn(usize, usize) -> P + Send + Sync>;
 
 /// Ops which can be applied to quantum states.
 #[derive(Clone)]
@@ -17,6 +22,10 @@ pub enum MatrixOp<P> {
     Swap(usize, Vec<usize>),
     /// (n) Control indices, Op indices, Op
     Control(usize, Vec<usize>, Box<MatrixOp<P>>),
+    /// Indices, single qubit matrix data applied to each index.
+    Broadcast(Vec<usize>, Vec<P>),
+    /// Indices, function generating the matrix entry for each (row, col)
+    Generated(Vec<usize>, MatrixGenerator<P>),
 }
 
 impl<P> MatrixOp<P> {
@@ -32,6 +41,8 @@ impl<P> MatrixOp<P> {
             MatrixOp::Control(_, os, _) => {
                 os.len()
             },
+            MatrixOp::Broadcast(indices, _) => indices.len(),
+            MatrixOp::Generated(indices, _) => indices.len(),
         }
     }
 
@@ -42,9 +53,32 @@ impl<P> MatrixOp<P> {
             MatrixOp::SparseMatrix(i, _) => i,
             MatrixOp::Swap(_, i) => i,
             MatrixOp::Control(_, i, _) => i,
+            MatrixOp::Broadcast(i, _) => i,
+            MatrixOp::Generated(i, _) => i,
         }
     }
 
+    /// Whether this op is a controlled op.
+    pub fn is_control(&self) -> bool {
+        matches!(self, MatrixOp::Control(_, _, _))
+    }
+
+    /// The op being controlled, if this is a controlled op.
+    pub fn inner_op(&self) -> Option<&MatrixOp<P>> {
+        match self {
+            MatrixOp::Control(_, _, op) => Some(op),
+            _ => None,
+        }
+    }
+
+    /// Whether this op is diagonal in the computational basis.
+    pub fn is_diagonal(&self) -> bool
+    where
+        P: Zero,
+    {
+        crate::matrix_ops::is_diagonal(self)
+    }
+
     /// Make a new dense matrix op
     pub fn new_matrix<Indx, Dat>(indices: Indx, data: Dat) -> Self
     where
@@ -89,6 +123,24 @@ impl<P> MatrixOp<P> {
         c.extend(r);
         Self::Control(cn, c, Box::new(op))
     }
+
+    /// Make a new broadcast op which applies the single qubit matrix `data` to each index.
+    pub fn new_broadcast<Indx, Dat>(indices: Indx, data: Dat) -> Self
+    where
+        Indx: Into<Vec<usize>>,
+        Dat: Into<Vec<P>>,
+    {
+        Self::Broadcast(indices.into(), data.into())
+    }
+
+    /// Make a new op whose matrix entries are computed by `f(row, col)` when applied.
+    pub fn new_generated<Indx, F>(indices: Indx, f: F) -> Self
+    where
+        Indx: Into<Vec<usize>>,
+        F: Fn(usize, usize) -> P + Send + Sync + 'static,
+    {
+        Self::Generated(indices.into(), Arc::new(f))
+    }
 }
 
 impl<P> MatrixOp<P>
@@ -112,6 +164,12 @@ where
                 let n_op_indices = o_indices.len() - n_control_indices;
                 op.sum_for_control_iterator(row, *n_control_indices, n_op_indices, f)
             }
+            MatrixOp::Broadcast(_, data) => BroadcastOpIterator::new(row, nindices, data)
+                .map(f)
+                .sum(),
+            MatrixOp::Generated(_, g) => GeneratedOpIterator::new(row, nindices, g.as_ref())
+                .map(f)
+                .sum(),
         }
     }
 
@@ -152,6 +210,19 @@ where
                 let n_op_indices = o_indices.len() - new_n_control_indices;
                 op.sum_for_control_iterator(row, n_control_indices, n_op_indices, f)
             }
+            MatrixOp::Broadcast(_, data) => {
+                let iter_builder = |row: usize| BroadcastOpIterator::new(row, n_op_indices, data);
+                ControlledOpIterator::new(row, n_control_indices, n_op_indices, iter_builder)
+                    .map(f)
+                    .sum()
+            }
+            MatrixOp::Generated(_, g) => {
+                let iter_builder =
+                    |row: usize| GeneratedOpIterator::new(row, n_op_indices, g.as_ref());
+                ControlledOpIterator::new(row, n_control_indices, n_op_indices, iter_builder)
+                    .map(f)
+                    .sum()
+            }
         }
     }
 }
@@ -168,6 +239,8 @@ impl<P> fmt::Debug for MatrixOp<P> {
                 let name = format!("C({:?})", *op);
                 (name, indices[..*num_c_indices].to_vec())
             }
+            MatrixOp::Broadcast(indices, _) => ("Broadcast".to_string(), indices.clone()),
+            MatrixOp::Generated(indices, _) => ("Generated".to_string(), indices.clone()),
         };
         let int_strings = indices
             .iter()
@@ -1,4 +1,5 @@
 use num_traits::{One, Zero};
+use std::fmt;
 use std::marker::PhantomData;
 
 use crate::utils::*;
@@ -218,6 +219,132 @@ where
     }
 }
 
+/// Iterator which provides the indices of nonzero columns for a given row of a single qubit matrix
+/// broadcast across multiple qubits.
+#[derive(Debug)]
+pub struct BroadcastOpIterator<'a, P>
+where
+    P: Clone + Zero + One,
+{
+    row: usize,
+    n: usize,
+    data: &'a [P],
+    fixed_cols: usize,
+    free_cols: usize,
+    next_sub: Option<usize>,
+}
+
+impl<'a, P> BroadcastOpIterator<'a, P>
+where
+    P: Clone + Zero + One,
+{
+    /// Build a new iterator using the row index, the number of qubits the matrix is broadcast
+    /// across, and the complex values of the single qubit matrix.
+    pub fn new(row: usize, n: usize, data: &'a [P]) -> BroadcastOpIterator<'a, P> {
+        // Each bit of the column is either fixed by the row, or free to take either value.
+        let (fixed_cols, free_cols, empty) =
+            (0..n).fold((0, 0, false), |(fixed, free, empty), bit| {
+                let row_bit = (row >> bit) & 1;
+                let zero_col = !data[row_bit << 1].is_zero();
+                let one_col = !data[(row_bit << 1) | 1].is_zero();
+                match (zero_col, one_col) {
+                    (true, true) => (fixed, free | (1 << bit), empty),
+                    (false, true) => (fixed | (1 << bit), free, empty),
+                    (true, false) => (fixed, free, empty),
+                    (false, false) => (fixed, free, true),
+                }
+            });
+        BroadcastOpIterator {
+            row,
+            n,
+            data,
+            fixed_cols,
+            free_cols,
+            next_sub: if empty { None } else { Some(0) },
+        }
+    }
+}
+
+impl<'a, P> Iterator for BroadcastOpIterator<'a, P>
+where
+    P: Clone + Zero + One,
+{
+    type Item = (usize, P);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sub = self.next_sub?;
+        // Step through subsets of the free bits in increasing order.
+        self.next_sub = if sub == self.free_cols {
+            None
+        } else {
+            Some(sub.wrapping_sub(self.free_cols) & self.free_cols)
+        };
+        let col = self.fixed_cols | sub;
+        let val = (0..self.n).fold(P::one(), |acc, bit| {
+            let row_bit = (self.row >> bit) & 1;
+            let col_bit = (col >> bit) & 1;
+            acc * self.data[(row_bit << 1) | col_bit].clone()
+        });
+        Some((col, val))
+    }
+}
+
+/// Iterator which provides the indices of nonzero columns for a given row of a matrix whose entries
+/// are computed on demand.
+pub struct GeneratedOpIterator<'a, P> {
+    row: usize,
+    n: usize,
+    f: &'a dyn Fn(usize, usize) -> P,
+    last_col: Option<usize>,
+}
+
+impl<'a, P> GeneratedOpIterator<'a, P> {
+    /// Build a new iterator using the row index, the number of qubits in the matrix, and the
+    /// function which computes the matrix entry at `(row, col)`.
+    pub fn new(
+        row: usize,
+        n: usize,
+        f: &'a dyn Fn(usize, usize) -> P,
+    ) -> GeneratedOpIterator<'a, P> {
+        GeneratedOpIterator {
+            row,
+            n,
+            f,
+            last_col: None,
+        }
+    }
+}
+
+impl<'a, P> fmt::Debug for GeneratedOpIterator<'a, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeneratedOpIterator")
+            .field("row", &self.row)
+            .field("n", &self.n)
+            .field("last_col", &self.last_col)
+            .finish()
+    }
+}
+
+impl<'a, P> Iterator for GeneratedOpIterator<'a, P>
+where
+    P: Zero,
+{
+    type Item = (usize, P);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.last_col.map(|col| col + 1).unwrap_or(0);
+        self.last_col = None;
+        for col in pos..(1 << self.n) {
+            let val = (self.f)(self.row, col);
+            if !val.is_zero() {
+                self.last_col = Some(col);
+                return Some((col, val));
+            }
+        }
+        None
+    }
+}
+
 /// Iterator which provides the indices of nonzero columns for a given function.
 #[derive(Debug)]
 pub struct FunctionOpIterator<P> {
@@ -377,4 +504,29 @@ mod iterator_tests {
         ];
         assert_eq!(mat, expected);
     }
+
+    #[test]
+    fn test_broadcast_iterator() {
+        let n = 2usize;
+        let d = from_reals(&[0.0, 1.0, 1.0, 0.0]);
+
+        let mat: Vec<Vec<f64>> = (0..1 << n)
+            .map(|i| -> Vec<f64> {
+                let it = BroadcastOpIterator::new(i, n, &d);
+                let v: Vec<f64> = (0..1 << n).map(|_| 0.0).collect();
+                it.fold(v, |mut v, (indx, _)| {
+                    v[indx] = 1.0;
+                    v
+                })
+            })
+            .collect();
+
+        let expected = vec![
+            vec![0.0, 0.0, 0.0, 1.0],
+            vec![0.0, 0.0, 1.0, 0.0],
+            vec![0.0, 1.0, 0.0, 0.0],
+            vec![1.0, 0.0, 0.0, 0.0],
+        ];
+        assert_eq!(mat, expected);
+    }
 }
@@ -34,6 +34,123 @@ pub fn get_index<P>(op: &MatrixOp<P>, i: usize) -> usize {
     op.indices()[i]
 }
 
+/// Get all qubit indices touched by `op`, including those of nested control ops, sorted and without
+/// duplicates.
+pub fn op_indices<P>(op: &MatrixOp<P>) -> Vec<usize> {
+    let mut indices = op.indices().to_vec();
+    if let MatrixOp::Control(_, _, inner) = op {
+        indices.extend(op_indices(inner));
+    }
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
+
+/// Check whether `op` is diagonal in the computational basis.
+pub fn is_diagonal<P: Zero>(op: &MatrixOp<P>) -> bool {
+    match op {
+        MatrixOp::Matrix(indices, data) => {
+            let side = 1 << indices.len();
+            data.iter()
+                .enumerate()
+                .all(|(i, v)| i / side == i % side || v.is_zero())
+        }
+        MatrixOp::SparseMatrix(_, rows) => rows
+            .iter()
+            .enumerate()
+            .all(|(row, cols)| cols.iter().all(|(col, v)| *col == row || v.is_zero())),
+        MatrixOp::Swap(_, _) => false,
+        MatrixOp::Control(_, _, inner) => is_diagonal(inner),
+        MatrixOp::Broadcast(_, data) => data[1].is_zero() && data[2].is_zero(),
+        MatrixOp::Generated(indices, f) => {
+            let side = 1 << indices.len();
+            (0..side).all(|row| (0..side).all(|col| col == row || f(row, col).is_zero()))
+        }
+    }
+}
+
+/// Check whether `a` and `b` are known to commute, either because they act on disjoint indices or
+/// because both are diagonal. A `false` result means the ops may not commute.
+pub fn commutes<P: Zero>(a: &MatrixOp<P>, b: &MatrixOp<P>) -> bool {
+    let b_indices = op_indices(b);
+    let disjoint = op_indices(a)
+        .iter()
+        .all(|indx| b_indices.binary_search(indx).is_err());
+    disjoint || (is_diagonal(a) && is_diagonal(b))
+}
+
+/// Get the diagonal entries of a dense or sparse op.
+fn diagonal_entries<P: Zero + Clone>(op: &MatrixOp<P>) -> Option<Vec<P>> {
+    match op {
+        MatrixOp::Matrix(indices, data) => {
+            let side = 1 << indices.len();
+            Some((0..side).map(|i| data[i * side + i].clone()).collect())
+        }
+        MatrixOp::SparseMatrix(_, rows) => Some(
+            rows.iter()
+                .enumerate()
+                .map(|(row, cols)| {
+                    cols.iter()
+                        .find(|(col, _)| *col == row)
+                        .map(|(_, v)| v.clone())
+                        .unwrap_or_else(P::zero)
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Fuse the dense `op` with `diagonal` applied after it into a single dense op, by scaling each row
+/// of the matrix by the matching diagonal entry. Returns None unless `op` is a `Matrix`, and
+/// `diagonal` is a diagonal `Matrix` or `SparseMatrix` on the same indices in the same order.
+pub fn fuse_diagonal<P>(op: &MatrixOp<P>, diagonal: &MatrixOp<P>) -> Option<MatrixOp<P>>
+where
+    P: Zero + Clone + Mul<Output = P>,
+{
+    match op {
+        MatrixOp::Matrix(indices, data)
+            if diagonal.indices() == indices.as_slice() && is_diagonal(diagonal) =>
+        {
+            let entries = diagonal_entries(diagonal)?;
+            let side = 1 << indices.len();
+            let data = data
+                .iter()
+                .enumerate()
+                .map(|(i, v)| entries[i / side].clone() * v.clone())
+                .collect();
+            Some(MatrixOp::Matrix(indices.clone(), data))
+        }
+        _ => None,
+    }
+}
+
+/// Iterate over the nonzero entries of `row` of a dense or sparse op as `(col, value)` pairs, so
+/// both can be applied the same way. Dense rows are given in column order and sparse rows in the
+/// order they are stored. Returns None for other ops.
+pub fn row_entries<P: Zero>(
+    op: &MatrixOp<P>,
+    row: usize,
+) -> Option<impl Iterator<Item = (usize, &P)>> {
+    let (dense, sparse) = match op {
+        MatrixOp::Matrix(indices, data) => {
+            let side = 1 << indices.len();
+            (
+                Some(data[row * side..(row + 1) * side].iter().enumerate()),
+                None,
+            )
+        }
+        MatrixOp::SparseMatrix(_, rows) => (None, Some(rows[row].iter().map(|(col, v)| (*col, v)))),
+        _ => return None,
+    };
+    let entries = dense
+        .into_iter()
+        .flatten()
+        .chain(sparse.into_iter().flatten())
+        .filter(|(_, v)| !v.is_zero());
+    Some(entries)
+}
+
 /// Compute vector element at a given row from op applied to input.
 pub fn apply_op_row<P>(
     n: usize,
@@ -151,6 +268,35 @@ pub fn apply_op_overwrite<P>(
     iter_mut!(output).enumerate().for_each(row_fn);
 }
 
+/// Like `apply_op_overwrite`, but always runs on the calling thread. For small states this avoids
+/// the overhead of splitting the work between threads.
+pub fn apply_op_overwrite_serial<P>(
+    n: usize,
+    op: &MatrixOp<P>,
+    input: &[P],
+    output: &mut [P],
+    input_offset: usize,
+    output_offset: usize,
+) where
+    P: AddAssign + Clone + One + Zero + Sum + Mul<Output = P> + Send + Sync,
+{
+    let mat_indices: Vec<usize> = (0..op.num_indices()).map(|i| get_index(op, i)).collect();
+    output
+        .iter_mut()
+        .enumerate()
+        .for_each(|(outputrow, outputloc)| {
+            *outputloc = apply_op_row_indices(
+                n,
+                op,
+                input,
+                outputrow,
+                input_offset,
+                output_offset,
+                &mat_indices,
+            );
+        });
+}
+
 /// Apply `ops` to the `input`, adding the results to `output`. If either start at a nonzero state
 /// index in their 0th index, use `input/output_offset`.
 /// This is much less efficient as compared to repeated applications of `apply_op`, if your ops can
@@ -29,9 +29,12 @@ mod tests {
 
     extern crate test;
 
+    use qip::builder::RunOptions;
+    use qip::prelude::*;
     use qip::state_ops::matrix_ops::{make_control_op, make_matrix_op};
     use qip_iterators::iterators::MatrixOp::{Matrix, SparseMatrix};
     use qip_iterators::matrix_ops::apply_ops;
+    use std::num::NonZeroUsize;
     use test::Bencher;
 
     #[bench]
@@ -391,4 +394,64 @@ mod tests {
 
         b.iter(|| apply_op(n, &op, &input, &mut output, 0, 0));
     }
+
+    fn small_circuit(min_parallel_qubits: usize) -> Vec<Complex<f64>> {
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(4).unwrap());
+        let _r = (0..10).fold(r, |r, _| {
+            let r = b.h(r);
+            b.t(r)
+        });
+        let options = RunOptions {
+            min_parallel_qubits,
+            ..Default::default()
+        };
+        b.calculate_state_with_options(None, options)
+            .into_state()
+            .unwrap()
+    }
+
+    #[bench]
+    fn bench_small_circuit_serial(b: &mut Bencher) {
+        b.iter(|| small_circuit(usize::MAX));
+    }
+
+    #[bench]
+    fn bench_small_circuit_parallel(b: &mut Bencher) {
+        b.iter(|| small_circuit(0));
+    }
+
+    fn dense_circuit(min_parallel_qubits: usize) -> Vec<Complex<f64>> {
+        let n = 16;
+        let h = 0.5;
+        let data = from_reals(&[
+            h, h, h, h, //
+            h, -h, h, -h, //
+            h, h, -h, -h, //
+            h, -h, -h, h,
+        ]);
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(n).unwrap());
+        let _r = (0..n).fold(r, |r, i| {
+            b.apply_vec_matrix_on(r, &[i, (i + 5) % n], data.clone())
+                .unwrap()
+        });
+        let options = RunOptions {
+            min_parallel_qubits,
+            ..Default::default()
+        };
+        b.calculate_state_with_options(None, options)
+            .into_state()
+            .unwrap()
+    }
+
+    #[bench]
+    fn bench_dense_circuit_serial(b: &mut Bencher) {
+        b.iter(|| dense_circuit(usize::MAX));
+    }
+
+    #[bench]
+    fn bench_dense_circuit_parallel(b: &mut Bencher) {
+        b.iter(|| dense_circuit(0));
+    }
 }
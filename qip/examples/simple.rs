@@ -27,9 +27,9 @@ fn main() -> Result<(), CircuitError> {
     let r = b.merge_two_registers(ra, rb);
     let (_, handle) = b.measure_stochastic(r);
 
-    let (state, measures) = b.calculate_state();
-    println!("{:?}", state);
-    println!("{:?}", measures.get_stochastic_measurement(handle));
+    let result = b.calculate_state();
+    println!("{:?}", result.state());
+    println!("{:?}", result.get_stochastic_measurement(handle));
     Ok(())
 }
 
@@ -1,28 +1,78 @@
 use crate::builder_traits::*;
 use crate::conditioning::{Conditionable, ConditionableSubcircuit};
-use crate::errors::{CircuitError, CircuitResult};
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
 use crate::inverter::Invertable;
 use crate::inverter::RecursiveCircuitBuilder;
-use crate::state_ops::matrix_ops::{make_control_op, make_matrix_op, make_swap_op};
-use crate::state_ops::measurement_ops::{measure, measure_probs};
-use crate::types::Precision;
+use crate::state_ops::linalg::{dagger, hermitian_eigh, matmul};
+use crate::state_ops::matrix_ops::{
+    make_broadcast_op, make_control_op, make_generated_op, make_matrix_op, make_sparse_matrix_op,
+    make_swap_op,
+};
+use crate::state_ops::measurement_ops::{
+    measure, measure_prob, measure_probs, prob_magnitude, MeasuredCondition,
+};
+use crate::types::{Precision, Representation};
+use crate::utils::transpose_sparse;
 use crate::Complex;
 use num_rational::{Ratio, Rational64};
 use num_traits::{One, ToPrimitive, Zero};
-use qip_iterators::matrix_ops::apply_op_overwrite;
+use qip_iterators::iterators::{MatrixGenerator, MatrixOp};
+use qip_iterators::matrix_ops::{apply_op_overwrite, apply_op_overwrite_serial, fuse_diagonal};
+use qip_iterators::utils::flip_bits;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::num::NonZeroUsize;
-use std::ops::Neg;
+use std::ops::{Add, Neg, Range};
+use std::sync::Arc;
+
+/// Tools for inspecting and comparing circuits made with the LocalBuilder.
+pub mod analysis;
+/// Exporting circuits made with the LocalBuilder as JSON.
+pub mod json;
 
 /// A local circuit builder for constructing circuits out of standard gates.
 /// LocalBuilder breaks complicated multi-register gates, like toffoli, into combinations of simple
 /// gates like CNOT.
+///
+/// Ops are stored in a single pipeline in the order they are added, and running the circuit applies
+/// each exactly once. Output registers therefore do not need to be merged into one before running:
+/// ops shared by several disjoint output registers are not repeated.
+///
+/// The precision `P` is used for every matrix and state vector in the circuit, so a simulation can
+/// be run entirely in single precision to halve its memory use.
+/// ```
+/// use qip::prelude::*;
+/// use std::num::NonZeroUsize;
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f32>::default();
+/// let r = b.register(NonZeroUsize::new(2).unwrap());
+/// let r = b.h(r);
+///
+/// let result = b.calculate_state();
+/// let state = result.state().unwrap();
+/// assert!((state[0].re - 0.5f32).abs() < 1e-6);
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Default, Debug)]
 pub struct LocalBuilder<P: Precision> {
     pipeline: Vec<(Vec<usize>, BuilderCircuitObject<P>)>,
     n: usize,
     zeroed_qubits: Vec<Qudit>,
     measurements: usize,
+    single_op_broadcast: bool,
+    initial_states: Vec<(Vec<usize>, SparseState<P>)>,
+    ancilla_checks: Vec<(usize, Vec<usize>)>,
+    gate_overrides: HashMap<String, Vec<Complex<P>>>,
+    retain_post_measurement_states: bool,
+    classical_registers: Vec<Vec<(MeasurementHandle, usize, usize)>>,
+    allocations: Vec<usize>,
+    measurement_conditions: Vec<(Range<usize>, MeasurementHandle, usize)>,
+    tags: HashMap<String, usize>,
+    reused_qubits: Vec<(usize, usize)>,
 }
 
 impl<P: Precision> LocalBuilder<P> {
@@ -57,607 +107,2659 @@ impl<P: Precision> LocalBuilder<P> {
     pub fn pipeline_depth(&self) -> usize {
         self.pipeline.len()
     }
-}
 
-/// The register implementation for the LocalBuilder.
-#[derive(Debug)]
-pub struct Qudit {
-    indices: Vec<usize>,
-}
+    pub(crate) fn pipeline(&self) -> &[(Vec<usize>, BuilderCircuitObject<P>)] {
+        &self.pipeline
+    }
 
-impl QubitRegister for Qudit {
-    fn n(&self) -> usize {
-        self.indices.len()
+    /// Whether the op at `pos` in the pipeline was added by `apply_on_measurement`.
+    pub(crate) fn is_applied_on_measurement(&self, pos: usize) -> bool {
+        self.measurement_conditions
+            .iter()
+            .any(|(range, _, _)| range.contains(&pos))
     }
 
-    fn indices(&self) -> &[usize] {
-        self.indices.as_ref()
+    /// Whether any qubits start in a state other than |0>.
+    pub(crate) fn has_initial_states(&self) -> bool {
+        !self.initial_states.is_empty()
     }
-}
 
-impl Qudit {
-    fn new<It>(indices: It) -> Option<Self>
+    /// Tag the current point in the circuit with `name`, so the ops between two tags can be
+    /// extracted with `extract_between`. Errors if `name` is already in use.
+    pub fn tag<S: Into<String>>(&mut self, name: S) -> CircuitResult<()> {
+        let name = name.into();
+        if self.tags.contains_key(&name) {
+            let message = format!("Tag {:?} is already in use", name);
+            return Err(CircuitError::new(message));
+        }
+        self.tags.insert(name, self.pipeline.len());
+        Ok(())
+    }
+
+    /// Get the ops added between the tags `start` and `end` as a subcircuit, which can be inspected
+    /// or applied to another register with `apply_subcircuit`. Errors if either tag is missing or
+    /// `end` comes before `start`.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let q = b.qubit();
+    /// let q = b.x(q);
+    /// b.tag("start")?;
+    /// let q = b.h(q);
+    /// b.tag("end")?;
+    /// assert_eq!(b.extract_between("start", "end")?.len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extract_between(
+        &self,
+        start: &str,
+        end: &str,
+    ) -> CircuitResult<<Self as Subcircuitable>::Subcircuit> {
+        let position = |name: &str| {
+            self.tags.get(name).copied().ok_or_else(|| {
+                let message = format!("No tag named {:?}", name);
+                CircuitError::new(message)
+            })
+        };
+        let (start_pos, end_pos) = (position(start)?, position(end)?);
+        if end_pos < start_pos {
+            let message = format!("Tag {:?} comes before tag {:?}", end, start);
+            return Err(CircuitError::new(message));
+        }
+        Ok(self.pipeline[start_pos..end_pos].to_vec())
+    }
+
+    /// Copy the ops applied so far to the qubits of `q` as a subcircuit over its relative indices,
+    /// so the same preparation can be replayed onto other registers, in this or another builder,
+    /// with `apply_subcircuit`. Temporary qubits which were released and handed out again only
+    /// include the ops since they were handed out. Errors if an op also acts on qubits outside of
+    /// `q`, if `q` was measured or had ops applied on a measurement, or if `q` has an initial state,
+    /// since those cannot be replayed.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let q = b.qubit();
+    /// let q = b.h(q);
+    /// let frozen = b.freeze(&q)?;
+    ///
+    /// let mut other = LocalBuilder::<f64>::default();
+    /// let r = other.qubit();
+    /// let r = other.apply_subcircuit(frozen, r)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn freeze(&self, q: &Qudit) -> CircuitResult<<Self as Subcircuitable>::Subcircuit> {
+        // Where each qubit of `q` was last handed out again, earlier ops belong to a previous use.
+        let starts = q
+            .indices
+            .iter()
+            .map(|i| {
+                self.reused_qubits
+                    .iter()
+                    .rev()
+                    .find(|(_, qi)| qi == i)
+                    .map(|(pos, _)| *pos)
+            })
+            .collect::<Vec<_>>();
+        let initial = self.initial_states.iter().find(|(indices, _)| {
+            q.indices
+                .iter()
+                .zip(starts.iter())
+                .any(|(i, start)| start.is_none() && indices.contains(i))
+        });
+        if let Some((indices, _)) = initial {
+            let message = format!("Cannot freeze the initial state of qubits {:?}", indices);
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::Unsupported,
+                message,
+            ));
+        }
+        // The relative index of `i` in `q`, if the op at `pos` is part of its current use.
+        let relative_index = |pos: usize, i: &usize| {
+            q.indices
+                .iter()
+                .position(|qi| qi == i)
+                .filter(|k| starts[*k].iter().all(|start| pos >= *start))
+        };
+        self.pipeline
+            .iter()
+            .enumerate()
+            .filter(|(pos, (indices, _))| indices.iter().any(|i| relative_index(*pos, i).is_some()))
+            .map(|(pos, (indices, obj))| {
+                if matches!(obj.object, BuilderCircuitObjectType::Measurement(_))
+                    || self.is_applied_on_measurement(pos)
+                {
+                    let message =
+                        format!("Cannot freeze the {} op at {:?}", obj.object.name(), pos);
+                    return Err(CircuitError::new(message));
+                }
+                let relative = indices
+                    .iter()
+                    .map(|i| relative_index(pos, i))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| {
+                        let message = format!(
+                            "The op at {:?} acts on qubits {:?} outside of the register",
+                            pos, indices
+                        );
+                        CircuitError::new(message)
+                    })?;
+                Ok((relative, obj.clone()))
+            })
+            .collect()
+    }
+
+    /// Returns the id of the `register` (or `qubit`) call which allocated the qubits of `q`, ids
+    /// count allocations from zero. Registers keep their origin through splits and merges, so this
+    /// is `None` only if `q` holds qubits from more than one allocation.
+    pub fn root_id(&self, q: &Qudit) -> Option<usize> {
+        let root = |i: &usize| self.allocations.partition_point(|start| start <= i) - 1;
+        let first = root(&q.indices[0]);
+        q.indices.iter().all(|i| root(i) == first).then_some(first)
+    }
+
+    /// Returns the qubit indices created by each `register` (or `qubit`) call, in the order of
+    /// their allocation ids as given by `root_id`. Temporary registers only appear when they
+    /// needed new qubits, since released ones are reused.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let q = b.qubit();
+    /// let r = b.register(NonZeroUsize::new(2).unwrap());
+    /// assert_eq!(b.allocation_timeline(), vec![(0, vec![0]), (1, vec![1, 2])]);
+    /// ```
+    pub fn allocation_timeline(&self) -> Vec<(usize, Vec<usize>)> {
+        self.allocations
+            .iter()
+            .enumerate()
+            .map(|(id, start)| {
+                let end = self.allocations.get(id + 1).cloned().unwrap_or(self.n);
+                (id, (*start..end).collect())
+            })
+            .collect()
+    }
+
+    /// Construct a register of `n` qubits which starts in `state` rather than `|0>`. The state must
+    /// have `2^n` entries and is divided by its norm before use, so it need not be normalized.
+    /// Errors if the norm is small enough that the state is effectively zero.
+    pub fn register_with_state(
+        &mut self,
+        n: NonZeroUsize,
+        mut state: Vec<Complex<P>>,
+    ) -> CircuitResult<Qudit> {
+        if state.len() != 1 << n.get() {
+            let message = format!(
+                "Initial state has {:?} entries versus expected 2^{:?}",
+                state.len(),
+                n.get()
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::StateSizeMismatch,
+                message,
+            ));
+        }
+        let norm = prob_magnitude(&state).sqrt();
+        if norm < P::epsilon().sqrt() {
+            return Err(CircuitError::new("Initial state must have a nonzero norm"));
+        }
+        state.iter_mut().for_each(|c| *c /= norm);
+        let r = self.register(n);
+        self.initial_states
+            .push((r.indices.clone(), sparse_entries(state)));
+        Ok(r)
+    }
+
+    /// Construct a register of `n` qubits which starts in the superposition given by `entries` of
+    /// (basis state, amplitude) pairs, with all other basis states at zero. Unlike
+    /// `register_with_state` this does not need all `2^n` amplitudes. Errors if a basis state is
+    /// out of range or repeated, or if the amplitudes are not normalized.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let s = std::f64::consts::FRAC_1_SQRT_2;
+    /// let entries = vec![(0b01, Complex::new(s, 0.0)), (0b10, Complex::new(0.0, s))];
+    /// let r = b.register_with_sparse_state(NonZeroUsize::new(2).unwrap(), entries)?;
+    /// let result = b.calculate_state();
+    /// let state = result.state().unwrap();
+    /// assert!((state[0b10].re - s).abs() < 1e-10);
+    /// assert!((state[0b01].im - s).abs() < 1e-10);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn register_with_sparse_state(
+        &mut self,
+        n: NonZeroUsize,
+        entries: Vec<(usize, Complex<P>)>,
+    ) -> CircuitResult<Qudit> {
+        if let Some((x, _)) = entries.iter().find(|(x, _)| *x >= 1 << n.get()) {
+            let message = format!(
+                "Basis state {:?} is out of range for a register of {:?} qubits",
+                x,
+                n.get()
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::IndexOutOfRange,
+                message,
+            ));
+        }
+        let mut seen = entries.iter().map(|(x, _)| *x).collect::<Vec<_>>();
+        seen.sort_unstable();
+        if let Some(w) = seen.windows(2).find(|w| w[0] == w[1]) {
+            let message = format!("Basis state {:?} is given more than once", w[0]);
+            return Err(CircuitError::new(message));
+        }
+        let norm = entries
+            .iter()
+            .map(|(_, c)| c.norm_sqr())
+            .fold(P::zero(), |a, b| a + b);
+        if (norm - P::one()).abs() > P::epsilon().sqrt() {
+            let message = format!("Initial state has magnitude {} rather than 1", norm);
+            return Err(CircuitError::new(message));
+        }
+        let r = self.register(n);
+        self.initial_states.push((r.indices.clone(), entries));
+        Ok(r)
+    }
+
+    /// Construct a single qubit which starts in `state` rather than `|0>`.
+    pub fn qubit_with_state(&mut self, state: Vec<Complex<P>>) -> CircuitResult<Qudit> {
+        self.register_with_state(NonZeroUsize::new(1).unwrap(), state)
+    }
+
+    /// Start the register `q` in an equal superposition of the classical values in `allowed`, with
+    /// zero amplitude on all others. `q` must not have had any ops applied or state set yet.
+    pub fn prepare_uniform_subset(&mut self, q: Qudit, allowed: &[usize]) -> CircuitResult<Qudit> {
+        let n = q.n();
+        if let Some(x) = allowed.iter().find(|x| **x >= 1 << n) {
+            let message = format!(
+                "Basis state {:?} is out of range for a register of {:?} qubits",
+                x, n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::IndexOutOfRange,
+                message,
+            ));
+        }
+        if allowed.is_empty() {
+            return Err(CircuitError::new("Must allow at least one basis state"));
+        }
+        let used = self
+            .pipeline
+            .iter()
+            .map(|(indices, _)| indices)
+            .chain(self.initial_states.iter().map(|(indices, _)| indices))
+            .any(|indices| indices.iter().any(|i| q.indices.contains(i)));
+        if used {
+            return Err(CircuitError::new(
+                "Register has already been used and cannot be given an initial state",
+            ));
+        }
+        let mut state: Vec<Complex<P>> = vec![Complex::zero(); 1 << n];
+        allowed.iter().for_each(|x| state[*x] = Complex::one());
+        let norm = prob_magnitude(&state).sqrt();
+        state.iter_mut().for_each(|c| *c /= norm);
+        self.initial_states
+            .push((q.indices.clone(), sparse_entries(state)));
+        Ok(q)
+    }
+
+    /// Record the circuit made by `f` on a fresh builder, then apply it to `r` conditioned on all
+    /// qubits in `cr` being `1`. This makes any unitary subroutine controlled without conditioning
+    /// each gate by hand. Errors if the subcircuit contains a measurement.
+    pub fn controlled_subcircuit<F>(
+        &mut self,
+        cr: Qudit,
+        r: Qudit,
+        f: F,
+    ) -> CircuitResult<(Qudit, Qudit)>
     where
-        It: Into<Vec<usize>>,
+        F: FnOnce(&mut Self, Qudit) -> CircuitResult<Qudit>,
     {
-        let indices = indices.into();
-        if !indices.is_empty() {
-            Some(Self { indices })
-        } else {
-            None
+        let mut sub_cb = self.new_similar();
+        let sub_r = sub_cb.register(r.n_nonzero());
+        f(&mut sub_cb, sub_r)?;
+        let sc = sub_cb.make_subcircuit()?;
+        if sc
+            .iter()
+            .any(|(_, co)| matches!(co.object, BuilderCircuitObjectType::Measurement(_)))
+        {
+            return Err(CircuitError::new(
+                "Controlled subcircuits cannot contain measurements.",
+            ));
         }
+        if sc.is_empty() {
+            return Ok((cr, r));
+        }
+        self.apply_conditioned_subcircuit(sc, cr, r)
     }
-    fn new_from_iter<It>(indices: It) -> Option<Self>
+
+    /// Apply the ops added by `f` to `r` only if the measurement `handle` gave `expected` when the
+    /// circuit is run, which allows classical feed-forward such as error correction. The
+    /// measurement must come earlier in the circuit. Errors if `expected` does not fit in the
+    /// measured qubits, or if `f` errors or adds a measurement, in which case the ops added by `f`
+    /// are removed again.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let (m, q) = (b.qubit(), b.qubit());
+    /// let m = b.x(m);
+    /// let (m, handle) = b.measure(m);
+    /// let q = b.apply_on_measurement(handle, 1, q, |b, q| Ok(b.x(q)))?;
+    /// let (q, q_handle) = b.measure(q);
+    /// assert_eq!(b.calculate_state().get_measurement(q_handle).0, 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn apply_on_measurement<F>(
+        &mut self,
+        handle: MeasurementHandle,
+        expected: usize,
+        r: Qudit,
+        f: F,
+    ) -> CircuitResult<Qudit>
     where
-        It: Iterator<Item = usize>,
+        F: FnOnce(&mut Self, Qudit) -> CircuitResult<Qudit>,
     {
-        let indices = indices.into_iter().collect::<Vec<_>>();
-        Self::new(indices)
+        let measured_n = self
+            .pipeline
+            .iter()
+            .filter(|(_, co)| {
+                matches!(
+                    co.object,
+                    BuilderCircuitObjectType::Measurement(
+                        MeasurementObject::Measurement | MeasurementObject::StochasticMeasurement
+                    )
+                )
+            })
+            .nth(handle.id)
+            .map(|(indices, _)| indices.len());
+        match measured_n {
+            None => {
+                let message = format!("Measurement {:?} is not in the circuit", handle.id);
+                return Err(CircuitError::new(message));
+            }
+            Some(n) if expected >> n != 0 => {
+                let message = format!(
+                    "Expected value {:?} does not fit in the {:?} measured qubits",
+                    expected, n
+                );
+                return Err(CircuitError::new(message));
+            }
+            Some(_) => {}
+        }
+
+        let start = self.pipeline.len();
+        let measurements = self.measurements;
+        let r = f(self, r).and_then(|r| {
+            if self.measurements != measurements {
+                Err(CircuitError::new(
+                    "Ops applied on a measurement cannot contain measurements.",
+                ))
+            } else {
+                Ok(r)
+            }
+        });
+        let r = match r {
+            Ok(r) => r,
+            Err(err) => {
+                self.truncate_pipeline(start, measurements);
+                return Err(err);
+            }
+        };
+        self.measurement_conditions
+            .push((start..self.pipeline.len(), handle, expected));
+        Ok(r)
     }
-}
 
-/// A pipeline object for the LocalBuilder.
-#[derive(Debug, Clone)]
-pub struct BuilderCircuitObject<P: Precision> {
-    n: usize,
-    object: BuilderCircuitObjectType<P>,
-}
+    /// Remove the ops from `start` onwards along with anything referring to them or to
+    /// measurements from `measurements` onwards, to undo ops added by a failed call.
+    fn truncate_pipeline(&mut self, start: usize, measurements: usize) {
+        self.pipeline.truncate(start);
+        self.measurements = measurements;
+        self.ancilla_checks.retain(|(pos, _)| *pos <= start);
+        self.tags.retain(|_, pos| *pos <= start);
+        self.reused_qubits.retain(|(pos, _)| *pos <= start);
+        self.measurement_conditions
+            .retain(|(range, _, _)| range.end <= start);
+        self.classical_registers
+            .iter_mut()
+            .for_each(|writes| writes.retain(|(handle, _, _)| handle.id < measurements));
+    }
 
-/// The type of pipeline object for LocalBuilder.
-#[derive(Debug, Clone)]
-pub enum BuilderCircuitObjectType<P: Precision> {
-    /// A unitary operation on the circuit.
-    Unitary(UnitaryMatrixObject<P>),
-    /// A measurement operation on the circuit.
-    Measurement(MeasurementObject),
-}
+    /// Apply the matrix with entries `f(row, col)` to `r`. Entries are computed as the circuit is
+    /// run rather than stored, which saves memory for large structured matrices.
+    pub fn apply_generated_matrix<F>(&mut self, r: Qudit, f: F) -> CircuitResult<Qudit>
+    where
+        F: Fn(usize, usize) -> Complex<P> + Send + Sync + 'static,
+    {
+        let n = r.n();
+        let co = BuilderCircuitObject {
+            n,
+            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Generated(
+                GeneratedMatrix::new(n, f),
+            )),
+        };
+        self.apply_circuit_object(r, co)
+    }
 
-/// The type of unitary matrix for LocalBuilder.
-#[derive(Debug, Clone)]
-pub enum UnitaryMatrixObject<P: Precision> {
-    /// A pauli X gate.
-    X,
-    /// A pauli Y gate.
-    Y,
-    /// A pauli Z gate.
-    Z,
-    /// A hadamard gate.
-    H,
-    /// A conditional phase gate by pi/4.
-    S,
-    /// A conditional phase gate by pi/8.
-    T,
-    /// A controlled pauli X gate, first qubit is control.
-    CNOT,
-    /// A swap gate between two qubits.
-    SWAP,
-    /// A conditional phase gate by RotationObject phase.
-    Rz(RotationObject<P>),
-    /// A Generic Matrix
-    MAT(Vec<Complex<P>>),
-    /// A global phase applied on this qubit.
-    /// This normally doesn't matter but can have an effect
-    /// when we are conditioning them.
-    GlobalPhase(RotationObject<P>),
-}
+    /// Apply the function `f` to `q_in` and `q_out`, mapping `|x>|y>` to `e^{i theta}|x>|y ^ z>`
+    /// where `(z, theta) = f(x)`.
+    pub fn apply_function<F>(
+        &mut self,
+        q_in: Qudit,
+        q_out: Qudit,
+        f: F,
+    ) -> CircuitResult<(Qudit, Qudit)>
+    where
+        F: Fn(usize) -> (usize, P),
+    {
+        self.apply_complex_function(q_in, q_out, |x| {
+            let (z, theta) = f(x);
+            (z, Complex::from_polar(P::one(), theta))
+        })
+    }
 
-/// Represents phase floats.
-#[derive(Debug, Clone)]
-pub enum RotationObject<P: Precision> {
-    /// A rotation represented by a floating point precision.
-    Floating(P),
-    /// A rotation represented by a fixed ratio times pi.
-    PiRational(Ratio<i64>),
-}
+    /// Apply the function `f` to `q_in` and `q_out`, mapping `|x>|y>` to `c|x>|y ^ z>` where
+    /// `(z, c) = f(x)`. Each factor `c` must have modulus 1 for the op to be unitary, this is checked
+    /// by evaluating `f` on every input when the op is added.
+    pub fn apply_complex_function<F>(
+        &mut self,
+        q_in: Qudit,
+        q_out: Qudit,
+        f: F,
+    ) -> CircuitResult<(Qudit, Qudit)>
+    where
+        F: Fn(usize) -> (usize, Complex<P>),
+    {
+        let (n_in, n_out) = (q_in.n(), q_out.n());
+        let mask = (1 << n_out) - 1;
+        let tolerance = P::epsilon().sqrt();
+        let table = (0..1 << n_in)
+            .map(|x| {
+                let (z, c) = f(x);
+                if (c.norm() - P::one()).abs() > tolerance {
+                    let message = format!(
+                        "Function output for {:?} has modulus {:?}, expected 1",
+                        x,
+                        c.norm()
+                    );
+                    Err(CircuitError::new(message))
+                } else {
+                    Ok((z & mask, c))
+                }
+            })
+            .collect::<CircuitResult<Vec<_>>>()?;
 
-impl<P: Precision> Neg for RotationObject<P> {
-    type Output = Self;
+        // The op is a permutation with phases, so it is stored with a single entry per row rather
+        // than as a dense matrix. Matrix indices are big-endian over the register while classical
+        // values are little-endian, so flip each half before and after looking up the output.
+        let mut rows = vec![vec![]; 1 << (n_in + n_out)];
+        (0..1 << (n_in + n_out)).for_each(|col| {
+            let x = flip_bits(n_in, col >> n_out);
+            let y = flip_bits(n_out, col & mask);
+            let (z, c) = table[x];
+            let row = (flip_bits(n_in, x) << n_out) | flip_bits(n_out, y ^ z);
+            rows[row].push((col, c));
+        });
+        let r = self.merge_two_registers(q_in, q_out);
+        let r = self.apply_sparse_matrix(r, rows)?;
+        match self.split_register_relative(r, 0..n_in) {
+            SplitResult::SPLIT(q_in, q_out) => Ok((q_in, q_out)),
+            _ => unreachable!(),
+        }
+    }
 
-    fn neg(self) -> Self::Output {
-        match self {
-            RotationObject::Floating(f) => Self::Floating(-f),
-            RotationObject::PiRational(r) => Self::PiRational(-r),
+    /// Apply the matrix `data` to the qubits at the absolute `indices`, without needing a register
+    /// for them. This is for tools which only track qubit indices, callers are responsible for not
+    /// using the qubits through a register at the same time.
+    pub fn apply_mat_absolute(
+        &mut self,
+        indices: Vec<usize>,
+        data: Vec<Complex<P>>,
+    ) -> CircuitResult<()> {
+        if let Some(i) = indices.iter().find(|i| **i >= self.n) {
+            let message = format!(
+                "Index {:?} is out of range for a circuit of {:?} qubits",
+                i, self.n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::IndexOutOfRange,
+                message,
+            ));
+        }
+        let mut sorted = indices.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        if sorted.len() != indices.len() {
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::RepeatedIndices,
+                "Indices must not repeat",
+            ));
         }
+        let r = Qudit::new(indices).ok_or_else(|| {
+            CircuitError::with_kind(
+                CircuitErrorKind::EmptyIndices,
+                "Must supply at least one op index",
+            )
+        })?;
+        self.apply_vec_matrix(r, data).map(|_| ())
     }
-}
 
-impl<P: Precision> PartialEq for UnitaryMatrixObject<P> {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::X, Self::X)
-            | (Self::Y, Self::Y)
-            | (Self::Z, Self::Z)
-            | (Self::H, Self::H)
-            | (Self::S, Self::S)
-            | (Self::T, Self::T)
-            | (Self::CNOT, Self::CNOT)
-            | (Self::SWAP, Self::SWAP) => true,
-            (Self::Rz(ra), Self::Rz(rb)) => ra.eq(rb),
-            (Self::MAT(ma), Self::MAT(mb)) => ma.eq(mb),
-            (Self::GlobalPhase(ra), Self::GlobalPhase(rb)) => ra.eq(rb),
-            (_, _) => false,
+    /// Measure the Hermitian `observable` (a `2^n x 2^n` matrix) on `r` by diagonalizing it and
+    /// measuring in its eigenbasis. The state collapses onto the measured eigenvector, and the
+    /// eigenvalue can be looked up with `Measurements::get_observable_measurement`.
+    pub fn measure_observable(
+        &mut self,
+        r: Qudit,
+        observable: &[Complex<P>],
+    ) -> CircuitResult<(Qudit, ObservableMeasurementHandle<P>)> {
+        let n = r.n();
+        if observable.len() != 1 << (2 * n) {
+            let message = format!(
+                "Observable has {:?} entries versus expected 2^2*{:?}",
+                observable.len(),
+                n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ));
         }
+        let (eigenvalues, basis) = hermitian_eigh(observable)?;
+        let r = self.apply_vec_matrix(r, dagger(&basis))?;
+        let (r, handle) = self.measure(r);
+        let r = self.apply_vec_matrix(r, basis)?;
+        Ok((
+            r,
+            ObservableMeasurementHandle {
+                handle,
+                n,
+                eigenvalues,
+            },
+        ))
     }
-}
 
-impl<P: Precision> PartialEq for BuilderCircuitObjectType<P> {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::Unitary(ua), Self::Unitary(ub)) => ua.eq(ub),
-            (Self::Measurement(ma), Self::Measurement(mb)) => ma.eq(mb),
-            (_, _) => false,
+    /// Allocate `n` ancilla qubits in `|0>` and pass them to `f`, which must return them to `|0>`
+    /// along with its result. The ancillas are then released for reuse. Whether they were actually
+    /// returned to `|0>` is checked when the circuit is run, see `RunResult::check_ancillas`.
+    pub fn with_ancilla<F, R>(&mut self, n: NonZeroUsize, f: F) -> CircuitResult<R>
+    where
+        F: FnOnce(&mut Self, Qudit) -> CircuitResult<(R, Qudit)>,
+    {
+        let ancilla = self.make_zeroed_temp_register(n);
+        let mut indices = ancilla.indices.clone();
+        let (res, ancilla) = f(self, ancilla)?;
+        let mut returned = ancilla.indices.clone();
+        indices.sort_unstable();
+        returned.sort_unstable();
+        if indices != returned {
+            return Err(CircuitError::new(
+                "Ancilla register returned does not match the one allocated",
+            ));
         }
+        self.ancilla_checks
+            .push((self.pipeline.len(), ancilla.indices.clone()));
+        self.return_zeroed_temp_register(ancilla);
+        Ok(res)
     }
-}
 
-impl<P: Precision> PartialEq for RotationObject<P> {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::Floating(pa), Self::Floating(pb)) => pa.eq(pb),
-            (Self::PiRational(ra), Self::PiRational(rb)) => ra.eq(rb),
-            (_, _) => false,
+    /// Override the matrix used for the named standard gate (`"x"`, `"y"`, `"z"`, `"h"`, `"s"`,
+    /// `"t"`, or `"cnot"`, case insensitive). Gates applied afterwards use `mat` in place of the
+    /// ideal matrix, which is useful to model calibration errors.
+    pub fn set_gate_matrix(&mut self, name: &str, mat: Vec<Complex<P>>) -> CircuitResult<()> {
+        let name = name.to_lowercase();
+        let expected = match name.as_str() {
+            "x" | "y" | "z" | "h" | "s" | "t" => 4,
+            "cnot" => 16,
+            _ => {
+                let message = format!("No standard gate named {:?}", name);
+                return Err(CircuitError::new(message));
+            }
+        };
+        if mat.len() != expected {
+            let message = format!(
+                "Matrix for {:?} has {:?} entries versus expected {:?}",
+                name,
+                mat.len(),
+                expected
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ));
         }
+        self.gate_overrides.insert(name, mat);
+        Ok(())
     }
-}
 
-impl<P: Precision> Eq for UnitaryMatrixObject<P> {}
+    /// Remove any override for the named standard gate.
+    pub fn clear_gate_matrix(&mut self, name: &str) {
+        self.gate_overrides.remove(&name.to_lowercase());
+    }
 
-impl<P: Precision> Eq for BuilderCircuitObjectType<P> {}
+    fn make_standard_gate(
+        &self,
+        n: usize,
+        object: UnitaryMatrixObject<P>,
+    ) -> BuilderCircuitObject<P> {
+        let object = match self.gate_overrides.get(object.name()) {
+            Some(mat) => UnitaryMatrixObject::MAT(mat.clone()),
+            None => object,
+        };
+        BuilderCircuitObject {
+            n,
+            object: BuilderCircuitObjectType::Unitary(object),
+        }
+    }
 
-impl<P: Precision> Eq for RotationObject<P> {}
+    /// When set, single qubit circuit objects broadcast across a larger register are added to the
+    /// pipeline as a single op rather than one op per qubit.
+    pub fn set_single_op_broadcast(&mut self, single_op_broadcast: bool) {
+        self.single_op_broadcast = single_op_broadcast;
+    }
 
-fn hash_p<P: Precision, H: Hasher>(f: P, state: &mut H) {
-    format!("{}", f).hash(state)
-}
+    /// When set, running the circuit keeps a copy of the state just after each (collapsing)
+    /// measurement, available through `RunResult::post_measurement_state`. Each copy is the size of
+    /// the full state so this is off by default.
+    pub fn set_retain_post_measurement_states(&mut self, retain: bool) {
+        self.retain_post_measurement_states = retain;
+    }
 
-impl<P: Precision> Hash for BuilderCircuitObjectType<P> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        match self {
-            BuilderCircuitObjectType::Measurement(m) => {
-                state.write_i8(0);
-                m.hash(state)
+    /// Clear the circuit so the builder can be reused for a new, independent circuit. Qubit indices
+    /// start again from zero, so any registers or measurement handles from before the reset are
+    /// invalid. Settings such as gate matrix overrides and single op broadcasting are kept.
+    pub fn reset(&mut self) {
+        self.pipeline.clear();
+        self.n = 0;
+        self.zeroed_qubits.clear();
+        self.measurements = 0;
+        self.initial_states.clear();
+        self.ancilla_checks.clear();
+        self.classical_registers.clear();
+        self.allocations.clear();
+        self.measurement_conditions.clear();
+        self.tags.clear();
+        self.reused_qubits.clear();
+    }
+
+    /// Add a barrier across the qubits of `rs`, each listed once even if given more than once.
+    /// Barriers leave the state unchanged, but the optimization passes such as `fuse_single_qubit`
+    /// and `cancel_inverses` never fuse, cancel or move ops on those qubits across them.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    ///
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let q = b.qubit();
+    /// let q = b.x(q);
+    /// b.barrier(&[&q]);
+    /// let q = b.x(q);
+    /// assert_eq!(b.cancel_inverses(), 0);
+    /// ```
+    pub fn barrier(&mut self, rs: &[&Qudit]) {
+        let mut indices = Vec::<usize>::new();
+        rs.iter()
+            .flat_map(|r| r.indices.iter().cloned())
+            .for_each(|i| {
+                if !indices.contains(&i) {
+                    indices.push(i)
+                }
+            });
+        if !indices.is_empty() {
+            let obj = BuilderCircuitObject {
+                n: indices.len(),
+                object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Barrier),
+            };
+            self.pipeline.push((indices, obj));
+        }
+    }
+
+    /// Merge each run of adjacent diagonal ops, such as the controlled phases of a fourier
+    /// transform, into a single diagonal matrix on the qubits they act on, so long as that is at
+    /// most `max_qubits` qubits. Diagonal ops commute so this leaves the circuit unchanged while
+    /// using fewer ops. A diagonal op directly after a dense matrix op on the same qubits is
+    /// absorbed into that matrix instead. Identity ops added by `pad_to_depth` are kept. Returns the
+    /// number of ops removed.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    /// use qip::gates::qft;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let r = b.register(NonZeroUsize::new(4).unwrap());
+    /// let r = qft(&mut b, r)?;
+    /// assert_eq!(b.fuse_diagonal_runs(4), 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fuse_diagonal_runs(&mut self, max_qubits: usize) -> usize {
+        // Runs may not cross positions which are referred to elsewhere in the builder.
+        let boundaries = self.referenced_positions();
+        let pipeline = std::mem::take(&mut self.pipeline);
+        let old_len = pipeline.len();
+        let mut new_positions = HashMap::new();
+        let mut run: DiagonalRun<P> = vec![];
+        for (pos, (indices, obj)) in pipeline.into_iter().enumerate() {
+            if boundaries.contains(&pos) {
+                self.push_diagonal_run(&mut run);
+                new_positions.insert(pos, self.pipeline.len());
             }
-            BuilderCircuitObjectType::Unitary(u) => {
-                state.write_i8(1);
-                u.hash(state)
+            let diagonal = match &obj.object {
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Identity) => None,
+                BuilderCircuitObjectType::Unitary(u) if obj.n == indices.len() => u
+                    .matrix_data()
+                    .and_then(|data| diagonal_entries(indices.len(), &data)),
+                _ => None,
+            };
+            match diagonal {
+                Some(_)
+                    if run.is_empty()
+                        && !boundaries.contains(&pos)
+                        && self.absorb_diagonal(&indices, &obj) => {}
+                Some(diagonal) if indices.len() <= max_qubits => {
+                    let mut run_indices = run
+                        .iter()
+                        .flat_map(|(indices, _, _)| indices.iter())
+                        .chain(indices.iter())
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    run_indices.sort_unstable();
+                    run_indices.dedup();
+                    if run_indices.len() > max_qubits {
+                        self.push_diagonal_run(&mut run);
+                    }
+                    run.push((indices, obj, diagonal));
+                }
+                _ => {
+                    self.push_diagonal_run(&mut run);
+                    self.pipeline.push((indices, obj));
+                }
             }
         }
+        self.push_diagonal_run(&mut run);
+        new_positions.insert(old_len, self.pipeline.len());
+        self.move_referenced_positions(&new_positions);
+        old_len - self.pipeline.len()
     }
-}
 
-impl<P: Precision> Hash for UnitaryMatrixObject<P> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        match self {
-            UnitaryMatrixObject::X => state.write_i8(0),
-            UnitaryMatrixObject::Y => state.write_i8(1),
-            UnitaryMatrixObject::Z => state.write_i8(2),
-            UnitaryMatrixObject::H => state.write_i8(3),
-            UnitaryMatrixObject::S => state.write_i8(4),
-            UnitaryMatrixObject::T => state.write_i8(5),
-            UnitaryMatrixObject::CNOT => state.write_i8(6),
-            UnitaryMatrixObject::SWAP => state.write_i8(7),
-            UnitaryMatrixObject::Rz(rot) => {
-                state.write_i8(8);
-                rot.hash(state);
+    /// Fuse `Rz` rotations applied one after another to the same qubits into a single rotation
+    /// by summing their angles, which is exact for rotations given as ratios of pi. Rotations
+    /// which sum to a multiple of 2pi are removed, leaving a global phase of pi in their place
+    /// if it is an odd multiple. Returns the number of ops removed from the pipeline.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let q = b.qubit();
+    /// let q = b.rz(q, 0.3);
+    /// let q = b.rz(q, -0.3);
+    /// assert_eq!(b.fuse_rotations(), 2);
+    /// assert_eq!(b.pipeline_depth(), 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fuse_rotations(&mut self) -> usize {
+        // Rotations may not be fused across positions referred to elsewhere in the builder.
+        let boundaries = self.referenced_positions();
+        let pipeline = std::mem::take(&mut self.pipeline);
+        let old_len = pipeline.len();
+        let mut new_positions = HashMap::new();
+        // The position of the last op on each qubit, if it is a rotation.
+        let mut last_rotation: HashMap<usize, usize> = HashMap::new();
+        for (pos, (indices, obj)) in pipeline.into_iter().enumerate() {
+            if boundaries.contains(&pos) {
+                last_rotation.clear();
+                new_positions.insert(pos, self.pipeline.len());
             }
-            UnitaryMatrixObject::GlobalPhase(rot) => {
-                state.write_i8(9);
-                rot.hash(state);
+            let (indices, obj) = match &obj.object {
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Rz(theta)) => {
+                    let previous = indices
+                        .first()
+                        .and_then(|i| last_rotation.get(i))
+                        .cloned()
+                        .filter(|p| {
+                            self.pipeline[*p].0 == indices
+                                && indices.iter().all(|i| last_rotation.get(i) == Some(p))
+                        });
+                    match previous {
+                        Some(p) => {
+                            if let BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Rz(
+                                previous,
+                            )) = &mut self.pipeline[p].1.object
+                            {
+                                *previous = previous.clone() + theta.clone();
+                            }
+                            continue;
+                        }
+                        None => {
+                            indices.iter().for_each(|i| {
+                                last_rotation.insert(*i, self.pipeline.len());
+                            });
+                            (indices, obj)
+                        }
+                    }
+                }
+                _ => {
+                    indices.iter().for_each(|i| {
+                        last_rotation.remove(i);
+                    });
+                    (indices, obj)
+                }
+            };
+            self.pipeline.push((indices, obj));
+        }
+        new_positions.insert(old_len, self.pipeline.len());
+
+        // Remove the rotations which are now full turns, each of which is a phase of -1 per qubit.
+        let fused = std::mem::take(&mut self.pipeline);
+        let mut fused_positions = Vec::with_capacity(fused.len() + 1);
+        for (indices, obj) in fused {
+            fused_positions.push(self.pipeline.len());
+            let turns = match &obj.object {
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Rz(theta)) => {
+                    theta.full_turns()
+                }
+                _ => None,
+            };
+            match turns {
+                Some(turns) if (turns * indices.len() as i64) % 2 == 0 => {}
+                Some(_) => {
+                    let obj = BuilderCircuitObject {
+                        n: obj.n,
+                        object: BuilderCircuitObjectType::Unitary(
+                            UnitaryMatrixObject::GlobalPhase(RotationObject::PiRational(
+                                Ratio::from_integer(1),
+                            )),
+                        ),
+                    };
+                    self.pipeline.push((indices, obj));
+                }
+                None => self.pipeline.push((indices, obj)),
             }
-            UnitaryMatrixObject::MAT(data) => {
-                state.write_i8(10);
-                data.iter().for_each(|c| {
-                    hash_p(c.re, state);
-                    hash_p(c.im, state);
+        }
+        fused_positions.push(self.pipeline.len());
+        new_positions
+            .values_mut()
+            .for_each(|pos| *pos = fused_positions[*pos]);
+        self.move_referenced_positions(&new_positions);
+        old_len - self.pipeline.len()
+    }
+
+    /// Multiply each run of single qubit unitaries applied one after another to the same qubit
+    /// into a single matrix op. Ops are never moved past other ops on the same qubit, so the
+    /// circuit is unchanged. Global phases are left alone as they are tracked separately from the
+    /// state, as is the identity padding added by `pad_to_depth`. Returns the number of ops removed
+    /// from the pipeline.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    ///
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let q = b.qubit();
+    /// let q = b.h(q);
+    /// let q = b.x(q);
+    /// let q = b.h(q);
+    /// assert_eq!(b.fuse_single_qubit(), 2);
+    /// assert_eq!(b.pipeline_depth(), 1);
+    /// ```
+    pub fn fuse_single_qubit(&mut self) -> usize {
+        // Runs may not cross positions which are referred to elsewhere in the builder.
+        let boundaries = self.referenced_positions();
+        let pipeline = std::mem::take(&mut self.pipeline);
+        let old_len = pipeline.len();
+        let mut new_positions = HashMap::new();
+        // The position and matrix of the last op on each qubit, if it is a single qubit unitary.
+        let mut last_single: HashMap<usize, (usize, Vec<Complex<P>>)> = HashMap::new();
+        for (pos, (indices, obj)) in pipeline.into_iter().enumerate() {
+            if boundaries.contains(&pos) {
+                last_single.clear();
+                new_positions.insert(pos, self.pipeline.len());
+            }
+            let data = match &obj.object {
+                BuilderCircuitObjectType::Unitary(
+                    UnitaryMatrixObject::GlobalPhase(_) | UnitaryMatrixObject::Identity,
+                ) => None,
+                BuilderCircuitObjectType::Unitary(u) if obj.n == 1 && indices.len() == 1 => {
+                    u.matrix_data()
+                }
+                _ => None,
+            };
+            match data {
+                Some(data) => match last_single.remove(&indices[0]) {
+                    Some((p, previous)) => {
+                        let product = matmul(&data, &previous);
+                        self.pipeline[p].1 = Self::vec_matrix_to_circuitobject(1, product.clone());
+                        last_single.insert(indices[0], (p, product));
+                    }
+                    None => {
+                        last_single.insert(indices[0], (self.pipeline.len(), data));
+                        self.pipeline.push((indices, obj));
+                    }
+                },
+                None => {
+                    indices.iter().for_each(|i| {
+                        last_single.remove(i);
+                    });
+                    self.pipeline.push((indices, obj));
+                }
+            }
+        }
+        new_positions.insert(old_len, self.pipeline.len());
+        self.move_referenced_positions(&new_positions);
+        old_len - self.pipeline.len()
+    }
+
+    /// Remove pairs of unitaries applied one after another to the same qubits which undo each
+    /// other, such as two Xs or two CNOTs, when nothing else acts on those qubits in between. Pairs
+    /// left adjacent by a removal are removed as well. Identity ops added by `pad_to_depth` are
+    /// never removed. Returns the number of ops removed from the pipeline.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    ///
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let q = b.qubit();
+    /// let q = b.h(q);
+    /// let q = b.x(q);
+    /// let q = b.x(q);
+    /// assert_eq!(b.cancel_inverses(), 2);
+    /// assert_eq!(b.pipeline_depth(), 1);
+    /// ```
+    pub fn cancel_inverses(&mut self) -> usize {
+        // Pairs may not cross positions which are referred to elsewhere in the builder.
+        let boundaries = self.referenced_positions();
+        let pipeline = std::mem::take(&mut self.pipeline);
+        let old_len = pipeline.len();
+        let mut boundary_positions = HashMap::new();
+        let mut kept: Vec<Option<(Vec<usize>, BuilderCircuitObject<P>)>> = vec![];
+        // The positions in `kept` of the remaining ops on each qubit, latest last.
+        let mut ops_on: HashMap<usize, Vec<usize>> = HashMap::new();
+        let tolerance = P::epsilon().sqrt();
+        for (pos, (indices, obj)) in pipeline.into_iter().enumerate() {
+            if boundaries.contains(&pos) {
+                ops_on.clear();
+                boundary_positions.insert(pos, kept.len());
+            }
+            let previous = indices
+                .first()
+                .and_then(|i| ops_on.get(i))
+                .and_then(|ops| ops.last())
+                .cloned()
+                .filter(|p| {
+                    indices
+                        .iter()
+                        .all(|i| ops_on.get(i).and_then(|ops| ops.last()) == Some(p))
+                });
+            let cancels = previous.and_then(|p| kept[p].as_ref().map(|entry| (p, entry)));
+            let cancels = cancels.and_then(|(p, (previous_indices, previous_obj))| {
+                let data = match (&previous_obj.object, &obj.object) {
+                    (BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Identity), _)
+                    | (_, BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Identity)) => None,
+                    (
+                        BuilderCircuitObjectType::Unitary(a),
+                        BuilderCircuitObjectType::Unitary(b),
+                    ) if *previous_indices == indices
+                        && obj.n == indices.len()
+                        && previous_obj.n == obj.n =>
+                    {
+                        a.matrix_data().zip(b.matrix_data())
+                    }
+                    _ => None,
+                };
+                data.filter(|(a, b)| {
+                    let side = 1 << obj.n;
+                    matmul(b, a).into_iter().enumerate().all(|(i, c)| {
+                        let expected = if i / side == i % side {
+                            Complex::one()
+                        } else {
+                            Complex::zero()
+                        };
+                        (c - expected).norm() < tolerance
+                    })
                 })
+                .map(|_| p)
+            });
+            match cancels {
+                Some(p) => {
+                    kept[p] = None;
+                    indices.iter().for_each(|i| {
+                        ops_on.get_mut(i).unwrap().pop();
+                    });
+                }
+                None => {
+                    indices
+                        .iter()
+                        .for_each(|i| ops_on.entry(*i).or_default().push(kept.len()));
+                    kept.push(Some((indices, obj)));
+                }
             }
         }
+        // Map positions in `kept` to positions once the cancelled ops are dropped.
+        let mut compacted = Vec::with_capacity(kept.len() + 1);
+        kept.into_iter().for_each(|entry| {
+            compacted.push(self.pipeline.len());
+            self.pipeline.extend(entry);
+        });
+        compacted.push(self.pipeline.len());
+        let mut new_positions = boundary_positions
+            .into_iter()
+            .map(|(pos, k)| (pos, compacted[k]))
+            .collect::<HashMap<_, _>>();
+        new_positions.insert(old_len, self.pipeline.len());
+        self.move_referenced_positions(&new_positions);
+        old_len - self.pipeline.len()
     }
-}
 
-impl<P: Precision> Hash for RotationObject<P> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        match self {
-            // Grossly inefficient but also don't hash floats.
-            RotationObject::Floating(f) => hash_p(*f, state),
-            RotationObject::PiRational(r) => r.hash(state),
+    /// Positions in the pipeline which are referred to by ancilla checks, tags, reused temporary
+    /// qubits, or ops applied on measurements, which optimizations must keep as boundaries.
+    fn referenced_positions(&self) -> HashSet<usize> {
+        self.ancilla_checks
+            .iter()
+            .map(|(pos, _)| *pos)
+            .chain(self.tags.values().cloned())
+            .chain(self.reused_qubits.iter().map(|(pos, _)| *pos))
+            .chain(
+                self.measurement_conditions
+                    .iter()
+                    .flat_map(|(range, _, _)| [range.start, range.end]),
+            )
+            .collect()
+    }
+
+    /// Update the positions referred to elsewhere in the builder after the pipeline was rewritten,
+    /// `new_positions` must contain every position from `referenced_positions`.
+    fn move_referenced_positions(&mut self, new_positions: &HashMap<usize, usize>) {
+        self.ancilla_checks
+            .iter_mut()
+            .for_each(|(pos, _)| *pos = new_positions[pos]);
+        self.tags
+            .values_mut()
+            .for_each(|pos| *pos = new_positions[pos]);
+        self.reused_qubits
+            .iter_mut()
+            .for_each(|(pos, _)| *pos = new_positions[pos]);
+        self.measurement_conditions
+            .iter_mut()
+            .for_each(|(range, _, _)| {
+                *range = new_positions[&range.start]..new_positions[&range.end]
+            });
+    }
+
+    /// Absorb the diagonal `obj` on `indices` into the last op in the pipeline if that is a dense
+    /// matrix on the same indices in the same order. Returns whether it was absorbed.
+    fn absorb_diagonal(&mut self, indices: &[usize], obj: &BuilderCircuitObject<P>) -> bool {
+        let fused = match (self.pipeline.last(), &obj.object) {
+            (
+                Some((
+                    last_indices,
+                    BuilderCircuitObject {
+                        n,
+                        object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::MAT(data)),
+                    },
+                )),
+                BuilderCircuitObjectType::Unitary(u),
+            ) if last_indices == indices && *n == indices.len() => u
+                .matrix_data()
+                .and_then(|diagonal| make_matrix_op(indices.to_vec(), diagonal).ok())
+                .zip(make_matrix_op(indices.to_vec(), data.clone()).ok())
+                .and_then(|(diagonal, op)| fuse_diagonal(&op, &diagonal)),
+            _ => None,
+        };
+        match fused {
+            Some(MatrixOp::Matrix(_, data)) => {
+                let last = self.pipeline.last_mut().unwrap();
+                last.1 = Self::vec_matrix_to_circuitobject(indices.len(), data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Add a run of diagonal ops to the pipeline as a single op, emptying `run`.
+    fn push_diagonal_run(&mut self, run: &mut DiagonalRun<P>) {
+        if run.len() <= 1 {
+            self.pipeline
+                .extend(run.drain(..).map(|(indices, obj, _)| (indices, obj)));
+            return;
+        }
+        let mut run_indices = run
+            .iter()
+            .flat_map(|(indices, _, _)| indices.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        run_indices.sort_unstable();
+        run_indices.dedup();
+        let n = run_indices.len();
+        let side = 1 << n;
+        // Both the run matrix and each op's diagonal are big-endian over their indices.
+        let diagonal = (0..side).map(|x| {
+            run.iter()
+                .map(|(indices, _, diagonal)| {
+                    let sub = indices.iter().fold(0, |acc, index| {
+                        let k = run_indices.binary_search(index).unwrap();
+                        (acc << 1) | ((x >> (n - 1 - k)) & 1)
+                    });
+                    diagonal[sub]
+                })
+                .product::<Complex<P>>()
+        });
+        let mut data = vec![Complex::zero(); side * side];
+        diagonal
+            .enumerate()
+            .for_each(|(i, c)| data[i * side + i] = c);
+        run.clear();
+        let obj = Self::vec_matrix_to_circuitobject(n, data);
+        self.pipeline.push((run_indices, obj));
+    }
+
+    /// Pad the circuit with identity ops so that every qubit has at least `target_depth` ops
+    /// applied to it, which aligns the qubit timelines when rendering or scheduling. Identity ops
+    /// are skipped when running the circuit.
+    pub fn pad_to_depth(&mut self, target_depth: usize) {
+        let mut depths = vec![0; self.n];
+        self.pipeline
+            .iter()
+            .flat_map(|(indices, _)| indices)
+            .for_each(|i| depths[*i] += 1);
+        depths
+            .into_iter()
+            .enumerate()
+            .filter(|(_, depth)| *depth < target_depth)
+            .for_each(|(i, depth)| {
+                (depth..target_depth).for_each(|_| {
+                    let co = BuilderCircuitObject {
+                        n: 1,
+                        object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Identity),
+                    };
+                    self.pipeline.push((vec![i], co));
+                })
+            });
+    }
+
+    /// Allocate a classical register of `n` bits which measurements can be written into with
+    /// `measure_into`. All bits start as `0`.
+    pub fn creg(&mut self, n: NonZeroUsize) -> ClassicalRegister {
+        self.classical_registers.push(vec![]);
+        ClassicalRegister {
+            id: self.classical_registers.len() - 1,
+            n: n.get(),
+        }
+    }
+
+    /// Measure `r` and write the result into the bits of `creg` starting at `offset`, with the
+    /// first qubit of `r` going to bit `offset`. Later measurements overwrite the bits written by
+    /// earlier ones, as with `measure q -> c` in QASM.
+    pub fn measure_into(
+        &mut self,
+        r: Qudit,
+        creg: &ClassicalRegister,
+        offset: usize,
+    ) -> CircuitResult<Qudit> {
+        if offset + r.n() > creg.n {
+            let message = format!(
+                "Cannot write {:?} bits at offset {:?} into a classical register of {:?} bits",
+                r.n(),
+                offset,
+                creg.n
+            );
+            return Err(CircuitError::new(message));
         }
+        let n = r.n();
+        let (r, handle) = self.measure(r);
+        self.classical_registers[creg.id].push((handle, offset, n));
+        Ok(r)
+    }
+
+    /// Reset `r` to `|0...0>` by measuring it and discarding the result. The rest of the state is
+    /// left as it would be after the measurement.
+    pub fn reset_to_zero(&mut self, r: Qudit) -> Qudit {
+        let obj = BuilderCircuitObject {
+            n: r.n(),
+            object: BuilderCircuitObjectType::Measurement(MeasurementObject::Reset),
+        };
+        self.pipeline.push((r.indices.clone(), obj));
+        r
     }
 }
 
-/// Represents a type of measurement in the circuit.
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
-pub enum MeasurementObject {
-    /// Performs a single measurement and collapses the wavefunction.
-    Measurement,
-    /// Simulates a series of measurements and returns a probability distribution over results.
-    /// Does not collapse the wavefunction.
-    StochasticMeasurement,
+/// The nonzero amplitudes of a register's initial state, keyed by basis state.
+type SparseState<P> = Vec<(usize, Complex<P>)>;
+
+/// Keep the nonzero amplitudes of a dense initial state along with their basis states.
+fn sparse_entries<P: Precision>(state: Vec<Complex<P>>) -> SparseState<P> {
+    state
+        .into_iter()
+        .enumerate()
+        .filter(|(_, c)| !c.is_zero())
+        .collect()
 }
 
-/// Represents the result of a measurement on the circuit.
-#[derive(Debug, Clone)]
-pub enum MeasurementResults<P: Precision> {
-    /// The result of a single measurement on selected qubits, returns the measurement as well as
-    /// the likelyhood of that measurement taking place.
-    Single(usize, P),
-    /// The probability of each measurement indexed by the measurement itself.
-    Stochastic(Vec<P>),
+/// Pick a measurement outcome with the probabilities `ps` using `rng`. Outcomes with zero
+/// probability are never picked.
+fn sample_outcome<P: Precision, R: Rng>(ps: &[P], rng: &mut R) -> MeasuredCondition<P> {
+    let mut r = P::from(rng.gen::<f64>()).unwrap() * ps.iter().cloned().sum::<P>();
+    let measured = ps
+        .iter()
+        .position(|p| {
+            r -= *p;
+            r < P::zero()
+        })
+        // Rounding can leave a little of `r` behind, so fall back to the last possible outcome.
+        .or_else(|| ps.iter().rposition(|p| *p > P::zero()))
+        .unwrap_or(ps.len() - 1);
+    MeasuredCondition {
+        measured,
+        prob: Some(ps[measured]),
+    }
 }
 
-/// A series of measurement results at the end of the circuit.
-#[derive(Debug)]
-pub struct Measurements<P: Precision> {
-    measurements: Vec<MeasurementResults<P>>,
+/// Diagonal ops waiting to be fused, along with their diagonal entries.
+type DiagonalRun<P> = Vec<(Vec<usize>, BuilderCircuitObject<P>, Vec<Complex<P>>)>;
+
+/// Get the diagonal of the `n` qubit matrix `data`, if all other entries are zero.
+fn diagonal_entries<P: Precision>(n: usize, data: &[Complex<P>]) -> Option<Vec<Complex<P>>> {
+    let side = 1 << n;
+    let off_diagonal_zero = data
+        .iter()
+        .enumerate()
+        .all(|(i, c)| i / side == i % side || c.is_zero());
+    off_diagonal_zero.then(|| (0..side).map(|i| data[i * side + i]).collect())
 }
 
-impl<P: Precision> Measurements<P> {
-    /// Get a measurement result given a handle.
-    pub fn get_measurement(&self, handle: MeasurementHandle) -> (usize, P) {
-        match &self.measurements[handle.id] {
-            MeasurementResults::Single(val, prob) => (*val, *prob),
-            MeasurementResults::Stochastic(_) => unreachable!(),
-        }
-    }
-    /// Get a stochastic measurement result given a handle.
-    pub fn get_stochastic_measurement(&self, handle: StochasticMeasurementHandle) -> &[P] {
-        match &self.measurements[handle.id] {
-            MeasurementResults::Single(_, _) => unreachable!(),
-            MeasurementResults::Stochastic(probs) => probs.as_slice(),
-        }
+/// A handle to a register of classical bits allocated with `LocalBuilder::creg`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassicalRegister {
+    id: usize,
+    n: usize,
+}
+
+impl ClassicalRegister {
+    /// The number of bits in the register.
+    pub fn n(&self) -> usize {
+        self.n
     }
 }
 
-impl<P: Precision> CircuitBuilder for LocalBuilder<P> {
-    type Register = Qudit;
-    type CircuitObject = BuilderCircuitObject<P>;
-    type StateCalculation = (Vec<Complex<P>>, Measurements<P>);
+/// The register implementation for the LocalBuilder.
+#[derive(Debug)]
+pub struct Qudit {
+    indices: Vec<usize>,
+}
 
+impl QubitRegister for Qudit {
     fn n(&self) -> usize {
-        self.n
+        self.indices.len()
     }
 
-    fn register(&mut self, n: NonZeroUsize) -> Self::Register {
-        let n: usize = n.into();
-        let r = Self::Register::new_from_iter(self.n..self.n + n).unwrap();
-        self.n += n;
-        r
+    fn indices(&self) -> &[usize] {
+        self.indices.as_ref()
     }
+}
 
-    fn merge_two_registers(&mut self, r1: Self::Register, r2: Self::Register) -> Self::Register {
-        Self::Register::new_from_iter(r1.indices.into_iter().chain(r2.indices.into_iter())).unwrap()
+impl Qudit {
+    /// Get the circuit-wide index of the qubit at position `relative` in this register, or `None`
+    /// if the register has `relative` or fewer qubits.
+    pub fn physical_index(&self, relative: usize) -> Option<usize> {
+        self.indices.get(relative).copied()
     }
 
-    fn split_register_relative<It>(
-        &mut self,
-        r: Self::Register,
-        indices: It,
-    ) -> SplitResult<Self::Register>
+    fn new<It>(indices: It) -> Option<Self>
     where
-        It: IntoIterator<Item = usize>,
+        It: Into<Vec<usize>>,
     {
-        let selected_indices = indices.into_iter().filter_map(|i| {
-            if i <= r.indices.len() {
-                Some(r.indices[i])
-            } else {
-                None
-            }
-        });
-        let r1 = Self::Register::new_from_iter(selected_indices);
-
-        let remaining_indices = r.indices.into_iter().filter(|oi| match &r1 {
-            Some(r1) => !r1.indices.contains(oi),
-            None => true,
-        });
-        let r2 = Self::Register::new_from_iter(remaining_indices);
-
-        match (r1, r2) {
-            (Some(r1), None) => SplitResult::SELECTED(r1),
-            (None, Some(r2)) => SplitResult::UNSELECTED(r2),
-            (Some(r1), Some(r2)) => SplitResult::SPLIT(r1, r2),
-            (None, None) => unreachable!(),
-        }
-    }
-
-    fn apply_circuit_object(
-        &mut self,
-        r: Self::Register,
-        c: Self::CircuitObject,
-    ) -> CircuitResult<Self::Register> {
-        if c.n == 1 || c.n == r.n() {
-            if c.n == 1 && r.n() > 1 {
-                // Do broadcasting
-                let rs = self.split_all_register(r);
-                rs.iter()
-                    .for_each(|r| self.pipeline.push((r.indices.clone(), c.clone())));
-                Ok(self.merge_registers(rs.into_iter()).unwrap())
-            } else {
-                // Normal application.
-                self.pipeline.push((r.indices.clone(), c));
-                Ok(r)
-            }
+        let indices = indices.into();
+        if !indices.is_empty() {
+            Some(Self { indices })
         } else {
-            Err(CircuitError::new(
-                "Matrix has incorrect N and cannot be broadcast",
-            ))
+            None
         }
     }
-
-    fn calculate_state_with_init<'a, It>(&mut self, it: It) -> Self::StateCalculation
+    fn new_from_iter<It>(indices: It) -> Option<Self>
     where
-        Self::Register: 'a,
-        It: IntoIterator<Item = (&'a Self::Register, usize)>,
+        It: Iterator<Item = usize>,
     {
-        let n = self.n();
-        let mut state = vec![Complex::zero(); 1 << n];
-        let arena = state.clone();
+        let indices = indices.into_iter().collect::<Vec<_>>();
+        Self::new(indices)
+    }
+}
 
-        let mut initial_index = 0;
-        it.into_iter()
-            .flat_map(|(r, x)| {
-                let rn = r.n();
-                r.indices
-                    .iter()
-                    .rev()
-                    .cloned()
-                    .enumerate()
-                    .map(move |(ri, i)| (n - 1 - i, (x >> (rn - 1 - ri)) & 1))
-            })
-            .for_each(|(index, bit)| initial_index |= bit << index);
-        state[initial_index] = Complex::one();
+/// A pipeline object for the LocalBuilder.
+#[derive(Debug, Clone)]
+pub struct BuilderCircuitObject<P: Precision> {
+    pub(crate) n: usize,
+    pub(crate) object: BuilderCircuitObjectType<P>,
+}
 
-        let (state, _, measurements) = self
-            .pipeline
-            .iter()
-            .try_fold(
-                (state, arena, vec![]),
-                |(state, mut arena, mut measurements), (indices, obj)| -> CircuitResult<_> {
-                    let BuilderCircuitObject { object, .. } = obj;
-                    match object {
-                        // Global phases do not affect state.
-                        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::GlobalPhase(_)) => {}
-                        // Other unitaries do though.
-                        BuilderCircuitObjectType::Unitary(object) => {
-                            let indices = indices.clone();
-                            let l = Complex::one();
-                            let o = Complex::zero();
-                            let i = Complex::i();
-                            let uop = match object {
-                                UnitaryMatrixObject::X => make_matrix_op(indices, vec![o, l, l, o]),
-                                UnitaryMatrixObject::Y => {
-                                    make_matrix_op(indices, vec![o, -i, i, o])
-                                }
-                                UnitaryMatrixObject::Z => {
-                                    make_matrix_op(indices, vec![l, o, o, -l])
-                                }
-                                UnitaryMatrixObject::H => {
-                                    let nl = Complex::one()
-                                        * P::from(std::f64::consts::FRAC_1_SQRT_2).unwrap();
-                                    make_matrix_op(indices, vec![nl, nl, nl, -nl])
-                                }
-                                UnitaryMatrixObject::S => make_matrix_op(indices, vec![l, o, o, i]),
-                                UnitaryMatrixObject::T => {
-                                    let t = Complex::from_polar(
-                                        P::one(),
-                                        P::from(std::f64::consts::FRAC_PI_4).unwrap(),
-                                    );
-                                    make_matrix_op(indices, vec![l, o, o, t])
-                                }
-                                UnitaryMatrixObject::CNOT => {
-                                    let cindex = vec![indices[0]];
-                                    let indices = indices[1..].to_vec();
-                                    make_control_op(
-                                        cindex,
-                                        make_matrix_op(indices, vec![o, l, l, o])?,
-                                    )
-                                }
-                                UnitaryMatrixObject::MAT(data) => {
-                                    make_matrix_op(indices, data.clone())
-                                }
-                                UnitaryMatrixObject::SWAP => {
-                                    let n = indices.len();
-                                    assert_eq!(n % 2, 0);
-                                    let x = n / 2;
-                                    let a_indices = indices[..x].to_vec();
-                                    let b_indices = indices[x..].to_vec();
-                                    make_swap_op(a_indices, b_indices)
-                                }
-                                UnitaryMatrixObject::Rz(theta) => {
-                                    let theta = match theta {
-                                        RotationObject::Floating(p) => *p,
-                                        RotationObject::PiRational(r) => {
-                                            r.to_f64().and_then(|f| P::from(f)).unwrap()
-                                        }
-                                    };
-                                    let h_theta = theta * P::from(0.5).unwrap();
-                                    make_matrix_op(
-                                        indices,
-                                        vec![
-                                            Complex::from_polar(P::one(), -h_theta),
-                                            Complex::zero(),
-                                            Complex::zero(),
-                                            Complex::from_polar(P::one(), h_theta),
-                                        ],
-                                    )
-                                }
-                                UnitaryMatrixObject::GlobalPhase(_) => unreachable!(),
-                            }?;
-                            apply_op_overwrite(n, &uop, &state, &mut arena, 0, 0);
-                        }
-                        BuilderCircuitObjectType::Measurement(object) => match object {
-                            MeasurementObject::Measurement => {
-                                let (measured, p) =
-                                    measure(n, indices, &state, &mut arena, None, None);
-                                measurements.push(MeasurementResults::Single(measured, p));
-                            }
-                            MeasurementObject::StochasticMeasurement => {
-                                let ps = measure_probs(n, indices, &state, None);
-                                measurements.push(MeasurementResults::Stochastic(ps));
-                            }
-                        },
-                    }
+/// The type of pipeline object for LocalBuilder.
+#[derive(Debug, Clone)]
+pub enum BuilderCircuitObjectType<P: Precision> {
+    /// A unitary operation on the circuit.
+    Unitary(UnitaryMatrixObject<P>),
+    /// A measurement operation on the circuit.
+    Measurement(MeasurementObject),
+}
 
-                    Ok((arena, state, measurements))
-                },
-            )
-            .unwrap();
-        (state, Measurements { measurements })
-    }
+/// The type of unitary matrix for LocalBuilder.
+#[derive(Debug, Clone)]
+pub enum UnitaryMatrixObject<P: Precision> {
+    /// A pauli X gate.
+    X,
+    /// A pauli Y gate.
+    Y,
+    /// A pauli Z gate.
+    Z,
+    /// A hadamard gate.
+    H,
+    /// A conditional phase gate by pi/4.
+    S,
+    /// A conditional phase gate by pi/8.
+    T,
+    /// A controlled pauli X gate, first qubit is control.
+    CNOT,
+    /// A swap gate between two qubits.
+    SWAP,
+    /// A conditional phase gate by RotationObject phase.
+    Rz(RotationObject<P>),
+    /// A Generic Matrix
+    MAT(Vec<Complex<P>>),
+    /// A Generic Matrix whose entries are computed on demand.
+    Generated(GeneratedMatrix<P>),
+    /// A Generic Matrix given by rows of `(column, value)` entries, ordered as for `MAT`.
+    Sparse(Vec<Vec<(usize, Complex<P>)>>),
+    /// A global phase applied on this qubit.
+    /// This normally doesn't matter but can have an effect
+    /// when we are conditioning them.
+    GlobalPhase(RotationObject<P>),
+    /// An identity gate, used to pad out circuits.
+    Identity,
+    /// An identity across several qubits which optimization passes may not move ops across.
+    Barrier,
 }
 
-impl<P: Precision> UnitaryBuilder<P> for LocalBuilder<P> {
-    fn vec_matrix_to_circuitobject(n: usize, data: Vec<Complex<P>>) -> Self::CircuitObject {
-        Self::CircuitObject {
-            n,
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::MAT(data)),
-        }
-    }
+/// A matrix on `n` qubits whose entries are computed by a function of `(row, col)` rather than
+/// stored, entries are ordered as for `UnitaryMatrixObject::MAT`.
+#[derive(Clone)]
+pub struct GeneratedMatrix<P: Precision> {
+    n: usize,
+    f: MatrixGenerator<Complex<P>>,
 }
 
-impl<P: Precision> CliffordTBuilder<P> for LocalBuilder<P> {
-    fn make_x(&self) -> Self::CircuitObject {
-        Self::CircuitObject {
-            n: 1,
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::X),
-        }
+impl<P: Precision> GeneratedMatrix<P> {
+    /// Make a new generated matrix on `n` qubits.
+    pub fn new<F>(n: usize, f: F) -> Self
+    where
+        F: Fn(usize, usize) -> Complex<P> + Send + Sync + 'static,
+    {
+        Self { n, f: Arc::new(f) }
     }
-    fn make_y(&self) -> Self::CircuitObject {
-        Self::CircuitObject {
-            n: 1,
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Y),
-        }
+
+    /// Compute the dense matrix data.
+    pub fn dense(&self) -> Vec<Complex<P>> {
+        let side = 1 << self.n;
+        (0..side * side)
+            .map(|i| (self.f)(i / side, i % side))
+            .collect()
     }
-    fn make_z(&self) -> Self::CircuitObject {
-        Self::CircuitObject {
-            n: 1,
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Z),
-        }
+
+    /// The conjugate transpose of the matrix.
+    fn dagger(&self) -> Self {
+        let f = self.f.clone();
+        Self::new(self.n, move |row, col| f(col, row).conj())
     }
-    fn make_h(&self) -> Self::CircuitObject {
-        Self::CircuitObject {
-            n: 1,
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::H),
-        }
+}
+
+impl<P: Precision> fmt::Debug for GeneratedMatrix<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeneratedMatrix")
+            .field("n", &self.n)
+            .finish()
     }
-    fn make_s(&self) -> Self::CircuitObject {
-        Self::CircuitObject {
-            n: 1,
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::S),
+}
+
+/// Represents phase floats.
+#[derive(Debug, Clone)]
+pub enum RotationObject<P: Precision> {
+    /// A rotation represented by a floating point precision.
+    Floating(P),
+    /// A rotation represented by a fixed ratio times pi.
+    PiRational(Ratio<i64>),
+}
+
+impl<P: Precision> RotationObject<P> {
+    /// The rotation in radians.
+    fn radians(&self) -> P {
+        match self {
+            RotationObject::Floating(p) => *p,
+            RotationObject::PiRational(r) => {
+                r.to_f64().and_then(|f| P::from(f)).unwrap()
+                    * P::from(std::f64::consts::PI).unwrap()
+            }
         }
     }
-    fn make_t(&self) -> Self::CircuitObject {
-        Self::CircuitObject {
-            n: 1,
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::T),
+
+    /// The number of full turns in the rotation, if it is a multiple of 2pi.
+    fn full_turns(&self) -> Option<i64> {
+        match self {
+            RotationObject::Floating(p) => {
+                let turns = *p / P::from(2.0 * std::f64::consts::PI).unwrap();
+                let rounded = turns.round();
+                ((turns - rounded).abs() < P::epsilon().sqrt())
+                    .then(|| rounded.to_i64())
+                    .flatten()
+            }
+            RotationObject::PiRational(r) => {
+                let turns = r / 2;
+                turns.is_integer().then(|| turns.to_integer())
+            }
         }
     }
-    fn make_cnot(&self) -> Self::CircuitObject {
-        Self::CircuitObject {
-            n: 2,
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::CNOT),
+}
+
+impl<P: Precision> BuilderCircuitObjectType<P> {
+    /// A short human readable name for the object.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuilderCircuitObjectType::Unitary(u) => u.name(),
+            BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement) => "measure",
+            BuilderCircuitObjectType::Measurement(MeasurementObject::StochasticMeasurement) => {
+                "measure_stochastic"
+            }
+            BuilderCircuitObjectType::Measurement(MeasurementObject::Reset) => "reset",
         }
     }
 }
 
-impl<P: Precision> TemporaryRegisterBuilder for LocalBuilder<P> {
-    fn make_zeroed_temp_qubit(&mut self) -> Self::Register {
-        if let Some(r) = self.zeroed_qubits.pop() {
-            r
-        } else {
-            self.qubit()
+impl<P: Precision> UnitaryMatrixObject<P> {
+    /// A short human readable name for the object.
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnitaryMatrixObject::X => "x",
+            UnitaryMatrixObject::Y => "y",
+            UnitaryMatrixObject::Z => "z",
+            UnitaryMatrixObject::H => "h",
+            UnitaryMatrixObject::S => "s",
+            UnitaryMatrixObject::T => "t",
+            UnitaryMatrixObject::CNOT => "cnot",
+            UnitaryMatrixObject::SWAP => "swap",
+            UnitaryMatrixObject::Rz(_) => "rz",
+            UnitaryMatrixObject::MAT(_) => "mat",
+            UnitaryMatrixObject::Generated(_) => "generated",
+            UnitaryMatrixObject::Sparse(_) => "sparse",
+            UnitaryMatrixObject::GlobalPhase(_) => "global_phase",
+            UnitaryMatrixObject::Identity => "identity",
+            UnitaryMatrixObject::Barrier => "barrier",
         }
     }
 
-    fn return_zeroed_temp_register(&mut self, r: Self::Register) {
-        let rs = self.split_all_register(r);
-        self.zeroed_qubits.extend(rs.into_iter());
+    /// The dense matrix data for this object, or None if it has no fixed size representation.
+    /// Single Qubit matrix is organized as  |0><0|, |0><1|, |1><0|, |1><1|
+    pub fn matrix_data(&self) -> Option<Vec<Complex<P>>> {
+        let l = Complex::one();
+        let o = Complex::zero();
+        let i = Complex::i();
+        match self {
+            UnitaryMatrixObject::X => Some(vec![o, l, l, o]),
+            UnitaryMatrixObject::Y => Some(vec![o, -i, i, o]),
+            UnitaryMatrixObject::Z => Some(vec![l, o, o, -l]),
+            UnitaryMatrixObject::H => {
+                let nl = Complex::one() * P::from(std::f64::consts::FRAC_1_SQRT_2).unwrap();
+                Some(vec![nl, nl, nl, -nl])
+            }
+            UnitaryMatrixObject::S => Some(vec![l, o, o, i]),
+            UnitaryMatrixObject::T => {
+                let t =
+                    Complex::from_polar(P::one(), P::from(std::f64::consts::FRAC_PI_4).unwrap());
+                Some(vec![l, o, o, t])
+            }
+            UnitaryMatrixObject::CNOT => Some(vec![l, o, o, o, o, l, o, o, o, o, o, l, o, o, l, o]),
+            UnitaryMatrixObject::Rz(theta) => {
+                let h_theta = theta.radians() * P::from(0.5).unwrap();
+                Some(vec![
+                    Complex::from_polar(P::one(), -h_theta),
+                    o,
+                    o,
+                    Complex::from_polar(P::one(), h_theta),
+                ])
+            }
+            UnitaryMatrixObject::MAT(data) => Some(data.clone()),
+            UnitaryMatrixObject::Generated(g) => Some(g.dense()),
+            UnitaryMatrixObject::Sparse(rows) => {
+                let side = rows.len();
+                let mut data = vec![o; side * side];
+                rows.iter().enumerate().for_each(|(row, entries)| {
+                    entries
+                        .iter()
+                        .for_each(|(col, c)| data[row * side + col] += c)
+                });
+                Some(data)
+            }
+            UnitaryMatrixObject::Identity => Some(vec![l, o, o, l]),
+            UnitaryMatrixObject::SWAP
+            | UnitaryMatrixObject::GlobalPhase(_)
+            | UnitaryMatrixObject::Barrier => None,
+        }
     }
 }
 
-impl<P: Precision> AdvancedCircuitBuilder<P> for LocalBuilder<P> {}
+impl<P: Precision> Neg for RotationObject<P> {
+    type Output = Self;
 
-/// A handle which points to a measurement result.
-#[derive(Debug, Clone, Copy)]
-pub struct MeasurementHandle {
-    id: usize,
+    fn neg(self) -> Self::Output {
+        match self {
+            RotationObject::Floating(f) => Self::Floating(-f),
+            RotationObject::PiRational(r) => Self::PiRational(-r),
+        }
+    }
 }
 
-impl<P: Precision> MeasurementBuilder for LocalBuilder<P> {
-    type MeasurementHandle = MeasurementHandle;
+impl<P: Precision> Add for RotationObject<P> {
+    type Output = Self;
 
-    fn measure(&mut self, r: Self::Register) -> (Self::Register, Self::MeasurementHandle) {
-        let obj = BuilderCircuitObject {
-            n: r.n(),
-            object: BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement),
-        };
-        self.pipeline.push((r.indices.clone(), obj));
-        let m = self.measurements;
-        self.measurements += 1;
-        (r, Self::MeasurementHandle { id: m })
+    fn add(self, other: Self) -> Self::Output {
+        match (self, other) {
+            (RotationObject::PiRational(a), RotationObject::PiRational(b)) => {
+                Self::PiRational(a + b)
+            }
+            (a, b) => Self::Floating(a.radians() + b.radians()),
+        }
     }
 }
 
-/// A handle which points to a stochastic measurement result.
-#[derive(Debug, Clone, Copy)]
-pub struct StochasticMeasurementHandle {
-    id: usize,
+impl<P: Precision> PartialEq for UnitaryMatrixObject<P> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::X, Self::X)
+            | (Self::Y, Self::Y)
+            | (Self::Z, Self::Z)
+            | (Self::H, Self::H)
+            | (Self::S, Self::S)
+            | (Self::T, Self::T)
+            | (Self::CNOT, Self::CNOT)
+            | (Self::SWAP, Self::SWAP)
+            | (Self::Identity, Self::Identity)
+            | (Self::Barrier, Self::Barrier) => true,
+            (Self::Rz(ra), Self::Rz(rb)) => ra.eq(rb),
+            (Self::MAT(ma), Self::MAT(mb)) => ma.eq(mb),
+            (Self::Generated(ga), Self::Generated(gb)) => ga.n == gb.n && Arc::ptr_eq(&ga.f, &gb.f),
+            (Self::Sparse(ra), Self::Sparse(rb)) => ra.eq(rb),
+            (Self::GlobalPhase(ra), Self::GlobalPhase(rb)) => ra.eq(rb),
+            (_, _) => false,
+        }
+    }
 }
 
-impl<P: Precision> StochasticMeasurementBuilder for LocalBuilder<P> {
-    type StochasticMeasurementHandle = StochasticMeasurementHandle;
-
-    fn measure_stochastic(
-        &mut self,
-        r: Self::Register,
-    ) -> (Self::Register, Self::StochasticMeasurementHandle) {
-        let obj = BuilderCircuitObject {
-            n: r.n(),
-            object: BuilderCircuitObjectType::Measurement(MeasurementObject::StochasticMeasurement),
-        };
-        self.pipeline.push((r.indices.clone(), obj));
-        let m = self.measurements;
-        self.measurements += 1;
-        (r, Self::StochasticMeasurementHandle { id: m })
+impl<P: Precision> PartialEq for BuilderCircuitObjectType<P> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Unitary(ua), Self::Unitary(ub)) => ua.eq(ub),
+            (Self::Measurement(ma), Self::Measurement(mb)) => ma.eq(mb),
+            (_, _) => false,
+        }
     }
 }
 
-impl<P: Precision> RotationsBuilder<P> for LocalBuilder<P> {
-    fn rz(&mut self, r: Self::Register, theta: P) -> Self::Register {
-        let co = Self::CircuitObject {
-            n: r.n(),
-            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Rz(
-                RotationObject::Floating(theta),
-            )),
-        };
-        self.apply_circuit_object(r, co).unwrap()
+impl<P: Precision> PartialEq for RotationObject<P> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Floating(pa), Self::Floating(pb)) => pa.eq(pb),
+            (Self::PiRational(ra), Self::PiRational(rb)) => ra.eq(rb),
+            (_, _) => false,
+        }
     }
-    fn rz_pi_by(&mut self, r: Self::Register, m: i64) -> CircuitResult<Self::Register> {
-        if m == 0 {
-            Err(CircuitError::new("Cannot rotate by pi/0"))
-        } else {
-            let co = Self::CircuitObject {
-                n: r.n(),
-                object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Rz(
-                    RotationObject::PiRational(Ratio::new(1, m)),
-                )),
-            };
-            self.apply_circuit_object(r, co)
+}
+
+impl<P: Precision> Eq for UnitaryMatrixObject<P> {}
+
+impl<P: Precision> Eq for BuilderCircuitObjectType<P> {}
+
+impl<P: Precision> Eq for RotationObject<P> {}
+
+fn hash_p<P: Precision, H: Hasher>(f: P, state: &mut H) {
+    format!("{}", f).hash(state)
+}
+
+impl<P: Precision> Hash for BuilderCircuitObjectType<P> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            BuilderCircuitObjectType::Measurement(m) => {
+                state.write_i8(0);
+                m.hash(state)
+            }
+            BuilderCircuitObjectType::Unitary(u) => {
+                state.write_i8(1);
+                u.hash(state)
+            }
         }
     }
 }
 
-impl<P: Precision> Conditionable for LocalBuilder<P> {
-    fn try_apply_with_condition(
-        &mut self,
-        cr: Self::Register,
-        r: Self::Register,
-        co: Self::CircuitObject,
-    ) -> Result<(Self::Register, Self::Register), CircuitError> {
-        match co.object {
-            BuilderCircuitObjectType::Unitary(unit) => match unit {
-                UnitaryMatrixObject::X => self.toffoli(cr, r),
-                UnitaryMatrixObject::Y => {
-                    let r = self.s(r);
+impl<P: Precision> Hash for UnitaryMatrixObject<P> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            UnitaryMatrixObject::X => state.write_i8(0),
+            UnitaryMatrixObject::Y => state.write_i8(1),
+            UnitaryMatrixObject::Z => state.write_i8(2),
+            UnitaryMatrixObject::H => state.write_i8(3),
+            UnitaryMatrixObject::S => state.write_i8(4),
+            UnitaryMatrixObject::T => state.write_i8(5),
+            UnitaryMatrixObject::CNOT => state.write_i8(6),
+            UnitaryMatrixObject::SWAP => state.write_i8(7),
+            UnitaryMatrixObject::Rz(rot) => {
+                state.write_i8(8);
+                rot.hash(state);
+            }
+            UnitaryMatrixObject::GlobalPhase(rot) => {
+                state.write_i8(9);
+                rot.hash(state);
+            }
+            UnitaryMatrixObject::MAT(data) => {
+                state.write_i8(10);
+                data.iter().for_each(|c| {
+                    hash_p(c.re, state);
+                    hash_p(c.im, state);
+                })
+            }
+            UnitaryMatrixObject::Generated(g) => {
+                // Generated matrices are only equal if they share a function.
+                state.write_i8(11);
+                state.write_usize(g.n);
+                state.write_usize(Arc::as_ptr(&g.f) as *const () as usize);
+            }
+            UnitaryMatrixObject::Identity => state.write_i8(12),
+            UnitaryMatrixObject::Barrier => state.write_i8(14),
+            UnitaryMatrixObject::Sparse(rows) => {
+                state.write_i8(13);
+                rows.iter().for_each(|entries| {
+                    state.write_usize(entries.len());
+                    entries.iter().for_each(|(col, c)| {
+                        state.write_usize(*col);
+                        hash_p(c.re, state);
+                        hash_p(c.im, state);
+                    })
+                })
+            }
+        }
+    }
+}
+
+impl<P: Precision> Hash for RotationObject<P> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            // Grossly inefficient but also don't hash floats.
+            RotationObject::Floating(f) => hash_p(*f, state),
+            RotationObject::PiRational(r) => r.hash(state),
+        }
+    }
+}
+
+/// Represents a type of measurement in the circuit.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum MeasurementObject {
+    /// Performs a single measurement and collapses the wavefunction.
+    Measurement,
+    /// Simulates a series of measurements and returns a probability distribution over results.
+    /// Does not collapse the wavefunction.
+    StochasticMeasurement,
+    /// Measures the qubits, discards the result, and leaves them in `|0...0>`.
+    Reset,
+}
+
+/// Represents the result of a measurement on the circuit.
+#[derive(Debug, Clone)]
+pub enum MeasurementResults<P: Precision> {
+    /// The result of a single measurement on selected qubits, returns the measurement as well as
+    /// the likelyhood of that measurement taking place.
+    Single(usize, P),
+    /// The probability of each measurement indexed by the measurement itself.
+    Stochastic(Vec<P>),
+}
+
+/// A series of measurement results at the end of the circuit.
+#[derive(Debug)]
+pub struct Measurements<P: Precision> {
+    measurements: Vec<MeasurementResults<P>>,
+}
+
+/// Options for running a circuit with `LocalBuilder::calculate_state_with_options`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunOptions {
+    /// Keep the final state in the result. When false only the measurement results and global
+    /// phase are returned, and ops after the last measurement are skipped since they cannot affect
+    /// them.
+    pub retain_state: bool,
+    /// Outcomes with a probability below this are treated as impossible when measuring. If only
+    /// one outcome remains the measurement is deterministic: it is returned with probability one
+    /// and no random sample is drawn. Raise this for low precision simulations.
+    pub deterministic_epsilon: f64,
+    /// Circuits with fewer qubits than this apply ops on a single thread, even with the `parallel`
+    /// feature enabled, since splitting up small states costs more than it saves.
+    pub min_parallel_qubits: usize,
+    /// Seed for the random numbers used to pick measurement outcomes. Runs of the same circuit
+    /// with the same seed give the same outcomes, while `None` uses fresh randomness every run.
+    pub seed: Option<u64>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            retain_state: true,
+            deterministic_epsilon: f64::EPSILON,
+            min_parallel_qubits: 10,
+            seed: None,
+        }
+    }
+}
+
+/// The result of running a circuit: the final state, if it was retained, and the measurement
+/// results in the order the measurements were added to the circuit.
+#[derive(Debug)]
+pub struct RunResult<P: Precision> {
+    state: Option<Vec<Complex<P>>>,
+    measurements: Measurements<P>,
+    post_measurement_states: HashMap<usize, Vec<Complex<P>>>,
+    classical_registers: Vec<usize>,
+    renormalization: P,
+    global_phase: Complex<P>,
+    dirty_ancillas: Vec<(Vec<usize>, P)>,
+}
+
+impl<P: Precision> RunResult<P> {
+    /// Make a new result from a final state and measurements.
+    pub fn new(state: Option<Vec<Complex<P>>>, measurements: Measurements<P>) -> Self {
+        Self {
+            state,
+            measurements,
+            post_measurement_states: HashMap::default(),
+            classical_registers: vec![],
+            renormalization: P::one(),
+            global_phase: Complex::one(),
+            dirty_ancillas: vec![],
+        }
+    }
+
+    /// Check that the ancillas released by `LocalBuilder::with_ancilla` were returned to `|0>`
+    /// during the run. Errors listing the first ancillas which were not, along with the
+    /// probability they were found in `|0>`.
+    pub fn check_ancillas(&self) -> CircuitResult<()> {
+        match self.dirty_ancillas.first() {
+            Some((indices, p)) => {
+                let message = format!(
+                    "Ancilla qubits {:?} were not returned to |0> (p = {})",
+                    indices, p
+                );
+                Err(CircuitError::with_kind(
+                    CircuitErrorKind::DirtyAncilla,
+                    message,
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The product of the probabilities of the outcomes of every measurement and reset in the run,
+    /// which is the share of the initial probability mass kept by the state after it was
+    /// renormalized. This weights the run when post-selecting on its outcomes.
+    pub fn total_renormalization_factor(&self) -> P {
+        self.renormalization
+    }
+
+    /// The product of the global phases applied in the run, such as those from
+    /// `LocalBuilder::apply_global_phase`. These are left out of the state since they cannot be
+    /// measured, but matter once the circuit is conditioned.
+    pub fn global_phase(&self) -> Complex<P> {
+        self.global_phase
+    }
+
+    /// The final state of the circuit, if it was retained.
+    pub fn state(&self) -> Option<&[Complex<P>]> {
+        self.state.as_deref()
+    }
+
+    /// Take the final state of the circuit, if it was retained.
+    pub fn into_state(self) -> Option<Vec<Complex<P>>> {
+        self.state
+    }
+
+    /// Get the amplitudes of the final state for the basis states `states`, which index into
+    /// `state`. Errors if the state was not retained or a basis state is out of range.
+    pub fn amplitudes(&self, states: &[usize]) -> CircuitResult<Vec<Complex<P>>> {
+        let state = self
+            .state
+            .as_ref()
+            .ok_or_else(|| CircuitError::new("The final state was not retained"))?;
+        states
+            .iter()
+            .map(|x| {
+                state.get(*x).cloned().ok_or_else(|| {
+                    let message = format!(
+                        "Basis state {:?} is out of range for a state of {:?} entries",
+                        x,
+                        state.len()
+                    );
+                    CircuitError::with_kind(CircuitErrorKind::IndexOutOfRange, message)
+                })
+            })
+            .collect()
+    }
+
+    /// The measurement results.
+    pub fn measurements(&self) -> &Measurements<P> {
+        &self.measurements
+    }
+
+    /// Split into the final state and measurement results.
+    pub fn into_parts(self) -> (Option<Vec<Complex<P>>>, Measurements<P>) {
+        (self.state, self.measurements)
+    }
+
+    /// Get a measurement result given a handle.
+    pub fn get_measurement(&self, handle: MeasurementHandle) -> (usize, P) {
+        self.measurements.get_measurement(handle)
+    }
+
+    /// Get a measurement result given the id of its handle, see
+    /// `Measurements::get_measurement_by_id`.
+    pub fn get_measurement_by_id(&self, id: usize) -> Option<(usize, P)> {
+        self.measurements.get_measurement_by_id(id)
+    }
+
+    /// Get the measured eigenvalue of an observable and the likelihood of measuring it.
+    pub fn get_observable_measurement(&self, handle: &ObservableMeasurementHandle<P>) -> (P, P) {
+        self.measurements.get_observable_measurement(handle)
+    }
+
+    /// Get a stochastic measurement result given a handle.
+    pub fn get_stochastic_measurement(&self, handle: StochasticMeasurementHandle) -> &[P] {
+        self.measurements.get_stochastic_measurement(handle)
+    }
+
+    /// Get the Shannon entropy, in bits, of a stochastic measurement's distribution.
+    pub fn distribution_entropy(&self, handle: StochasticMeasurementHandle) -> P {
+        self.measurements.distribution_entropy(handle)
+    }
+
+    /// Get the most likely outcome of a stochastic measurement, the lowest in case of a tie.
+    pub fn most_likely(&self, handle: StochasticMeasurementHandle) -> usize {
+        self.measurements.most_likely(handle)
+    }
+
+    /// Get the probability of each qubit in a stochastic measurement being `|1>`, in the order of
+    /// the qubits in the measured register.
+    pub fn marginal_probabilities(&self, handle: StochasticMeasurementHandle) -> Vec<P> {
+        self.measurements.marginal_probabilities(handle)
+    }
+
+    /// Get the values of all classical registers at the end of the run, in the order they were
+    /// allocated.
+    pub fn classical_registers(&self) -> &[usize] {
+        &self.classical_registers
+    }
+
+    /// Get the value of the classical register `creg` at the end of the run, with bit `i` of the
+    /// result holding bit `i` of the register.
+    pub fn get_classical_register(&self, creg: &ClassicalRegister) -> usize {
+        self.classical_registers[creg.id]
+    }
+
+    /// Get the state just after the measurement given by `handle` collapsed it. Only available if
+    /// the circuit was run with `LocalBuilder::set_retain_post_measurement_states` enabled.
+    pub fn post_measurement_state(&self, handle: MeasurementHandle) -> Option<&[Complex<P>]> {
+        self.post_measurement_states
+            .get(&handle.id)
+            .map(|s| s.as_slice())
+    }
+}
+
+impl<P: Precision> Measurements<P> {
+    /// Make a new set of measurement results, ordered as the measurements were made.
+    pub fn new(measurements: Vec<MeasurementResults<P>>) -> Self {
+        Self { measurements }
+    }
+
+    /// Iterate over all measurement results in the order the measurements were made.
+    pub fn iter(&self) -> impl Iterator<Item = &MeasurementResults<P>> {
+        self.measurements.iter()
+    }
+
+    /// The number of measurements made.
+    pub fn len(&self) -> usize {
+        self.measurements.len()
+    }
+
+    /// Whether no measurements were made.
+    pub fn is_empty(&self) -> bool {
+        self.measurements.is_empty()
+    }
+
+    /// Get a measurement result given a handle.
+    pub fn get_measurement(&self, handle: MeasurementHandle) -> (usize, P) {
+        match &self.measurements[handle.id] {
+            MeasurementResults::Single(val, prob) => (*val, *prob),
+            MeasurementResults::Stochastic(_) => unreachable!(),
+        }
+    }
+
+    /// Get the measured value and its probability for the measurement with the given id, see
+    /// `MeasurementHandle::id`. Returns None if there is no such measurement, or if it was a
+    /// stochastic measurement.
+    pub fn get_measurement_by_id(&self, id: usize) -> Option<(usize, P)> {
+        match self.measurements.get(id)? {
+            MeasurementResults::Single(val, prob) => Some((*val, *prob)),
+            MeasurementResults::Stochastic(_) => None,
+        }
+    }
+
+    /// Get the measured eigenvalue of an observable and the likelihood of measuring it.
+    pub fn get_observable_measurement(&self, handle: &ObservableMeasurementHandle<P>) -> (P, P) {
+        let (measured, p) = self.get_measurement(handle.handle);
+        // Measured values are little endian in the register, the eigenbasis is big endian.
+        let k = flip_bits(handle.n, measured);
+        (handle.eigenvalues[k], p)
+    }
+    /// Get a stochastic measurement result given a handle.
+    pub fn get_stochastic_measurement(&self, handle: StochasticMeasurementHandle) -> &[P] {
+        match &self.measurements[handle.id] {
+            MeasurementResults::Single(_, _) => unreachable!(),
+            MeasurementResults::Stochastic(probs) => probs.as_slice(),
+        }
+    }
+
+    /// Get the Shannon entropy, in bits, of a stochastic measurement's distribution.
+    pub fn distribution_entropy(&self, handle: StochasticMeasurementHandle) -> P {
+        -self
+            .get_stochastic_measurement(handle)
+            .iter()
+            .filter(|p| **p > P::zero())
+            .map(|p| *p * p.log2())
+            .sum::<P>()
+    }
+
+    /// Get the most likely outcome of a stochastic measurement, the lowest in case of a tie.
+    pub fn most_likely(&self, handle: StochasticMeasurementHandle) -> usize {
+        self.get_stochastic_measurement(handle)
+            .iter()
+            .enumerate()
+            .fold((0, P::zero()), |(best, best_p), (x, p)| {
+                if *p > best_p {
+                    (x, *p)
+                } else {
+                    (best, best_p)
+                }
+            })
+            .0
+    }
+
+    /// Get the probability of each qubit in a stochastic measurement being `|1>`, in the order of
+    /// the qubits in the measured register.
+    pub fn marginal_probabilities(&self, handle: StochasticMeasurementHandle) -> Vec<P> {
+        let probs = self.get_stochastic_measurement(handle);
+        let n = probs.len().trailing_zeros() as usize;
+        (0..n)
+            .map(|i| {
+                probs
+                    .iter()
+                    .enumerate()
+                    .filter(|(x, _)| (x >> i) & 1 == 1)
+                    .map(|(_, p)| *p)
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+impl<P: Precision> CircuitBuilder for LocalBuilder<P> {
+    type Register = Qudit;
+    type CircuitObject = BuilderCircuitObject<P>;
+    type StateCalculation = RunResult<P>;
+
+    fn n(&self) -> usize {
+        self.n
+    }
+
+    fn register(&mut self, n: NonZeroUsize) -> Self::Register {
+        let n: usize = n.into();
+        let r = Self::Register::new_from_iter(self.n..self.n + n).unwrap();
+        self.allocations.push(self.n);
+        self.n += n;
+        r
+    }
+
+    fn merge_two_registers(&mut self, r1: Self::Register, r2: Self::Register) -> Self::Register {
+        debug_assert!(
+            !indices_overlap(&r1, &r2),
+            "Cannot merge overlapping registers {:?} and {:?}",
+            r1.indices,
+            r2.indices
+        );
+        Self::Register::new_from_iter(r1.indices.into_iter().chain(r2.indices.into_iter())).unwrap()
+    }
+
+    fn split_register_relative<It>(
+        &mut self,
+        r: Self::Register,
+        indices: It,
+    ) -> SplitResult<Self::Register>
+    where
+        It: IntoIterator<Item = usize>,
+    {
+        let selected_indices = indices.into_iter().filter_map(|i| r.physical_index(i));
+        let r1 = Self::Register::new_from_iter(selected_indices);
+
+        let remaining_indices = r.indices.into_iter().filter(|oi| match &r1 {
+            Some(r1) => !r1.indices.contains(oi),
+            None => true,
+        });
+        let r2 = Self::Register::new_from_iter(remaining_indices);
+
+        match (r1, r2) {
+            (Some(r1), None) => SplitResult::SELECTED(r1),
+            (None, Some(r2)) => SplitResult::UNSELECTED(r2),
+            (Some(r1), Some(r2)) => SplitResult::SPLIT(r1, r2),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    fn apply_circuit_object(
+        &mut self,
+        r: Self::Register,
+        c: Self::CircuitObject,
+    ) -> CircuitResult<Self::Register> {
+        if c.n == 1 || c.n == r.n() {
+            if c.n == 1 && r.n() > 1 && !self.single_op_broadcast {
+                // Do broadcasting
+                let rs = self.split_all_register(r);
+                rs.iter()
+                    .for_each(|r| self.pipeline.push((r.indices.clone(), c.clone())));
+                Ok(self.merge_registers(rs.into_iter()).unwrap())
+            } else {
+                // Normal application, or a single broadcast op.
+                self.pipeline.push((r.indices.clone(), c));
+                Ok(r)
+            }
+        } else {
+            Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                "Matrix has incorrect N and cannot be broadcast",
+            ))
+        }
+    }
+
+    fn calculate_state_with_init<'a, It>(&mut self, it: It) -> Self::StateCalculation
+    where
+        Self::Register: 'a,
+        It: IntoIterator<Item = (&'a Self::Register, usize)>,
+    {
+        self.calculate_state_with_options(it, RunOptions::default())
+    }
+}
+
+impl<P: Precision> LocalBuilder<P> {
+    /// Calculate the state at the end of the circuit as in `calculate_state_with_init`, picking
+    /// measurement outcomes with random numbers drawn from `seed`. The same circuit and seed always
+    /// give the same measurements.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let r = b.register(NonZeroUsize::new(8).unwrap());
+    /// let r = b.h(r);
+    /// let (r, handle) = b.measure(r);
+    ///
+    /// let first = b.calculate_state_with_seed(None, 42).get_measurement(handle);
+    /// let second = b.calculate_state_with_seed(None, 42).get_measurement(handle);
+    /// assert_eq!(first, second);
+    /// ```
+    pub fn calculate_state_with_seed<'a, It>(&mut self, it: It, seed: u64) -> RunResult<P>
+    where
+        It: IntoIterator<Item = (&'a Qudit, usize)>,
+    {
+        let options = RunOptions {
+            seed: Some(seed),
+            ..Default::default()
+        };
+        self.calculate_state_with_options(it, options)
+    }
+
+    /// Calculate the state at the end of the circuit as in `calculate_state_with_init`, using
+    /// `options` to control what is kept.
+    pub fn calculate_state_with_options<'a, It>(
+        &mut self,
+        it: It,
+        options: RunOptions,
+    ) -> RunResult<P>
+    where
+        It: IntoIterator<Item = (&'a Qudit, usize)>,
+    {
+        let n = self.n();
+        let mut state = vec![Complex::zero(); 1 << n];
+
+        let mut initial_index = 0;
+        it.into_iter()
+            .flat_map(|(r, x)| {
+                let rn = r.n();
+                r.indices
+                    .iter()
+                    .rev()
+                    .cloned()
+                    .enumerate()
+                    .map(move |(ri, i)| (n - 1 - i, (x >> (rn - 1 - ri)) & 1))
+            })
+            .for_each(|(index, bit)| initial_index |= bit << index);
+
+        // Registers constructed with an initial state are expanded over the classical state.
+        let initial_amplitudes = self.initial_states.iter().fold(
+            vec![(initial_index, Complex::one())],
+            |acc, (indices, amplitudes)| {
+                let mask: usize = indices.iter().map(|i| 1 << (n - 1 - i)).sum();
+                acc.into_iter()
+                    .flat_map(|(index, amp)| {
+                        amplitudes
+                            .iter()
+                            .filter(|(_, a)| !a.is_zero())
+                            .map(move |(x, a)| {
+                                let bits: usize = indices
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(ri, i)| ((x >> ri) & 1) << (n - 1 - i))
+                                    .sum();
+                                ((index & !mask) | bits, amp * a)
+                            })
+                    })
+                    .collect()
+            },
+        );
+        initial_amplitudes
+            .into_iter()
+            .for_each(|(index, amp)| state[index] = amp);
+        self.run_from_state(state, options)
+    }
+
+    /// Run the circuit starting from the full `2^n` entry state vector `initial`, for instance to
+    /// resume from the state of an earlier run. This replaces the initial state of every qubit,
+    /// including registers made with `register_with_state`. Errors if `initial` is the wrong
+    /// length or is not normalized.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    /// use qip::builder::RunOptions;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let q = b.qubit();
+    /// let _q = b.h(q);
+    /// let s = std::f64::consts::FRAC_1_SQRT_2;
+    /// let plus = vec![Complex::new(s, 0.0), Complex::new(s, 0.0)];
+    /// let result = b.calculate_state_from_vector(plus, RunOptions::default())?;
+    /// assert!((result.state().unwrap()[0].re - 1.0).abs() < 1e-10);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn calculate_state_from_vector(
+        &mut self,
+        initial: Vec<Complex<P>>,
+        options: RunOptions,
+    ) -> CircuitResult<RunResult<P>> {
+        let n = self.n();
+        if initial.len() != 1 << n {
+            let message = format!(
+                "Initial state has {:?} entries versus expected 2^{:?}",
+                initial.len(),
+                n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::StateSizeMismatch,
+                message,
+            ));
+        }
+        let norm = prob_magnitude(&initial);
+        if (norm - P::one()).abs() > P::epsilon().sqrt() {
+            let message = format!("Initial state has magnitude {} rather than 1", norm);
+            return Err(CircuitError::new(message));
+        }
+        Ok(self.run_from_state(initial, options))
+    }
+
+    /// Whether the op at `pos` is skipped because it was applied on a measurement which gave a
+    /// different value.
+    fn skipped_on_measurement(&self, pos: usize, measurements: &[MeasurementResults<P>]) -> bool {
+        self.measurement_conditions
+            .iter()
+            .any(|(range, handle, expected)| {
+                range.contains(&pos)
+                    && match &measurements[handle.id] {
+                        MeasurementResults::Single(measured, _) => measured != expected,
+                        MeasurementResults::Stochastic(_) => unreachable!(),
+                    }
+            })
+    }
+
+    fn run_from_state(&mut self, state: Vec<Complex<P>>, options: RunOptions) -> RunResult<P> {
+        let n = self.n();
+        let epsilon = P::from(options.deterministic_epsilon).unwrap();
+        let mut rng = options.seed.map(StdRng::seed_from_u64);
+        let arena = vec![Complex::zero(); state.len()];
+
+        // Without the final state only ops up to the last measurement can affect the results.
+        let end = if options.retain_state {
+            self.pipeline.len()
+        } else {
+            self.pipeline
+                .iter()
+                .rposition(|(_, obj)| {
+                    matches!(obj.object, BuilderCircuitObjectType::Measurement(_))
+                })
+                .map(|i| i + 1)
+                .unwrap_or(0)
+        };
+
+        let mut post_measurement_states = HashMap::default();
+        let mut renormalization = P::one();
+        let mut global_phase = Complex::one();
+        let mut dirty_ancillas = vec![];
+        let (state, _, measurements) = self.pipeline[..end]
+            .iter()
+            .enumerate()
+            .try_fold(
+                (state, arena, vec![]),
+                |(mut state, mut arena, mut measurements),
+                 (i, (indices, obj))|
+                 -> CircuitResult<_> {
+                    let BuilderCircuitObject { n: obj_n, object } = obj;
+                    // Ops applied on a measurement are skipped unless it gave the expected value.
+                    let skipped = self.skipped_on_measurement(i, &measurements);
+                    // Whether the op wrote its output into the arena.
+                    let overwritten = match object {
+                        _ if skipped => false,
+                        // Global phases, identities and barriers do not affect state, but phases
+                        // are recorded in the result.
+                        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::GlobalPhase(
+                            theta,
+                        )) => {
+                            global_phase *= Complex::from_polar(P::one(), theta.radians());
+                            false
+                        }
+                        BuilderCircuitObjectType::Unitary(
+                            UnitaryMatrixObject::Identity | UnitaryMatrixObject::Barrier,
+                        ) => false,
+                        // Other unitaries do though.
+                        BuilderCircuitObjectType::Unitary(object) => {
+                            let indices = indices.clone();
+                            let uop = match object {
+                                UnitaryMatrixObject::CNOT => {
+                                    let l = Complex::one();
+                                    let o = Complex::zero();
+                                    let cindex = vec![indices[0]];
+                                    let indices = indices[1..].to_vec();
+                                    make_control_op(
+                                        cindex,
+                                        make_matrix_op(indices, vec![o, l, l, o])?,
+                                    )
+                                }
+                                UnitaryMatrixObject::SWAP => {
+                                    let n = indices.len();
+                                    assert_eq!(n % 2, 0);
+                                    let x = n / 2;
+                                    let a_indices = indices[..x].to_vec();
+                                    let b_indices = indices[x..].to_vec();
+                                    make_swap_op(a_indices, b_indices)
+                                }
+                                UnitaryMatrixObject::GlobalPhase(_)
+                                | UnitaryMatrixObject::Identity
+                                | UnitaryMatrixObject::Barrier => unreachable!(),
+                                UnitaryMatrixObject::Generated(g)
+                                    if !(*obj_n == 1 && indices.len() > 1) =>
+                                {
+                                    let f = g.f.clone();
+                                    make_generated_op(indices, move |row, col| f(row, col))
+                                }
+                                UnitaryMatrixObject::Sparse(rows)
+                                    if !(*obj_n == 1 && indices.len() > 1) =>
+                                {
+                                    make_sparse_matrix_op(
+                                        indices,
+                                        rows.clone(),
+                                        Representation::BigEndian,
+                                    )
+                                }
+                                object => {
+                                    let data = object.matrix_data().unwrap();
+                                    if *obj_n == 1 && indices.len() > 1 {
+                                        make_broadcast_op(indices, data)
+                                    } else {
+                                        make_matrix_op(indices, data)
+                                    }
+                                }
+                            }?;
+                            if n >= options.min_parallel_qubits {
+                                apply_op_overwrite(n, &uop, &state, &mut arena, 0, 0);
+                            } else {
+                                apply_op_overwrite_serial(n, &uop, &state, &mut arena, 0, 0);
+                            }
+                            true
+                        }
+                        BuilderCircuitObjectType::Measurement(object) => match object {
+                            MeasurementObject::Measurement => {
+                                let ps = measure_probs(n, indices, &state, None);
+                                let mut possible =
+                                    ps.iter().enumerate().filter(|(_, p)| **p >= epsilon);
+                                let condition = match (possible.next(), possible.next()) {
+                                    (Some((m, p)), None) => Some(MeasuredCondition {
+                                        measured: m,
+                                        prob: Some(*p),
+                                    }),
+                                    _ => None,
+                                };
+                                let deterministic = condition.is_some();
+                                let condition = condition.unwrap_or_else(|| match rng.as_mut() {
+                                    Some(rng) => sample_outcome(&ps, rng),
+                                    None => sample_outcome(&ps, &mut rand::thread_rng()),
+                                });
+                                let (measured, p) =
+                                    measure(n, indices, &state, &mut arena, None, Some(condition));
+                                let p = if deterministic { P::one() } else { p };
+                                renormalization *= p;
+                                measurements.push(MeasurementResults::Single(measured, p));
+                                true
+                            }
+                            MeasurementObject::StochasticMeasurement => {
+                                let ps = measure_probs(n, indices, &state, None);
+                                measurements.push(MeasurementResults::Stochastic(ps));
+                                false
+                            }
+                            MeasurementObject::Reset => {
+                                let ps = measure_probs(n, indices, &state, None);
+                                let condition = match rng.as_mut() {
+                                    Some(rng) => sample_outcome(&ps, rng),
+                                    None => sample_outcome(&ps, &mut rand::thread_rng()),
+                                };
+                                let (_, p) =
+                                    measure(n, indices, &state, &mut arena, None, Some(condition));
+                                renormalization *= p;
+                                // The collapsed state has fixed values on the indices, so clearing
+                                // them moves each amplitude to a distinct position.
+                                let mask = indices
+                                    .iter()
+                                    .fold(0, |acc, index| acc | (1 << (n - 1 - index)));
+                                state.iter_mut().for_each(|c| *c = Complex::zero());
+                                arena.iter().enumerate().for_each(|(i, c)| {
+                                    state[i & !mask] += c;
+                                });
+                                false
+                            }
+                        },
+                    };
+
+                    let (state, arena) = if overwritten {
+                        (arena, state)
+                    } else {
+                        (state, arena)
+                    };
+
+                    if self.retain_post_measurement_states
+                        && matches!(
+                            object,
+                            BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement)
+                        )
+                    {
+                        post_measurement_states.insert(measurements.len() - 1, state.clone());
+                    }
+
+                    // Check any ancillas released at this point were returned to |0>.
+                    self.ancilla_checks
+                        .iter()
+                        .filter(|(pos, _)| *pos == i + 1)
+                        .for_each(|(_, indices)| {
+                            let p = measure_prob(n, 0, indices, &state, None);
+                            if (P::one() - p).abs() >= P::epsilon().sqrt() {
+                                dirty_ancillas.push((indices.clone(), p));
+                            }
+                        });
+                    Ok((state, arena, measurements))
+                },
+            )
+            .unwrap();
+        // Ops after the last measurement were not run, but their global phases are still recorded.
+        self.pipeline
+            .iter()
+            .enumerate()
+            .skip(end)
+            .filter(|(i, _)| !self.skipped_on_measurement(*i, &measurements))
+            .for_each(|(_, (_, obj))| {
+                if let BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::GlobalPhase(theta)) =
+                    &obj.object
+                {
+                    global_phase *= Complex::from_polar(P::one(), theta.radians());
+                }
+            });
+        let state = if options.retain_state {
+            Some(state)
+        } else {
+            None
+        };
+        let measurements = Measurements::new(measurements);
+        let classical_registers = self
+            .classical_registers
+            .iter()
+            .map(|writes| {
+                writes.iter().fold(0, |acc, (handle, offset, n)| {
+                    let (measured, _) = measurements.get_measurement(*handle);
+                    let mask = ((1 << n) - 1) << offset;
+                    (acc & !mask) | (measured << offset)
+                })
+            })
+            .collect();
+        let mut result = RunResult::new(state, measurements);
+        result.post_measurement_states = post_measurement_states;
+        result.classical_registers = classical_registers;
+        result.renormalization = renormalization;
+        result.global_phase = global_phase;
+        result.dirty_ancillas = dirty_ancillas;
+        result
+    }
+}
+
+impl<P: Precision> UnitaryBuilder<P> for LocalBuilder<P> {
+    fn vec_matrix_to_circuitobject(n: usize, data: Vec<Complex<P>>) -> Self::CircuitObject {
+        Self::CircuitObject {
+            n,
+            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::MAT(data)),
+        }
+    }
+
+    fn sparse_matrix_to_circuitobject(
+        n: usize,
+        rows: Vec<Vec<(usize, Complex<P>)>>,
+    ) -> Self::CircuitObject {
+        Self::CircuitObject {
+            n,
+            object: BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Sparse(rows)),
+        }
+    }
+}
+
+impl<P: Precision> CliffordTBuilder<P> for LocalBuilder<P> {
+    fn make_x(&self) -> Self::CircuitObject {
+        self.make_standard_gate(1, UnitaryMatrixObject::X)
+    }
+    fn make_y(&self) -> Self::CircuitObject {
+        self.make_standard_gate(1, UnitaryMatrixObject::Y)
+    }
+    fn make_z(&self) -> Self::CircuitObject {
+        self.make_standard_gate(1, UnitaryMatrixObject::Z)
+    }
+    fn make_h(&self) -> Self::CircuitObject {
+        self.make_standard_gate(1, UnitaryMatrixObject::H)
+    }
+    fn make_s(&self) -> Self::CircuitObject {
+        self.make_standard_gate(1, UnitaryMatrixObject::S)
+    }
+    fn make_t(&self) -> Self::CircuitObject {
+        self.make_standard_gate(1, UnitaryMatrixObject::T)
+    }
+    fn make_cnot(&self) -> Self::CircuitObject {
+        self.make_standard_gate(2, UnitaryMatrixObject::CNOT)
+    }
+}
+
+impl<P: Precision> TemporaryRegisterBuilder for LocalBuilder<P> {
+    fn make_zeroed_temp_qubit(&mut self) -> Self::Register {
+        if let Some(r) = self.zeroed_qubits.pop() {
+            self.reused_qubits.push((self.pipeline.len(), r.indices[0]));
+            r
+        } else {
+            self.qubit()
+        }
+    }
+
+    fn return_zeroed_temp_register(&mut self, r: Self::Register) {
+        let rs = self.split_all_register(r);
+        self.zeroed_qubits.extend(rs.into_iter());
+    }
+}
+
+impl<P: Precision> AdvancedCircuitBuilder<P> for LocalBuilder<P> {}
+
+/// A handle which points to a measurement result.
+#[derive(Debug, Clone, Copy)]
+pub struct MeasurementHandle {
+    id: usize,
+}
+
+impl MeasurementHandle {
+    /// The id of the measurement, which counts up from zero in the order measurements are added to
+    /// the circuit.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl<P: Precision> MeasurementBuilder for LocalBuilder<P> {
+    type MeasurementHandle = MeasurementHandle;
+
+    fn measure(&mut self, r: Self::Register) -> (Self::Register, Self::MeasurementHandle) {
+        let obj = BuilderCircuitObject {
+            n: r.n(),
+            object: BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement),
+        };
+        self.pipeline.push((r.indices.clone(), obj));
+        let m = self.measurements;
+        self.measurements += 1;
+        (r, Self::MeasurementHandle { id: m })
+    }
+}
+
+/// A handle which points to the measurement of an observable, see
+/// `LocalBuilder::measure_observable`.
+#[derive(Debug, Clone)]
+pub struct ObservableMeasurementHandle<P: Precision> {
+    handle: MeasurementHandle,
+    n: usize,
+    eigenvalues: Vec<P>,
+}
+
+/// A handle which points to a stochastic measurement result.
+#[derive(Debug, Clone, Copy)]
+pub struct StochasticMeasurementHandle {
+    id: usize,
+}
+
+impl StochasticMeasurementHandle {
+    /// The id of the measurement, which counts up from zero in the order measurements are added to
+    /// the circuit.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl<P: Precision> StochasticMeasurementBuilder for LocalBuilder<P> {
+    type StochasticMeasurementHandle = StochasticMeasurementHandle;
+
+    fn measure_stochastic(
+        &mut self,
+        r: Self::Register,
+    ) -> (Self::Register, Self::StochasticMeasurementHandle) {
+        let obj = BuilderCircuitObject {
+            n: r.n(),
+            object: BuilderCircuitObjectType::Measurement(MeasurementObject::StochasticMeasurement),
+        };
+        self.pipeline.push((r.indices.clone(), obj));
+        let m = self.measurements;
+        self.measurements += 1;
+        (r, Self::StochasticMeasurementHandle { id: m })
+    }
+}
+
+impl<P: Precision> RotationsBuilder<P> for LocalBuilder<P> {
+    fn make_rz(&self, theta: P) -> Self::CircuitObject {
+        self.make_standard_gate(1, UnitaryMatrixObject::Rz(RotationObject::Floating(theta)))
+    }
+    fn make_rz_ratio(&self, theta: Rational64) -> CircuitResult<Self::CircuitObject> {
+        Ok(self.make_standard_gate(
+            1,
+            UnitaryMatrixObject::Rz(RotationObject::PiRational(theta)),
+        ))
+    }
+}
+
+impl<P: Precision> Conditionable for LocalBuilder<P> {
+    fn try_apply_with_condition(
+        &mut self,
+        cr: Self::Register,
+        r: Self::Register,
+        co: Self::CircuitObject,
+    ) -> Result<(Self::Register, Self::Register), CircuitError> {
+        if co.n == 1 && r.n() > 1 {
+            // Single qubit gates broadcast across r are conditioned one at a time.
+            let rs = self.split_all_register(r);
+            let (cr, rs) = rs.into_iter().try_fold((cr, vec![]), |(cr, mut acc), r| {
+                let (cr, r) = self.try_apply_with_condition(cr, r, co.clone())?;
+                acc.push(r);
+                Ok((cr, acc))
+            })?;
+            let r = self.merge_registers(rs).unwrap();
+            return Ok((cr, r));
+        }
+        match co.object {
+            BuilderCircuitObjectType::Unitary(unit) => match unit {
+                UnitaryMatrixObject::X => self.toffoli(cr, r),
+                UnitaryMatrixObject::Y => {
+                    let r = self.s(r);
                     let (cr, r) = self.toffoli(cr, r)?;
                     let r = self.s_dagger(r);
                     Ok((cr, r))
@@ -665,295 +2767,2236 @@ impl<P: Precision> Conditionable for LocalBuilder<P> {
                 UnitaryMatrixObject::Z => {
                     let r = self.h(r);
                     let (cr, r) = self.toffoli(cr, r)?;
-                    let r = self.h(r);
+                    let r = self.h(r);
+                    Ok((cr, r))
+                }
+                UnitaryMatrixObject::H => {
+                    let r = self.ry_pi_by(r, 4)?;
+                    let (cr, r) = self.toffoli(cr, r)?;
+                    let r = self.ry_pi_by(r, -4)?;
+                    Ok((cr, r))
+                }
+                UnitaryMatrixObject::S => {
+                    let cr = self.merge_two_registers(cr, r);
+                    let tq = self.make_zeroed_temp_qubit();
+                    let (cr, tq) = self.toffoli(cr, tq)?;
+                    let tq = self.s(tq);
+                    let (cr, tq) = self.toffoli(cr, tq)?;
+                    self.return_zeroed_temp_register(tq);
+                    let (cr, r) = self.split_last_qubit(cr);
+                    let r = r.unwrap();
+                    Ok((cr, r))
+                }
+                UnitaryMatrixObject::T => {
+                    let cr = self.merge_two_registers(cr, r);
+                    let tq = self.make_zeroed_temp_qubit();
+                    let (cr, tq) = self.toffoli(cr, tq)?;
+                    let tq = self.t(tq);
+                    let (cr, tq) = self.toffoli(cr, tq)?;
+                    self.return_zeroed_temp_register(tq);
+                    let (cr, r) = self.split_last_qubit(cr);
+                    let r = r.unwrap();
+                    Ok((cr, r))
+                }
+                UnitaryMatrixObject::SWAP => {
+                    let n = r.n();
+                    assert_eq!(n % 2, 0);
+                    let rs = self.split_all_register(r);
+                    let (ra, rb) = split_vector_at(rs, n / 2);
+                    let (cr, ras, rbs) = ra.into_iter().zip(rb.into_iter()).try_fold(
+                        (cr, vec![], vec![]),
+                        |(cr, mut ras, mut rbs), (ra, rb)| {
+                            debug_assert_eq!(ra.n(), 1);
+                            debug_assert_eq!(rb.n(), 1);
+
+                            // With the program macro we would be doing this:
+                            // let (cr, ra, rb) = program!(self, cr, ra, rb;
+                            //     control not |cr, ra,| rb;
+                            //     control not |cr, rb,| ra;
+                            //     control not |cr, ra,| rb;
+                            // )?;
+
+                            let new_cr = self.merge_two_registers(cr, ra);
+                            let (new_cr, rb) = self.cnot(new_cr, rb)?;
+                            let (cr, ra) = self.split_last_qubit(new_cr);
+                            let ra = ra.unwrap();
+
+                            let new_cr = self.merge_two_registers(cr, rb);
+                            let (new_cr, ra) = self.cnot(new_cr, ra)?;
+                            let (cr, rb) = self.split_last_qubit(new_cr);
+                            let rb = rb.unwrap();
+
+                            let new_cr = self.merge_two_registers(cr, ra);
+                            let (new_cr, rb) = self.cnot(new_cr, rb)?;
+                            let (cr, ra) = self.split_last_qubit(new_cr);
+                            let ra = ra.unwrap();
+
+                            ras.push(ra);
+                            rbs.push(rb);
+                            Ok((cr, ras, rbs))
+                        },
+                    )?;
+                    let r = self
+                        .merge_registers(ras.into_iter().chain(rbs.into_iter()))
+                        .unwrap();
+                    Ok((cr, r))
+                }
+                UnitaryMatrixObject::CNOT => {
+                    assert_eq!(r.n(), 2);
+                    let (ra, r) = self.split_first_qubit(r);
+                    let ra = ra.unwrap();
+                    let cr = self.merge_two_registers(cr, ra);
+                    let (cr, r) = self.toffoli(cr, r)?;
+                    let (cr, ra) = self.split_last_qubit(cr);
+                    let ra = ra.unwrap();
+                    let r = self.merge_two_registers(ra, r);
                     Ok((cr, r))
                 }
-                UnitaryMatrixObject::H => {
-                    let r = self.ry_pi_by(r, 4)?;
-                    let (cr, r) = self.toffoli(cr, r)?;
-                    let r = self.ry_pi_by(r, -4)?;
+                UnitaryMatrixObject::Identity => Ok((cr, r)),
+                UnitaryMatrixObject::Barrier => {
+                    self.barrier(&[&cr, &r]);
                     Ok((cr, r))
                 }
-                UnitaryMatrixObject::S => {
-                    let cr = self.merge_two_registers(cr, r);
+                UnitaryMatrixObject::GlobalPhase(phase) => {
+                    // So a global phase applied to r if cr is actually just a phase gate on the
+                    // conditioned qubits, r doesn't enter.
+
                     let tq = self.make_zeroed_temp_qubit();
                     let (cr, tq) = self.toffoli(cr, tq)?;
-                    let tq = self.s(tq);
+
+                    let tq = match phase {
+                        RotationObject::Floating(theta) => {
+                            let half_phase = theta * P::from(0.5).unwrap();
+                            let tq = self.rz(tq, theta);
+                            self.apply_global_phase(tq, half_phase)
+                        }
+                        RotationObject::PiRational(r) => {
+                            let half_phase = r / 2;
+                            let tq = self.rz_ratio(tq, r)?;
+                            self.apply_global_phase_ratio(tq, half_phase)
+                        }
+                    };
+
                     let (cr, tq) = self.toffoli(cr, tq)?;
                     self.return_zeroed_temp_register(tq);
-                    let (cr, r) = self.split_last_qubit(cr);
-                    let r = r.unwrap();
                     Ok((cr, r))
                 }
-                UnitaryMatrixObject::T => {
-                    let cr = self.merge_two_registers(cr, r);
-                    let tq = self.make_zeroed_temp_qubit();
-                    let (cr, tq) = self.toffoli(cr, tq)?;
-                    let tq = self.t(tq);
-                    let (cr, tq) = self.toffoli(cr, tq)?;
-                    self.return_zeroed_temp_register(tq);
-                    let (cr, r) = self.split_last_qubit(cr);
-                    let r = r.unwrap();
-                    Ok((cr, r))
+                UnitaryMatrixObject::Rz(phase) => {
+                    // X Rz(-theta/2) X Rz(theta/2) = Rz(theta), while without the X gates the
+                    // two rotations cancel.
+                    let (half, neg_half) = match phase {
+                        RotationObject::Floating(theta) => {
+                            let half = theta * P::from(0.5).unwrap();
+                            (self.make_rz(half), self.make_rz(-half))
+                        }
+                        RotationObject::PiRational(theta) => (
+                            self.make_rz_ratio(theta / 2)?,
+                            self.make_rz_ratio(-theta / 2)?,
+                        ),
+                    };
+                    let r = self.apply_circuit_object(r, half)?;
+                    let (cr, r) = self.toffoli(cr, r)?;
+                    let r = self.apply_circuit_object(r, neg_half)?;
+                    let (cr, r) = self.toffoli(cr, r)?;
+                    Ok((cr, r))
+                }
+                UnitaryMatrixObject::Generated(_) | UnitaryMatrixObject::Sparse(_) => {
+                    let data = unit.matrix_data().unwrap();
+                    let co = Self::vec_matrix_to_circuitobject(co.n, data);
+                    self.try_apply_with_condition(cr, r, co)
+                }
+                UnitaryMatrixObject::MAT(data) => {
+                    let crn = cr.n();
+                    let data = controlled_matrix_data(crn, co.n, &data);
+                    let cr = self.merge_two_registers(cr, r);
+                    let cr = self.apply_vec_matrix(cr, data)?;
+                    match self.split_register_relative(cr, 0..crn) {
+                        SplitResult::SPLIT(cr, r) => Ok((cr, r)),
+                        SplitResult::SELECTED(_) => unreachable!(),
+                        SplitResult::UNSELECTED(_) => unreachable!(),
+                    }
+                }
+            },
+            BuilderCircuitObjectType::Measurement(_) => {
+                Err(CircuitError::new("Cannot condition measurements."))
+            }
+        }
+    }
+}
+
+/// Embed the `n` qubit matrix `data` in a matrix over `n_control + n` qubits which applies `data`
+/// only when all control qubits are `1`, and is the identity otherwise.
+fn controlled_matrix_data<P: Precision>(
+    n_control: usize,
+    n: usize,
+    data: &[Complex<P>],
+) -> Vec<Complex<P>> {
+    let side = 1 << (n_control + n);
+    let mask = (1 << n) - 1;
+    let control_mask = side - 1 - mask;
+    (0..side * side)
+        .map(|i| {
+            let (row, col) = (i / side, i % side);
+            if row & control_mask == control_mask && col & control_mask == control_mask {
+                data[((row & mask) << n) | (col & mask)]
+            } else if row == col {
+                Complex::one()
+            } else {
+                Complex::zero()
+            }
+        })
+        .collect()
+}
+
+fn split_vector_at<T>(mut v: Vec<T>, x: usize) -> (Vec<T>, Vec<T>) {
+    let n = v.len();
+    assert!(n >= x);
+    let mut b = vec![];
+    for _ in 0..(n - x) {
+        b.push(v.pop().unwrap());
+    }
+    b.reverse();
+    (v, b)
+}
+
+impl<P: Precision> Subcircuitable for LocalBuilder<P> {
+    type Subcircuit = Vec<(Vec<usize>, Self::CircuitObject)>;
+
+    fn make_subcircuit(&self) -> CircuitResult<Self::Subcircuit> {
+        Ok(self.pipeline.clone())
+    }
+
+    fn apply_subcircuit(
+        &mut self,
+        sc: Self::Subcircuit,
+        r: Self::Register,
+    ) -> CircuitResult<Self::Register> {
+        apply_pipeline_objects(self, sc, r)
+    }
+}
+
+impl<P: Precision> Invertable for LocalBuilder<P> {
+    type SimilarBuilder = Self;
+
+    fn new_similar(&self) -> Self {
+        Self::default()
+    }
+
+    fn invert_subcircuit(sc: Self::Subcircuit) -> CircuitResult<Self::Subcircuit> {
+        sc.into_iter()
+            .rev()
+            .try_fold(vec![], |mut acc, (indices, co)| {
+                invert_circuit_object(co)?
+                    .into_iter()
+                    .for_each(|co| acc.push((indices.clone(), co)));
+                Ok(acc)
+            })
+    }
+}
+
+impl<P: Precision> ConditionableSubcircuit for LocalBuilder<P> {
+    fn apply_conditioned_subcircuit(
+        &mut self,
+        sc: Self::Subcircuit,
+        cr: Self::Register,
+        r: Self::Register,
+    ) -> Result<(Self::Register, Self::Register), CircuitError> {
+        let mut cb = self.condition_with(cr);
+        let r = apply_pipeline_objects(&mut cb, sc, r)?;
+        let cr = cb.dissolve();
+        Ok((cr, r))
+    }
+}
+
+fn apply_pipeline_objects<CB, CO>(
+    cb: &mut CB,
+    sc: CB::Subcircuit,
+    r: CB::Register,
+) -> CircuitResult<CB::Register>
+where
+    CB: CircuitBuilder<CircuitObject = CO>
+        + Subcircuitable<Subcircuit = Vec<(Vec<usize>, CO)>>
+        + TemporaryRegisterBuilder,
+{
+    let rn = r.n();
+    let mut rs = cb.split_all_register(r);
+    let max_r_index = sc
+        .iter()
+        .flat_map(|(indices, _)| indices.iter().cloned().max())
+        .max()
+        .unwrap();
+    // Need temp qubits for excess.
+    if let Some(temp_n) = NonZeroUsize::new((1 + max_r_index).saturating_sub(rn)) {
+        let temp = cb.make_zeroed_temp_register(temp_n);
+        rs.extend(cb.split_all_register(temp).into_iter());
+    }
+    let mut rs = rs.into_iter().map(Some).collect::<Vec<_>>();
+    sc.into_iter().try_for_each(|(indices, co)| {
+        let sub_rs = indices.iter().map(|index| rs[*index].take().unwrap());
+        let sub_r = cb.merge_registers(sub_rs).unwrap();
+        let sub_r = cb.apply_circuit_object(sub_r, co)?;
+        let sub_rs = cb.split_all_register(sub_r);
+        indices
+            .into_iter()
+            .zip(sub_rs.into_iter())
+            .for_each(|(index, r)| {
+                rs[index] = Some(r);
+            });
+        assert!(rs.iter().all(|r| r.is_some()), "Not all qubits returned");
+        Ok(())
+    })?;
+    let rs = rs.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+    let (rs, trs) = split_vector_at(rs, rn);
+    if let Some(tr) = cb.merge_registers(trs) {
+        cb.return_zeroed_temp_register(tr);
+    }
+    let r = cb.merge_registers(rs.into_iter()).unwrap();
+    Ok(r)
+}
+
+fn invert_circuit_object<P: Precision>(
+    co: BuilderCircuitObject<P>,
+) -> CircuitResult<Vec<BuilderCircuitObject<P>>> {
+    match co.object {
+        BuilderCircuitObjectType::Unitary(u) => {
+            let new_objs = match u {
+                UnitaryMatrixObject::X
+                | UnitaryMatrixObject::Y
+                | UnitaryMatrixObject::Z
+                | UnitaryMatrixObject::H
+                | UnitaryMatrixObject::CNOT
+                | UnitaryMatrixObject::SWAP
+                | UnitaryMatrixObject::Identity
+                | UnitaryMatrixObject::Barrier => vec![u],
+                UnitaryMatrixObject::S => vec![UnitaryMatrixObject::Z, u],
+                UnitaryMatrixObject::T => vec![UnitaryMatrixObject::Z, UnitaryMatrixObject::S, u],
+                UnitaryMatrixObject::Rz(phase) => vec![UnitaryMatrixObject::Rz(phase.neg())],
+                UnitaryMatrixObject::GlobalPhase(phase) => {
+                    vec![UnitaryMatrixObject::GlobalPhase(phase.neg())]
+                }
+                UnitaryMatrixObject::MAT(data) => {
+                    // Matrix is unitary, inverse is just dagger
+                    let mut inverse_data = vec![Complex::zero(); data.len()];
+                    let nn = 1 << co.n;
+                    (0..nn).for_each(|i| {
+                        (0..nn).for_each(|j| {
+                            let index_a = i * nn + j;
+                            let index_b = j * nn + i;
+                            inverse_data[index_a] = data[index_b].conj();
+                        })
+                    });
+                    vec![UnitaryMatrixObject::MAT(inverse_data)]
+                }
+                UnitaryMatrixObject::Generated(g) => {
+                    vec![UnitaryMatrixObject::Generated(g.dagger())]
+                }
+                UnitaryMatrixObject::Sparse(rows) => {
+                    let columns = transpose_sparse(rows)
+                        .into_iter()
+                        .map(|entries| entries.into_iter().map(|(i, c)| (i, c.conj())).collect())
+                        .collect();
+                    vec![UnitaryMatrixObject::Sparse(columns)]
+                }
+            };
+            Ok(new_objs
+                .into_iter()
+                .map(|new_obj| BuilderCircuitObject {
+                    n: co.n,
+                    object: BuilderCircuitObjectType::Unitary(new_obj),
+                })
+                .collect())
+        }
+        BuilderCircuitObjectType::Measurement(_) => {
+            Err(CircuitError::new("Cannot invert measurement."))
+        }
+    }
+}
+
+impl<P: Precision> RecursiveCircuitBuilder<P> for LocalBuilder<P> {
+    type RecursiveSimilarBuilder = Self::SimilarBuilder;
+}
+
+#[cfg(test)]
+mod builder_tests {
+    use super::*;
+    use crate::state_ops::linalg::{kron, matmul};
+    use crate::test_utils::assert_states_equal;
+    use rand::rngs::mock::StepRng;
+
+    fn assert_states_close(a: &[Complex<f64>], b: &[Complex<f64>]) {
+        assert_eq!(a.len(), b.len());
+        a.iter()
+            .zip(b.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10, "{:?} != {:?}", a, b));
+    }
+
+    #[test]
+    fn test_single_op_broadcast() {
+        let n = NonZeroUsize::new(3).unwrap();
+
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(n);
+        let r = b.s(r);
+        let r = b.h(r);
+        let split_state = b
+            .calculate_state_with_init([(&r, 0b010)])
+            .into_state()
+            .unwrap();
+
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(n);
+        let r = b.s(r);
+        b.set_single_op_broadcast(true);
+        let depth = b.pipeline_depth();
+        let r = b.h(r);
+        assert_eq!(b.pipeline_depth(), depth + 1);
+        let broadcast_state = b
+            .calculate_state_with_init([(&r, 0b010)])
+            .into_state()
+            .unwrap();
+
+        assert_states_close(&split_state, &broadcast_state);
+    }
+
+    #[test]
+    fn test_stochastic_measurement_keeps_state() {
+        // Stochastic measurements leave the state in place, so later ops must read from it rather
+        // than from the scratch buffer.
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let q = b.h(q);
+        let (q, _handle) = b.measure_stochastic(q);
+        let q = b.s(q);
+        let (q, _handle) = b.measure_stochastic(q);
+        let _q = b.h(q);
+        let state = b.calculate_state().into_state().unwrap();
+        let half = Complex::new(0.5, 0.0);
+        let half_i = Complex::new(0.0, 0.5);
+        assert_states_close(&state, &[half + half_i, half - half_i]);
+    }
+
+    #[test]
+    fn test_qubit_with_state() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let plus = Complex::new(1.0, 0.0);
+        let q = b.qubit_with_state(vec![plus, plus])?;
+        let r = b.qubit();
+        let r = b.x(r);
+        let (q, handle) = b.measure_stochastic(q);
+        let _r = b.merge_two_registers(q, r);
+        let result = b.calculate_state();
+        let state = result.state().unwrap();
+        let probs = result.get_stochastic_measurement(handle);
+        assert!((probs[0] - 0.5).abs() < 1e-10);
+        assert!((probs[1] - 0.5).abs() < 1e-10);
+        let half = std::f64::consts::FRAC_1_SQRT_2;
+        assert_states_close(state, &[0.0, half, 0.0, half].map(|x| Complex::new(x, 0.0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_with_state_bad_size() {
+        let mut b = LocalBuilder::<f64>::default();
+        let n = NonZeroUsize::new(2).unwrap();
+        assert!(b.register_with_state(n, vec![Complex::one(); 2]).is_err());
+        assert!(b.register_with_state(n, vec![Complex::zero(); 4]).is_err());
+        assert!(b
+            .register_with_state(n, vec![Complex::new(1e-12, 0.0); 4])
+            .is_err());
+    }
+
+    #[test]
+    fn test_register_with_state_normalizes() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let state = (1..=8).map(|x| Complex::new(x as f64, -0.5)).collect();
+        let r = b.register_with_state(NonZeroUsize::new(3).unwrap(), state)?;
+        let (_r, handle) = b.measure_stochastic(r);
+        let result = b.calculate_state();
+        let total: f64 = result.get_stochastic_measurement(handle).iter().sum();
+        assert!((total - 1.0).abs() < 1e-10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_controlled_subcircuit() -> CircuitResult<()> {
+        for control in [0, 1] {
+            let mut b = LocalBuilder::<f64>::default();
+            let cr = b.qubit();
+            let r = b.qubit();
+            let (cr, r) = b.controlled_subcircuit(cr, r, |b, r| {
+                let r = b.x(r);
+                Ok(b.z(r))
+            })?;
+            let (r, handle) = b.measure_stochastic(r);
+            let result = b.calculate_state_with_init([(&cr, control)]);
+            let probs = result.get_stochastic_measurement(handle);
+            assert!((probs[control] - 1.0).abs() < 1e-10);
+            let _r = b.merge_two_registers(cr, r);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_controlled_subcircuit_mat() -> CircuitResult<()> {
+        for control in [0, 1] {
+            let mut b = LocalBuilder::<f64>::default();
+            let cr = b.qubit();
+            let r = b.qubit();
+            let (cr, r) = b.controlled_subcircuit(cr, r, |b, r| {
+                let (o, l) = (Complex::zero(), Complex::one());
+                b.apply_matrix(r, [o, l, l, o])
+            })?;
+            let (r, handle) = b.measure_stochastic(r);
+            let result = b.calculate_state_with_init([(&cr, control)]);
+            let probs = result.get_stochastic_measurement(handle);
+            assert!((probs[control] - 1.0).abs() < 1e-10);
+            let _r = b.merge_two_registers(cr, r);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_controlled_global_phase() -> CircuitResult<()> {
+        // A controlled global phase of theta is a relative phase of theta on the control.
+        let theta = std::f64::consts::FRAC_PI_3;
+        for ratio in [false, true] {
+            let mut b = LocalBuilder::<f64>::default();
+            let cr = b.qubit();
+            let r = b.qubit();
+            let cr = b.h(cr);
+            let (cr, r) = b.controlled_subcircuit(cr, r, |b, r| {
+                Ok(if ratio {
+                    b.apply_global_phase_ratio(r, Rational64::new(1, 3))
+                } else {
+                    b.apply_global_phase(r, theta)
+                })
+            })?;
+            let _r = b.merge_two_registers(cr, r);
+            // Temporary qubits used for conditioning come last and are returned to |0>. The
+            // phase tracked separately from the state is part of the relative phase.
+            let result = b.calculate_state();
+            let phase = result.global_phase();
+            let state = result.state().unwrap();
+            let state = state
+                .iter()
+                .step_by(state.len() / 4)
+                .map(|c| c * phase)
+                .collect::<Vec<_>>();
+            let s = std::f64::consts::FRAC_1_SQRT_2;
+            let expected = [
+                Complex::new(s, 0.0),
+                Complex::zero(),
+                Complex::from_polar(s, theta),
+                Complex::zero(),
+            ];
+            assert_states_close(&state, &expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_controlled_subcircuit_measurement() {
+        let mut b = LocalBuilder::<f64>::default();
+        let cr = b.qubit();
+        let r = b.qubit();
+        let res = b.controlled_subcircuit(cr, r, |b, r| {
+            let (r, _) = b.measure(r);
+            Ok(r)
+        });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_generated_matrix() -> CircuitResult<()> {
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        let run = |generated: bool| -> CircuitResult<Vec<Complex<f64>>> {
+            let mut b = LocalBuilder::<f64>::default();
+            let r = b.register(NonZeroUsize::new(2).unwrap());
+            let r = if generated {
+                b.apply_generated_matrix(r, move |row, col| {
+                    let sign = if (row & col).count_ones() % 2 == 1 {
+                        -1.0
+                    } else {
+                        1.0
+                    };
+                    Complex::new(sign * 0.5, 0.0)
+                })?
+            } else {
+                b.h(r)
+            };
+            let state = b
+                .calculate_state_with_init([(&r, 0b01)])
+                .into_state()
+                .unwrap();
+            Ok(state)
+        };
+        assert_states_close(&run(true)?, &run(false)?);
+        assert!((run(true)?[0].re - h * h).abs() < 1e-10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_measure_observable_z() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let z = [1.0, 0.0, 0.0, -1.0].map(|x| Complex::new(x, 0.0));
+        let (_q, handle) = b.measure_observable(q, &z)?;
+        let result = b.calculate_state();
+        let state = result.state().unwrap();
+        let (eigenvalue, p) = result.get_observable_measurement(&handle);
+        assert!((eigenvalue - 1.0).abs() < 1e-10);
+        assert!((p - 1.0).abs() < 1e-10);
+        assert_states_close(state, &[Complex::one(), Complex::zero()]);
+
+        // Z on the first qubit of the register, which starts as |1>.
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let zi = [
+            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, -1.0,
+        ]
+        .map(|x| Complex::new(x, 0.0));
+        let (r, handle) = b.measure_observable(r, &zi)?;
+        let result = b.calculate_state_with_init([(&r, 0b01)]);
+        let (eigenvalue, _) = result.get_observable_measurement(&handle);
+        assert!((eigenvalue + 1.0).abs() < 1e-10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_measure_observable_not_hermitian() {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let m = [0.0, 1.0, 0.0, 0.0].map(|x| Complex::new(x, 0.0));
+        assert!(b.measure_observable(q, &m).is_err());
+    }
+
+    #[test]
+    fn test_with_ancilla_toffoli() -> CircuitResult<()> {
+        for x in 0..8 {
+            let mut b = LocalBuilder::<f64>::default();
+            let cr = b.register(NonZeroUsize::new(3).unwrap());
+            let t = b.qubit();
+            let (cr, t) = b.with_ancilla(NonZeroUsize::new(1).unwrap(), |b, anc| {
+                let (head, tail) = match b.split_register_relative(cr, [0, 1]) {
+                    SplitResult::SPLIT(head, tail) => (head, tail),
+                    _ => unreachable!(),
+                };
+                let (head, anc) = b.toffoli(head, anc)?;
+                let tail = b.merge_two_registers(anc, tail);
+                let (tail, t) = b.toffoli(tail, t)?;
+                let (anc, tail) = b.split_first_qubit(tail);
+                let anc = anc.unwrap();
+                let (head, anc) = b.toffoli(head, anc)?;
+                let cr = b.merge_two_registers(head, tail);
+                Ok(((cr, t), anc))
+            })?;
+            let (t, handle) = b.measure_stochastic(t);
+            let result = b.calculate_state_with_init([(&cr, x)]);
+            result.check_ancillas()?;
+            let expected = if x == 0b111 { 1 } else { 0 };
+            let probs = result.get_stochastic_measurement(handle);
+            assert!((probs[expected] - 1.0).abs() < 1e-10);
+            let _r = b.merge_two_registers(cr, t);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_ancilla_dirty() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let q = b.with_ancilla(NonZeroUsize::new(1).unwrap(), |b, anc| {
+            let q = b.h(q);
+            let (q, anc) = b.cnot(q, anc)?;
+            Ok((q, anc))
+        })?;
+        let err = b
+            .calculate_state_with_init([(&q, 0)])
+            .check_ancillas()
+            .unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::DirtyAncilla);
+        assert!(err.msg.contains("[1]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_gate_matrix() -> CircuitResult<()> {
+        let theta = 0.1f64;
+        let (c, s) = (theta.cos(), theta.sin());
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        // Hadamard followed by a small Y rotation.
+        let imperfect = [h * (c - s), h * (c + s), h * (c + s), -h * (c - s)]
+            .map(|x| Complex::new(x, 0.0))
+            .to_vec();
+
+        let mut b = LocalBuilder::<f64>::default();
+        b.set_gate_matrix("H", imperfect.clone())?;
+        let q = b.qubit();
+        let _q = b.h(q);
+        let state = b.calculate_state().into_state().unwrap();
+        assert_states_close(&state, &[imperfect[0], imperfect[2]]);
+        assert!((state[0].re - h).abs() > 1e-3);
+
+        b.clear_gate_matrix("h");
+        assert!(b.set_gate_matrix("foo", imperfect).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_result_in_order() {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.x(q);
+        let (q, first) = b.measure(q);
+        let (r, second) = b.measure_stochastic(r);
+        let (q, third) = b.measure(q);
+        let _r = b.merge_two_registers(q, r);
+
+        let result = b.calculate_state();
+        assert_eq!(result.state().map(|s| s.len()), Some(4));
+        assert_eq!(result.get_measurement(first).0, 1);
+        assert_eq!(result.get_stochastic_measurement(second), &[1.0, 0.0]);
+        assert_eq!(result.get_measurement(third).0, 1);
+
+        let kinds = result
+            .measurements()
+            .iter()
+            .map(|m| matches!(m, MeasurementResults::Single(_, _)))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![true, false, true]);
+
+        let (state, measurements) = result.into_parts();
+        let result = RunResult::new(state, measurements);
+        assert_eq!(result.measurements().len(), 3);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let (q, _) = b.measure(q);
+        let q = b.x(q);
+        let _r = b.merge_two_registers(q, r);
+        assert_eq!(b.n(), 2);
+
+        b.reset();
+        assert_eq!(b.n(), 0);
+        assert_eq!(b.pipeline_depth(), 0);
+        let q = b.qubit();
+        assert_eq!(q.indices, vec![0]);
+        let (q, handle) = b.measure(q);
+        let _q = b.h(q);
+        let result = b.calculate_state();
+        assert_eq!(result.into_state().unwrap().len(), 2);
+        assert_eq!(handle.id, 0);
+    }
+
+    #[test]
+    fn test_apply_complex_function() -> CircuitResult<()> {
+        // A uniform superposition over |x>|y> maps to the sum of e^{0.3ix}|x>|y ^ (x + 1) % 4>.
+        let mut b = LocalBuilder::<f64>::default();
+        let q_in = b.register(NonZeroUsize::new(2).unwrap());
+        let q_out = b.register(NonZeroUsize::new(2).unwrap());
+        let q_in = b.h(q_in);
+        let q_out = b.h(q_out);
+        let (q_in, q_out) = b.apply_complex_function(q_in, q_out, |x| {
+            ((x + 1) % 4, Complex::from_polar(1.0, 0.3 * x as f64))
+        })?;
+        let _r = b.merge_two_registers(q_in, q_out);
+        let state = b.calculate_state().into_state().unwrap();
+
+        // Registers are little-endian while the state index is big-endian over the qubits.
+        let index = |x: usize, y: usize| (flip_bits(2, x) << 2) | flip_bits(2, y);
+        let mut expected = vec![Complex::zero(); 16];
+        (0..4).for_each(|x| {
+            (0..4).for_each(|y| {
+                expected[index(x, y ^ ((x + 1) % 4))] = Complex::from_polar(0.25, 0.3 * x as f64)
+            })
+        });
+        assert_states_close(&state, &expected);
+
+        // Each |x>|0> should map to e^{0.3ix}|x>|x>.
+        let mut b = LocalBuilder::<f64>::default();
+        let q_in = b.register(NonZeroUsize::new(2).unwrap());
+        let q_out = b.register(NonZeroUsize::new(2).unwrap());
+        let (q_in, q_out) =
+            b.apply_complex_function(q_in, q_out, |x| (x, Complex::from_polar(1.0, 0.3)))?;
+        let (q_out, handle) = b.measure(q_out);
+        let r = b.merge_two_registers(q_in, q_out);
+        let result = b.calculate_state_with_init([(&r, 0b01)]);
+        assert_eq!(result.get_measurement(handle).0, 0b01);
+
+        let q_in = b.qubit();
+        let q_out = b.qubit();
+        assert!(b
+            .apply_complex_function(q_in, q_out, |x| (x, Complex::new(0.5, 0.0)))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_disjoint_outputs_share_ops() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.h(q);
+        let (q, r) = b.cnot(q, r)?;
+        // Two separate output trees which share the h and cnot as ancestors.
+        let (_q, hq) = b.measure_stochastic(q);
+        let (_r, hr) = b.measure_stochastic(r);
+
+        let result = b.calculate_state();
+        assert_eq!(b.pipeline_depth(), 4);
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let expected = [s, 0.0, 0.0, s].map(Complex::from);
+        assert_states_close(result.state().unwrap(), &expected);
+        for handle in [hq, hr] {
+            let probs = result.get_stochastic_measurement(handle);
+            assert!((probs[0] - 0.5).abs() < 1e-10);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_physical_index() {
+        let mut b = LocalBuilder::<f64>::default();
+        let _q = b.qubit();
+        let r = b.register(NonZeroUsize::new(3).unwrap());
+        assert_eq!(r.physical_index(0), Some(1));
+        assert_eq!(r.physical_index(2), Some(3));
+        assert_eq!(r.physical_index(3), None);
+
+        // Out of range indices are ignored when splitting.
+        match b.split_register_relative(r, [1, 3]) {
+            SplitResult::SPLIT(ra, rb) => {
+                assert_eq!(ra.indices, vec![2]);
+                assert_eq!(rb.indices, vec![1, 3]);
+            }
+            _ => panic!("Expected a split"),
+        }
+    }
+
+    #[test]
+    fn test_post_measurement_state() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        b.set_retain_post_measurement_states(true);
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.h(q);
+        let (q, r) = b.cnot(q, r)?;
+        let (q, handle) = b.measure(q);
+        let q = b.h(q);
+        let _r = b.merge_two_registers(q, r);
+
+        let result = b.calculate_state();
+        let (measured, _) = result.get_measurement(handle);
+        let mut expected = vec![Complex::zero(); 4];
+        expected[if measured == 0 { 0 } else { 3 }] = Complex::one();
+        assert_states_close(result.post_measurement_state(handle).unwrap(), &expected);
+
+        b.set_retain_post_measurement_states(false);
+        assert!(b.calculate_state().post_measurement_state(handle).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_u3() {
+        let run = |f: &dyn Fn(&mut LocalBuilder<f64>, Qudit) -> Qudit| {
+            let mut b = LocalBuilder::<f64>::default();
+            let r = b.register(NonZeroUsize::new(2).unwrap());
+            let r = f(&mut b, r);
+            b.calculate_state_with_init([(&r, 0b01)])
+                .into_state()
+                .unwrap()
+        };
+        let pi = std::f64::consts::PI;
+        assert_states_equal(
+            &run(&|b, r| b.u3(r, pi / 2.0, 0.0, pi)),
+            &run(&|b, r| b.h(r)),
+            1e-10,
+        );
+        assert_states_equal(
+            &run(&|b, r| b.u3(r, pi, 0.0, pi)),
+            &run(&|b, r| b.x(r)),
+            1e-10,
+        );
+    }
+
+    #[test]
+    fn test_run_without_state() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.x(q);
+        let (q, r) = b.cnot(q, r)?;
+        let (r, handle) = b.measure(r);
+        let (r, stochastic_handle) = b.measure_stochastic(r);
+        let r = b.h(r);
+        let _r = b.merge_two_registers(q, r);
+
+        let options = RunOptions {
+            retain_state: false,
+            ..Default::default()
+        };
+        let result = b.calculate_state_with_options(None, options);
+        assert!(result.state().is_none());
+        assert_eq!(result.get_measurement(handle), (1, 1.0));
+        assert_eq!(
+            result.get_stochastic_measurement(stochastic_handle),
+            &[0.0, 1.0]
+        );
+
+        let result = b.calculate_state_with_options(None, RunOptions::default());
+        assert!(result.state().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prepare_uniform_subset() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(3).unwrap());
+        let r = b.prepare_uniform_subset(r, &[0b001, 0b010, 0b100])?;
+        let (r, handle) = b.measure_stochastic(r);
+        assert!(b.prepare_uniform_subset(r, &[0]).is_err());
+
+        let result = b.calculate_state();
+        let probs = result.get_stochastic_measurement(handle);
+        probs.iter().enumerate().for_each(|(x, p)| {
+            let expected = if [1, 2, 4].contains(&x) {
+                1.0 / 3.0
+            } else {
+                0.0
+            };
+            assert!((p - expected).abs() < 1e-10);
+        });
+
+        let q = b.qubit();
+        assert!(b.prepare_uniform_subset(q, &[2]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_measure_into_creg() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q0 = b.qubit();
+        let q1 = b.qubit();
+        let c = b.creg(NonZeroUsize::new(3).unwrap());
+        let q1 = b.x(q1);
+        let q0 = b.measure_into(q0, &c, 0)?;
+        let q1 = b.measure_into(q1, &c, 1)?;
+        let q1 = b.measure_into(q1, &c, 2)?;
+        let r = b.merge_two_registers(q0, q1);
+        assert!(b.measure_into(r, &c, 2).is_err());
+
+        let result = b.calculate_state();
+        assert_eq!(result.get_classical_register(&c), 0b110);
+        Ok(())
+    }
+
+    #[test]
+    fn test_distribution_entropy() {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.h(q);
+        let r = b.x(r);
+        let (q, hq) = b.measure_stochastic(q);
+        let (r, hr) = b.measure_stochastic(r);
+        let _r = b.merge_two_registers(q, r);
+
+        let result = b.calculate_state();
+        assert!((result.distribution_entropy(hq) - 1.0).abs() < 1e-10);
+        assert!(result.distribution_entropy(hr).abs() < 1e-10);
+        assert_eq!(result.most_likely(hr), 1);
+    }
+
+    #[test]
+    fn test_apply_mat_absolute() -> CircuitResult<()> {
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let h = [s, s, s, -s].map(Complex::from).to_vec();
+        let mut cnot = vec![Complex::zero(); 16];
+        [(0, 0), (1, 1), (2, 3), (3, 2)]
+            .iter()
+            .for_each(|(row, col)| cnot[row * 4 + col] = Complex::one());
+
+        let mut a = LocalBuilder::<f64>::default();
+        let q = a.qubit();
+        let r = a.qubit();
+        let q = a.apply_vec_matrix(q, h.clone())?;
+        let qr = a.merge_two_registers(q, r);
+        let _qr = a.apply_vec_matrix(qr, cnot.clone())?;
+
+        let mut b = LocalBuilder::<f64>::default();
+        let _r = b.register(NonZeroUsize::new(2).unwrap());
+        b.apply_mat_absolute(vec![0], h)?;
+        b.apply_mat_absolute(vec![0, 1], cnot.clone())?;
+        assert!(b.apply_mat_absolute(vec![1, 2], cnot.clone()).is_err());
+        assert!(b.apply_mat_absolute(vec![1, 1], cnot).is_err());
+
+        assert_states_close(
+            a.calculate_state().state().unwrap(),
+            b.calculate_state().state().unwrap(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pad_to_depth() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.h(q);
+        let (q, r) = b.cnot(q, r)?;
+        let q = b.x(q);
+        let _r = b.merge_two_registers(q, r);
+        let before = b.calculate_state().into_state().unwrap();
+
+        b.pad_to_depth(4);
+        let mut depths = [0, 0];
+        b.pipeline
+            .iter()
+            .flat_map(|(indices, _)| indices)
+            .for_each(|i| depths[*i] += 1);
+        assert_eq!(depths, [4, 4]);
+        assert_states_close(&before, b.calculate_state().state().unwrap());
+
+        // Optimizations leave the padding in place.
+        let depth = b.pipeline_depth();
+        assert_eq!(b.cancel_inverses(), 0);
+        assert_eq!(b.fuse_single_qubit(), 0);
+        assert_eq!(b.fuse_diagonal_runs(2), 0);
+        assert_eq!(b.pipeline_depth(), depth);
+        Ok(())
+    }
+
+    #[test]
+    fn test_indices_overlap() {
+        let mut b = LocalBuilder::<f64>::default();
+        let ra = b.register(NonZeroUsize::new(2).unwrap());
+        let rb = b.register(NonZeroUsize::new(2).unwrap());
+        assert!(!indices_overlap(&ra, &rb));
+        assert!(indices_overlap(&ra, &ra));
+        let alias = Qudit::new(vec![3, 4]).unwrap();
+        assert!(indices_overlap(&rb, &alias));
+        assert!(!indices_overlap(&ra, &alias));
+    }
+
+    #[test]
+    fn test_deterministic_epsilon() {
+        // Leaves a probability of about 1e-10 of measuring |1>.
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let q = b.rx(q, 2e-5);
+        let (_q, handle) = b.measure(q);
+
+        let result = b.calculate_state_with_options(None, RunOptions::default());
+        let (_, p) = result.get_measurement(handle);
+        assert!(p < 1.0);
+
+        let options = RunOptions {
+            deterministic_epsilon: 1e-6,
+            ..Default::default()
+        };
+        let result = b.calculate_state_with_options(None, options);
+        assert_eq!(result.get_measurement(handle), (0, 1.0));
+        let state = result.state().unwrap();
+        assert!((state[0].norm() - 1.0).abs() < 1e-10);
+        assert_eq!(state[1], Complex::zero());
+    }
+
+    #[test]
+    fn test_root_id() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.register(NonZeroUsize::new(3).unwrap());
+        assert_eq!(b.root_id(&q), Some(0));
+        assert_eq!(b.root_id(&r), Some(1));
+
+        let (ra, rb) = match b.split_register_relative(r, [0]) {
+            SplitResult::SPLIT(ra, rb) => (ra, rb),
+            _ => unreachable!(),
+        };
+        let rb = b.h(rb);
+        assert_eq!(b.root_id(&rb), Some(1));
+        let r = b.merge_two_registers(rb, ra);
+        assert_eq!(b.root_id(&r), Some(1));
+
+        let r = b.merge_two_registers(q, r);
+        assert_eq!(b.root_id(&r), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocation_timeline() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let r = b.h(r);
+        let q = b.qubit();
+        let (r, q) = b.with_ancilla(NonZeroUsize::new(2).unwrap(), |_, a| Ok(((r, q), a)))?;
+        // The released ancillas are reused rather than allocated again.
+        let _r = b.with_ancilla(NonZeroUsize::new(1).unwrap(), |_, a| Ok((r, a)))?;
+        assert_eq!(
+            b.allocation_timeline(),
+            vec![(0, vec![0, 1]), (1, vec![2]), (2, vec![3]), (3, vec![4])]
+        );
+        assert_eq!(b.root_id(&q), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_phase_gates() {
+        // Phases are only visible in superposition, so start each qubit in |+>.
+        let run = |f: &dyn Fn(&mut LocalBuilder<f64>, Qudit) -> Qudit, n: usize| {
+            let mut b = LocalBuilder::<f64>::default();
+            let r = b.register(NonZeroUsize::new(n).unwrap());
+            let r = b.h(r);
+            let _r = f(&mut b, r);
+            b.calculate_state().into_state().unwrap()
+        };
+        for n in [1, 2] {
+            let z = run(&|b, r| b.z(r), n);
+            let ss = run(&|b, r| (0..2).fold(r, |r, _| b.s(r)), n);
+            let tttt = run(&|b, r| (0..4).fold(r, |r, _| b.t(r)), n);
+            assert_states_close(&ss, &z);
+            assert_states_close(&tttt, &z);
+
+            let identity = run(&|_, r| r, n);
+            let s_dagger = run(
+                &|b, r| {
+                    let r = b.s(r);
+                    b.s_dagger(r)
+                },
+                n,
+            );
+            let t_dagger = run(
+                &|b, r| {
+                    let r = b.t(r);
+                    b.t_dagger(r)
+                },
+                n,
+            );
+            assert_states_close(&s_dagger, &identity);
+            assert_states_close(&t_dagger, &identity);
+        }
+    }
+
+    #[test]
+    fn test_rotations_unitary() {
+        for theta in [0.0, 0.3, 1.0, std::f64::consts::PI, 4.5, -2.0] {
+            for axis in 0..3 {
+                for n in [1, 2] {
+                    let mut b = LocalBuilder::<f64>::default();
+                    let r = b.register(NonZeroUsize::new(n).unwrap());
+                    let r = match axis {
+                        0 => b.rx(r, theta),
+                        1 => b.ry(r, theta),
+                        _ => b.rz(r, theta),
+                    };
+                    let columns =
+                        make_circuit_matrix(&mut b, &r, |result| result.into_state().unwrap());
+                    // The columns of a unitary are orthonormal.
+                    columns.iter().enumerate().for_each(|(i, a)| {
+                        columns.iter().enumerate().for_each(|(j, b)| {
+                            let dot: Complex<f64> =
+                                a.iter().zip(b.iter()).map(|(a, b)| a.conj() * b).sum();
+                            let expected = if i == j { 1.0 } else { 0.0 };
+                            assert!((dot - Complex::new(expected, 0.0)).norm() < 1e-10);
+                        })
+                    });
+                }
+            }
+            let mut b = LocalBuilder::<f64>::default();
+            let q = b.qubit();
+            let q = b.rz(q, theta);
+            let columns = make_circuit_matrix(&mut b, &q, |result| result.into_state().unwrap());
+            let zero = Complex::zero();
+            assert_states_close(&columns[0], &[Complex::from_polar(1.0, -theta / 2.0), zero]);
+            assert_states_close(&columns[1], &[zero, Complex::from_polar(1.0, theta / 2.0)]);
+        }
+    }
+
+    #[test]
+    fn test_cnot_toffoli_keep_indices() -> CircuitResult<()> {
+        for x in 0..8 {
+            let mut b = LocalBuilder::<f64>::default();
+            let (ca, cb, t) = (b.qubit(), b.qubit(), b.qubit());
+            let (ca, t) = b.cnot(ca, t)?;
+            assert_eq!((ca.indices(), t.indices()), (&[0][..], &[2][..]));
+            let cr = b.merge_two_registers(ca, cb);
+            let (cr, t) = b.toffoli(cr, t)?;
+            assert_eq!((cr.indices(), t.indices()), (&[0, 1][..], &[2][..]));
+
+            let r = b.merge_two_registers(cr, t);
+            let (r, handle) = b.measure(r);
+            let result = b.calculate_state_with_init([(&r, x)]);
+            let (ca, cb, t) = (x & 1, (x >> 1) & 1, (x >> 2) & 1);
+            let expected = ca | (cb << 1) | ((t ^ ca ^ (ca & cb)) << 2);
+            assert_eq!(result.get_measurement(handle).0, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_condition_on_self() {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        // Registers cannot normally be aliased, so forge a second handle to the same qubit.
+        let alias = Qudit::new([0]).unwrap();
+        let mut cb = b.condition_with(q);
+        let co = cb.make_x();
+        let err = cb.apply_circuit_object(alias, co).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::OverlappingIndices);
+        assert_eq!(cb.control_indices(), vec![0]);
+    }
+
+    #[test]
+    fn test_min_parallel_qubits() {
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(4).unwrap());
+        let r = b.h(r);
+        let r = b.t(r);
+        let _r = b.rx(r, 0.3);
+        let run = |b: &mut LocalBuilder<f64>, min_parallel_qubits| {
+            let options = RunOptions {
+                min_parallel_qubits,
+                ..Default::default()
+            };
+            b.calculate_state_with_options(None, options)
+                .into_state()
+                .unwrap()
+        };
+        let serial = run(&mut b, usize::MAX);
+        let parallel = run(&mut b, 0);
+        assert_eq!(serial, parallel);
+    }
+
+    /// Build a circuit on `n` qubits out of `depth` random single qubit unitaries and dense two
+    /// qubit unitaries on random qubits.
+    fn random_circuit(n: usize, depth: usize, seed: u64) -> CircuitResult<LocalBuilder<f64>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut random_unitary = || {
+            let (theta, phi, lambda) = (rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>());
+            let (s, c) = (theta * 3.0).sin_cos();
+            vec![
+                Complex::from(c),
+                -Complex::from_polar(s, lambda * 6.0),
+                Complex::from_polar(s, phi * 6.0),
+                Complex::from_polar(c, (phi + lambda) * 6.0),
+            ]
+        };
+        let (o, l) = (Complex::zero(), Complex::one());
+        let cnot = vec![l, o, o, o, o, l, o, o, o, o, o, l, o, o, l, o];
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(n).unwrap());
+        (0..depth).try_fold(r, |r, i| {
+            let (a, c) = (i % n, (i * 7 + 3) % n);
+            if a == c {
+                b.apply_vec_matrix_on(r, &[a], random_unitary())
+            } else {
+                let u = kron(&random_unitary(), 1, &random_unitary(), 1)?;
+                b.apply_vec_matrix_on(r, &[a, c], matmul(&cnot, &u))
+            }
+        })?;
+        Ok(b)
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() -> CircuitResult<()> {
+        let mut b = random_circuit(12, 40, 1)?;
+        let mut run = |min_parallel_qubits| {
+            let options = RunOptions {
+                min_parallel_qubits,
+                ..Default::default()
+            };
+            b.calculate_state_with_options(None, options)
+                .into_state()
+                .unwrap()
+        };
+        let serial = run(usize::MAX);
+        let parallel = run(0);
+        serial
+            .iter()
+            .zip(parallel.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-12));
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_with_sparse_state() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let n = NonZeroUsize::new(10).unwrap();
+        let (a, c) = (Complex::new(0.6, 0.0), Complex::new(0.0, -0.8));
+        let _r = b.register_with_sparse_state(n, vec![(3, a), (1000, c)])?;
+        let result = b.calculate_state();
+        // Register values are little-endian while the state is indexed big-endian.
+        let amps = result.amplitudes(&[0b1100000000, 0b0001011111, 0])?;
+        assert_eq!(amps, vec![a, c, Complex::zero()]);
+
+        assert!(b.register_with_sparse_state(n, vec![(1024, a)]).is_err());
+        assert!(b
+            .register_with_sparse_state(n, vec![(3, a), (3, c)])
+            .is_err());
+        assert!(b.register_with_sparse_state(n, vec![(3, a)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuse_single_qubit() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let (q, r) = (b.qubit(), b.qubit());
+        let q = b.h(q);
+        let q = b.x(q);
+        let q = b.t(q);
+        let r = b.t(r);
+        let (q, r) = b.cnot(q, r)?;
+        let q = b.s(q);
+        let r = b.h(r);
+        let q = b.h(q);
+        let r = b.merge_two_registers(q, r);
+        let states = |b: &mut LocalBuilder<f64>| {
+            (0..4)
+                .map(|x| b.calculate_state_with_init([(&r, x)]).into_state().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let expected = states(&mut b);
+
+        assert_eq!(b.fuse_single_qubit(), 3);
+        assert_eq!(b.pipeline_depth(), 5);
+        states(&mut b)
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(fused, expected)| assert_states_close(fused, expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_inverses() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let (q, r) = (b.qubit(), b.qubit());
+        let q = b.h(q);
+        let q = b.x(q);
+        let q = b.x(q);
+        let (q, r) = b.cnot(q, r)?;
+        let r = b.z(r);
+        let r = b.z(r);
+        let (q, r) = b.cnot(q, r)?;
+        // The cnot in between keeps these apart.
+        let q = b.t(q);
+        let (q, r) = b.cnot(q, r)?;
+        let q = b.t_dagger(q);
+        let r = b.merge_two_registers(q, r);
+        let states = |b: &mut LocalBuilder<f64>| {
+            (0..4)
+                .map(|x| b.calculate_state_with_init([(&r, x)]).into_state().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let expected = states(&mut b);
+
+        assert_eq!(b.cancel_inverses(), 6);
+        // The h, t, cnot, and the three ops of t_dagger remain.
+        assert_eq!(b.pipeline_depth(), 6);
+        states(&mut b)
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(cancelled, expected)| assert_states_close(cancelled, expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_barrier_blocks_optimizations() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let (q, r) = (b.qubit(), b.qubit());
+        let q = b.x(q);
+        let r = b.h(r);
+        b.barrier(&[&q]);
+        let q = b.x(q);
+        // The barrier is only on q, so the hs on r still cancel.
+        let r = b.h(r);
+        let r = b.merge_two_registers(q, r);
+        let expected = b
+            .calculate_state_with_init([(&r, 0b10)])
+            .into_state()
+            .unwrap();
+
+        assert_eq!(b.cancel_inverses(), 2);
+        assert_eq!(b.fuse_single_qubit(), 0);
+        assert_eq!(b.fuse_diagonal_runs(2), 0);
+        assert_eq!(b.pipeline_depth(), 3);
+        let state = b
+            .calculate_state_with_init([(&r, 0b10)])
+            .into_state()
+            .unwrap();
+        assert_states_close(&state, &expected);
+
+        // Repeated registers are only listed once.
+        b.barrier(&[&r, &r]);
+        let (indices, obj) = b.pipeline.last().unwrap();
+        assert_eq!(indices, &[0, 1]);
+        assert_eq!(obj.n, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_freeze_and_replay() -> CircuitResult<()> {
+        let prepare = |b: &mut LocalBuilder<f64>, r: Qudit| -> CircuitResult<Qudit> {
+            let (q, r) = b.split_first_qubit(r);
+            let q = b.h(q.unwrap());
+            let (q, r) = b.cnot(q, r)?;
+            let r = b.rz(r, 0.4);
+            Ok(b.merge_two_registers(q, r))
+        };
+        let mut b = LocalBuilder::<f64>::default();
+        let other = b.qubit();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let other = b.x(other);
+        let r = prepare(&mut b, r)?;
+        let frozen = b.freeze(&r)?;
+        assert_eq!(frozen.len(), 3);
+        assert!(frozen
+            .iter()
+            .all(|(indices, _)| indices.iter().all(|i| *i < 2)));
+
+        // Replaying onto fresh registers matches building the circuit directly.
+        (0..4).try_for_each(|x| {
+            let mut replayed = LocalBuilder::<f64>::default();
+            let r = replayed.register(NonZeroUsize::new(2).unwrap());
+            let r = replayed.apply_subcircuit(frozen.clone(), r)?;
+            let mut direct = LocalBuilder::<f64>::default();
+            let d = direct.register(NonZeroUsize::new(2).unwrap());
+            let d = prepare(&mut direct, d)?;
+            assert_states_close(
+                &replayed
+                    .calculate_state_with_init([(&r, x)])
+                    .into_state()
+                    .unwrap(),
+                &direct
+                    .calculate_state_with_init([(&d, x)])
+                    .into_state()
+                    .unwrap(),
+            );
+            CircuitResult::Ok(())
+        })?;
+
+        let (_other, r) = b.cnot(other, r)?;
+        assert!(b.freeze(&r).is_err());
+        let q = b.qubit();
+        let (q, _handle) = b.measure(q);
+        assert!(b.freeze(&q).is_err());
+        let q = b.qubit_with_state(vec![Complex::zero(), Complex::one()])?;
+        assert_eq!(
+            b.freeze(&q).unwrap_err().kind,
+            CircuitErrorKind::Unsupported
+        );
+
+        // A reused ancilla only brings along the ops since it was handed out again.
+        let one = NonZeroUsize::new(1).unwrap();
+        b.with_ancilla(one, |b, a| {
+            let a = b.h(a);
+            Ok(((), b.h(a)))
+        })?;
+        let frozen = b.with_ancilla(one, |b, a| {
+            let a = b.x(a);
+            let frozen = b.freeze(&a)?;
+            Ok((frozen, b.x(a)))
+        })?;
+        assert_eq!(frozen.len(), 1);
+        assert_eq!(frozen[0].1.object.name(), "x");
+        Ok(())
+    }
+
+    #[test]
+    fn test_marginal_probabilities() {
+        let mut b = LocalBuilder::<f64>::default();
+        let (q, r) = (b.qubit(), b.qubit());
+        let q = b.h(q);
+        let r = b.x(r);
+        let r = b.merge_two_registers(q, r);
+        let (_r, handle) = b.measure_stochastic(r);
+        let result = b.calculate_state();
+        let marginals = result.marginal_probabilities(handle);
+        assert_eq!(marginals.len(), 2);
+        assert!((marginals[0] - 0.5).abs() < 1e-10);
+        assert!((marginals[1] - 1.0).abs() < 1e-10);
+        // The state is left untouched.
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        assert!((result.state().unwrap()[0b01].re - s).abs() < 1e-10);
+    }
+
+    fn precision_circuit<P: Precision>() -> CircuitResult<Vec<Complex<P>>> {
+        let mut b = LocalBuilder::<P>::default();
+        let a = b.qubit();
+        let rest = b.register(NonZeroUsize::new(2).unwrap());
+        let a = b.h(a);
+        let rest = b.h(rest);
+        let a = b.rz(a, P::from(0.3).unwrap());
+        let (a, rest) = b.cnot(a, rest)?;
+        let a = b.t(a);
+        let rest = b.rx(rest, P::from(1.1).unwrap());
+        let _r = b.merge_two_registers(a, rest);
+        Ok(b.calculate_state().into_state().unwrap())
+    }
+
+    #[test]
+    fn test_f32_matches_f64() -> CircuitResult<()> {
+        let single = precision_circuit::<f32>()?;
+        let double = precision_circuit::<f64>()?;
+        assert_eq!(single.len(), double.len());
+        single.iter().zip(double.iter()).for_each(|(a, b)| {
+            assert!((a.re as f64 - b.re).abs() < 1e-6);
+            assert!((a.im as f64 - b.im).abs() < 1e-6);
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_vec_matrix_dagger() -> CircuitResult<()> {
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        let single = vec![
+            Complex::new(h, 0.0),
+            Complex::new(0.0, h),
+            Complex::new(0.0, h),
+            Complex::new(h, 0.0),
+        ];
+        // A cyclic shift |x> -> e^{ix}|x+1>.
+        let shift = (0..16)
+            .map(|i| {
+                let (row, col) = (i / 4, i % 4);
+                if row == (col + 1) % 4 {
+                    Complex::from_polar(1.0, col as f64)
+                } else {
+                    Complex::zero()
                 }
-                UnitaryMatrixObject::SWAP => {
-                    let n = r.n();
-                    assert_eq!(n % 2, 0);
-                    let rs = self.split_all_register(r);
-                    let (ra, rb) = split_vector_at(rs, n / 2);
-                    let (cr, ras, rbs) = ra.into_iter().zip(rb.into_iter()).try_fold(
-                        (cr, vec![], vec![]),
-                        |(cr, mut ras, mut rbs), (ra, rb)| {
-                            debug_assert_eq!(ra.n(), 1);
-                            debug_assert_eq!(rb.n(), 1);
+            })
+            .collect::<Vec<_>>();
+        for data in [single, shift] {
+            let mut b = LocalBuilder::<f64>::default();
+            let r = b.register(NonZeroUsize::new(2).unwrap());
+            let r = b.h(r);
+            let r = b.t(r);
+            let before = b
+                .calculate_state_with_init([(&r, 0b01)])
+                .into_state()
+                .unwrap();
+            let r = match <[Complex<f64>; 4]>::try_from(data.clone()) {
+                Ok(single) => b.broadcast_single_qubit_matrix(r, single),
+                Err(_) => b.apply_vec_matrix(r, data.clone())?,
+            };
+            let r = b.apply_vec_matrix_dagger(r, data)?;
+            let after = b
+                .calculate_state_with_init([(&r, 0b01)])
+                .into_state()
+                .unwrap();
+            assert_states_close(&before, &after);
+        }
+
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        assert!(b
+            .apply_vec_matrix_dagger(r, vec![Complex::one(); 8])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_on_measurement() -> CircuitResult<()> {
+        // Bit flip code: syndrome bit 0 is q0 ^ q1 and bit 1 is q1 ^ q2.
+        let corrections = [(0b01, 0), (0b11, 1), (0b10, 2)];
+        let encode = |b: &mut LocalBuilder<f64>| -> CircuitResult<Vec<Qudit>> {
+            let (q0, q1, q2) = (b.qubit(), b.qubit(), b.qubit());
+            let q0 = b.ry(q0, 0.7);
+            let (q0, q1) = b.cnot(q0, q1)?;
+            let (q0, q2) = b.cnot(q0, q2)?;
+            Ok(vec![q0, q1, q2])
+        };
+        for error in [None, Some(0), Some(1), Some(2)] {
+            let mut b = LocalBuilder::<f64>::default();
+            let mut qs = encode(&mut b)?.into_iter().map(Some).collect::<Vec<_>>();
+            if let Some(k) = error {
+                qs[k] = qs[k].take().map(|q| b.x(q));
+            }
+            let (q0, q1, q2) = (qs[0].take(), qs[1].take(), qs[2].take());
+            let syndrome = b.register(NonZeroUsize::new(2).unwrap());
+            let (s0, s1) = b.split_first_qubit(syndrome);
+            let (q0, s0) = b.cnot(q0.unwrap(), s0.unwrap())?;
+            let (q1, s0) = b.cnot(q1.unwrap(), s0)?;
+            let (q1, s1) = b.cnot(q1, s1)?;
+            let (q2, s1) = b.cnot(q2.unwrap(), s1)?;
+            let syndrome = b.merge_two_registers(s0, s1);
+            let (_syndrome, handle) = b.measure(syndrome);
+            let mut qs = [Some(q0), Some(q1), Some(q2)];
+            for (expected, k) in corrections {
+                let q = qs[k].take().unwrap();
+                qs[k] = Some(b.apply_on_measurement(handle, expected, q, |b, q| Ok(b.x(q)))?);
+            }
+            let result = b.calculate_state();
+            let measured = result.get_measurement(handle).0;
+            let expected_syndrome = error
+                .map(|k| corrections.iter().find(|(_, c)| *c == k).unwrap().0)
+                .unwrap_or(0);
+            assert_eq!(measured, expected_syndrome);
+
+            let mut eb = LocalBuilder::<f64>::default();
+            let _qs = encode(&mut eb)?;
+            let es = eb.register(NonZeroUsize::new(2).unwrap());
+            let expected = eb
+                .calculate_state_with_init([(&es, expected_syndrome)])
+                .into_state()
+                .unwrap();
+            assert_states_close(result.state().unwrap(), &expected);
+        }
+
+        let mut b = LocalBuilder::<f64>::default();
+        let (m, q) = (b.qubit(), b.qubit());
+        let (_m, handle) = b.measure(m);
+        let depth = b.pipeline_depth();
+        assert!(b
+            .apply_on_measurement(handle, 0, q, |b, q| {
+                let q = b.x(q);
+                Ok(b.measure(q).0)
+            })
+            .is_err());
+        // Failed calls leave no ops or measurements behind.
+        assert_eq!(b.pipeline_depth(), depth);
+        let q = b.qubit();
+        assert!(b
+            .apply_on_measurement(handle, 0, q, |b, q| {
+                let _q = b.x(q);
+                Err(CircuitError::new("failed"))
+            })
+            .is_err());
+        assert_eq!(b.pipeline_depth(), depth);
+        let q = b.qubit();
+        let (_q, next) = b.measure(q);
+        assert_eq!(next.id(), handle.id() + 1);
+
+        // The expected value must fit in the measured qubits.
+        let q = b.qubit();
+        assert!(b
+            .apply_on_measurement(handle, 2, q, |b, q| Ok(b.x(q)))
+            .is_err());
+        assert_eq!(b.pipeline_depth(), depth + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_state_with_seed() {
+        let mut b = LocalBuilder::<f64>::default();
+        let handles = (0..8)
+            .map(|_| {
+                let q = b.qubit();
+                let q = b.h(q);
+                let q = b.reset_to_zero(q);
+                let q = b.h(q);
+                b.measure(q).1
+            })
+            .collect::<Vec<_>>();
+        let mut measured = |seed| {
+            let result = b.calculate_state_with_seed(None, seed);
+            handles
+                .iter()
+                .map(|handle| result.get_measurement(*handle).0)
+                .collect::<Vec<_>>()
+        };
+        let first = measured(7);
+        assert_eq!(measured(7), first);
+        assert!((0..8).any(|seed| measured(seed) != first));
+    }
+
+    #[test]
+    fn test_total_renormalization_factor() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        // Measuring |1> has a probability of sin^2(0.4).
+        let q = b
+            .apply_matrix(
+                q,
+                [
+                    Complex::new(0.4f64.cos(), 0.0),
+                    Complex::new(-(0.4f64.sin()), 0.0),
+                    Complex::new(0.4f64.sin(), 0.0),
+                    Complex::new(0.4f64.cos(), 0.0),
+                ],
+            )
+            .unwrap();
+        let (_q, handle) = b.measure(q);
+        let r = b.h(r);
+        let _r = b.reset_to_zero(r);
+        for seed in 0..4 {
+            let result = b.calculate_state_with_seed(None, seed);
+            let (measured, p) = result.get_measurement(handle);
+            let expected = if measured == 1 {
+                0.4f64.sin().powi(2)
+            } else {
+                0.4f64.cos().powi(2)
+            };
+            assert!((p - expected).abs() < 1e-10);
+            assert!((result.total_renormalization_factor() - p * 0.5).abs() < 1e-10);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_phase() {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let q = b.h(q);
+        let expected = b.calculate_state().into_state().unwrap();
+
+        let q = b.apply_global_phase(q, 0.3);
+        let _q = b.apply_global_phase_pi_by(q, 2);
+        let result = b.calculate_state();
+        assert_eq!(result.state(), Some(expected.as_slice()));
+        let phase = Complex::from_polar(1.0, 0.3 + std::f64::consts::FRAC_PI_2);
+        assert!((result.global_phase() - phase).norm() < 1e-10);
+
+        // Phases after the last measurement are recorded even when the state is not kept, unless
+        // they are applied on a measurement which gave a different value.
+        let mut b = LocalBuilder::<f64>::default();
+        let (q, r) = (b.qubit(), b.qubit());
+        let (q, handle) = b.measure(q);
+        let r = b.apply_global_phase(r, 0.3);
+        let r = b
+            .apply_on_measurement(handle, 1, r, |b, r| Ok(b.apply_global_phase(r, 0.5)))
+            .unwrap();
+        let _qr = (q, b.apply_global_phase_pi_by(r, 2));
+        let options = RunOptions {
+            retain_state: false,
+            ..Default::default()
+        };
+        let result = b.calculate_state_with_options(None, options);
+        assert!(result.state().is_none());
+        let phase = Complex::from_polar(1.0, 0.3 + std::f64::consts::FRAC_PI_2);
+        assert!((result.global_phase() - phase).norm() < 1e-10);
+    }
+
+    #[test]
+    fn test_get_measurement_by_id() {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.x(q);
+        let (_q, q_handle) = b.measure(q);
+        let (r, r_handle) = b.measure(r);
+        let (_r, stochastic_handle) = b.measure_stochastic(r);
+        let result = b.calculate_state();
+        assert_eq!(result.get_measurement_by_id(q_handle.id()), Some((1, 1.0)));
+        assert_eq!(result.get_measurement_by_id(r_handle.id()), Some((0, 1.0)));
+        assert_eq!(result.get_measurement_by_id(stochastic_handle.id()), None);
+        assert_eq!(result.get_measurement_by_id(3), None);
+    }
+
+    #[test]
+    fn test_amplitudes() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let rest = b.register(NonZeroUsize::new(2).unwrap());
+        let q = b.h(q);
+        let (q, rest) = b.cnot(q, rest)?;
+        let _r = b.merge_two_registers(q, rest);
+        let result = b.calculate_state();
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let amplitudes = result.amplitudes(&[0b000, 0b111, 0b010])?;
+        assert_states_close(
+            &amplitudes,
+            &[Complex::new(s, 0.0), Complex::new(s, 0.0), Complex::zero()],
+        );
+        assert!(result.amplitudes(&[0b1000]).is_err());
+
+        let options = RunOptions {
+            retain_state: false,
+            ..Default::default()
+        };
+        let result = b.calculate_state_with_options(None, options);
+        assert!(result.amplitudes(&[0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_to_zero() {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.x(q);
+        let r = b.h(r);
+        let q = b.reset_to_zero(q);
+        let (q, handle) = b.measure(q);
+        let r = b.merge_two_registers(q, r);
+        let result = b.calculate_state();
+        assert_eq!(result.get_measurement(handle), (0, 1.0));
+        let state = result.state().unwrap();
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let expected = vec![
+            Complex::new(s, 0.0),
+            Complex::new(s, 0.0),
+            Complex::zero(),
+            Complex::zero(),
+        ];
+        assert_states_close(state, &expected);
+        assert_eq!(r.n(), 2);
+    }
+
+    #[test]
+    fn test_calculate_state_from_vector() -> CircuitResult<()> {
+        let amplitudes = [(0.1, 0.2), (0.5, -0.3), (0.0, 0.4), (-0.6, 0.1)]
+            .iter()
+            .map(|(re, im)| Complex::new(*re, *im))
+            .collect::<Vec<Complex<f64>>>();
+        let norm = prob_magnitude(&amplitudes).sqrt();
+        let amplitudes = amplitudes.iter().map(|c| c / norm).collect::<Vec<_>>();
+        let circuit = |b: &mut LocalBuilder<f64>, r: Qudit| -> CircuitResult<Qudit> {
+            let (q0, q1) = b.split_first_qubit(r);
+            let (q0, q1) = b.cnot(q0.unwrap(), q1)?;
+            let q1 = b.ry(q1, 0.4);
+            Ok(b.merge_two_registers(q0, q1))
+        };
+
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register_with_state(NonZeroUsize::new(2).unwrap(), amplitudes.clone())?;
+        let _r = circuit(&mut b, r)?;
+        let expected = b.calculate_state().into_state().unwrap();
+
+        // Register values are little-endian while the state vector is big-endian.
+        let initial = (0..4)
+            .map(|i| amplitudes[((i & 1) << 1) | (i >> 1)])
+            .collect::<Vec<_>>();
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let _r = circuit(&mut b, r)?;
+        let result = b.calculate_state_from_vector(initial, RunOptions::default())?;
+        assert_states_close(result.state().unwrap(), &expected);
 
-                            // With the program macro we would be doing this:
-                            // let (cr, ra, rb) = program!(self, cr, ra, rb;
-                            //     control not |cr, ra,| rb;
-                            //     control not |cr, rb,| ra;
-                            //     control not |cr, ra,| rb;
-                            // )?;
+        assert!(b
+            .calculate_state_from_vector(amplitudes[..2].to_vec(), RunOptions::default())
+            .is_err());
+        assert!(b
+            .calculate_state_from_vector(vec![Complex::one(); 4], RunOptions::default())
+            .is_err());
+        Ok(())
+    }
 
-                            let new_cr = self.merge_two_registers(cr, ra);
-                            let (new_cr, rb) = self.cnot(new_cr, rb)?;
-                            let (cr, ra) = self.split_last_qubit(new_cr);
-                            let ra = ra.unwrap();
+    #[test]
+    fn test_extract_between() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let r = b.h(r);
+        b.tag("start")?;
+        let (q0, q1) = b.split_first_qubit(r);
+        let (q0, q1) = b.cnot(q0.unwrap(), q1)?;
+        let q1 = b.t(q1);
+        b.tag("end")?;
+        let r = b.merge_two_registers(q0, q1);
+        let _r = b.h(r);
 
-                            let new_cr = self.merge_two_registers(cr, rb);
-                            let (new_cr, ra) = self.cnot(new_cr, ra)?;
-                            let (cr, rb) = self.split_last_qubit(new_cr);
-                            let rb = rb.unwrap();
+        let sc = b.extract_between("start", "end")?;
+        let names = sc
+            .iter()
+            .map(|(indices, co)| (indices.clone(), co.object.name()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![(vec![0, 1], "cnot"), (vec![1], "t")]);
+        assert!(b.extract_between("end", "start").is_err());
+        assert!(b.extract_between("start", "missing").is_err());
+        assert!(b.tag("start").is_err());
 
-                            let new_cr = self.merge_two_registers(cr, ra);
-                            let (new_cr, rb) = self.cnot(new_cr, rb)?;
-                            let (cr, ra) = self.split_last_qubit(new_cr);
-                            let ra = ra.unwrap();
+        // Re-simulating the extracted ops matches building them directly.
+        let mut eb = LocalBuilder::<f64>::default();
+        let r = eb.register(NonZeroUsize::new(2).unwrap());
+        let r = eb.h(r);
+        let _r = eb.apply_subcircuit(sc, r)?;
+        let mut db = LocalBuilder::<f64>::default();
+        let (q0, q1) = (db.qubit(), db.qubit());
+        let (q0, q1) = (db.h(q0), db.h(q1));
+        let (_q0, q1) = db.cnot(q0, q1)?;
+        let _q1 = db.t(q1);
+        assert_states_close(
+            &eb.calculate_state().into_state().unwrap(),
+            &db.calculate_state().into_state().unwrap(),
+        );
+        Ok(())
+    }
 
-                            ras.push(ra);
-                            rbs.push(rb);
-                            Ok((cr, ras, rbs))
-                        },
-                    )?;
-                    let r = self
-                        .merge_registers(ras.into_iter().chain(rbs.into_iter()))
-                        .unwrap();
-                    Ok((cr, r))
-                }
-                UnitaryMatrixObject::CNOT => {
-                    assert_eq!(r.n(), 2);
-                    let (ra, r) = self.split_first_qubit(r);
-                    let ra = ra.unwrap();
-                    let cr = self.merge_two_registers(cr, ra);
-                    let (cr, r) = self.toffoli(cr, r)?;
-                    let (cr, ra) = self.split_last_qubit(cr);
-                    let ra = ra.unwrap();
-                    let r = self.merge_two_registers(ra, r);
-                    Ok((cr, r))
-                }
-                UnitaryMatrixObject::GlobalPhase(phase) => {
-                    // So a global phase applied to r if cr is actually just a phase gate on the
-                    // conditioned qubits, r doesn't enter.
+    #[test]
+    fn test_apply_sparse_matrix() -> CircuitResult<()> {
+        // A cyclic shift |x> -> e^{ix}|x+1> with rows ordered as for dense matrices.
+        let rows = (0..4)
+            .map(|row| {
+                let col = (row + 3) % 4;
+                vec![(col, Complex::from_polar(1.0, col as f64))]
+            })
+            .collect::<Vec<_>>();
+        let mut dense = vec![Complex::zero(); 16];
+        rows.iter().enumerate().for_each(|(row, entries)| {
+            entries
+                .iter()
+                .for_each(|(col, c)| dense[row * 4 + col] = *c)
+        });
+        let prepare = |b: &mut LocalBuilder<f64>| {
+            let r = b.register(NonZeroUsize::new(2).unwrap());
+            let r = b.h(r);
+            b.t(r)
+        };
 
-                    let tq = self.make_zeroed_temp_qubit();
-                    let (cr, tq) = self.toffoli(cr, tq)?;
+        let mut b = LocalBuilder::<f64>::default();
+        let r = prepare(&mut b);
+        let _r = b.apply_sparse_matrix(r, rows.clone())?;
+        let sparse = b.calculate_state().into_state().unwrap();
+        let mut b = LocalBuilder::<f64>::default();
+        let r = prepare(&mut b);
+        let _r = b.apply_vec_matrix(r, dense)?;
+        let expected = b.calculate_state().into_state().unwrap();
+        assert_states_close(&sparse, &expected);
 
-                    let tq = match phase {
-                        RotationObject::Floating(theta) => {
-                            let half_phase = theta * P::from(0.5).unwrap();
-                            let tq = self.rz(tq, half_phase);
-                            self.apply_global_phase(tq, half_phase)
-                        }
-                        RotationObject::PiRational(r) => {
-                            let half_phase = r / 2;
-                            let tq = self.rz_ratio(tq, half_phase)?;
-                            self.apply_global_phase_ratio(tq, half_phase)
-                        }
-                    };
+        // The inverse undoes the sparse matrix.
+        let mut b = LocalBuilder::<f64>::default();
+        let r = prepare(&mut b);
+        let before = b.calculate_state().into_state().unwrap();
+        let r = b.apply_sparse_matrix(r, rows.clone())?;
+        let _r = crate::inverter::inverter(&mut b, vec![r], |b, mut rs| {
+            let r = b.apply_sparse_matrix(rs.pop().unwrap(), rows.clone())?;
+            Ok(vec![r])
+        })?;
+        assert_states_close(&b.calculate_state().into_state().unwrap(), &before);
 
-                    let (cr, tq) = self.toffoli(cr, tq)?;
-                    self.return_zeroed_temp_register(tq);
-                    Ok((cr, r))
-                }
-                UnitaryMatrixObject::Rz(phase) => {
-                    let crn = cr.n();
-                    let cr = self.merge_two_registers(cr, r);
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let r = b.apply_sparse_matrix(r, rows[..2].to_vec()).unwrap_err();
+        assert_eq!(r.kind, CircuitErrorKind::MatrixSizeMismatch);
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let mut out_of_range = rows.clone();
+        out_of_range[1] = vec![(4, Complex::one())];
+        assert!(b.apply_sparse_matrix(r, out_of_range).is_err());
+        Ok(())
+    }
 
-                    let tq = self.make_zeroed_temp_qubit();
-                    let (cr, tq) = self.toffoli(cr, tq)?;
-                    let tq = match phase {
-                        RotationObject::Floating(phase) => self.rz(tq, phase),
-                        RotationObject::PiRational(phase) => self.rz_ratio(tq, phase)?,
-                    };
-                    let (cr, tq) = self.toffoli(cr, tq)?;
-                    self.return_zeroed_temp_register(tq);
+    #[test]
+    fn test_fuse_diagonal_into_matrix() -> CircuitResult<()> {
+        let u = crate::state_ops::linalg::random_unitary(2, &mut StdRng::seed_from_u64(3));
+        let diagonal = [1.0, 0.4, -0.7, 2.0].map(|theta| Complex::from_polar(1.0, theta));
+        let mut d = vec![Complex::zero(); 16];
+        (0..4).for_each(|i| d[i * 5] = diagonal[i]);
+        let run = |fuse: bool, swapped: bool| -> CircuitResult<(usize, Vec<Complex<f64>>)> {
+            let mut b = LocalBuilder::<f64>::default();
+            let q = b.qubit();
+            let r = b.qubit();
+            let qr = b.merge_two_registers(q, r);
+            let qr = b.h(qr);
+            let qr = b.apply_vec_matrix(qr, u.clone())?;
+            let qr = if swapped {
+                let (q, r) = b.split_first_qubit(qr);
+                let rq = b.merge_two_registers(r, q.unwrap());
+                b.apply_vec_matrix(rq, d.clone())?
+            } else {
+                b.apply_vec_matrix(qr, d.clone())?
+            };
+            let _qr = b.rz(qr, 0.3);
+            let removed = if fuse { b.fuse_diagonal_runs(1) } else { 0 };
+            Ok((removed, b.calculate_state().into_state().unwrap()))
+        };
+        // The diagonal on the same qubits as the matrix is absorbed, while the Rz after it is on
+        // single qubits and so is not.
+        let (removed, fused) = run(true, false)?;
+        assert_eq!(removed, 1);
+        assert_states_close(&fused, &run(false, false)?.1);
+        // Diagonals on the same qubits in a different order are left as they are.
+        let (removed, fused) = run(true, true)?;
+        assert_eq!(removed, 0);
+        assert_states_close(&fused, &run(false, true)?.1);
+        Ok(())
+    }
 
-                    match self.split_register_relative(cr, 0..crn) {
-                        SplitResult::SPLIT(cr, r) => Ok((cr, r)),
-                        SplitResult::SELECTED(_) => unreachable!(),
-                        SplitResult::UNSELECTED(_) => unreachable!(),
-                    }
+    #[test]
+    fn test_fuse_diagonal_runs() -> CircuitResult<()> {
+        let n = NonZeroUsize::new(4).unwrap();
+        for x in [0, 1, 0b0110, 0b1011, 0b1111] {
+            let run = |max_qubits: Option<usize>| -> CircuitResult<_> {
+                let mut b = LocalBuilder::<f64>::default();
+                let r = b.register(n);
+                b.tag("start")?;
+                let r = crate::gates::qft(&mut b, r)?;
+                b.tag("end")?;
+                let r = b.h(r);
+                b.tag("tail")?;
+                let names = |sc: Vec<(Vec<usize>, BuilderCircuitObject<f64>)>| {
+                    sc.into_iter()
+                        .map(|(indices, co)| (indices, co.object.name()))
+                        .collect::<Vec<_>>()
+                };
+                let tail = names(b.extract_between("end", "tail")?);
+                if let Some(max_qubits) = max_qubits {
+                    b.fuse_diagonal_runs(max_qubits);
+                }
+                // Tags still mark the same points in the circuit.
+                assert_eq!(names(b.extract_between("end", "tail")?), tail);
+                assert_eq!(
+                    b.extract_between("start", "end")?.len() + tail.len(),
+                    b.pipeline_depth()
+                );
+                let state = b.calculate_state_with_init([(&r, x)]).into_state().unwrap();
+                Ok((b.pipeline_depth(), state))
+            };
+            let (naive_depth, naive) = run(None)?;
+            for max_qubits in [2, 3, 4] {
+                let (depth, fused) = run(Some(max_qubits))?;
+                assert_states_close(&fused, &naive);
+                if max_qubits > 2 {
+                    assert!(depth < naive_depth);
+                } else {
+                    assert_eq!(depth, naive_depth);
                 }
-                UnitaryMatrixObject::MAT(_data) => todo!(),
-            },
-            BuilderCircuitObjectType::Measurement(_) => {
-                Err(CircuitError::new("Cannot condition measurements."))
             }
         }
+        Ok(())
     }
-}
 
-fn split_vector_at<T>(mut v: Vec<T>, x: usize) -> (Vec<T>, Vec<T>) {
-    let n = v.len();
-    assert!(n >= x);
-    let mut b = vec![];
-    for _ in 0..(n - x) {
-        b.push(v.pop().unwrap());
-    }
-    b.reverse();
-    (v, b)
-}
+    #[test]
+    fn test_fuse_rotations() -> CircuitResult<()> {
+        let rotations = |b: &LocalBuilder<f64>| {
+            b.pipeline
+                .iter()
+                .map(|(indices, co)| (indices.clone(), co.object.clone()))
+                .collect::<Vec<_>>()
+        };
+        let rz = |theta| BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Rz(theta));
 
-impl<P: Precision> Subcircuitable for LocalBuilder<P> {
-    type Subcircuit = Vec<(Vec<usize>, Self::CircuitObject)>;
+        let mut b = LocalBuilder::<f64>::default();
+        let (q, r) = (b.qubit(), b.qubit());
+        let q = b.rz_pi_by(q, 2)?;
+        let r = b.h(r);
+        let _q = b.rz_pi_by(q, 2)?;
+        let r = b.rz(r, 0.5);
+        let r = b.h(r);
+        let _r = b.rz(r, 0.5);
+        assert_eq!(b.fuse_rotations(), 1);
+        assert_eq!(
+            rotations(&b),
+            vec![
+                (
+                    vec![0],
+                    rz(RotationObject::PiRational(Ratio::from_integer(1)))
+                ),
+                (
+                    vec![1],
+                    BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::H)
+                ),
+                (vec![1], rz(RotationObject::Floating(0.5))),
+                (
+                    vec![1],
+                    BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::H)
+                ),
+                (vec![1], rz(RotationObject::Floating(0.5))),
+            ]
+        );
 
-    fn make_subcircuit(&self) -> CircuitResult<Self::Subcircuit> {
-        Ok(self.pipeline.clone())
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let q = b.h(q);
+        let q = b.rz(q, 0.7);
+        let _q = b.rz(q, -0.7);
+        assert_eq!(b.fuse_rotations(), 2);
+        assert_eq!(b.pipeline_depth(), 1);
+
+        // Two pi is a phase of -1, which is kept for when the circuit is conditioned.
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let q = b.rz_pi_by(q, 1)?;
+        let _q = b.rz_pi_by(q, 1)?;
+        assert_eq!(b.fuse_rotations(), 1);
+        assert_eq!(
+            rotations(&b),
+            vec![(
+                vec![0],
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::GlobalPhase(
+                    RotationObject::PiRational(Ratio::from_integer(1))
+                ))
+            )]
+        );
+        Ok(())
     }
 
-    fn apply_subcircuit(
-        &mut self,
-        sc: Self::Subcircuit,
-        r: Self::Register,
-    ) -> CircuitResult<Self::Register> {
-        apply_pipeline_objects(self, sc, r)
+    #[test]
+    fn test_broadcast_single_qubit_matrix_where() {
+        let (o, l) = (Complex::zero(), Complex::one());
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(4).unwrap());
+        let r = b.broadcast_single_qubit_matrix_where(r, [o, l, l, o], |i| i % 2 == 0);
+        assert_eq!(r.indices(), &[0, 1, 2, 3]);
+        assert_eq!(b.pipeline_depth(), 2);
+        let (r, handle) = b.measure(r);
+        let result = b.calculate_state_with_init([(&r, 0b1000)]);
+        assert_eq!(result.get_measurement(handle), (0b1101, 1.0));
     }
-}
 
-impl<P: Precision> Invertable for LocalBuilder<P> {
-    type SimilarBuilder = Self;
+    #[test]
+    fn test_apply_vec_matrix_on() -> CircuitResult<()> {
+        let (o, l) = (Complex::zero(), Complex::one());
+        let run = |rel_indices: &[usize], data: Vec<Complex<f64>>, x: usize| {
+            let mut b = LocalBuilder::<f64>::default();
+            let r = b.register(NonZeroUsize::new(3).unwrap());
+            let r = b.apply_vec_matrix_on(r, rel_indices, data)?;
+            assert_eq!(r.indices(), &[0, 1, 2]);
+            let (r, handle) = b.measure(r);
+            let result = b.calculate_state_with_init([(&r, x)]);
+            Ok(result.get_measurement(handle).0)
+        };
+        assert_eq!(run(&[1], vec![o, l, l, o], 0b000)?, 0b010);
 
-    fn new_similar(&self) -> Self {
-        Self::default()
-    }
+        // A CNOT controlled by the last qubit onto the first.
+        let cnot = vec![l, o, o, o, o, l, o, o, o, o, o, l, o, o, l, o];
+        assert_eq!(run(&[2, 0], cnot.clone(), 0b100)?, 0b101);
+        assert_eq!(run(&[2, 0], cnot.clone(), 0b001)?, 0b001);
 
-    fn invert_subcircuit(sc: Self::Subcircuit) -> CircuitResult<Self::Subcircuit> {
-        sc.into_iter()
-            .rev()
-            .try_fold(vec![], |mut acc, (indices, co)| {
-                invert_circuit_object(co)?
-                    .into_iter()
-                    .for_each(|co| acc.push((indices.clone(), co)));
-                Ok(acc)
-            })
+        assert!(run(&[3], vec![o, l, l, o], 0).is_err());
+        assert!(run(&[0, 0], cnot.clone(), 0).is_err());
+        assert!(run(&[0], cnot, 0).is_err());
+        Ok(())
     }
-}
 
-impl<P: Precision> ConditionableSubcircuit for LocalBuilder<P> {
-    fn apply_conditioned_subcircuit(
-        &mut self,
-        sc: Self::Subcircuit,
-        cr: Self::Register,
-        r: Self::Register,
-    ) -> Result<(Self::Register, Self::Register), CircuitError> {
-        let mut cb = self.condition_with(cr);
-        let r = apply_pipeline_objects(&mut cb, sc, r)?;
-        let cr = cb.dissolve();
-        Ok((cr, r))
+    /// A builder which only implements the required trait methods, to check the defaults.
+    struct MinimalBuilder(LocalBuilder<f64>);
+
+    impl CircuitBuilder for MinimalBuilder {
+        type Register = Qudit;
+        type CircuitObject = BuilderCircuitObject<f64>;
+        type StateCalculation = RunResult<f64>;
+
+        fn n(&self) -> usize {
+            self.0.n()
+        }
+
+        fn register(&mut self, n: NonZeroUsize) -> Self::Register {
+            self.0.register(n)
+        }
+
+        fn merge_two_registers(&mut self, r1: Qudit, r2: Qudit) -> Self::Register {
+            self.0.merge_two_registers(r1, r2)
+        }
+
+        fn split_register_relative<It>(&mut self, r: Qudit, indices: It) -> SplitResult<Qudit>
+        where
+            It: IntoIterator<Item = usize>,
+        {
+            self.0.split_register_relative(r, indices)
+        }
+
+        fn apply_circuit_object(
+            &mut self,
+            r: Qudit,
+            c: Self::CircuitObject,
+        ) -> CircuitResult<Self::Register> {
+            self.0.apply_circuit_object(r, c)
+        }
+
+        fn calculate_state_with_init<'a, It>(&mut self, it: It) -> Self::StateCalculation
+        where
+            Qudit: 'a,
+            It: IntoIterator<Item = (&'a Qudit, usize)>,
+        {
+            self.0.calculate_state_with_init(it)
+        }
     }
-}
 
-fn apply_pipeline_objects<CB, CO>(
-    cb: &mut CB,
-    sc: CB::Subcircuit,
-    r: CB::Register,
-) -> CircuitResult<CB::Register>
-where
-    CB: CircuitBuilder<CircuitObject = CO>
-        + Subcircuitable<Subcircuit = Vec<(Vec<usize>, CO)>>
-        + TemporaryRegisterBuilder,
-{
-    let rn = r.n();
-    let mut rs = cb.split_all_register(r);
-    let max_r_index = sc
-        .iter()
-        .flat_map(|(indices, _)| indices.iter().cloned().max())
-        .max()
-        .unwrap();
-    // Need temp qubits for excess.
-    if let Some(temp_n) = NonZeroUsize::new(1 + max_r_index - rn) {
-        let temp = cb.make_zeroed_temp_register(temp_n);
-        rs.extend(cb.split_all_register(temp).into_iter());
+    impl UnitaryBuilder<f64> for MinimalBuilder {
+        fn vec_matrix_to_circuitobject(n: usize, data: Vec<Complex<f64>>) -> Self::CircuitObject {
+            LocalBuilder::vec_matrix_to_circuitobject(n, data)
+        }
     }
-    let mut rs = rs.into_iter().map(Some).collect::<Vec<_>>();
-    sc.into_iter().try_for_each(|(indices, co)| {
-        let sub_rs = indices.iter().map(|index| rs[*index].take().unwrap());
-        let sub_r = cb.merge_registers(sub_rs).unwrap();
-        let sub_r = cb.apply_circuit_object(sub_r, co)?;
-        let sub_rs = cb.split_all_register(sub_r);
-        indices
-            .into_iter()
-            .zip(sub_rs.into_iter())
-            .for_each(|(index, r)| {
-                rs[index] = Some(r);
-            });
-        assert!(rs.iter().all(|r| r.is_some()), "Not all qubits returned");
+
+    impl CliffordTBuilder<f64> for MinimalBuilder {}
+
+    impl RotationsBuilder<f64> for MinimalBuilder {}
+
+    #[test]
+    fn test_default_make_rz() -> CircuitResult<()> {
+        let mut b = MinimalBuilder(LocalBuilder::default());
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let r = b.h(r);
+        let r = b.rz(r, 0.3);
+        let _r = b.rx(r, -0.8);
+        let state = b.calculate_state().into_state().unwrap();
+
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let r = b.h(r);
+        let r = b.rz(r, 0.3);
+        let _r = b.rx(r, -0.8);
+        assert_states_close(&state, b.calculate_state().state().unwrap());
         Ok(())
-    })?;
-    let rs = rs.into_iter().map(Option::unwrap).collect::<Vec<_>>();
-    let (rs, trs) = split_vector_at(rs, rn);
-    if let Some(tr) = cb.merge_registers(trs) {
-        cb.return_zeroed_temp_register(tr);
     }
-    let r = cb.merge_registers(rs.into_iter()).unwrap();
-    Ok(r)
-}
 
-fn invert_circuit_object<P: Precision>(
-    co: BuilderCircuitObject<P>,
-) -> CircuitResult<Vec<BuilderCircuitObject<P>>> {
-    match co.object {
-        BuilderCircuitObjectType::Unitary(u) => {
-            let new_objs = match u {
-                UnitaryMatrixObject::X
-                | UnitaryMatrixObject::Y
-                | UnitaryMatrixObject::Z
-                | UnitaryMatrixObject::H
-                | UnitaryMatrixObject::CNOT
-                | UnitaryMatrixObject::SWAP => vec![u],
-                UnitaryMatrixObject::S => vec![UnitaryMatrixObject::Z, u],
-                UnitaryMatrixObject::T => vec![UnitaryMatrixObject::Z, UnitaryMatrixObject::S, u],
-                UnitaryMatrixObject::Rz(phase) => vec![UnitaryMatrixObject::Rz(phase.neg())],
-                UnitaryMatrixObject::GlobalPhase(phase) => {
-                    vec![UnitaryMatrixObject::GlobalPhase(phase.neg())]
-                }
-                UnitaryMatrixObject::MAT(data) => {
-                    // Matrix is unitary, inverse is just dagger
-                    let mut inverse_data = vec![Complex::zero(); data.len()];
-                    let nn = 1 << co.n;
-                    (0..nn).for_each(|i| {
-                        (0..nn).for_each(|j| {
-                            let index_a = i * nn + j;
-                            let index_b = j * nn + i;
-                            inverse_data[index_a] = data[index_b].conj();
-                        })
-                    });
-                    vec![UnitaryMatrixObject::MAT(inverse_data)]
-                }
+    #[test]
+    fn test_rotation_ratios_are_multiples_of_pi() -> CircuitResult<()> {
+        let pi = std::f64::consts::PI;
+        for axis in 0..3 {
+            let run = |ratio: bool| -> CircuitResult<Vec<Complex<f64>>> {
+                let mut b = LocalBuilder::<f64>::default();
+                let r = b.register(NonZeroUsize::new(2).unwrap());
+                let r = b.h(r);
+                let r = b.t(r);
+                let theta = Rational64::new(3, 4);
+                let _r = match (axis, ratio) {
+                    (0, true) => b.rx_ratio(r, theta)?,
+                    (1, true) => b.ry_ratio(r, theta)?,
+                    (_, true) => b.rz_ratio(r, theta)?,
+                    (0, false) => b.rx(r, 0.75 * pi),
+                    (1, false) => b.u3(r, 0.75 * pi, 0.0, 0.0),
+                    (_, false) => b.rz(r, 0.75 * pi),
+                };
+                Ok(b.calculate_state().into_state().unwrap())
             };
-            Ok(new_objs
-                .into_iter()
-                .map(|new_obj| BuilderCircuitObject {
-                    n: co.n,
-                    object: BuilderCircuitObjectType::Unitary(new_obj),
-                })
-                .collect())
+            assert_states_close(&run(true)?, &run(false)?);
         }
-        BuilderCircuitObjectType::Measurement(_) => {
-            Err(CircuitError::new("Cannot invert measurement."))
+
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let q = b.h(q);
+        let _q = b.rz_pi_by(q, 4)?;
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let expected = [
+            Complex::from_polar(s, -pi / 8.0),
+            Complex::from_polar(s, pi / 8.0),
+        ];
+        assert_states_close(b.calculate_state().state().unwrap(), &expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ry_direction() {
+        // Ry(pi/2) takes |0> to |+> and |1> to -|->.
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        for (init, expected) in [(0, [s, s]), (1, [-s, s])] {
+            let mut b = LocalBuilder::<f64>::default();
+            let q = b.qubit();
+            let q = b.ry(q, std::f64::consts::FRAC_PI_2);
+            let result = b.calculate_state_with_init([(&q, init)]);
+            let expected = expected.map(|x| Complex::new(x, 0.0));
+            assert_states_close(result.state().unwrap(), &expected);
         }
     }
-}
 
-impl<P: Precision> RecursiveCircuitBuilder<P> for LocalBuilder<P> {
-    type RecursiveSimilarBuilder = Self::SimilarBuilder;
+    #[test]
+    fn test_rz_pi_rational_matrix_data() {
+        let pi = std::f64::consts::PI;
+        for (ratio, theta) in [((1, 2), pi / 2.0), ((-3, 4), -0.75 * pi)] {
+            let rational = UnitaryMatrixObject::<f64>::Rz(RotationObject::PiRational(Ratio::new(
+                ratio.0, ratio.1,
+            )));
+            let floating = UnitaryMatrixObject::<f64>::Rz(RotationObject::Floating(theta));
+            assert_states_close(
+                &rational.matrix_data().unwrap(),
+                &floating.matrix_data().unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_sample_outcome_skips_impossible_outcomes() {
+        let ps = [0.0, 0.5, 0.0, 0.5, 0.0];
+        // The lowest and highest values the rng can give.
+        let first = sample_outcome(&ps, &mut StepRng::new(0, 0));
+        assert_eq!(first.measured, 1);
+        let last = sample_outcome(&ps, &mut StepRng::new(u64::MAX, 0));
+        assert_eq!(last.measured, 3);
+    }
 }
@@ -0,0 +1,752 @@
+use super::{
+    BuilderCircuitObject, BuilderCircuitObjectType, LocalBuilder, MeasurementObject,
+    UnitaryMatrixObject,
+};
+use crate::types::Precision;
+use std::cmp::max;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// A single pipeline entry summarized for reporting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpSummary {
+    /// Position of the op in its pipeline.
+    pub position: usize,
+    /// Qubit indices the op acts on.
+    pub indices: Vec<usize>,
+    /// Name of the op.
+    pub name: &'static str,
+}
+
+/// A difference between two circuits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitDiff {
+    /// An op present in the second circuit but not in the first.
+    Added(OpSummary),
+    /// An op present in the first circuit but not in the second.
+    Removed(OpSummary),
+    /// An op in the first circuit replaced by an op in the second.
+    Changed(OpSummary, OpSummary),
+}
+
+type PipelineEntry<P> = (Vec<usize>, BuilderCircuitObject<P>);
+
+fn summarize<P: Precision>(pipeline: &[PipelineEntry<P>], position: usize) -> OpSummary {
+    let (indices, obj) = &pipeline[position];
+    OpSummary {
+        position,
+        indices: indices.clone(),
+        name: obj.object.name(),
+    }
+}
+
+fn ops_equal<P: Precision>(a: &PipelineEntry<P>, b: &PipelineEntry<P>) -> bool {
+    a.0 == b.0 && a.1.n == b.1.n && a.1.object == b.1.object
+}
+
+/// Group the pipeline positions into moments, placing each op one moment after the latest op on
+/// any of its qubits. Ops within a moment act on disjoint qubits and are ordered by their lowest
+/// qubit index.
+fn schedule_moments<P: Precision>(pipeline: &[PipelineEntry<P>], n: usize) -> Vec<Vec<usize>> {
+    let mut next_moment = vec![0; n];
+    let mut moments: Vec<Vec<usize>> = vec![];
+    pipeline.iter().enumerate().for_each(|(pos, (indices, _))| {
+        let moment = indices.iter().map(|i| next_moment[*i]).max().unwrap_or(0);
+        indices.iter().for_each(|i| next_moment[*i] = moment + 1);
+        if moments.len() <= moment {
+            moments.resize(moment + 1, vec![]);
+        }
+        moments[moment].push(pos);
+    });
+    let lowest = |pos: &usize| pipeline[*pos].0.iter().min().cloned();
+    moments.iter_mut().for_each(|ops| ops.sort_by_key(lowest));
+    moments
+}
+
+/// Compare two circuits moment by moment, where each op is scheduled as early as its qubits allow,
+/// and return the first point at which they diverge. Circuits which only differ in the order ops
+/// were added to disjoint qubits are the same, so `None` means the circuits are identical.
+/// Within a moment ops are matched by their lowest qubit index.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::analysis::{diff_circuits, CircuitDiff};
+///
+/// let mut a = LocalBuilder::<f64>::default();
+/// let q = a.qubit();
+/// let _q = a.h(q);
+///
+/// let mut b = LocalBuilder::<f64>::default();
+/// let q = b.qubit();
+/// let _q = b.x(q);
+///
+/// let diff = diff_circuits(&a, &b);
+/// assert!(matches!(diff, Some(CircuitDiff::Changed(before, after)) if before.name == "h" && after.name == "x"));
+/// ```
+pub fn diff_circuits<P: Precision>(
+    a: &LocalBuilder<P>,
+    b: &LocalBuilder<P>,
+) -> Option<CircuitDiff> {
+    let (pa, pb) = (a.pipeline.as_slice(), b.pipeline.as_slice());
+    let (ma, mb) = (schedule_moments(pa, a.n), schedule_moments(pb, b.n));
+    let lowest = |pipeline: &[PipelineEntry<P>], pos: usize| pipeline[pos].0.iter().min().cloned();
+    let empty = vec![];
+    (0..max(ma.len(), mb.len())).find_map(|moment| {
+        let (oa, ob) = (
+            ma.get(moment).unwrap_or(&empty),
+            mb.get(moment).unwrap_or(&empty),
+        );
+        let (mut i, mut j) = (0, 0);
+        while i < oa.len() || j < ob.len() {
+            let key_a = oa.get(i).map(|pos| lowest(pa, *pos));
+            let key_b = ob.get(j).map(|pos| lowest(pb, *pos));
+            match (key_a, key_b) {
+                (Some(ka), Some(kb)) if ka == kb => {
+                    if !ops_equal(&pa[oa[i]], &pb[ob[j]]) {
+                        return Some(CircuitDiff::Changed(
+                            summarize(pa, oa[i]),
+                            summarize(pb, ob[j]),
+                        ));
+                    }
+                    i += 1;
+                    j += 1;
+                }
+                (Some(ka), Some(kb)) if ka < kb => {
+                    return Some(CircuitDiff::Removed(summarize(pa, oa[i])))
+                }
+                (Some(_), None) => return Some(CircuitDiff::Removed(summarize(pa, oa[i]))),
+                _ => return Some(CircuitDiff::Added(summarize(pb, ob[j]))),
+            }
+        }
+        None
+    })
+}
+
+/// Whether the op is a barrier or identity padding, which mark up the circuit without doing work.
+fn is_placeholder<P: Precision>(obj: &BuilderCircuitObject<P>) -> bool {
+    matches!(
+        obj.object,
+        BuilderCircuitObjectType::Unitary(
+            UnitaryMatrixObject::Identity | UnitaryMatrixObject::Barrier
+        )
+    )
+}
+
+/// Estimate the number of complex multiply-adds needed to apply a pipeline entry to the state of
+/// an `n` qubit circuit.
+fn op_cost<P: Precision>(n: usize, entry: &PipelineEntry<P>) -> usize {
+    let (indices, obj) = entry;
+    let entries_per_row = match &obj.object {
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::CNOT) => 2,
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::SWAP)
+        | BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::GlobalPhase(_))
+        | BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Identity)
+        | BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Barrier) => 1,
+        // Dense, generated and broadcast ops all touch every column of the ops' rows.
+        BuilderCircuitObjectType::Unitary(_) => 1 << indices.len(),
+        // One pass to find the probabilities and one to collapse the state.
+        BuilderCircuitObjectType::Measurement(_) => 2,
+    };
+    (1 << n) * entries_per_row
+}
+
+/// Return the `top_k` most expensive ops in the circuit along with their estimated cost in complex
+/// multiply-adds, most expensive first. Ops of equal cost are kept in pipeline order.
+/// This is useful for finding the bottleneck in a large circuit without running it.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::analysis::dominant_ops;
+/// use std::num::NonZeroUsize;
+///
+/// let mut b = LocalBuilder::<f64>::default();
+/// let q = b.qubit();
+/// let r = b.register(NonZeroUsize::new(2).unwrap());
+/// let q = b.h(q);
+/// let _r = b.swap(q, r);
+///
+/// let ops = dominant_ops(&b, 1);
+/// assert_eq!(ops[0].0.name, "h");
+/// ```
+pub fn dominant_ops<P: Precision>(b: &LocalBuilder<P>, top_k: usize) -> Vec<(OpSummary, usize)> {
+    let mut costs = b
+        .pipeline
+        .iter()
+        .enumerate()
+        .map(|(position, entry)| (position, op_cost(b.n, entry)))
+        .collect::<Vec<_>>();
+    costs.sort_by(|(_, a), (_, b)| b.cmp(a));
+    costs
+        .into_iter()
+        .take(top_k)
+        .map(|(position, cost)| (summarize(&b.pipeline, position), cost))
+        .collect()
+}
+
+/// Whether a scheduled measurement collapses the state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeasurementKind {
+    /// A single measurement which collapses the state, see `MeasurementBuilder::measure`.
+    Single,
+    /// A distribution over outcomes which leaves the state untouched, see
+    /// `StochasticMeasurementBuilder::measure_stochastic`.
+    Stochastic,
+}
+
+/// Return the measurements in the circuit in the order they will be performed, as the id of each
+/// measurement handle, the kind of measurement, and the qubit indices it measures. This tells an
+/// external controller which classical data to expect from a run.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::analysis::{measurement_schedule, MeasurementKind};
+///
+/// let mut b = LocalBuilder::<f64>::default();
+/// let q = b.qubit();
+/// let (_q, handle) = b.measure(q);
+/// assert_eq!(
+///     measurement_schedule(&b),
+///     vec![(handle.id(), MeasurementKind::Single, vec![0])]
+/// );
+/// ```
+pub fn measurement_schedule<P: Precision>(
+    b: &LocalBuilder<P>,
+) -> Vec<(usize, MeasurementKind, Vec<usize>)> {
+    b.pipeline
+        .iter()
+        .filter_map(|(indices, obj)| match &obj.object {
+            BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement) => {
+                Some((MeasurementKind::Single, indices.clone()))
+            }
+            BuilderCircuitObjectType::Measurement(MeasurementObject::StochasticMeasurement) => {
+                Some((MeasurementKind::Stochastic, indices.clone()))
+            }
+            BuilderCircuitObjectType::Measurement(MeasurementObject::Reset)
+            | BuilderCircuitObjectType::Unitary(_) => None,
+        })
+        .enumerate()
+        .map(|(id, (kind, indices))| (id, kind, indices))
+        .collect()
+}
+
+/// Return the qubits which were allocated but have no op applied to them and no initial state,
+/// sorted by index. These stay in `|0>` (or their classical initial value) for the whole circuit,
+/// which is often a sign of a forgotten gate. Barriers and identity padding do not count as ops.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::analysis::idle_qubits;
+///
+/// let mut b = LocalBuilder::<f64>::default();
+/// let q = b.qubit();
+/// let _unused = b.qubit();
+/// let _q = b.h(q);
+/// assert_eq!(idle_qubits(&b), vec![1]);
+/// ```
+pub fn idle_qubits<P: Precision>(b: &LocalBuilder<P>) -> Vec<usize> {
+    let mut used = vec![false; b.n];
+    b.pipeline
+        .iter()
+        .filter(|(_, obj)| !is_placeholder(obj))
+        .map(|(indices, _)| indices)
+        .chain(b.initial_states.iter().map(|(indices, _)| indices))
+        .flatten()
+        .for_each(|i| used[*i] = true);
+    used.into_iter()
+        .enumerate()
+        .filter(|(_, used)| !used)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Durations of gates on a device in nanoseconds, keyed by op name (see `OpSummary::name`). Ops
+/// without an entry are taken to be instantaneous.
+pub type GateDurations = HashMap<String, f64>;
+
+/// Estimate the execution time of the circuit in nanoseconds on a device with the given gate
+/// `durations`. Each op starts as soon as all of its qubits are free, so this is the length of the
+/// critical path through the circuit.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::analysis::{circuit_duration, GateDurations};
+///
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (q, r) = (b.qubit(), b.qubit());
+/// let q = b.h(q);
+/// let _r = b.h(r);
+///
+/// let durations = GateDurations::from([("h".to_string(), 20.0)]);
+/// assert_eq!(circuit_duration(&b, &durations), 20.0);
+/// ```
+pub fn circuit_duration<P: Precision>(b: &LocalBuilder<P>, durations: &GateDurations) -> f64 {
+    let mut free_at = vec![0.0f64; b.n];
+    b.pipeline.iter().for_each(|(indices, obj)| {
+        let duration = durations.get(obj.object.name()).copied().unwrap_or(0.0);
+        let start = indices.iter().map(|i| free_at[*i]).fold(0.0, f64::max);
+        indices.iter().for_each(|i| free_at[*i] = start + duration);
+    });
+    free_at.into_iter().fold(0.0, f64::max)
+}
+
+/// Summary statistics for the size of a circuit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitStats {
+    /// Number of ops of each kind, keyed by op name (see `OpSummary::name`).
+    pub gate_counts: HashMap<&'static str, usize>,
+    /// Length of the longest chain of ops where each shares a qubit with the previous one.
+    pub depth: usize,
+    /// Number of qubits in the circuit.
+    pub n_qubits: usize,
+}
+
+/// Count the ops in the circuit by kind and find its depth, where each op is placed one time step
+/// after the latest op touching any of its qubits. Barriers and identity padding are left out of
+/// both.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::analysis::circuit_stats;
+///
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (q, r) = (b.qubit(), b.qubit());
+/// let q = b.h(q);
+/// let _r = b.h(r);
+///
+/// let stats = circuit_stats(&b);
+/// assert_eq!(stats.gate_counts["h"], 2);
+/// assert_eq!(stats.depth, 1);
+/// assert_eq!(stats.n_qubits, 2);
+/// ```
+pub fn circuit_stats<P: Precision>(b: &LocalBuilder<P>) -> CircuitStats {
+    let mut gate_counts = HashMap::new();
+    let mut steps = vec![0; b.n];
+    b.pipeline
+        .iter()
+        .filter(|(_, obj)| !is_placeholder(obj))
+        .for_each(|(indices, obj)| {
+            *gate_counts.entry(obj.object.name()).or_insert(0) += 1;
+            let step = indices.iter().map(|i| steps[*i]).max().unwrap_or(0) + 1;
+            indices.iter().for_each(|i| steps[*i] = step);
+        });
+    CircuitStats {
+        gate_counts,
+        depth: steps.into_iter().max().unwrap_or(0),
+        n_qubits: b.n,
+    }
+}
+
+/// Estimate the largest amount of memory in bytes used while running the circuit, where
+/// `precision_bytes` is the size of a single real number (8 for `f64`). A run holds the state and
+/// an equally sized buffer for the next state, plus whatever the op being applied needs: the dense
+/// matrix of a multi-qubit unitary, or the outcome probabilities of a measurement. Retained
+/// post-measurement states are counted as they accumulate.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::analysis::peak_memory_bytes;
+///
+/// let mut b = LocalBuilder::<f64>::default();
+/// let q = b.qubit();
+/// let _q = b.h(q);
+/// // Two state vectors of two amplitudes, and the four entries of H.
+/// assert_eq!(peak_memory_bytes(&b, 8), (2 * 2 + 4) * 16);
+/// ```
+pub fn peak_memory_bytes<P: Precision>(b: &LocalBuilder<P>, precision_bytes: usize) -> u64 {
+    let complex_bytes = 2 * precision_bytes as u64;
+    let state_bytes = (1u64 << b.n) * complex_bytes;
+    let mut retained = 0;
+    let op_bytes = b
+        .pipeline
+        .iter()
+        .map(|(indices, obj)| {
+            let extra = match &obj.object {
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Generated(_)) => 0,
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Sparse(rows)) => {
+                    rows.iter().map(|row| row.len() as u64).sum::<u64>()
+                        * (complex_bytes + size_of::<usize>() as u64)
+                }
+                BuilderCircuitObjectType::Unitary(object) => object
+                    .matrix_data()
+                    .map(|data| data.len() as u64 * complex_bytes)
+                    .unwrap_or(0),
+                BuilderCircuitObjectType::Measurement(object) => {
+                    let probs = (1u64 << indices.len()) * precision_bytes as u64;
+                    if b.retain_post_measurement_states
+                        && matches!(object, MeasurementObject::Measurement)
+                    {
+                        retained += state_bytes;
+                    }
+                    probs
+                }
+            };
+            retained + extra
+        })
+        .max()
+        .unwrap_or(0);
+    2 * state_bytes + op_bytes
+}
+
+/// The label drawn for an op on each of the qubits it acts on.
+fn op_labels<P: Precision>(entry: &PipelineEntry<P>) -> Vec<&'static str> {
+    let (indices, obj) = entry;
+    let label = match &obj.object {
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::CNOT) => {
+            return vec!["●", "X"];
+        }
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::SWAP) => "×",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::X) => "X",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Y) => "Y",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Z) => "Z",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::H) => "H",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::S) => "S",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::T) => "T",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Rz(_)) => "Rz",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::GlobalPhase(_)) => "Ph",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Identity) => "I",
+        BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Barrier) => "░",
+        BuilderCircuitObjectType::Unitary(_) => "U",
+        BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement) => "M",
+        BuilderCircuitObjectType::Measurement(MeasurementObject::StochasticMeasurement) => "M~",
+        BuilderCircuitObjectType::Measurement(MeasurementObject::Reset) => "|0>",
+    };
+    vec![label; indices.len()]
+}
+
+/// Draw the circuit as text with one row per qubit and one column per time step, where each op
+/// is placed in the first column after the previous ops on its qubits. Multi-qubit ops are joined
+/// by a vertical line, CNOT controls are drawn as `●` and swapped qubits as `×`. Other matrices
+/// are drawn as `U` and measurements as `M`.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::analysis::print_circuit;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (q, r) = (b.qubit(), b.qubit());
+/// let q = b.h(q);
+/// let (q, r) = b.cnot(q, r)?;
+/// assert_eq!(print_circuit(&b), "q0: ─H──●─\nq1: ────X─\n");
+/// # Ok(())
+/// # }
+/// ```
+pub fn print_circuit<P: Precision>(b: &LocalBuilder<P>) -> String {
+    // Each column holds the label for every qubit, if it has one.
+    let mut columns: Vec<Vec<Option<&'static str>>> = vec![];
+    let mut free_at = vec![0; b.n];
+    b.pipeline.iter().for_each(|entry| {
+        let (indices, obj) = entry;
+        let labels = op_labels(entry);
+        // Multi-qubit ops take up the rows between their qubits too.
+        let rows = if obj.n > 1 {
+            let min = indices.iter().cloned().min().unwrap();
+            let max = indices.iter().cloned().max().unwrap();
+            (min..=max).collect::<Vec<_>>()
+        } else {
+            indices.clone()
+        };
+        let column = rows.iter().map(|row| free_at[*row]).max().unwrap_or(0);
+        if column == columns.len() {
+            columns.push(vec![None; b.n]);
+        }
+        rows.iter().for_each(|row| {
+            free_at[*row] = column + 1;
+            columns[column][*row] = Some("│");
+        });
+        indices
+            .iter()
+            .zip(labels)
+            .for_each(|(row, label)| columns[column][*row] = Some(label));
+    });
+
+    let widths = columns
+        .iter()
+        .map(|column| {
+            column
+                .iter()
+                .flatten()
+                .map(|label| label.chars().count())
+                .max()
+                .unwrap_or(1)
+        })
+        .collect::<Vec<_>>();
+    let label_width = format!("q{}", b.n.saturating_sub(1)).len();
+    (0..b.n)
+        .map(|row| {
+            let mut line = format!("{:<width$}: ", format!("q{}", row), width = label_width);
+            columns
+                .iter()
+                .zip(widths.iter())
+                .for_each(|(column, width)| {
+                    let label = column[row].unwrap_or("─");
+                    let padding = "─".repeat(width - label.chars().count());
+                    line.push('─');
+                    line.push_str(label);
+                    line.push_str(&padding);
+                    line.push('─');
+                });
+            line.push('\n');
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod analysis_tests {
+    use super::*;
+    use crate::prelude::*;
+    use num_traits::{One, Zero};
+    use std::num::NonZeroUsize;
+
+    fn make_circuit(b: &mut LocalBuilder<f64>, modified: bool) -> CircuitResult<()> {
+        let ra = b.qubit();
+        let rb = b.register(NonZeroUsize::new(2).unwrap());
+        let ra = b.h(ra);
+        let (ra, rb) = b.cnot(ra, rb)?;
+        let rb = if modified { b.y(rb) } else { b.x(rb) };
+        let _r = b.merge_two_registers(ra, rb);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_identical() -> CircuitResult<()> {
+        let mut a = LocalBuilder::default();
+        let mut b = LocalBuilder::default();
+        make_circuit(&mut a, false)?;
+        make_circuit(&mut b, false)?;
+        assert!(diff_circuits(&a, &b).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_reordered_disjoint_ops() -> CircuitResult<()> {
+        let mut a = LocalBuilder::<f64>::default();
+        let (q, r) = (a.qubit(), a.qubit());
+        let q = a.h(q);
+        let r = a.x(r);
+        let (_q, _r) = a.cnot(q, r)?;
+
+        let mut b = LocalBuilder::<f64>::default();
+        let (q, r) = (b.qubit(), b.qubit());
+        let r = b.x(r);
+        let q = b.h(q);
+        let (_q, _r) = b.cnot(q, r)?;
+        assert_eq!(diff_circuits(&a, &b), None);
+
+        // Ops on the same qubit do not commute, so their order still matters.
+        let mut c = LocalBuilder::<f64>::default();
+        let (q, r) = (c.qubit(), c.qubit());
+        let r = c.x(r);
+        let q = c.x(q);
+        let q = c.h(q);
+        let (_q, _r) = c.cnot(q, r)?;
+        match diff_circuits(&a, &c) {
+            Some(CircuitDiff::Changed(before, after)) => {
+                assert_eq!((before.name, after.name), ("h", "x"));
+                assert_eq!(before.indices, vec![0]);
+            }
+            d => panic!("Unexpected diff {:?}", d),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_changed() -> CircuitResult<()> {
+        let mut a = LocalBuilder::default();
+        let mut b = LocalBuilder::default();
+        make_circuit(&mut a, false)?;
+        make_circuit(&mut b, true)?;
+        match diff_circuits(&a, &b) {
+            Some(CircuitDiff::Changed(before, after)) => {
+                assert_eq!(before.name, "x");
+                assert_eq!(after.name, "y");
+                assert_eq!(before.indices, vec![1]);
+                assert_eq!(before.position, after.position);
+            }
+            d => panic!("Unexpected diff {:?}", d),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_added() -> CircuitResult<()> {
+        let mut a = LocalBuilder::default();
+        let mut b = LocalBuilder::default();
+        make_circuit(&mut a, false)?;
+        make_circuit(&mut b, false)?;
+        let q = b.qubit();
+        let _q = b.z(q);
+        assert_eq!(
+            diff_circuits(&a, &b),
+            Some(CircuitDiff::Added(OpSummary {
+                position: a.pipeline_depth(),
+                indices: vec![3],
+                name: "z",
+            }))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dominant_ops() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.register(NonZeroUsize::new(3).unwrap());
+        let q = b.h(q);
+        let r = b.x(r);
+        let (q, r) = b.cnot(q, r)?;
+        let data = (0..64)
+            .map(|i| {
+                if i % 9 == 0 {
+                    Complex::one()
+                } else {
+                    Complex::zero()
+                }
+            })
+            .collect();
+        let r = b.apply_vec_matrix(r, data)?;
+        let q = b.z(q);
+        let _r = b.merge_two_registers(q, r);
+
+        let ops = dominant_ops(&b, 2);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].0.name, "mat");
+        assert_eq!(ops[0].0.indices, vec![1, 2, 3]);
+        assert_eq!(ops[0].1, 16 * 8);
+        assert!(ops[1].1 < ops[0].1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_measurement_schedule() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let (r, hr) = b.measure_stochastic(r);
+        let q = b.h(q);
+        let (q, r) = b.cnot(q, r)?;
+        let (q, hq) = b.measure(q);
+        let _r = b.merge_two_registers(q, r);
+
+        assert_eq!(
+            measurement_schedule(&b),
+            vec![
+                (hr.id(), MeasurementKind::Stochastic, vec![1, 2]),
+                (hq.id(), MeasurementKind::Single, vec![0]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_idle_qubits() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q0 = b.qubit();
+        let q1 = b.qubit();
+        let q2 = b.qubit();
+        let (q0, q2) = b.cnot(q0, q2)?;
+        let r = b.merge_registers([q0, q1, q2]).unwrap();
+        assert_eq!(idle_qubits(&b), vec![1]);
+        b.barrier(&[&r]);
+        b.pad_to_depth(3);
+        assert_eq!(idle_qubits(&b), vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_print_circuit() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let (q0, q1, q2) = (b.qubit(), b.qubit(), b.qubit());
+        let q0 = b.h(q0);
+        let q1 = b.x(q1);
+        let (q0, q2) = b.cnot(q0, q2)?;
+        let q1 = b.rz(q1, 0.5);
+        let (q1, q2) = b.swap(q1, q2)?;
+        let _q = b.measure(q0);
+        let _r = b.merge_two_registers(q1, q2);
+        let expected = "\
+q0: ─H──●──M───────────
+q1: ─X──│──Rz──●──X──●─
+q2: ────X──────X──●──X─
+";
+        assert_eq!(print_circuit(&b), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_peak_memory_bytes() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(3).unwrap());
+        let r = b.apply_vec_matrix(r, (0..64).map(|i| Complex::new(i as f64, 0.0)).collect())?;
+        let _r = b.h(r);
+        let final_state_bytes = 8 * 16;
+        let peak = peak_memory_bytes(&b, 8);
+        assert_eq!(peak, (2 * 8 + 64) * 16);
+        assert!(peak > final_state_bytes);
+
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(3).unwrap());
+        let _r = b.h(r);
+        assert!(peak_memory_bytes(&b, 8) < peak);
+        Ok(())
+    }
+
+    #[test]
+    fn test_circuit_stats_staircase() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let qs = (0..5).map(|_| b.qubit()).collect::<Vec<_>>();
+        let mut qs = qs.into_iter().map(|q| b.h(q)).collect::<Vec<_>>();
+        // Each cnot waits for the previous one, giving a depth of 1 + 4.
+        let first = qs.remove(0);
+        let r = qs.into_iter().try_fold(first, |c, t| {
+            let (_c, t) = b.cnot(c, t)?;
+            CircuitResult::Ok(t)
+        })?;
+
+        let stats = circuit_stats(&b);
+        assert_eq!(stats.depth, 5);
+        assert_eq!(stats.n_qubits, 5);
+        assert_eq!(stats.gate_counts.len(), 2);
+        assert_eq!(stats.gate_counts["h"], 5);
+        assert_eq!(stats.gate_counts["cnot"], 4);
+
+        // Barriers and padding are not counted.
+        b.barrier(&[&r]);
+        b.pad_to_depth(8);
+        assert_eq!(circuit_stats(&b), stats);
+        Ok(())
+    }
+
+    #[test]
+    fn test_circuit_duration() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let (q0, q1, q2) = (b.qubit(), b.qubit(), b.qubit());
+        // The chain on q0 and q1 takes 10 + 50 + 20 = 80ns.
+        let q0 = b.h(q0);
+        let (q0, q1) = b.cnot(q0, q1)?;
+        let q1 = b.x(q1);
+        // While q2 runs three shorter gates in parallel.
+        let q2 = b.x(q2);
+        let q2 = b.x(q2);
+        let q2 = b.x(q2);
+        let _r = b.merge_registers([q0, q1, q2]);
+
+        let durations = GateDurations::from([
+            ("h".to_string(), 10.0),
+            ("cnot".to_string(), 50.0),
+            ("x".to_string(), 20.0),
+        ]);
+        assert_eq!(circuit_duration(&b, &durations), 80.0);
+        assert_eq!(circuit_duration(&b, &GateDurations::new()), 0.0);
+        Ok(())
+    }
+}
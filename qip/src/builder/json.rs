@@ -0,0 +1,818 @@
+use super::{
+    BuilderCircuitObject, BuilderCircuitObjectType, LocalBuilder, MeasurementHandle,
+    MeasurementObject, Qudit, RotationObject, UnitaryMatrixObject,
+};
+use crate::builder_traits::CircuitBuilder;
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
+use crate::types::Precision;
+use crate::Complex;
+use num_rational::Ratio;
+use std::fmt::Write;
+use std::num::NonZeroUsize;
+
+fn float_json<P: Precision>(x: P) -> CircuitResult<String> {
+    if x.is_finite() {
+        Ok(x.to_string())
+    } else {
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::Unsupported,
+            format!("The non-finite value {} cannot be written as JSON", x),
+        ))
+    }
+}
+
+fn complex_json<P: Precision>(c: &Complex<P>) -> CircuitResult<String> {
+    Ok(format!("[{},{}]", float_json(c.re)?, float_json(c.im)?))
+}
+
+fn entry_json<P: Precision>(index: usize, c: &Complex<P>) -> CircuitResult<String> {
+    Ok(format!(
+        "[{},{},{}]",
+        index,
+        float_json(c.re)?,
+        float_json(c.im)?
+    ))
+}
+
+fn entries_json<P: Precision>(entries: &[(usize, Complex<P>)]) -> CircuitResult<String> {
+    let entries = entries
+        .iter()
+        .map(|(index, c)| entry_json(*index, c))
+        .collect::<CircuitResult<Vec<_>>>()?;
+    Ok(format!("[{}]", entries.join(",")))
+}
+
+fn indices_json(indices: &[usize]) -> String {
+    let indices = indices.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+    format!("[{}]", indices.join(","))
+}
+
+fn rotation_json<P: Precision>(rot: &RotationObject<P>) -> CircuitResult<String> {
+    Ok(match rot {
+        RotationObject::Floating(theta) => format!("\"theta\":{}", float_json(*theta)?),
+        RotationObject::PiRational(r) => format!("\"pi_ratio\":[{},{}]", r.numer(), r.denom()),
+    })
+}
+
+/// Write the circuit in `b` as JSON: an object with the number of qubits `n` and the ordered list
+/// of `ops`. Each op has the `gate` name, the qubit `indices` it acts on, and `n`, the number of
+/// qubits of its matrix, which is one for single qubit gates broadcast over several indices.
+/// Rotations add either `theta` or `pi_ratio` (a fraction of pi as `[numerator, denominator]`),
+/// dense matrices their `data` as `[re, im]` pairs and sparse matrices their `rows` of
+/// `[column, re, im]` entries. Ops added by `apply_on_measurement` list their `conditions`, each
+/// the `measurement` id and the `value` it must have for the op to be applied. If any qubits
+/// start in a state other than |0>, the circuit also has `initial_states`, each with the qubit
+/// `indices` and the nonzero entries of the `state` as `[basis_state, re, im]`. Errors if the
+/// circuit contains a generated matrix, which is only defined by a function, or a non-finite
+/// value, which JSON cannot represent. Use `from_json` to read the circuit back.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::json::to_json;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let q = b.qubit();
+/// let _q = b.h(q);
+/// assert_eq!(
+///     to_json(&b)?,
+///     r#"{"n":1,"ops":[{"gate":"h","indices":[0],"n":1}]}"#
+/// );
+/// # Ok(())
+/// # }
+/// ```
+pub fn to_json<P: Precision>(b: &LocalBuilder<P>) -> CircuitResult<String> {
+    let ops = b
+        .pipeline
+        .iter()
+        .enumerate()
+        .map(|(pos, (indices, obj))| {
+            let mut op = format!(
+                "{{\"gate\":\"{}\",\"indices\":{},\"n\":{}",
+                obj.object.name(),
+                indices_json(indices),
+                obj.n
+            );
+            match &obj.object {
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Rz(rot))
+                | BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::GlobalPhase(rot)) => {
+                    write!(op, ",{}", rotation_json(rot)?).unwrap()
+                }
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::MAT(data)) => {
+                    let data = data
+                        .iter()
+                        .map(complex_json)
+                        .collect::<CircuitResult<Vec<_>>>()?;
+                    write!(op, ",\"data\":[{}]", data.join(",")).unwrap()
+                }
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Sparse(rows)) => {
+                    let rows = rows
+                        .iter()
+                        .map(|entries| entries_json(entries))
+                        .collect::<CircuitResult<Vec<_>>>()?;
+                    write!(op, ",\"rows\":[{}]", rows.join(",")).unwrap()
+                }
+                BuilderCircuitObjectType::Unitary(UnitaryMatrixObject::Generated(_)) => {
+                    return Err(CircuitError::with_kind(
+                        CircuitErrorKind::Unsupported,
+                        "Generated matrices are defined by a function and cannot be written as JSON",
+                    ))
+                }
+                BuilderCircuitObjectType::Unitary(_)
+                | BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement)
+                | BuilderCircuitObjectType::Measurement(
+                    MeasurementObject::StochasticMeasurement,
+                )
+                | BuilderCircuitObjectType::Measurement(MeasurementObject::Reset) => {}
+            }
+            let conditions = b
+                .measurement_conditions
+                .iter()
+                .filter(|(range, _, _)| range.contains(&pos))
+                .map(|(_, handle, value)| {
+                    format!("{{\"measurement\":{},\"value\":{}}}", handle.id(), value)
+                })
+                .collect::<Vec<_>>();
+            if !conditions.is_empty() {
+                write!(op, ",\"conditions\":[{}]", conditions.join(",")).unwrap()
+            }
+            op.push('}');
+            Ok(op)
+        })
+        .collect::<CircuitResult<Vec<_>>>()?;
+    let mut json = format!("{{\"n\":{}", b.n);
+    if !b.initial_states.is_empty() {
+        let states = b
+            .initial_states
+            .iter()
+            .map(|(indices, state)| {
+                Ok(format!(
+                    "{{\"indices\":{},\"state\":{}}}",
+                    indices_json(indices),
+                    entries_json(state)?
+                ))
+            })
+            .collect::<CircuitResult<Vec<_>>>()?;
+        write!(json, ",\"initial_states\":[{}]", states.join(",")).unwrap()
+    }
+    write!(json, ",\"ops\":[{}]}}", ops.join(",")).unwrap();
+    Ok(json)
+}
+
+/// A parsed JSON value. Numbers keep their text so they can be read as integers or as `P` without
+/// losing precision.
+#[derive(Debug)]
+enum Json<'a> {
+    Number(&'a str),
+    String(String),
+    Array(Vec<Json<'a>>),
+    Object(Vec<(String, Json<'a>)>),
+}
+
+fn parse_error<S: AsRef<str>>(msg: S) -> CircuitError {
+    CircuitError::with_kind(
+        CircuitErrorKind::Parse,
+        format!("Circuit JSON parse error: {}", msg.as_ref()),
+    )
+}
+
+/// A recursive descent parser for the subset of JSON written by `to_json`: objects, arrays,
+/// strings without escapes and numbers.
+struct JsonParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn parse(text: &'a str) -> CircuitResult<Json<'a>> {
+        let mut parser = Self { text, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != text.len() {
+            return Err(parser.error("expected the end of the input"));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, msg: &str) -> CircuitError {
+        parse_error(format!("{} at byte {}", msg, self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn expect(&mut self, c: char) -> CircuitResult<()> {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", c)))
+        }
+    }
+
+    fn value(&mut self) -> CircuitResult<Json<'a>> {
+        match self.peek() {
+            Some('{') => {
+                let entries = self.list('{', '}', |parser| {
+                    let key = parser.string()?;
+                    parser.expect(':')?;
+                    Ok((key, parser.value()?))
+                })?;
+                Ok(Json::Object(entries))
+            }
+            Some('[') => Ok(Json::Array(self.list('[', ']', Self::value)?)),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                let len = self.text[start..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c)))
+                    .unwrap_or(self.text.len() - start);
+                self.pos += len;
+                Ok(Json::Number(&self.text[start..self.pos]))
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Parse the items between `open` and `close`, separated by commas.
+    fn list<T, F>(&mut self, open: char, close: char, mut item: F) -> CircuitResult<Vec<T>>
+    where
+        F: FnMut(&mut Self) -> CircuitResult<T>,
+    {
+        self.expect(open)?;
+        let mut items = vec![];
+        if self.peek() == Some(close) {
+            self.pos += close.len_utf8();
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.peek() == Some(',') {
+                self.pos += 1;
+            } else {
+                self.expect(close)?;
+                return Ok(items);
+            }
+        }
+    }
+
+    fn string(&mut self) -> CircuitResult<String> {
+        self.expect('"')?;
+        let len = self.text[self.pos..]
+            .find(['"', '\\'])
+            .ok_or_else(|| self.error("unterminated string"))?;
+        let s = self.text[self.pos..self.pos + len].to_string();
+        self.pos += len;
+        self.expect('"')?;
+        Ok(s)
+    }
+}
+
+impl<'a> Json<'a> {
+    fn as_object(&self, what: &str) -> CircuitResult<&[(String, Json<'a>)]> {
+        match self {
+            Json::Object(entries) => Ok(entries),
+            _ => Err(parse_error(format!("{} must be an object", what))),
+        }
+    }
+
+    fn as_array(&self, what: &str) -> CircuitResult<&[Json<'a>]> {
+        match self {
+            Json::Array(items) => Ok(items),
+            _ => Err(parse_error(format!("{} must be an array", what))),
+        }
+    }
+
+    fn as_str(&self, what: &str) -> CircuitResult<&str> {
+        match self {
+            Json::String(s) => Ok(s),
+            _ => Err(parse_error(format!("{} must be a string", what))),
+        }
+    }
+
+    fn as_number<T: std::str::FromStr>(&self, what: &str) -> CircuitResult<T> {
+        match self {
+            Json::Number(text) => text.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| parse_error(format!("{} must be a number of the right type", what)))
+    }
+
+    fn as_float<P: Precision>(&self, what: &str) -> CircuitResult<P> {
+        match self {
+            Json::Number(text) => P::from_str_radix(text, 10).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| parse_error(format!("{} must be a number", what)))
+    }
+}
+
+/// Get the value of `key` in the object `entries`, if present.
+fn get<'b, 'a>(entries: &'b [(String, Json<'a>)], key: &str) -> Option<&'b Json<'a>> {
+    entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Get the value of `key` in the object `entries`, erroring if it is missing.
+fn require<'b, 'a>(entries: &'b [(String, Json<'a>)], key: &str) -> CircuitResult<&'b Json<'a>> {
+    get(entries, key).ok_or_else(|| parse_error(format!("missing `{}`", key)))
+}
+
+fn complex_from_json<P: Precision>(json: &Json) -> CircuitResult<Complex<P>> {
+    match json.as_array("complex number")? {
+        [re, im] => Ok(Complex::new(re.as_float("re")?, im.as_float("im")?)),
+        _ => Err(parse_error("complex numbers must be [re, im]")),
+    }
+}
+
+/// Read the `[index, re, im]` entries of a sparse row or state, with each index below `side`.
+fn entries_from_json<P: Precision>(
+    json: &Json,
+    side: usize,
+) -> CircuitResult<Vec<(usize, Complex<P>)>> {
+    json.as_array("entries")?
+        .iter()
+        .map(|entry| match entry.as_array("entry")? {
+            [index, re, im] => {
+                let index = index.as_number::<usize>("entry index")?;
+                if index >= side {
+                    let message = format!("entry index {} is out of range", index);
+                    return Err(CircuitError::with_kind(
+                        CircuitErrorKind::IndexOutOfRange,
+                        message,
+                    ));
+                }
+                Ok((index, Complex::new(re.as_float("re")?, im.as_float("im")?)))
+            }
+            _ => Err(parse_error("entries must be [index, re, im]")),
+        })
+        .collect()
+}
+
+/// Read qubit indices, checking that they are below `n` and not repeated.
+fn indices_from_json(json: &Json, n: usize) -> CircuitResult<Vec<usize>> {
+    let indices = json
+        .as_array("indices")?
+        .iter()
+        .map(|i| i.as_number::<usize>("index"))
+        .collect::<CircuitResult<Vec<_>>>()?;
+    if indices.is_empty() {
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Ops and initial states must have at least one index",
+        ));
+    }
+    if let Some(i) = indices.iter().find(|i| **i >= n) {
+        let message = format!("Index {:?} is out of range for {:?} qubits", i, n);
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::IndexOutOfRange,
+            message,
+        ));
+    }
+    let mut sorted = indices.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    if sorted.len() != indices.len() {
+        let message = format!("Indices {:?} are repeated", indices);
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::RepeatedIndices,
+            message,
+        ));
+    }
+    Ok(indices)
+}
+
+fn rotation_from_json<P: Precision>(
+    op: &[(String, Json)],
+    name: &str,
+) -> CircuitResult<RotationObject<P>> {
+    match (get(op, "theta"), get(op, "pi_ratio")) {
+        (Some(theta), None) => Ok(RotationObject::Floating(theta.as_float("theta")?)),
+        (None, Some(ratio)) => match ratio.as_array("pi_ratio")? {
+            [numer, denom] => {
+                let denom = denom.as_number::<i64>("pi_ratio denominator")?;
+                if denom == 0 {
+                    return Err(parse_error("pi_ratio denominator must not be zero"));
+                }
+                let numer = numer.as_number::<i64>("pi_ratio numerator")?;
+                Ok(RotationObject::PiRational(Ratio::new(numer, denom)))
+            }
+            _ => Err(parse_error("pi_ratio must be [numerator, denominator]")),
+        },
+        _ => Err(parse_error(format!(
+            "{} ops need exactly one of `theta` or `pi_ratio`",
+            name
+        ))),
+    }
+}
+
+/// Read an op written by `to_json` and check that its matrix fits its qubits.
+fn op_from_json<P: Precision>(
+    op: &[(String, Json)],
+    n: usize,
+) -> CircuitResult<(Vec<usize>, BuilderCircuitObject<P>)> {
+    let name = require(op, "gate")?.as_str("gate")?;
+    let indices = indices_from_json(require(op, "indices")?, n)?;
+    let op_n = require(op, "n")?.as_number::<usize>("n")?;
+    // Broadcast ops apply a single qubit matrix to each of their indices.
+    if op_n != 1 && op_n != indices.len() {
+        let message = format!(
+            "The {} op has n = {:?} but acts on {:?} qubits",
+            name,
+            op_n,
+            indices.len()
+        );
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::MatrixSizeMismatch,
+            message,
+        ));
+    }
+    let side = 1usize
+        .checked_shl(op_n as u32)
+        .filter(|side| side.checked_mul(*side).is_some())
+        .ok_or_else(|| parse_error(format!("the {} op is too large", name)))?;
+    let size_mismatch = |expected: &str| {
+        let message = format!("The {} op on {:?} qubits must {}", name, op_n, expected);
+        CircuitError::with_kind(CircuitErrorKind::MatrixSizeMismatch, message)
+    };
+    let unitary = match name {
+        "x" => UnitaryMatrixObject::X,
+        "y" => UnitaryMatrixObject::Y,
+        "z" => UnitaryMatrixObject::Z,
+        "h" => UnitaryMatrixObject::H,
+        "s" => UnitaryMatrixObject::S,
+        "t" => UnitaryMatrixObject::T,
+        "cnot" if op_n == 2 && indices.len() == 2 => UnitaryMatrixObject::CNOT,
+        "cnot" => return Err(size_mismatch("act on two qubits")),
+        "swap" if op_n == indices.len() && op_n % 2 == 0 => UnitaryMatrixObject::SWAP,
+        "swap" => return Err(size_mismatch("act on an even number of qubits")),
+        "rz" => UnitaryMatrixObject::Rz(rotation_from_json(op, name)?),
+        "global_phase" => UnitaryMatrixObject::GlobalPhase(rotation_from_json(op, name)?),
+        "identity" => UnitaryMatrixObject::Identity,
+        "barrier" => UnitaryMatrixObject::Barrier,
+        "mat" => {
+            let data = require(op, "data")?
+                .as_array("data")?
+                .iter()
+                .map(complex_from_json)
+                .collect::<CircuitResult<Vec<_>>>()?;
+            if data.len() != side * side {
+                return Err(size_mismatch(&format!("have {} entries", side * side)));
+            }
+            UnitaryMatrixObject::MAT(data)
+        }
+        "sparse" => {
+            let rows = require(op, "rows")?
+                .as_array("rows")?
+                .iter()
+                .map(|row| entries_from_json(row, side))
+                .collect::<CircuitResult<Vec<_>>>()?;
+            if rows.len() != side {
+                return Err(size_mismatch(&format!("have {} rows", side)));
+            }
+            UnitaryMatrixObject::Sparse(rows)
+        }
+        "measure" | "measure_stochastic" | "reset" => {
+            if op_n != indices.len() {
+                return Err(size_mismatch("have n equal to its number of indices"));
+            }
+            let measurement = match name {
+                "measure" => MeasurementObject::Measurement,
+                "measure_stochastic" => MeasurementObject::StochasticMeasurement,
+                _ => MeasurementObject::Reset,
+            };
+            let obj = BuilderCircuitObject {
+                n: op_n,
+                object: BuilderCircuitObjectType::Measurement(measurement),
+            };
+            return Ok((indices, obj));
+        }
+        "generated" => {
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::Unsupported,
+                "Generated matrices are defined by a function and cannot be read from JSON",
+            ))
+        }
+        _ => return Err(parse_error(format!("unknown gate `{}`", name))),
+    };
+    let obj = BuilderCircuitObject {
+        n: op_n,
+        object: BuilderCircuitObjectType::Unitary(unitary),
+    };
+    Ok((indices, obj))
+}
+
+/// Read a circuit written by `to_json` into a new builder, along with a register of all of its
+/// qubits in order, or `None` if it has no qubits. The ops, initial states and conditions on
+/// measurements are restored, so the circuit runs as it did before it was written. Errors if the
+/// JSON is malformed, or if an op or initial state does not fit the qubits it is on.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::builder::json::{from_json, to_json};
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let q = b.qubit();
+/// let _q = b.x(q);
+/// let (mut loaded, r) = from_json::<f64>(&to_json(&b)?)?;
+/// assert_eq!(to_json(&loaded)?, to_json(&b)?);
+/// let (_r, handle) = loaded.measure(r.unwrap());
+/// assert_eq!(loaded.calculate_state().get_measurement(handle).0, 1);
+/// # Ok(())
+/// # }
+/// ```
+pub fn from_json<P: Precision>(json: &str) -> CircuitResult<(LocalBuilder<P>, Option<Qudit>)> {
+    let root = JsonParser::parse(json)?;
+    let root = root.as_object("circuit")?;
+    let n = require(root, "n")?.as_number::<usize>("n")?;
+    let mut b = LocalBuilder::default();
+    let r = NonZeroUsize::new(n).map(|n| b.register(n));
+
+    if let Some(states) = get(root, "initial_states") {
+        for state in states.as_array("initial_states")? {
+            let state = state.as_object("initial state")?;
+            let indices = indices_from_json(require(state, "indices")?, n)?;
+            let side = 1usize
+                .checked_shl(indices.len() as u32)
+                .ok_or_else(|| parse_error("initial state is too large"))?;
+            let entries = entries_from_json(require(state, "state")?, side)?;
+            let overlaps = b
+                .initial_states
+                .iter()
+                .any(|(other, _)| other.iter().any(|i| indices.contains(i)));
+            if overlaps {
+                let message = format!("Initial states overlap on qubits {:?}", indices);
+                return Err(CircuitError::with_kind(
+                    CircuitErrorKind::OverlappingIndices,
+                    message,
+                ));
+            }
+            b.initial_states.push((indices, entries));
+        }
+    }
+
+    // Each measurement so far with the number of qubits it measured, or None if it was stochastic.
+    let mut measured = vec![];
+    for (pos, op) in require(root, "ops")?.as_array("ops")?.iter().enumerate() {
+        let op = op.as_object("op")?;
+        let (indices, obj) = op_from_json(op, n)?;
+        let conditions = match get(op, "conditions") {
+            Some(conditions) => conditions.as_array("conditions")?,
+            None => &[],
+        };
+        for condition in conditions {
+            let condition = condition.as_object("condition")?;
+            let id = require(condition, "measurement")?.as_number::<usize>("measurement")?;
+            let value = require(condition, "value")?.as_number::<usize>("value")?;
+            if matches!(
+                obj.object,
+                BuilderCircuitObjectType::Measurement(
+                    MeasurementObject::Measurement | MeasurementObject::StochasticMeasurement
+                )
+            ) {
+                return Err(parse_error(format!(
+                    "the measurement at {} cannot be applied on a measurement",
+                    pos
+                )));
+            }
+            match measured.get(id) {
+                Some(Some(bits)) if value.checked_shr(*bits as u32).unwrap_or(0) == 0 => {}
+                Some(Some(bits)) => {
+                    return Err(parse_error(format!(
+                        "value {} does not fit in the {} qubits of measurement {}",
+                        value, bits, id
+                    )))
+                }
+                _ => {
+                    return Err(parse_error(format!(
+                        "the op at {} is applied on measurement {}, which is not an earlier \
+                         single measurement",
+                        pos, id
+                    )))
+                }
+            }
+            // Consecutive ops on the same condition share a range.
+            let handle = MeasurementHandle { id };
+            let extended = b
+                .measurement_conditions
+                .iter_mut()
+                .find(|(range, h, v)| range.end == pos && h.id == id && *v == value);
+            match extended {
+                Some((range, _, _)) => range.end = pos + 1,
+                None => b.measurement_conditions.push((pos..pos + 1, handle, value)),
+            }
+        }
+        match obj.object {
+            BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement) => {
+                measured.push(Some(indices.len()))
+            }
+            BuilderCircuitObjectType::Measurement(MeasurementObject::StochasticMeasurement) => {
+                measured.push(None)
+            }
+            _ => {}
+        }
+        b.pipeline.push((indices, obj));
+    }
+    b.measurements = measured.len();
+    Ok((b, r))
+}
+
+#[cfg(test)]
+mod json_tests {
+    use super::*;
+    use crate::prelude::*;
+    use num_traits::{One, Zero};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_to_json() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let q = b.qubit();
+        let r = b.apply_vec_matrix(
+            r,
+            (0..16)
+                .map(|i| {
+                    if i % 5 == 0 {
+                        Complex::one()
+                    } else {
+                        Complex::zero()
+                    }
+                })
+                .collect(),
+        )?;
+        let (ra, rb) = b.split_first_qubit(r);
+        let (q, ra) = b.cnot(q, ra.unwrap())?;
+        let (ra, rb) = b.cnot(ra, rb)?;
+        let rb = b.rz_pi_by(rb, 4)?;
+        let (_rb, _handle) = b.measure(rb);
+        let _ra = b.rz(ra, 0.5);
+        let _q = q;
+
+        let identity = (0..16)
+            .map(|i| if i % 5 == 0 { "[1,0]" } else { "[0,0]" })
+            .collect::<Vec<_>>()
+            .join(",");
+        let expected = [
+            format!(
+                r#"{{"gate":"mat","indices":[0,1],"n":2,"data":[{}]}}"#,
+                identity
+            ),
+            r#"{"gate":"cnot","indices":[2,0],"n":2}"#.to_string(),
+            r#"{"gate":"cnot","indices":[0,1],"n":2}"#.to_string(),
+            r#"{"gate":"rz","indices":[1],"n":1,"pi_ratio":[1,4]}"#.to_string(),
+            r#"{"gate":"measure","indices":[1],"n":1}"#.to_string(),
+            r#"{"gate":"rz","indices":[0],"n":1,"theta":0.5}"#.to_string(),
+        ];
+        assert_eq!(
+            to_json(&b)?,
+            format!(r#"{{"n":3,"ops":[{}]}}"#, expected.join(","))
+        );
+
+        let q = b.qubit();
+        let _q = b.apply_generated_matrix(q, |row, col| {
+            if row == col {
+                Complex::one()
+            } else {
+                Complex::zero()
+            }
+        })?;
+        assert!(to_json(&b).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_json_conditions_and_initial_states() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let m = b.qubit_with_state(vec![Complex::zero(), Complex::one()])?;
+        let q = b.qubit();
+        let (_m, handle) = b.measure(m);
+        let q = b.apply_on_measurement(handle, 1, q, |b, q| Ok(b.x(q)))?;
+        let _q = b.z(q);
+
+        let expected = [
+            r#"{"gate":"measure","indices":[0],"n":1}"#,
+            r#"{"gate":"x","indices":[1],"n":1,"conditions":[{"measurement":0,"value":1}]}"#,
+            r#"{"gate":"z","indices":[1],"n":1}"#,
+        ];
+        assert_eq!(
+            to_json(&b)?,
+            format!(
+                r#"{{"n":2,"initial_states":[{{"indices":[0],"state":[[1,1,0]]}}],"ops":[{}]}}"#,
+                expected.join(",")
+            )
+        );
+
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let _q = b.rz(q, f64::NAN);
+        let err = to_json(&b).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::Unsupported);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_round_trip() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let r = b.register_with_state(
+            NonZeroUsize::new(2).unwrap(),
+            vec![
+                Complex::new(s, 0.0),
+                Complex::zero(),
+                Complex::zero(),
+                Complex::new(0.0, s),
+            ],
+        )?;
+        let (q, m) = (b.qubit(), b.qubit());
+        let u = crate::state_ops::linalg::random_unitary(2, &mut StdRng::seed_from_u64(1));
+        let r = b.apply_vec_matrix(r, u)?;
+        let (ra, rb) = b.split_first_qubit(r);
+        let (ra, q) = b.swap(ra.unwrap(), q)?;
+        let (q, rb) = b.cnot(q, rb)?;
+        let rb = b.rz_pi_by(rb, 3)?;
+        let q = b.rz(q, 0.25);
+        let q = b.apply_sparse_matrix(
+            q,
+            vec![
+                vec![(1, Complex::new(0.0, 1.0))],
+                vec![(0, Complex::new(0.0, 1.0))],
+            ],
+        )?;
+        let m = b.h(m);
+        let (m, handle) = b.measure(m);
+        let q = b.apply_on_measurement(handle, 1, q, |b, q| {
+            let q = b.x(q);
+            Ok(b.apply_global_phase_pi_by(q, 4))
+        })?;
+        let _r = b.merge_registers([ra, rb, q, m]);
+
+        let json = to_json(&b)?;
+        let (mut loaded, loaded_r) = from_json::<f64>(&json)?;
+        assert_eq!(to_json(&loaded)?, json);
+        assert_eq!(loaded_r.unwrap().indices(), &[0, 1, 2, 3]);
+        assert_eq!(loaded.pipeline_depth(), b.pipeline_depth());
+        for seed in 0..4 {
+            let expected = b.calculate_state_with_seed(None, seed);
+            let result = loaded.calculate_state_with_seed(None, seed);
+            assert_eq!(
+                result.get_measurement(handle),
+                expected.get_measurement(handle)
+            );
+            assert_eq!(result.global_phase(), expected.global_phase());
+            assert_eq!(result.state(), expected.state());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_json_errors() {
+        let from = |json: &str| from_json::<f64>(json).unwrap_err().kind;
+        assert_eq!(from(r#"{"n":1,"ops":["#), CircuitErrorKind::Parse);
+        assert_eq!(from(r#"{"n":1}"#), CircuitErrorKind::Parse);
+        assert_eq!(
+            from(r#"{"n":1,"ops":[{"gate":"foo","indices":[0],"n":1}]}"#),
+            CircuitErrorKind::Parse
+        );
+        assert_eq!(
+            from(r#"{"n":1,"ops":[{"gate":"x","indices":[1],"n":1}]}"#),
+            CircuitErrorKind::IndexOutOfRange
+        );
+        assert_eq!(
+            from(r#"{"n":2,"ops":[{"gate":"cnot","indices":[0,0],"n":2}]}"#),
+            CircuitErrorKind::RepeatedIndices
+        );
+        assert_eq!(
+            from(r#"{"n":1,"ops":[{"gate":"mat","indices":[0],"n":1,"data":[[1,0]]}]}"#),
+            CircuitErrorKind::MatrixSizeMismatch
+        );
+        assert_eq!(
+            from(r#"{"n":1,"ops":[{"gate":"rz","indices":[0],"n":1}]}"#),
+            CircuitErrorKind::Parse
+        );
+        // Conditions must refer to an earlier single measurement.
+        assert_eq!(
+            from(
+                r#"{"n":1,"ops":[{"gate":"x","indices":[0],"n":1,"conditions":[{"measurement":0,"value":1}]}]}"#
+            ),
+            CircuitErrorKind::Parse
+        );
+        assert_eq!(
+            from(
+                r#"{"n":1,"ops":[{"gate":"measure_stochastic","indices":[0],"n":1},{"gate":"x","indices":[0],"n":1,"conditions":[{"measurement":0,"value":1}]}]}"#
+            ),
+            CircuitErrorKind::Parse
+        );
+    }
+}
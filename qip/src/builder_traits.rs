@@ -1,4 +1,5 @@
-use crate::errors::{CircuitError, CircuitResult};
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
+use crate::state_ops::linalg::dagger;
 use crate::types::Precision;
 use num_complex::Complex;
 use num_rational::Rational64;
@@ -18,6 +19,12 @@ pub trait QubitRegister {
     fn indices(&self) -> &[usize];
 }
 
+/// Check whether registers `a` and `b` share any qubit indices. Registers in a valid circuit never
+/// overlap, so this helps track down aliasing bugs.
+pub fn indices_overlap<RA: QubitRegister, RB: QubitRegister>(a: &RA, b: &RB) -> bool {
+    a.indices().iter().any(|i| b.indices().contains(i))
+}
+
 /// Result of splitting a register in two.
 #[derive(Debug)]
 pub enum SplitResult<R: QubitRegister + Debug> {
@@ -260,6 +267,138 @@ pub trait UnitaryBuilder<P: Precision>: CircuitBuilder {
         self.apply_circuit_object(r, Self::matrix_to_circuitobject(n, data))
     }
 
+    /// Apply the matrix `data` to the qubits of `r` at the relative positions `rel_indices`, taken
+    /// in that order, without splitting `r` by hand. The register is returned with its qubits in
+    /// their original order.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let r = b.register(NonZeroUsize::new(3).unwrap());
+    /// let (o, l) = (Complex::new(0.0, 0.0), Complex::new(1.0, 0.0));
+    /// let r = b.apply_vec_matrix_on(r, &[1], vec![o, l, l, o])?;
+    /// let (r, handle) = b.measure(r);
+    /// assert_eq!(b.calculate_state().get_measurement(handle).0, 0b010);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn apply_vec_matrix_on(
+        &mut self,
+        r: Self::Register,
+        rel_indices: &[usize],
+        data: Vec<Complex<P>>,
+    ) -> CircuitResult<Self::Register> {
+        let n = rel_indices.len();
+        if let Some(index) = rel_indices.iter().find(|index| **index >= r.n()) {
+            let message = format!(
+                "Relative index {:?} is out of range for a register of {:?} qubits",
+                index,
+                r.n()
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::IndexOutOfRange,
+                message,
+            ));
+        }
+        if (1..n).any(|i| rel_indices[..i].contains(&rel_indices[i])) {
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::RepeatedIndices,
+                "Relative indices must not be repeated",
+            ));
+        }
+        if n == 0 || data.len() != 1 << (2 * n) {
+            let message = format!(
+                "Matrix has {:?} entries versus expected 2^(2*{:?})",
+                data.len(),
+                n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ));
+        }
+        let mut qs = self
+            .split_all_register(r)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        let selected = rel_indices.iter().map(|i| qs[*i].take().unwrap());
+        let selected = self.merge_registers(selected).unwrap();
+        let selected = self.apply_vec_matrix(selected, data)?;
+        let selected = self.split_all_register(selected);
+        rel_indices
+            .iter()
+            .zip(selected)
+            .for_each(|(i, q)| qs[*i] = Some(q));
+        Ok(self.merge_registers(qs.into_iter().flatten()).unwrap())
+    }
+
+    /// Apply the conjugate transpose of the matrix `data`, which undoes `apply_vec_matrix` with the
+    /// same unitary. A single qubit matrix is applied to each qubit in `r`.
+    fn apply_vec_matrix_dagger(
+        &mut self,
+        r: Self::Register,
+        data: Vec<Complex<P>>,
+    ) -> CircuitResult<Self::Register> {
+        let n = if data.len() == 4 { 1 } else { r.n() };
+        if data.len() != 1 << (2 * n) {
+            let message = format!(
+                "Matrix has {:?} entries versus expected 2^(2*{:?})",
+                data.len(),
+                n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ));
+        }
+        let data = dagger(&data);
+        self.apply_circuit_object(r, Self::vec_matrix_to_circuitobject(n, data))
+    }
+
+    /// Apply the sparse matrix given by `rows` of `(column, value)` entries to `r`, with rows and
+    /// columns ordered as for `apply_vec_matrix`. Large, mostly empty matrices can be applied this
+    /// way without storing every entry. Errors unless there are `2^n` rows, each with at least one
+    /// entry and all columns below `2^n`.
+    fn apply_sparse_matrix(
+        &mut self,
+        r: Self::Register,
+        rows: Vec<Vec<(usize, Complex<P>)>>,
+    ) -> CircuitResult<Self::Register> {
+        let n = r.n();
+        let side = 1 << n;
+        if rows.len() != side {
+            let message = format!(
+                "Sparse matrix has {:?} rows versus expected 2^{:?}",
+                rows.len(),
+                n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ));
+        }
+        if let Some(row) = rows.iter().position(|row| row.is_empty()) {
+            let message = format!("Row {:?} of the sparse matrix has no entries", row);
+            return Err(CircuitError::new(message));
+        }
+        if let Some((col, _)) = rows.iter().flatten().find(|(col, _)| *col >= side) {
+            let message = format!(
+                "Sparse matrix column {:?} is out of range for {:?} qubits",
+                col, n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::IndexOutOfRange,
+                message,
+            ));
+        }
+        self.apply_circuit_object(r, Self::sparse_matrix_to_circuitobject(n, rows))
+    }
+
     /// Single qubit matrices can be applied to each qubit in a register unambiguously.
     /// Matrix is organized as  |0><0|, |0><1|, |1><0|, |1><1|
     fn broadcast_single_qubit_matrix(
@@ -267,11 +406,37 @@ pub trait UnitaryBuilder<P: Precision>: CircuitBuilder {
         r: Self::Register,
         data: [Complex<P>; 4],
     ) -> Self::Register {
-        let n = r.n();
-        self.apply_circuit_object(r, Self::matrix_to_circuitobject(n, data))
+        self.apply_circuit_object(r, Self::matrix_to_circuitobject(1, data))
             .unwrap()
     }
 
+    /// Apply the single qubit matrix `data` to each qubit of `r` whose relative index satisfies
+    /// `predicate`, leaving the others alone. For instance `|i| i % 2 == 0` selects every other
+    /// qubit when building up layers of gates.
+    fn broadcast_single_qubit_matrix_where<F>(
+        &mut self,
+        r: Self::Register,
+        data: [Complex<P>; 4],
+        predicate: F,
+    ) -> Self::Register
+    where
+        F: Fn(usize) -> bool,
+    {
+        let qs = self
+            .split_all_register(r)
+            .into_iter()
+            .enumerate()
+            .map(|(i, q)| {
+                if predicate(i) {
+                    self.broadcast_single_qubit_matrix(q, data)
+                } else {
+                    q
+                }
+            })
+            .collect::<Vec<_>>();
+        self.merge_registers(qs).unwrap()
+    }
+
     /// Make a circuit object out of an arbitrary matrix
     /// Single Qubit matrix is organized as  |0><0|, |0><1|, |1><0|, |1><1|
     fn matrix_to_circuitobject<const N: usize>(
@@ -284,6 +449,22 @@ pub trait UnitaryBuilder<P: Precision>: CircuitBuilder {
     /// Make a circuit object out of an arbitrary matrix
     /// Single Qubit matrix is organized as  |0><0|, |0><1|, |1><0|, |1><1|
     fn vec_matrix_to_circuitobject(n: usize, data: Vec<Complex<P>>) -> Self::CircuitObject;
+
+    /// Make a circuit object out of a sparse matrix given by rows of `(column, value)` entries.
+    /// By default this fills in the dense matrix.
+    fn sparse_matrix_to_circuitobject(
+        n: usize,
+        rows: Vec<Vec<(usize, Complex<P>)>>,
+    ) -> Self::CircuitObject {
+        let side = 1 << n;
+        let mut data = vec![Complex::zero(); side * side];
+        rows.into_iter().enumerate().for_each(|(row, entries)| {
+            entries
+                .into_iter()
+                .for_each(|(col, c)| data[row * side + col] += c)
+        });
+        Self::vec_matrix_to_circuitobject(n, data)
+    }
 }
 
 /// A Builder which can construct Clifford Circuit Elements.
@@ -570,8 +751,30 @@ pub trait AdvancedCircuitBuilder<P: Precision>:
 
 /// A Builder which can construct arbitrary rotations around axes.
 pub trait RotationsBuilder<P: Precision>: CliffordTBuilder<P> {
+    /// Make a circuit object which rotates a single qubit around z by `theta`.
+    /// Equivalent to calling `matrix_to_circuitobject` with \[e^{-i theta/2}, 0, 0, e^{i theta/2}\]
+    fn make_rz(&self, theta: P) -> Self::CircuitObject {
+        let h_theta = theta / P::from(2).unwrap();
+        Self::matrix_to_circuitobject(
+            1,
+            [
+                Complex::from_polar(P::one(), -h_theta),
+                Complex::zero(),
+                Complex::zero(),
+                Complex::from_polar(P::one(), h_theta),
+            ],
+        )
+    }
+    /// Make a circuit object which rotates around z by `theta` times pi.
+    fn make_rz_ratio(&self, theta: Rational64) -> CircuitResult<Self::CircuitObject> {
+        let theta = P::from(theta).unwrap() * P::from(std::f64::consts::PI).unwrap();
+        Ok(self.make_rz(theta))
+    }
     /// Rotate around z.
-    fn rz(&mut self, r: Self::Register, theta: P) -> Self::Register;
+    fn rz(&mut self, r: Self::Register, theta: P) -> Self::Register {
+        let co = self.make_rz(theta);
+        self.apply_circuit_object(r, co).unwrap()
+    }
     /// Rotate around x.
     fn rx(&mut self, r: Self::Register, theta: P) -> Self::Register {
         let r = self.h(r);
@@ -582,21 +785,22 @@ pub trait RotationsBuilder<P: Precision>: CliffordTBuilder<P> {
     fn ry(&mut self, r: Self::Register, theta: P) -> Self::Register {
         let r = self.s_dagger(r);
         let r = self.h(r);
-        let r = self.rz(r, -theta);
+        let r = self.rz(r, theta);
         let r = self.h(r);
         self.s(r)
     }
-    /// Rotate around z.
+    /// Rotate around z by `theta` times pi.
     fn rz_ratio(&mut self, r: Self::Register, theta: Rational64) -> CircuitResult<Self::Register> {
-        Ok(self.rz(r, P::from(theta).unwrap()))
+        let co = self.make_rz_ratio(theta)?;
+        self.apply_circuit_object(r, co)
     }
-    /// Rotate around z.
+    /// Rotate around x by `theta` times pi.
     fn rx_ratio(&mut self, r: Self::Register, theta: Rational64) -> CircuitResult<Self::Register> {
         let r = self.h(r);
         let r = self.rz_ratio(r, theta)?;
         Ok(self.h(r))
     }
-    /// Rotate around z.
+    /// Rotate around y by `theta` times pi.
     fn ry_ratio(&mut self, r: Self::Register, theta: Rational64) -> CircuitResult<Self::Register> {
         let r = self.s(r);
         let r = self.h(r);
@@ -606,7 +810,11 @@ pub trait RotationsBuilder<P: Precision>: CliffordTBuilder<P> {
     }
     /// Rotate around z by pi/m
     fn rz_pi_by(&mut self, r: Self::Register, m: i64) -> CircuitResult<Self::Register> {
-        self.rz_ratio(r, Rational64::new(1, m))
+        if m == 0 {
+            Err(CircuitError::new("Cannot rotate by pi/0"))
+        } else {
+            self.rz_ratio(r, Rational64::new(1, m))
+        }
     }
     /// Rotate around x by pi/m
     fn rx_pi_by(&mut self, r: Self::Register, m: i64) -> CircuitResult<Self::Register> {
@@ -616,6 +824,19 @@ pub trait RotationsBuilder<P: Precision>: CliffordTBuilder<P> {
     fn ry_pi_by(&mut self, r: Self::Register, m: i64) -> CircuitResult<Self::Register> {
         self.ry_ratio(r, Rational64::new(1, m))
     }
+    /// Apply the general single qubit gate `U3(theta, phi, lambda)` to each qubit in `r`.
+    /// Matrix is cos(theta/2), -e^{i lambda} sin(theta/2), e^{i phi} sin(theta/2),
+    /// e^{i (phi + lambda)} cos(theta/2)
+    fn u3(&mut self, r: Self::Register, theta: P, phi: P, lambda: P) -> Self::Register {
+        let (s, c) = (theta / P::from(2).unwrap()).sin_cos();
+        let data = [
+            Complex::from(c),
+            -Complex::from_polar(s, lambda),
+            Complex::from_polar(s, phi),
+            Complex::from_polar(c, phi + lambda),
+        ];
+        self.broadcast_single_qubit_matrix(r, data)
+    }
 }
 
 /// A builder that can take destructive measurements.
@@ -1,5 +1,5 @@
-use crate::builder_traits::{QubitRegister, SplitResult, Subcircuitable};
-use crate::errors::{CircuitError, CircuitResult};
+use crate::builder_traits::{indices_overlap, QubitRegister, SplitResult, Subcircuitable};
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
 use crate::inverter::Invertable;
 use crate::inverter::RecursiveCircuitBuilder;
 use crate::prelude::*;
@@ -8,6 +8,31 @@ use num_complex::Complex;
 use num_rational::Rational64;
 use std::num::NonZeroUsize;
 
+/// Check that `controls` can be given to `Conditionable::with_controls` without taking ownership of
+/// them. Errors if there are no registers or if any two of them overlap.
+pub fn check_controls<R: QubitRegister>(controls: &[R]) -> CircuitResult<()> {
+    if controls.is_empty() {
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "At least one control register is required",
+        ));
+    }
+    for (i, ra) in controls.iter().enumerate() {
+        if let Some(rb) = controls[i + 1..].iter().find(|rb| indices_overlap(ra, *rb)) {
+            let message = format!(
+                "Control registers {:?} and {:?} overlap",
+                ra.indices(),
+                rb.indices()
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::OverlappingIndices,
+                message,
+            ));
+        }
+    }
+    Ok(())
+}
+
 /// A CircuitBuilder is conditionable if it can condition all unitaries with a given register.
 pub trait Conditionable: CircuitBuilder {
     /// Attempt to condition a circuit object `co` applied to `r` with the register `cr`.
@@ -22,6 +47,61 @@ pub trait Conditionable: CircuitBuilder {
     fn condition_with(&mut self, cr: Self::Register) -> Conditioned<Self> {
         Conditioned::new(self, cr)
     }
+
+    /// Construct a new circuitbuilder which conditions all unitaries on every register in
+    /// `controls` at once, so ops only apply when all of them are `|1>`. The registers are merged
+    /// in order, use `Conditioned::dissolve_controls` to get them back. Errors as `check_controls`
+    /// does, in which case the registers are consumed, so check them with `check_controls` first
+    /// if they are needed after a failure.
+    fn with_controls(
+        &mut self,
+        controls: Vec<Self::Register>,
+    ) -> CircuitResult<Conditioned<'_, Self>> {
+        check_controls(&controls)?;
+        let control_sizes = controls.iter().map(|r| r.n()).collect();
+        let cr = self.merge_registers(controls).unwrap();
+        let mut conditioned = Conditioned::new(self, cr);
+        conditioned.control_sizes = control_sizes;
+        Ok(conditioned)
+    }
+
+    /// Apply the ops from `f` conditioned on `cr` being `|0...0>` rather than `|1...1>`, by
+    /// flipping `cr` with X gates around a builder conditioned with `condition_with`. Returns `cr`
+    /// and the result of `f`. If `f` errors, `cr` is flipped back before the error is returned,
+    /// though any ops `f` applied before failing are kept.
+    ///
+    /// # Example
+    /// ```
+    /// use qip::prelude::*;
+    ///
+    /// # fn main() -> CircuitResult<()> {
+    /// let mut b = LocalBuilder::<f64>::default();
+    /// let c = b.qubit();
+    /// let q = b.qubit();
+    /// // Flips q since c is |0>.
+    /// let (c, q) = b.anti_condition_with(c, |cb| Ok(cb.x(q)))?;
+    /// let (q, handle) = b.measure(q);
+    /// assert_eq!(b.calculate_state().get_measurement(handle).0, 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn anti_condition_with<P, F, R>(
+        &mut self,
+        cr: Self::Register,
+        f: F,
+    ) -> CircuitResult<(Self::Register, R)>
+    where
+        P: Precision,
+        Self: CliffordTBuilder<P>,
+        F: FnOnce(&mut Conditioned<Self>) -> CircuitResult<R>,
+    {
+        let cr = self.not(cr);
+        let mut cb = self.condition_with(cr);
+        let res = f(&mut cb);
+        let cr = cb.dissolve();
+        let cr = self.not(cr);
+        res.map(|res| (cr, res))
+    }
 }
 
 /// A CircuitBuilder which conditions all unitaries with a given register.
@@ -29,13 +109,16 @@ pub trait Conditionable: CircuitBuilder {
 pub struct Conditioned<'a, CB: Conditionable + ?Sized> {
     parent: &'a mut CB,
     cr: Option<CB::Register>,
+    control_sizes: Vec<usize>,
 }
 
 impl<'a, CB: Conditionable + ?Sized> Conditioned<'a, CB> {
     fn new(cb: &'a mut CB, cr: CB::Register) -> Self {
+        let control_sizes = vec![cr.n()];
         Self {
             parent: cb,
             cr: Some(cr),
+            control_sizes,
         }
     }
 
@@ -43,6 +126,41 @@ impl<'a, CB: Conditionable + ?Sized> Conditioned<'a, CB> {
     pub fn dissolve(self) -> CB::Register {
         self.cr.unwrap()
     }
+
+    /// Dissolve the Conditioned circuit builder and split the conditioning register back into the
+    /// registers given to `Conditionable::with_controls`, in the same order.
+    pub fn dissolve_controls(self) -> Vec<CB::Register> {
+        let parent = self.parent;
+        let (mut controls, rest) =
+            self.control_sizes
+                .into_iter()
+                .fold((vec![], self.cr), |(mut controls, rest), n| {
+                    let rest = rest.unwrap();
+                    let rest = match parent.split_register_relative(rest, 0..n) {
+                        SplitResult::SPLIT(r, rest) => {
+                            controls.push(r);
+                            Some(rest)
+                        }
+                        SplitResult::SELECTED(r) => {
+                            controls.push(r);
+                            None
+                        }
+                        SplitResult::UNSELECTED(_) => unreachable!(),
+                    };
+                    (controls, rest)
+                });
+        controls.extend(rest);
+        controls
+    }
+
+    /// The indices of the qubits conditioning this builder. This only includes the register
+    /// given to this builder, not those of any builder it is nested inside.
+    pub fn control_indices(&self) -> Vec<usize> {
+        self.cr
+            .as_ref()
+            .map(|cr| cr.indices().to_vec())
+            .unwrap_or_default()
+    }
 }
 
 impl<'a, CB: Conditionable + ?Sized> CircuitBuilder for Conditioned<'a, CB> {
@@ -79,6 +197,18 @@ impl<'a, CB: Conditionable + ?Sized> CircuitBuilder for Conditioned<'a, CB> {
         c: Self::CircuitObject,
     ) -> CircuitResult<Self::Register> {
         let cr = self.cr.take().unwrap();
+        if indices_overlap(&cr, &r) {
+            let message = format!(
+                "Cannot apply an op to {:?} conditioned on the overlapping register {:?}",
+                r.indices(),
+                cr.indices()
+            );
+            self.cr = Some(cr);
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::OverlappingIndices,
+                message,
+            ));
+        }
         let (cr, r) = self.parent.try_apply_with_condition(cr, r, c)?;
         self.cr = Some(cr);
         Ok(r)
@@ -130,40 +260,12 @@ impl<'a, P: Precision, CB: Conditionable + CliffordTBuilder<P> + ?Sized> Cliffor
 impl<'a, P: Precision, CB: Conditionable + RotationsBuilder<P> + ?Sized> RotationsBuilder<P>
     for Conditioned<'a, CB>
 {
-    fn rz(&mut self, r: Self::Register, theta: P) -> Self::Register {
-        self.parent.rz(r, theta)
-    }
-
-    fn rx(&mut self, r: Self::Register, theta: P) -> Self::Register {
-        self.parent.rx(r, theta)
-    }
-
-    fn ry(&mut self, r: Self::Register, theta: P) -> Self::Register {
-        self.parent.ry(r, theta)
-    }
-
-    fn rz_ratio(&mut self, r: Self::Register, theta: Rational64) -> CircuitResult<Self::Register> {
-        self.parent.rz_ratio(r, theta)
+    fn make_rz(&self, theta: P) -> Self::CircuitObject {
+        self.parent.make_rz(theta)
     }
 
-    fn rx_ratio(&mut self, r: Self::Register, theta: Rational64) -> CircuitResult<Self::Register> {
-        self.parent.rx_ratio(r, theta)
-    }
-
-    fn ry_ratio(&mut self, r: Self::Register, theta: Rational64) -> CircuitResult<Self::Register> {
-        self.parent.ry_ratio(r, theta)
-    }
-
-    fn rz_pi_by(&mut self, r: Self::Register, m: i64) -> CircuitResult<Self::Register> {
-        self.parent.rz_pi_by(r, m)
-    }
-
-    fn rx_pi_by(&mut self, r: Self::Register, m: i64) -> CircuitResult<Self::Register> {
-        self.parent.rx_pi_by(r, m)
-    }
-
-    fn ry_pi_by(&mut self, r: Self::Register, m: i64) -> CircuitResult<Self::Register> {
-        self.parent.ry_pi_by(r, m)
+    fn make_rz_ratio(&self, theta: Rational64) -> CircuitResult<Self::CircuitObject> {
+        self.parent.make_rz_ratio(theta)
     }
 }
 
@@ -281,3 +383,158 @@ where
 {
     type RecursiveSimilarBuilder = Self::SimilarBuilder;
 }
+
+#[cfg(test)]
+mod conditioning_tests {
+    use super::*;
+
+    #[test]
+    fn test_control_indices() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let cr = b.register(NonZeroUsize::new(2).unwrap());
+        let mut cb = b.condition_with(cr);
+        assert_eq!(cb.control_indices(), vec![2, 3]);
+        let r = cb.x(r);
+        assert_eq!(cb.control_indices(), vec![2, 3]);
+        let cr = cb.dissolve();
+        let _r = b.merge_two_registers(cr, r);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_controls() -> CircuitResult<()> {
+        for x in 0..8 {
+            let mut b = LocalBuilder::<f64>::default();
+            let q = b.qubit();
+            let controls = vec![b.qubit(), b.qubit(), b.qubit()];
+            let mut cb = b.with_controls(controls)?;
+            assert_eq!(cb.control_indices(), vec![1, 2, 3]);
+            let q = cb.x(q);
+            let controls = cb.dissolve_controls();
+            assert_eq!(
+                controls
+                    .iter()
+                    .map(|r| r.indices().to_vec())
+                    .collect::<Vec<_>>(),
+                vec![vec![1], vec![2], vec![3]]
+            );
+            // Start the controls in the bits of x.
+            let init = controls
+                .iter()
+                .enumerate()
+                .map(|(i, r)| (r, (x >> i) & 1))
+                .collect::<Vec<_>>();
+            let (_q, handle) = b.measure(q);
+            let result = b.calculate_state_with_init(init);
+            let expected = if x == 0b111 { 1 } else { 0 };
+            assert_eq!(result.get_measurement(handle).0, expected);
+        }
+
+        let mut b = LocalBuilder::<f64>::default();
+        assert!(b.with_controls(vec![]).is_err());
+
+        // Overlapping controls are found without taking the registers.
+        struct Indices(Vec<usize>);
+        impl QubitRegister for Indices {
+            fn n(&self) -> usize {
+                self.0.len()
+            }
+            fn indices(&self) -> &[usize] {
+                &self.0
+            }
+        }
+        let controls = [Indices(vec![0, 1]), Indices(vec![2]), Indices(vec![1])];
+        let err = check_controls(&controls).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::OverlappingIndices);
+        assert!(check_controls(&controls[..2]).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_anti_condition_with() -> CircuitResult<()> {
+        for control in [0, 1] {
+            let mut b = LocalBuilder::<f64>::default();
+            let c = b.qubit();
+            let q = b.qubit();
+            let (c, q) = b.anti_condition_with(c, |cb| Ok(cb.x(q)))?;
+            let (c, control_handle) = b.measure(c);
+            let (_q, handle) = b.measure(q);
+            let result = b.calculate_state_with_init([(&c, control)]);
+            assert_eq!(result.get_measurement(control_handle).0, control);
+            assert_eq!(result.get_measurement(handle).0, 1 - control);
+        }
+
+        // A failing `f` leaves the control register as it was.
+        let mut b = LocalBuilder::<f64>::default();
+        let c = b.qubit();
+        let q = b.qubit();
+        let _q = b.h(q);
+        let depth = b.pipeline_depth();
+        let before = b.calculate_state().into_state().unwrap();
+        let err = b
+            .anti_condition_with(c, |_| -> CircuitResult<()> {
+                Err(CircuitError::new("failed"))
+            })
+            .unwrap_err();
+        assert_eq!(err.msg, "failed");
+        let after = b.calculate_state().into_state().unwrap();
+        crate::test_utils::assert_states_equal(&after, &before, 1e-10);
+        assert_eq!(b.cancel_inverses(), 2);
+        assert_eq!(b.pipeline_depth(), depth);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditioned_rotations() -> CircuitResult<()> {
+        fn rotate<B: RotationsBuilder<f64>>(
+            b: &mut B,
+            method: usize,
+            r: B::Register,
+        ) -> CircuitResult<B::Register> {
+            let theta = Rational64::new(1, 3);
+            match method {
+                0 => Ok(b.rx(r, 0.4)),
+                1 => Ok(b.ry(r, 0.4)),
+                2 => Ok(b.rz(r, 0.4)),
+                3 => b.rx_ratio(r, theta),
+                4 => b.ry_ratio(r, theta),
+                5 => b.rz_ratio(r, theta),
+                6 => b.rx_pi_by(r, 3),
+                7 => b.ry_pi_by(r, 3),
+                8 => b.rz_pi_by(r, 3),
+                _ => Ok(r),
+            }
+        }
+        // Prepare and measure along generic axes so rotations about any axis change the outcome
+        // probabilities. Method 9 applies no rotation.
+        let probs = |method: usize, control: Option<usize>| -> CircuitResult<Vec<f64>> {
+            let mut b = LocalBuilder::<f64>::default();
+            let c = b.qubit();
+            let t = b.qubit();
+            let t = b.u3(t, 0.7, 0.2, 0.9);
+            let (c, t) = match control {
+                Some(_) => {
+                    let mut cb = b.condition_with(c);
+                    let t = rotate(&mut cb, method, t)?;
+                    (cb.dissolve(), t)
+                }
+                None => (c, rotate(&mut b, method, t)?),
+            };
+            let t = b.u3(t, 1.1, 0.5, 0.3);
+            let (_t, handle) = b.measure_stochastic(t);
+            let result = b.calculate_state_with_init([(&c, control.unwrap_or(0))]);
+            Ok(result.get_stochastic_measurement(handle).to_vec())
+        };
+        let unrotated = probs(9, None)?;
+        for method in 0..9 {
+            let rotated = probs(method, None)?;
+            assert!((rotated[0] - unrotated[0]).abs() > 0.01);
+            let not_controlled = probs(method, Some(0))?;
+            assert!((not_controlled[0] - unrotated[0]).abs() < 1e-10);
+            let controlled = probs(method, Some(1))?;
+            assert!((controlled[0] - rotated[0]).abs() < 1e-10);
+        }
+        Ok(())
+    }
+}
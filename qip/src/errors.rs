@@ -1,19 +1,88 @@
+use std::error::Error;
+use std::fmt;
+
+/// The kind of a circuit error, so callers can tell failures apart without parsing messages.
+/// ```
+/// use qip::prelude::*;
+/// use qip::qasm::run_qasm;
+///
+/// let err = run_qasm::<f64>("qreg q[2]; h q[2];").unwrap_err();
+/// assert_eq!(err.kind, CircuitErrorKind::Parse);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CircuitErrorKind {
+    /// An op or register was given no indices.
+    EmptyIndices,
+    /// An index or basis state is too large for the qubits it refers to.
+    IndexOutOfRange,
+    /// The same index was given more than once.
+    RepeatedIndices,
+    /// Registers or indices which must be disjoint overlap.
+    OverlappingIndices,
+    /// A matrix does not have the number of entries its qubits require.
+    MatrixSizeMismatch,
+    /// A state does not have the number of entries its qubits require.
+    StateSizeMismatch,
+    /// Input such as QASM could not be parsed.
+    Parse,
+    /// The circuit uses a feature which is not supported by the operation.
+    Unsupported,
+    /// Ancilla qubits were not returned to |0> when they were released.
+    DirtyAncilla,
+    /// Any other error, see the message for details.
+    Other,
+}
+
 /// An error from building the circuit.
 #[derive(Debug)]
 pub struct CircuitError {
+    /// The kind of error.
+    pub kind: CircuitErrorKind,
     /// The error message.
     pub msg: String,
 }
 
 impl CircuitError {
-    /// Construct a new error.
+    /// Construct a new error of kind `Other`.
     pub fn new<S>(msg: S) -> Self
         where
             S: Into<String>,
     {
-        Self { msg: msg.into() }
+        Self::with_kind(CircuitErrorKind::Other, msg)
+    }
+
+    /// Construct a new error of the given `kind`.
+    pub fn with_kind<S>(kind: CircuitErrorKind, msg: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            kind,
+            msg: msg.into(),
+        }
     }
 }
 
+impl fmt::Display for CircuitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+/// Circuit errors can be boxed and propagated alongside other errors with `?`.
+/// ```
+/// use qip::prelude::*;
+///
+/// fn build() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut b = LocalBuilder::<f64>::default();
+///     let q = b.qubit();
+///     b.rz_pi_by(q, 0)?;
+///     Ok(())
+/// }
+/// assert!(build().is_err());
+/// ```
+impl Error for CircuitError {}
+
 /// A result which may contain a circuit error.
 pub type CircuitResult<T> = Result<T, CircuitError>;
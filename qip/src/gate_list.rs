@@ -0,0 +1,181 @@
+use crate::builder_traits::{CliffordTBuilder, QubitRegister, SplitManyResult, SplitResult};
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
+use crate::types::Precision;
+use num_complex::Complex;
+
+/// The gates which can appear in a `GateList`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GateName {
+    /// Pauli X on each index.
+    X,
+    /// Pauli Y on each index.
+    Y,
+    /// Pauli Z on each index.
+    Z,
+    /// Hadamard on each index.
+    H,
+    /// S on each index.
+    S,
+    /// T on each index.
+    T,
+    /// CNOT controlled by the first index onto each of the rest.
+    CNOT,
+    /// Swaps the first half of the indices with the second half.
+    SWAP,
+    /// An arbitrary matrix on all the indices, supplied alongside the gate.
+    Mat,
+}
+
+/// An ordered list of gates, each given by its name, the absolute qubit indices it acts on, and
+/// a matrix for `GateName::Mat`.
+pub type GateList<P> = Vec<(GateName, Vec<usize>, Option<Vec<Complex<P>>>)>;
+
+/// Build the circuit described by `gates` on a fresh set of qubits, one per index used. Returns a
+/// single qubit register for each index, in order of index.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gate_list::{build_from_gate_list, GateList, GateName};
+///
+/// # fn main() -> CircuitResult<()> {
+/// let gates: GateList<f64> = vec![
+///     (GateName::H, vec![0], None),
+///     (GateName::CNOT, vec![0, 1], None),
+/// ];
+/// let mut b = LocalBuilder::<f64>::default();
+/// let qs = build_from_gate_list(&mut b, &gates)?;
+/// assert_eq!(qs.len(), 2);
+/// # Ok(())
+/// # }
+/// ```
+pub fn build_from_gate_list<P, CB>(
+    b: &mut CB,
+    gates: &GateList<P>,
+) -> CircuitResult<Vec<CB::Register>>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P>,
+{
+    let n = gates
+        .iter()
+        .flat_map(|(_, indices, _)| indices)
+        .max()
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let mut qs = (0..n).map(|_| Some(b.qubit())).collect::<Vec<_>>();
+    gates.iter().try_for_each(|(name, indices, data)| {
+        if indices.is_empty() {
+            let message = format!("Gate {:?} must act on at least one index", name);
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::EmptyIndices,
+                message,
+            ));
+        }
+        if data.is_some() != (*name == GateName::Mat) {
+            let message = format!("Only a Mat gate takes a matrix, found {:?}", name);
+            return Err(CircuitError::new(message));
+        }
+        let rs = indices
+            .iter()
+            .map(|i| {
+                qs[*i].take().ok_or_else(|| {
+                    CircuitError::with_kind(
+                        CircuitErrorKind::RepeatedIndices,
+                        format!("Index {:?} is repeated in gate {:?}", i, name),
+                    )
+                })
+            })
+            .collect::<CircuitResult<Vec<_>>>()?;
+        let r = b.merge_registers(rs).unwrap();
+        let r = apply_gate(b, *name, r, data.as_ref())?;
+        let rs = match b.split_relative_index_groups(r, (0..indices.len()).map(|i| [i])) {
+            SplitManyResult::AllSelected(rs) => rs,
+            SplitManyResult::Remaining(_, _) => unreachable!(),
+        };
+        indices.iter().zip(rs).for_each(|(i, r)| qs[*i] = Some(r));
+        Ok(())
+    })?;
+    Ok(qs.into_iter().map(Option::unwrap).collect())
+}
+
+fn apply_gate<P, CB>(
+    b: &mut CB,
+    name: GateName,
+    r: CB::Register,
+    data: Option<&Vec<Complex<P>>>,
+) -> CircuitResult<CB::Register>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P>,
+{
+    let r = match name {
+        GateName::X => b.x(r),
+        GateName::Y => b.y(r),
+        GateName::Z => b.z(r),
+        GateName::H => b.h(r),
+        GateName::S => b.s(r),
+        GateName::T => b.t(r),
+        GateName::CNOT => match b.split_first_qubit(r) {
+            (Some(c), t) => {
+                let (c, t) = b.cnot(c, t)?;
+                b.merge_two_registers(c, t)
+            }
+            (None, _) => {
+                return Err(CircuitError::new(
+                    "CNOT must act on a control and at least one target",
+                ))
+            }
+        },
+        GateName::SWAP => {
+            let half = r.n() / 2;
+            match b.split_register_relative(r, 0..half) {
+                SplitResult::SPLIT(ra, rb) => {
+                    let (ra, rb) = b.swap(ra, rb)?;
+                    b.merge_two_registers(ra, rb)
+                }
+                _ => {
+                    return Err(CircuitError::new(
+                        "Swap must act on an even number of indices",
+                    ))
+                }
+            }
+        }
+        GateName::Mat => b.apply_vec_matrix(r, data.unwrap().clone())?,
+    };
+    Ok(r)
+}
+
+#[cfg(test)]
+mod gate_list_tests {
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn test_bell_from_gate_list() -> CircuitResult<()> {
+        let gates: GateList<f64> = vec![
+            (GateName::H, vec![0], None),
+            (GateName::CNOT, vec![0, 1], None),
+        ];
+        let mut a = LocalBuilder::<f64>::default();
+        let qs = build_from_gate_list(&mut a, &gates)?;
+        let _r = a.merge_registers(qs);
+
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit();
+        let r = b.qubit();
+        let q = b.h(q);
+        let (q, r) = b.cnot(q, r)?;
+        let _r = b.merge_two_registers(q, r);
+
+        let sa = a.calculate_state().into_state().unwrap();
+        let sb = b.calculate_state().into_state().unwrap();
+        sa.iter()
+            .zip(sb.iter())
+            .for_each(|(x, y)| assert!((x - y).norm() < 1e-10));
+
+        let repeated: GateList<f64> = vec![(GateName::CNOT, vec![0, 0], None)];
+        assert!(build_from_gate_list(&mut a, &repeated).is_err());
+        Ok(())
+    }
+}
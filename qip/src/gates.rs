@@ -0,0 +1,968 @@
+use crate::builder_traits::{
+    AdvancedCircuitBuilder, CircuitBuilder, CliffordTBuilder, MeasurementBuilder, QubitRegister,
+    RotationsBuilder, SplitManyResult, UnitaryBuilder,
+};
+use crate::conditioning::Conditionable;
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
+use crate::types::Precision;
+use num_complex::Complex;
+use num_traits::{One, Zero};
+
+type Three<R> = (R, R, R);
+type Controlled<R> = (Vec<R>, R, Option<R>);
+type Measured<R, H> = (Vec<R>, R, H);
+
+fn check_single_qubits<R: QubitRegister>(rs: &[&R]) -> CircuitResult<()> {
+    if rs.iter().all(|r| r.n() == 1) {
+        Ok(())
+    } else {
+        Err(CircuitError::new("Expected single qubit registers"))
+    }
+}
+
+fn split_three<CB: CircuitBuilder>(b: &mut CB, r: CB::Register) -> Three<CB::Register> {
+    match b.split_relative_index_groups(r, [[0], [1], [2]]) {
+        SplitManyResult::AllSelected(mut rs) => {
+            let c = rs.pop().unwrap();
+            let b = rs.pop().unwrap();
+            let a = rs.pop().unwrap();
+            (a, b, c)
+        }
+        SplitManyResult::Remaining(_, _) => unreachable!(),
+    }
+}
+
+/// Applies a Z to `target` if both `c1` and `c2` are `|1>`. The gate only adds a phase of -1 to
+/// the `|111>` component, so it is symmetric in all three qubits: which are controls and which is
+/// the target makes no difference.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gates::ccz;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (c1, c2, t) = (b.qubit(), b.qubit(), b.qubit());
+/// let (c1, c2, t) = ccz(&mut b, c1, c2, t)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn ccz<P, CB>(
+    b: &mut CB,
+    c1: CB::Register,
+    c2: CB::Register,
+    target: CB::Register,
+) -> CircuitResult<Three<CB::Register>>
+where
+    P: Precision,
+    CB: AdvancedCircuitBuilder<P>,
+{
+    check_single_qubits(&[&c1, &c2, &target])?;
+    let target = b.h(target);
+    let cr = b.merge_two_registers(c1, c2);
+    let (cr, target) = b.toffoli(cr, target)?;
+    let target = b.h(target);
+    let (c1, c2) = b.split_last_qubit(cr);
+    Ok((c1, c2.unwrap(), target))
+}
+
+/// Applies a phase of `e^{i theta}` to `target` if both `c1` and `c2` are `|1>`. Like `ccz` this
+/// only changes the `|111>` component and so is symmetric in all three qubits.
+pub fn ccp<P, CB>(
+    b: &mut CB,
+    c1: CB::Register,
+    c2: CB::Register,
+    target: CB::Register,
+    theta: P,
+) -> CircuitResult<Three<CB::Register>>
+where
+    P: Precision,
+    CB: UnitaryBuilder<P>,
+{
+    check_single_qubits(&[&c1, &c2, &target])?;
+    let r = b.merge_registers([c1, c2, target]).unwrap();
+    let data = (0..64)
+        .map(|i| match (i / 8, i % 8) {
+            (7, 7) => Complex::from_polar(P::one(), theta),
+            (row, col) if row == col => Complex::one(),
+            _ => Complex::zero(),
+        })
+        .collect();
+    let r = b.apply_vec_matrix(r, data)?;
+    Ok(split_three(b, r))
+}
+
+/// Apply a y rotation by `theta` to the single qubit `r`.
+fn ry<P, CB>(b: &mut CB, r: CB::Register, theta: P) -> CircuitResult<CB::Register>
+where
+    P: Precision,
+    CB: UnitaryBuilder<P>,
+{
+    let (s, c) = (theta / P::from(2).unwrap()).sin_cos();
+    b.apply_matrix(
+        r,
+        [
+            Complex::from(c),
+            Complex::from(-s),
+            Complex::from(s),
+            Complex::from(c),
+        ],
+    )
+}
+
+/// Applies the Margolus gate: a toffoli from `c1` and `c2` onto `target` up to a relative phase of
+/// `-1` on `|c1 c2 target> = |101>`. It uses three CNOTs rather than the six of a full toffoli.
+///
+/// Because of the relative phase this is only a substitute for a toffoli when it is used in a
+/// matched pair with `margolus_inverse`, bracketing a subcircuit which only uses `target` (and the
+/// controls) as classical controls. The phases then cancel exactly.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gates::{margolus, margolus_inverse};
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (c1, c2, t, out) = (b.qubit(), b.qubit(), b.qubit(), b.qubit());
+/// let (c1, c2, t) = margolus(&mut b, c1, c2, t)?;
+/// let (t, out) = b.cnot(t, out)?;
+/// let (c1, c2, t) = margolus_inverse(&mut b, c1, c2, t)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn margolus<P, CB>(
+    b: &mut CB,
+    c1: CB::Register,
+    c2: CB::Register,
+    target: CB::Register,
+) -> CircuitResult<Three<CB::Register>>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P>,
+{
+    check_single_qubits(&[&c1, &c2, &target])?;
+    let angle = P::from(std::f64::consts::FRAC_PI_4).unwrap();
+    let target = ry(b, target, angle)?;
+    let (c2, target) = b.cnot(c2, target)?;
+    let target = ry(b, target, angle)?;
+    let (c1, target) = b.cnot(c1, target)?;
+    let target = ry(b, target, -angle)?;
+    let (c2, target) = b.cnot(c2, target)?;
+    let target = ry(b, target, -angle)?;
+    Ok((c1, c2, target))
+}
+
+/// Undoes `margolus`, see its documentation for how the pair must be used. The Margolus gate is
+/// real and self-inverse so this applies the same sequence of gates.
+pub fn margolus_inverse<P, CB>(
+    b: &mut CB,
+    c1: CB::Register,
+    c2: CB::Register,
+    target: CB::Register,
+) -> CircuitResult<Three<CB::Register>>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P>,
+{
+    margolus(b, c1, c2, target)
+}
+
+/// Rotates each qubit of `target` around x by `theta` if `control` is `|1>`.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gates::crx;
+///
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (c, t) = (b.qubit(), b.qubit());
+/// let (c, t) = crx(&mut b, c, t, std::f64::consts::FRAC_PI_2);
+/// ```
+pub fn crx<P, CB>(
+    b: &mut CB,
+    control: CB::Register,
+    target: CB::Register,
+    theta: P,
+) -> (CB::Register, CB::Register)
+where
+    P: Precision,
+    CB: RotationsBuilder<P> + Conditionable,
+{
+    let mut cb = b.condition_with(control);
+    let target = cb.rx(target, theta);
+    (cb.dissolve(), target)
+}
+
+/// Rotates each qubit of `target` around y by `theta` if `control` is `|1>`.
+pub fn cry<P, CB>(
+    b: &mut CB,
+    control: CB::Register,
+    target: CB::Register,
+    theta: P,
+) -> (CB::Register, CB::Register)
+where
+    P: Precision,
+    CB: RotationsBuilder<P> + Conditionable,
+{
+    let mut cb = b.condition_with(control);
+    let target = cb.ry(target, theta);
+    (cb.dissolve(), target)
+}
+
+/// Rotates each qubit of `target` around z by `theta` if `control` is `|1>`.
+pub fn crz<P, CB>(
+    b: &mut CB,
+    control: CB::Register,
+    target: CB::Register,
+    theta: P,
+) -> (CB::Register, CB::Register)
+where
+    P: Precision,
+    CB: RotationsBuilder<P> + Conditionable,
+{
+    let mut cb = b.condition_with(control);
+    let target = cb.rz(target, theta);
+    (cb.dissolve(), target)
+}
+
+/// Applies the matrix `data` to `target` if all qubits in `control` are `|1>`, returning both
+/// registers. `data` is ordered as for `UnitaryBuilder::apply_vec_matrix` and its size is checked
+/// against `target` before any ops are added.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gates::controlled_mat;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (c, t) = (b.qubit(), b.qubit());
+/// let (o, l) = (Complex::new(0.0, 0.0), Complex::new(1.0, 0.0));
+/// let (c, t) = controlled_mat(&mut b, c, t, vec![o, l, l, o])?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn controlled_mat<P, CB>(
+    b: &mut CB,
+    control: CB::Register,
+    target: CB::Register,
+    data: Vec<Complex<P>>,
+) -> CircuitResult<(CB::Register, CB::Register)>
+where
+    P: Precision,
+    CB: UnitaryBuilder<P> + Conditionable,
+{
+    let expected = 1 << (2 * target.n());
+    if data.len() != expected {
+        let message = format!(
+            "Matrix has {:?} entries versus expected {:?} for {:?} target qubits",
+            data.len(),
+            expected,
+            target.n()
+        );
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::MatrixSizeMismatch,
+            message,
+        ));
+    }
+    let mut cb = b.condition_with(control);
+    let target = cb.apply_vec_matrix(target, data)?;
+    Ok((cb.dissolve(), target))
+}
+
+/// Applies a Z to `target` if `control` is `|1>`. This only adds a phase of -1 to the `|11>`
+/// component, so swapping `control` and `target` gives the same gate.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gates::cz;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (c, t) = (b.qubit(), b.qubit());
+/// let (c, t) = cz(&mut b, c, t)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn cz<P, CB>(
+    b: &mut CB,
+    control: CB::Register,
+    target: CB::Register,
+) -> CircuitResult<(CB::Register, CB::Register)>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P>,
+{
+    check_single_qubits(&[&control, &target])?;
+    let target = b.h(target);
+    let (control, target) = b.cnot(control, target)?;
+    let target = b.h(target);
+    Ok((control, target))
+}
+
+/// Applies a phase of `e^{i theta}` to `target` if `control` is `|1>`. Like `cz` this only
+/// changes the `|11>` component and so is symmetric in the two qubits.
+pub fn cphase<P, CB>(
+    b: &mut CB,
+    control: CB::Register,
+    target: CB::Register,
+    theta: P,
+) -> CircuitResult<(CB::Register, CB::Register)>
+where
+    P: Precision,
+    CB: UnitaryBuilder<P>,
+{
+    check_single_qubits(&[&control, &target])?;
+    let r = b.merge_two_registers(control, target);
+    let data = (0..16)
+        .map(|i| match (i / 4, i % 4) {
+            (3, 3) => Complex::from_polar(P::one(), theta),
+            (row, col) if row == col => Complex::one(),
+            _ => Complex::zero(),
+        })
+        .collect();
+    let r = b.apply_vec_matrix(r, data)?;
+    let (control, target) = b.split_last_qubit(r);
+    Ok((control, target.unwrap()))
+}
+
+/// Swaps `ra` and `rb` if all qubits in `control` are `|1>`, also known as the Fredkin gate.
+/// `ra` and `rb` may span several qubits but must have the same size.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gates::cswap;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (c, ra, rb) = (b.qubit(), b.qubit(), b.qubit());
+/// let (c, ra, rb) = cswap(&mut b, c, ra, rb)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn cswap<P, CB>(
+    b: &mut CB,
+    control: CB::Register,
+    ra: CB::Register,
+    rb: CB::Register,
+) -> CircuitResult<Three<CB::Register>>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P> + Conditionable,
+{
+    if ra.n() != rb.n() {
+        let message = format!(
+            "Controlled swap must be between registers of the same size, found {:?} and {:?}",
+            ra.n(),
+            rb.n()
+        );
+        return Err(CircuitError::new(message));
+    }
+    let mut cb = b.condition_with(control);
+    let (ra, rb) = cb.swap(ra, rb)?;
+    Ok((cb.dissolve(), ra, rb))
+}
+
+/// The angle `2 pi / 2^k` of the `k`th rotation in the fourier transform.
+fn qft_angle<P: Precision>(k: usize) -> P {
+    P::from(std::f64::consts::TAU).unwrap() / P::from(1u64 << k).unwrap()
+}
+
+/// Swap qubit `i` with qubit `n - 1 - i` and merge them back into a single register.
+fn reverse_qubits<P, CB>(
+    b: &mut CB,
+    mut qs: Vec<Option<CB::Register>>,
+) -> CircuitResult<CB::Register>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P>,
+{
+    let n = qs.len();
+    for i in 0..n / 2 {
+        let qa = qs[i].take().unwrap();
+        let qb = qs[n - 1 - i].take().unwrap();
+        let (qa, qb) = b.swap(qa, qb)?;
+        qs[i] = Some(qa);
+        qs[n - 1 - i] = Some(qb);
+    }
+    b.merge_registers(qs.into_iter().map(Option::unwrap))
+        .ok_or_else(|| CircuitError::new("No registers found"))
+}
+
+/// Applies the quantum fourier transform to `r`, taking `|x>` to
+/// `sum_y e^{2 pi i x y / 2^n} |y> / sqrt(2^n)` where `x` and `y` are the classical values of the
+/// register. This is the ladder of hadamards and controlled phase rotations followed by the swaps
+/// which reverse the qubit order.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gates::{inverse_qft, qft};
+/// use std::num::NonZeroUsize;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let r = b.register(NonZeroUsize::new(3).unwrap());
+/// let r = qft(&mut b, r)?;
+/// let r = inverse_qft(&mut b, r)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn qft<P, CB>(b: &mut CB, r: CB::Register) -> CircuitResult<CB::Register>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P>,
+{
+    let mut qs = b
+        .split_all_register(r)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+    for i in (0..qs.len()).rev() {
+        let mut qi = b.h(qs[i].take().unwrap());
+        for m in (0..i).rev() {
+            let qm = qs[m].take().unwrap();
+            let (qm, q) = cphase(b, qm, qi, qft_angle::<P>(i - m + 1))?;
+            qs[m] = Some(qm);
+            qi = q;
+        }
+        qs[i] = Some(qi);
+    }
+    reverse_qubits(b, qs)
+}
+
+/// Applies the inverse of `qft` to `r`, the same circuit in reverse with negated phases.
+pub fn inverse_qft<P, CB>(b: &mut CB, r: CB::Register) -> CircuitResult<CB::Register>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P>,
+{
+    let qs = b.split_all_register(r).into_iter().map(Some).collect();
+    let r = reverse_qubits(b, qs)?;
+    let mut qs = b
+        .split_all_register(r)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+    for i in 0..qs.len() {
+        let mut qi = qs[i].take().unwrap();
+        for (m, qm_ref) in qs.iter_mut().enumerate().take(i) {
+            let qm = qm_ref.take().unwrap();
+            let (qm, q) = cphase(b, qm, qi, -qft_angle::<P>(i - m + 1))?;
+            *qm_ref = Some(qm);
+            qi = q;
+        }
+        qs[i] = Some(b.h(qi));
+    }
+    b.merge_registers(qs.into_iter().map(Option::unwrap))
+        .ok_or_else(|| CircuitError::new("No registers found"))
+}
+
+/// Measures the parity of the qubits in `qs` onto `ancilla`, which should start in `|0>`. Each
+/// data qubit is CNOTed onto the ancilla which is then measured, so the handle gives `1` for odd
+/// parity and `0` for even. The data qubits are only entangled with the ancilla, this is the core
+/// of stabilizer syndrome extraction.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::gates::measure_parity;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let qs = vec![b.qubit(), b.qubit()];
+/// let ancilla = b.qubit();
+/// let (qs, ancilla, handle) = measure_parity(&mut b, qs, ancilla)?;
+/// let r = b.merge_registers(qs).unwrap();
+/// let result = b.calculate_state_with_init([(&r, 0b01)]);
+/// assert_eq!(result.get_measurement(handle).0, 1);
+/// # Ok(())
+/// # }
+/// ```
+pub fn measure_parity<P, CB>(
+    b: &mut CB,
+    qs: Vec<CB::Register>,
+    ancilla: CB::Register,
+) -> CircuitResult<Measured<CB::Register, CB::MeasurementHandle>>
+where
+    P: Precision,
+    CB: CliffordTBuilder<P> + MeasurementBuilder,
+{
+    check_single_qubits(&[&ancilla])?;
+    let (qs, ancilla) = qs
+        .into_iter()
+        .try_fold((vec![], ancilla), |(mut qs, ancilla), q| {
+            let (q, ancilla) = b.cnot(q, ancilla)?;
+            qs.push(q);
+            Ok((qs, ancilla))
+        })?;
+    let (ancilla, handle) = b.measure(ancilla);
+    Ok((qs, ancilla, handle))
+}
+
+/// Applies a toffoli from `x` and `y` onto `t`.
+fn toffoli_pair<P, CB>(
+    b: &mut CB,
+    x: CB::Register,
+    y: CB::Register,
+    t: CB::Register,
+) -> CircuitResult<Three<CB::Register>>
+where
+    P: Precision,
+    CB: AdvancedCircuitBuilder<P>,
+{
+    let cr = b.merge_two_registers(x, y);
+    let (cr, t) = b.toffoli(cr, t)?;
+    let (x, y) = b.split_last_qubit(cr);
+    Ok((x, y.unwrap(), t))
+}
+
+/// Applies a NOT to `target` if all qubits in `controls` are `|1>`.
+/// When `ancillas` is provided it must contain at least `controls.len() - 2` qubits in `|0>`, which
+/// are used for a chain of toffolis and returned to `|0>`. Otherwise this falls back on `toffoli`,
+/// which borrows zeroed temporary qubits from the builder as needed.
+pub fn mcx<P, CB>(
+    b: &mut CB,
+    controls: Vec<CB::Register>,
+    target: CB::Register,
+    ancillas: Option<CB::Register>,
+) -> CircuitResult<Controlled<CB::Register>>
+where
+    P: Precision,
+    CB: AdvancedCircuitBuilder<P>,
+{
+    check_single_qubits(&[&target])?;
+    let k = controls.iter().map(|r| r.n()).sum::<usize>();
+    if k == 0 {
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Must supply at least one control qubit",
+        ));
+    }
+    let ancillas = match ancillas {
+        Some(ancillas) if k > 2 => ancillas,
+        ancillas => {
+            // Too few controls to need ancillas, or none provided.
+            let ns = controls.iter().map(|r| r.n()).collect::<Vec<_>>();
+            let cr = b.merge_registers(controls).unwrap();
+            let (cr, target) = b.toffoli(cr, target)?;
+            let controls = split_sizes(b, cr, &ns);
+            return Ok((controls, target, ancillas));
+        }
+    };
+    if ancillas.n() < k - 2 {
+        let message = format!(
+            "{:?} controls need at least {:?} ancillas, found {:?}",
+            k,
+            k - 2,
+            ancillas.n()
+        );
+        return Err(CircuitError::new(message));
+    }
+
+    let ns = controls.iter().map(|r| r.n()).collect::<Vec<_>>();
+    let cr = b.merge_registers(controls).unwrap();
+    let mut cs = b
+        .split_all_register(cr)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+    let an = ancillas.n();
+    let mut ans = b
+        .split_all_register(ancillas)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+
+    // Compute the running AND of the controls into the ancillas: a_0 = c_0 c_1, a_i = a_{i-1} c_{i+1}
+    (0..k - 2).try_for_each(|i| and_chain_step(b, &mut cs, &mut ans, i))?;
+    let (x, y, target) = toffoli_pair(
+        b,
+        ans[k - 3].take().unwrap(),
+        cs[k - 1].take().unwrap(),
+        target,
+    )?;
+    ans[k - 3] = Some(x);
+    cs[k - 1] = Some(y);
+    (0..k - 2)
+        .rev()
+        .try_for_each(|i| and_chain_step(b, &mut cs, &mut ans, i))?;
+
+    let cr = b
+        .merge_registers(cs.into_iter().map(Option::unwrap))
+        .unwrap();
+    let controls = split_sizes(b, cr, &ns);
+    let ancillas = b.merge_registers(ans.into_iter().map(Option::unwrap));
+    debug_assert_eq!(ancillas.as_ref().map(|r| r.n()), Some(an));
+    Ok((controls, target, ancillas))
+}
+
+/// Toggles `ans[i]` by the AND of the previous link in the chain and the next control.
+fn and_chain_step<P, CB>(
+    b: &mut CB,
+    cs: &mut [Option<CB::Register>],
+    ans: &mut [Option<CB::Register>],
+    i: usize,
+) -> CircuitResult<()>
+where
+    P: Precision,
+    CB: AdvancedCircuitBuilder<P>,
+{
+    let x = if i == 0 { &mut cs[0] } else { &mut ans[i - 1] };
+    let x = x.take().unwrap();
+    let y = cs[i + 1].take().unwrap();
+    let (x, y, t) = toffoli_pair(b, x, y, ans[i].take().unwrap())?;
+    if i == 0 {
+        cs[0] = Some(x);
+    } else {
+        ans[i - 1] = Some(x);
+    }
+    cs[i + 1] = Some(y);
+    ans[i] = Some(t);
+    Ok(())
+}
+
+/// Split `r` into consecutive registers with sizes `ns`.
+fn split_sizes<CB: CircuitBuilder>(b: &mut CB, r: CB::Register, ns: &[usize]) -> Vec<CB::Register> {
+    let (_, ranges) = ns.iter().fold((0, vec![]), |(n, mut acc), rn| {
+        acc.push(n..n + rn);
+        (n + rn, acc)
+    });
+    match b.split_relative_index_groups(r, ranges) {
+        SplitManyResult::AllSelected(rs) => rs,
+        SplitManyResult::Remaining(_, _) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod gates_tests {
+    use super::*;
+    use crate::builder::Qudit;
+    use crate::prelude::*;
+    use crate::test_utils::assert_states_equal;
+    use crate::utils::parity;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_ccz_phase() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let (c1, c2, t) = (b.qubit(), b.qubit(), b.qubit());
+        let (c1, c2, t) = (b.h(c1), b.h(c2), b.h(t));
+        let (c1, c2, t) = ccz(&mut b, c1, c2, t)?;
+        let _r = b.merge_registers([c1, c2, t]);
+        let state = b.calculate_state().into_state().unwrap();
+
+        let amp = 1.0 / 8.0f64.sqrt();
+        state.iter().enumerate().for_each(|(i, c)| {
+            let expected = if i == 7 { -amp } else { amp };
+            assert!((c - Complex::new(expected, 0.0)).norm() < 1e-10);
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_ccp_phase() -> CircuitResult<()> {
+        let theta = 0.3f64;
+        let mut b = LocalBuilder::<f64>::default();
+        let (c1, c2, t) = (b.qubit(), b.qubit(), b.qubit());
+        let (c1, c2, t) = (b.h(c1), b.h(c2), b.h(t));
+        let (c1, c2, t) = ccp(&mut b, c1, c2, t, theta)?;
+        let _r = b.merge_registers([c1, c2, t]);
+        let state = b.calculate_state().into_state().unwrap();
+
+        let amp = 1.0 / 8.0f64.sqrt();
+        state.iter().enumerate().for_each(|(i, c)| {
+            let expected = if i == 7 {
+                Complex::from_polar(amp, theta)
+            } else {
+                Complex::new(amp, 0.0)
+            };
+            assert!((c - expected).norm() < 1e-10);
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_cswap_swap_test() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let ancilla = b.qubit();
+        let prepare = |b: &mut LocalBuilder<f64>| {
+            let r = b.register(NonZeroUsize::new(2).unwrap());
+            let r = b.h(r);
+            let r = b.t(r);
+            let (r0, r1) = b.split_first_qubit(r);
+            let r1 = b.rz(r1, 0.7);
+            b.merge_registers(r0.into_iter().chain([r1])).unwrap()
+        };
+        let (ra, rb) = (prepare(&mut b), prepare(&mut b));
+        let ancilla = b.h(ancilla);
+        let (ancilla, ra, rb) = cswap(&mut b, ancilla, ra, rb)?;
+        let ancilla = b.h(ancilla);
+        let (_ancilla, handle) = b.measure_stochastic(ancilla);
+        let _r = b.merge_two_registers(ra, rb);
+        let result = b.calculate_state();
+        let probs = result.get_stochastic_measurement(handle);
+        assert!((probs[0] - 1.0).abs() < 1e-10);
+
+        // Orthogonal states only agree half the time.
+        let mut b = LocalBuilder::<f64>::default();
+        let (ancilla, ra, rb) = (b.qubit(), b.qubit(), b.qubit());
+        let rb = b.x(rb);
+        let ancilla = b.h(ancilla);
+        let (ancilla, _ra, _rb) = cswap(&mut b, ancilla, ra, rb)?;
+        let ancilla = b.h(ancilla);
+        let (_ancilla, handle) = b.measure_stochastic(ancilla);
+        let result = b.calculate_state();
+        let probs = result.get_stochastic_measurement(handle);
+        assert!((probs[0] - 0.5).abs() < 1e-10);
+
+        let (c, ra) = (b.qubit(), b.qubit());
+        let rb = b.register(NonZeroUsize::new(2).unwrap());
+        assert!(cswap(&mut b, c, ra, rb).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_controlled_mat_hadamard() -> CircuitResult<()> {
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        let mut b = LocalBuilder::<f64>::default();
+        let (c, t) = (b.qubit(), b.qubit());
+        let data = [h, h, h, -h].map(|x| Complex::new(x, 0.0)).to_vec();
+        let (c, t) = controlled_mat(&mut b, c, t, data)?;
+        let r = b.merge_two_registers(c, t);
+        let matrix = make_circuit_matrix(&mut b, &r, |result| result.into_state().unwrap());
+        // Columns are indexed by the little-endian register value, rows by the big-endian state.
+        let expected = [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, h, 0.0, h],
+            [0.0, h, 0.0, -h],
+        ];
+        matrix.iter().enumerate().for_each(|(col, column)| {
+            column.iter().enumerate().for_each(|(row, c)| {
+                assert!((c - Complex::new(expected[row][col], 0.0)).norm() < 1e-10);
+            })
+        });
+
+        let (c, t) = (b.qubit(), b.qubit());
+        let data = vec![Complex::one(); 16];
+        assert!(controlled_mat(&mut b, c, t, data).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cz_symmetric() -> CircuitResult<()> {
+        let matrix = |swapped: bool| -> CircuitResult<Vec<Vec<Complex<f64>>>> {
+            let mut b = LocalBuilder::<f64>::default();
+            let (q0, q1) = (b.qubit(), b.qubit());
+            let (q0, q1) = if swapped {
+                let (q1, q0) = cz(&mut b, q1, q0)?;
+                (q0, q1)
+            } else {
+                cz(&mut b, q0, q1)?
+            };
+            let r = b.merge_two_registers(q0, q1);
+            Ok(make_circuit_matrix(&mut b, &r, |result| {
+                result.into_state().unwrap()
+            }))
+        };
+        let (forward, backward) = (matrix(false)?, matrix(true)?);
+        assert_eq!(forward, backward);
+        // Columns are indexed by the little-endian register value, rows by the big-endian state.
+        forward.iter().enumerate().for_each(|(col, column)| {
+            let diagonal = ((col & 1) << 1) | (col >> 1);
+            column.iter().enumerate().for_each(|(row, c)| {
+                let expected = match (row, col) {
+                    (3, 3) => -1.0,
+                    (row, _) if row == diagonal => 1.0,
+                    _ => 0.0,
+                };
+                assert!((c - Complex::new(expected, 0.0)).norm() < 1e-10);
+            })
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_cphase() -> CircuitResult<()> {
+        let theta = 0.3f64;
+        let mut b = LocalBuilder::<f64>::default();
+        let (c, t) = (b.qubit(), b.qubit());
+        let (c, t) = (b.h(c), b.h(t));
+        let (c, t) = cphase(&mut b, c, t, theta)?;
+        let _r = b.merge_two_registers(c, t);
+        let state = b.calculate_state().into_state().unwrap();
+        state.iter().enumerate().for_each(|(i, c)| {
+            let expected = if i == 3 {
+                Complex::from_polar(0.5, theta)
+            } else {
+                Complex::new(0.5, 0.0)
+            };
+            assert!((c - expected).norm() < 1e-10);
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_mcx() -> CircuitResult<()> {
+        for use_ancillas in [false, true] {
+            for x in 0..16 {
+                let mut b = LocalBuilder::<f64>::default();
+                let controls = (0..4).map(|_| b.qubit()).collect::<Vec<_>>();
+                let t = b.qubit();
+                let ancillas = if use_ancillas { b.qudit(2) } else { None };
+                let (controls, t, ancillas) = mcx(&mut b, controls, t, ancillas)?;
+                let cr = b.merge_registers(controls).unwrap();
+                let (t, t_handle) = b.measure_stochastic(t);
+                let ancilla_handle = ancillas.map(|a| b.measure_stochastic(a).1);
+
+                let result = b.calculate_state_with_init([(&cr, x)]);
+                let expected = if x == 0b1111 { 1 } else { 0 };
+                let probs = result.get_stochastic_measurement(t_handle);
+                assert!((probs[expected] - 1.0).abs() < 1e-10);
+                if let Some(handle) = ancilla_handle {
+                    let probs = result.get_stochastic_measurement(handle);
+                    assert!((probs[0] - 1.0).abs() < 1e-10);
+                }
+                let _r = b.merge_two_registers(cr, t);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_margolus_pair() -> CircuitResult<()> {
+        for x in 0..8 {
+            let run = |use_margolus: bool| -> CircuitResult<Vec<Complex<f64>>> {
+                let mut b = LocalBuilder::<f64>::default();
+                let (c1, c2, t, out) = (b.qubit(), b.qubit(), b.qubit(), b.qubit());
+                let (c1, c2, t, out) = if use_margolus {
+                    let (c1, c2, t) = margolus(&mut b, c1, c2, t)?;
+                    let (t, out) = b.cnot(t, out)?;
+                    let (c1, c2, t) = margolus_inverse(&mut b, c1, c2, t)?;
+                    (c1, c2, t, out)
+                } else {
+                    let cr = b.merge_two_registers(c1, c2);
+                    let (cr, t) = b.toffoli(cr, t)?;
+                    let (t, out) = b.cnot(t, out)?;
+                    let (cr, t) = b.toffoli(cr, t)?;
+                    let (c1, c2) = b.split_last_qubit(cr);
+                    (c1, c2.unwrap(), t, out)
+                };
+                let r = b.merge_registers([c1, c2, t, out]).unwrap();
+                Ok(b.calculate_state_with_init([(&r, x)]).into_state().unwrap())
+            };
+            assert_states_equal(&run(true)?, &run(false)?, 1e-10);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_controlled_rotations() -> CircuitResult<()> {
+        let theta = 0.7f64;
+        let prepare = |b: &mut LocalBuilder<f64>, r: Qudit| {
+            let r = b.h(r);
+            b.t(r)
+        };
+        let rotate = |b: &mut LocalBuilder<f64>, axis: usize, r: Qudit| match axis {
+            0 => b.rx(r, theta),
+            1 => b.ry(r, theta),
+            _ => b.rz(r, theta),
+        };
+        for axis in 0..3 {
+            let target_state = |rotated: bool| {
+                let mut b = LocalBuilder::<f64>::default();
+                let t = b.register(NonZeroUsize::new(2).unwrap());
+                let t = prepare(&mut b, t);
+                let _t = if rotated { rotate(&mut b, axis, t) } else { t };
+                b.calculate_state().into_state().unwrap()
+            };
+            let (unrotated, rotated) = (target_state(false), target_state(true));
+
+            // With the control in |+> the state is (|0>|psi> + |1>R|psi>)/sqrt(2).
+            let mut b = LocalBuilder::<f64>::default();
+            let c = b.qubit();
+            let t = b.register(NonZeroUsize::new(2).unwrap());
+            let c = b.h(c);
+            let t = prepare(&mut b, t);
+            let (c, t) = match axis {
+                0 => crx(&mut b, c, t, theta),
+                1 => cry(&mut b, c, t, theta),
+                _ => crz(&mut b, c, t, theta),
+            };
+            let _r = b.merge_two_registers(c, t);
+            // Temporary qubits used for conditioning come last and are returned to |0>.
+            let state = b.calculate_state().into_state().unwrap();
+            let state = state
+                .iter()
+                .step_by(state.len() / 8)
+                .cloned()
+                .collect::<Vec<_>>();
+            let expected = unrotated
+                .into_iter()
+                .chain(rotated)
+                .map(|c| c * std::f64::consts::FRAC_1_SQRT_2)
+                .collect::<Vec<_>>();
+            assert_states_equal(&state, &expected, 1e-10);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_measure_parity() -> CircuitResult<()> {
+        for x in 0..8usize {
+            let mut b = LocalBuilder::<f64>::default();
+            let qs = (0..3).map(|_| b.qubit()).collect::<Vec<_>>();
+            let ancilla = b.qubit();
+            let (qs, ancilla, handle) = measure_parity(&mut b, qs, ancilla)?;
+            let r = b.merge_registers(qs).unwrap();
+            let result = b.calculate_state_with_init([(&r, x)]);
+            let expected = parity(x) as usize;
+            assert_eq!(result.get_measurement(handle), (expected, 1.0));
+            let _r = b.merge_two_registers(r, ancilla);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_qft_matches_dft() -> CircuitResult<()> {
+        let n = 3;
+        let size = 1usize << n;
+        // The register is little-endian while the state index is big-endian.
+        let value = |i: usize| (0..n).fold(0, |acc, k| acc | (((i >> k) & 1) << (n - 1 - k)));
+        for x in 0..size {
+            let mut b = LocalBuilder::<f64>::default();
+            let r = b.register(NonZeroUsize::new(n).unwrap());
+            let r = qft(&mut b, r)?;
+            let state = b.calculate_state_with_init([(&r, x)]).into_state().unwrap();
+            let expected = (0..size)
+                .map(|i| {
+                    let phase = std::f64::consts::TAU * (x * value(i)) as f64 / size as f64;
+                    Complex::from_polar(1.0 / (size as f64).sqrt(), phase)
+                })
+                .collect::<Vec<_>>();
+            assert_states_equal(&state, &expected, 1e-10);
+
+            let mut b = LocalBuilder::<f64>::default();
+            let r = b.register(NonZeroUsize::new(n).unwrap());
+            let r = qft(&mut b, r)?;
+            let r = inverse_qft(&mut b, r)?;
+            let state = b.calculate_state_with_init([(&r, x)]).into_state().unwrap();
+            let expected = (0..size)
+                .map(|i| {
+                    if value(i) == x {
+                        Complex::one()
+                    } else {
+                        Complex::zero()
+                    }
+                })
+                .collect::<Vec<_>>();
+            assert_states_equal(&state, &expected, 1e-10);
+        }
+        Ok(())
+    }
+}
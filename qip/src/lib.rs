@@ -53,7 +53,7 @@
 //! // Now q is the end result of the above circuit, and we can run the circuit by referencing it.
 //!
 //! // Run circuit with a given precision.
-//! let (_, measured) = b.calculate_state_with_init([(&ra, 0b000), (&rb, 0b001)]);
+//! let measured = b.calculate_state_with_init([(&ra, 0b000), (&rb, 0b001)]);
 //!
 //! // Lookup the result of the measurement we performed using the handle, and the probability
 //! // of getting that measurement.
@@ -244,15 +244,24 @@ pub mod builder_traits;
 pub mod conditioning;
 /// Circuit builder error types.
 pub mod errors;
+/// An ordered gate list representation of circuits.
+pub mod gate_list;
+/// Common multi-qubit gates built out of the standard builder traits.
+pub mod gates;
 /// Functions and traits for inverting circuits.
 pub mod inverter;
 /// Types for helping procedural macros.
 #[cfg(feature = "macros")]
 pub mod macros;
+/// Running OpenQASM 2 circuits with the LocalBuilder, and writing them back out.
+pub mod qasm;
 /// Standard quantum fourier transform implementation.
 pub mod qfft;
 /// Lower-level circuit operations.
 pub mod state_ops;
+/// Helpers for comparing states in tests.
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 /// Reusable types.
 pub mod types;
 /// Utility functions for bit and index manipulation
@@ -0,0 +1,745 @@
+use crate::builder::{
+    BuilderCircuitObjectType, ClassicalRegister, LocalBuilder, MeasurementObject, Qudit,
+    RotationObject, RunResult, UnitaryMatrixObject,
+};
+use crate::builder_traits::{
+    AdvancedCircuitBuilder, CircuitBuilder, CliffordTBuilder, RotationsBuilder, SplitManyResult,
+};
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
+use crate::types::Precision;
+use crate::Complex;
+use num_traits::{One, Zero};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::num::NonZeroUsize;
+
+/// Parse the OpenQASM 2 program `qasm`, build it with a `LocalBuilder` and run it.
+///
+/// Qubits from all `qreg` declarations are allocated in order, and each `creg` becomes a classical
+/// register whose final value is available from `RunResult::classical_registers` in declaration
+/// order. The gates of `qelib1.inc` which have a direct builder equivalent are supported: `id`,
+/// `x`, `y`, `z`, `h`, `s`, `sdg`, `t`, `tdg`, `rx`, `ry`, `rz`, `u3`, `cx`, `cz`, `swap` and `ccx`.
+/// `reset` and `barrier` are supported. Malformed statements give an error starting
+/// with `QASM parse error`, while valid statements this runner cannot simulate, such as gate
+/// definitions or `if`, give an error starting with `Unsupported QASM`.
+///
+/// # Example
+/// ```
+/// use qip::qasm::run_qasm;
+///
+/// let qasm = r#"
+///     OPENQASM 2.0;
+///     include "qelib1.inc";
+///     qreg q[2];
+///     creg c[2];
+///     x q[1];
+///     measure q -> c;
+/// "#;
+/// let result = run_qasm::<f64>(qasm).unwrap();
+/// assert_eq!(result.classical_registers(), &[0b10]);
+/// ```
+pub fn run_qasm<P: Precision>(qasm: &str) -> CircuitResult<RunResult<P>> {
+    let mut program = QasmProgram::<P>::default();
+    split_statements(qasm)?
+        .into_iter()
+        .try_for_each(|(line, statement)| program.apply_statement(line, &statement))?;
+    Ok(program.b.calculate_state())
+}
+
+#[derive(Default)]
+struct QasmProgram<P: Precision> {
+    b: LocalBuilder<P>,
+    qubits: Vec<Option<Qudit>>,
+    qregs: HashMap<String, (usize, usize)>,
+    cregs: HashMap<String, ClassicalRegister>,
+}
+
+fn parse_error<S: AsRef<str>>(line: usize, msg: S) -> CircuitError {
+    CircuitError::with_kind(
+        CircuitErrorKind::Parse,
+        format!("QASM parse error on line {}: {}", line, msg.as_ref()),
+    )
+}
+
+fn unsupported_error<S: AsRef<str>>(line: usize, msg: S) -> CircuitError {
+    CircuitError::with_kind(
+        CircuitErrorKind::Unsupported,
+        format!("Unsupported QASM on line {}: {}", line, msg.as_ref()),
+    )
+}
+
+/// Split `qasm` into statements without comments, each with the line it starts on.
+fn split_statements(qasm: &str) -> CircuitResult<Vec<(usize, String)>> {
+    let mut statements = vec![];
+    let mut current = String::new();
+    let mut start = 1;
+    for (i, line) in qasm.lines().enumerate() {
+        let line = line.split("//").next().unwrap_or_default();
+        for (j, part) in line.split(';').enumerate() {
+            if j > 0 {
+                statements.push((start, current.trim().to_string()));
+                current.clear();
+            }
+            if current.trim().is_empty() {
+                start = i + 1;
+            }
+            current.push_str(part);
+            current.push(' ');
+        }
+    }
+    if current.trim().is_empty() {
+        Ok(statements)
+    } else {
+        Err(parse_error(start, "expected `;` at end of statement"))
+    }
+}
+
+/// Split `s` into a name and the contents of an optional `[n]` suffix.
+fn parse_indexed(line: usize, s: &str) -> CircuitResult<(&str, Option<usize>)> {
+    let s = s.trim();
+    match s.split_once('[') {
+        None => Ok((s, None)),
+        Some((name, rest)) => {
+            let index = rest
+                .strip_suffix(']')
+                .and_then(|index| index.trim().parse().ok())
+                .ok_or_else(|| parse_error(line, format!("invalid index in `{}`", s)))?;
+            Ok((name.trim(), Some(index)))
+        }
+    }
+}
+
+/// Number of qubits and parameters taken by each supported gate.
+fn gate_arity(name: &str) -> Option<(usize, usize)> {
+    match name {
+        "id" | "x" | "y" | "z" | "h" | "s" | "sdg" | "t" | "tdg" => Some((1, 0)),
+        "rx" | "ry" | "rz" => Some((1, 1)),
+        "u3" | "U" => Some((1, 3)),
+        "cx" | "CX" | "cz" | "swap" => Some((2, 0)),
+        "ccx" => Some((3, 0)),
+        _ => None,
+    }
+}
+
+impl<P: Precision> QasmProgram<P> {
+    fn apply_statement(&mut self, line: usize, statement: &str) -> CircuitResult<()> {
+        if statement.is_empty() {
+            return Ok(());
+        }
+        let keyword_end = statement
+            .find(|c: char| c.is_whitespace() || c == '(')
+            .unwrap_or(statement.len());
+        let (keyword, rest) = statement.split_at(keyword_end);
+        match keyword {
+            "OPENQASM" => {
+                if rest.trim().starts_with('2') {
+                    Ok(())
+                } else {
+                    Err(unsupported_error(
+                        line,
+                        format!("OpenQASM version {}", rest.trim()),
+                    ))
+                }
+            }
+            "include" => Ok(()),
+            "barrier" => {
+                let indices = rest
+                    .split(',')
+                    .map(|arg| self.resolve_qubits(line, arg))
+                    .collect::<CircuitResult<Vec<_>>>()?
+                    .concat();
+                let rs = self.take_qubits(line, &indices)?;
+                self.b.barrier(&rs.iter().collect::<Vec<_>>());
+                indices
+                    .into_iter()
+                    .zip(rs)
+                    .for_each(|(i, r)| self.qubits[i] = Some(r));
+                Ok(())
+            }
+            "qreg" | "creg" => {
+                let (name, size) = parse_indexed(line, rest)?;
+                let size = size
+                    .and_then(NonZeroUsize::new)
+                    .ok_or_else(|| parse_error(line, "registers need a nonzero size"))?;
+                if self.qregs.contains_key(name) || self.cregs.contains_key(name) {
+                    return Err(parse_error(line, format!("`{}` is already declared", name)));
+                }
+                if keyword == "qreg" {
+                    self.qregs
+                        .insert(name.to_string(), (self.qubits.len(), size.get()));
+                    let b = &mut self.b;
+                    self.qubits.extend((0..size.get()).map(|_| Some(b.qubit())));
+                } else {
+                    let creg = self.b.creg(size);
+                    self.cregs.insert(name.to_string(), creg);
+                }
+                Ok(())
+            }
+            "measure" => {
+                let (q, c) = rest
+                    .split_once("->")
+                    .ok_or_else(|| parse_error(line, "expected `measure q -> c`"))?;
+                let qs = self.resolve_qubits(line, q)?;
+                let (c, offset) = parse_indexed(line, c)?;
+                let creg = *self
+                    .cregs
+                    .get(c)
+                    .ok_or_else(|| parse_error(line, format!("unknown creg `{}`", c)))?;
+                let bits = match offset {
+                    Some(offset) if qs.len() == 1 && offset < creg.n() => vec![offset],
+                    None if qs.len() == creg.n() => (0..creg.n()).collect(),
+                    _ => return Err(parse_error(line, "measurement and creg sizes differ")),
+                };
+                qs.into_iter().zip(bits).try_for_each(|(q, bit)| {
+                    let r = self.take_qubits(line, &[q])?.pop().unwrap();
+                    let r = self.b.measure_into(r, &creg, bit)?;
+                    self.qubits[q] = Some(r);
+                    Ok(())
+                })
+            }
+            "reset" => {
+                let qs = self.resolve_qubits(line, rest)?;
+                qs.into_iter().try_for_each(|q| {
+                    let r = self.take_qubits(line, &[q])?.pop().unwrap();
+                    self.qubits[q] = Some(self.b.reset_to_zero(r));
+                    Ok(())
+                })
+            }
+            "gate" | "opaque" | "if" => {
+                Err(unsupported_error(line, format!("`{}` statements", keyword)))
+            }
+            name => {
+                let (num_qubits, num_params) = gate_arity(name)
+                    .ok_or_else(|| unsupported_error(line, format!("gate `{}`", name)))?;
+                let (params, args) = match rest.trim_start().strip_prefix('(') {
+                    Some(rest) => rest
+                        .rsplit_once(')')
+                        .ok_or_else(|| parse_error(line, "unclosed parameter list"))?,
+                    None => ("", rest),
+                };
+                let params = if params.trim().is_empty() {
+                    vec![]
+                } else {
+                    params
+                        .split(',')
+                        .map(|p| parse_expression(line, p))
+                        .collect::<CircuitResult<Vec<_>>>()?
+                };
+                if params.len() != num_params {
+                    let message = format!(
+                        "`{}` takes {} parameters, found {}",
+                        name,
+                        num_params,
+                        params.len()
+                    );
+                    return Err(parse_error(line, message));
+                }
+                let args = args
+                    .split(',')
+                    .map(|arg| self.resolve_qubits(line, arg))
+                    .collect::<CircuitResult<Vec<_>>>()?;
+                if args.len() != num_qubits {
+                    let message = format!(
+                        "`{}` takes {} qubit arguments, found {}",
+                        name,
+                        num_qubits,
+                        args.len()
+                    );
+                    return Err(parse_error(line, message));
+                }
+                // Whole registers given as arguments broadcast the gate over their qubits.
+                let repeats = args.iter().map(|arg| arg.len()).max().unwrap_or(1);
+                if args
+                    .iter()
+                    .any(|arg| arg.len() != 1 && arg.len() != repeats)
+                {
+                    return Err(parse_error(line, "register arguments differ in size"));
+                }
+                let params = params
+                    .into_iter()
+                    .map(|p| P::from(p).unwrap())
+                    .collect::<Vec<_>>();
+                (0..repeats).try_for_each(|k| {
+                    let indices = args
+                        .iter()
+                        .map(|arg| if arg.len() == 1 { arg[0] } else { arg[k] })
+                        .collect::<Vec<_>>();
+                    let rs = self.take_qubits(line, &indices)?;
+                    let rs = apply_gate(&mut self.b, name, &params, rs)?;
+                    indices
+                        .into_iter()
+                        .zip(rs)
+                        .for_each(|(i, r)| self.qubits[i] = Some(r));
+                    Ok(())
+                })
+            }
+        }
+    }
+
+    /// Get the indices of the qubits referred to by `q[i]` or the whole register `q`.
+    fn resolve_qubits(&self, line: usize, arg: &str) -> CircuitResult<Vec<usize>> {
+        let (name, index) = parse_indexed(line, arg)?;
+        let (offset, size) = *self
+            .qregs
+            .get(name)
+            .ok_or_else(|| parse_error(line, format!("unknown qreg `{}`", name)))?;
+        match index {
+            None => Ok((offset..offset + size).collect()),
+            Some(index) if index < size => Ok(vec![offset + index]),
+            Some(index) => Err(parse_error(
+                line,
+                format!("index {} out of range for `{}`", index, name),
+            )),
+        }
+    }
+
+    fn take_qubits(&mut self, line: usize, indices: &[usize]) -> CircuitResult<Vec<Qudit>> {
+        let rs = indices
+            .iter()
+            .map(|i| self.qubits[*i].take())
+            .collect::<Vec<_>>();
+        if rs.iter().all(Option::is_some) {
+            Ok(rs.into_iter().map(Option::unwrap).collect())
+        } else {
+            indices
+                .iter()
+                .zip(rs)
+                .filter_map(|(i, r)| r.map(|r| (i, r)))
+                .for_each(|(i, r)| self.qubits[*i] = Some(r));
+            Err(parse_error(line, "a qubit is repeated in the arguments"))
+        }
+    }
+}
+
+fn apply_gate<P: Precision>(
+    b: &mut LocalBuilder<P>,
+    name: &str,
+    params: &[P],
+    mut rs: Vec<Qudit>,
+) -> CircuitResult<Vec<Qudit>> {
+    let rs = match (name, rs.len()) {
+        (_, 1) => {
+            let r = rs.pop().unwrap();
+            let r = match name {
+                "x" => b.x(r),
+                "y" => b.y(r),
+                "z" => b.z(r),
+                "h" => b.h(r),
+                "s" => b.s(r),
+                "sdg" => b.s_dagger(r),
+                "t" => b.t(r),
+                "tdg" => b.t_dagger(r),
+                "rx" => b.rx(r, params[0]),
+                "ry" => b.ry(r, params[0]),
+                "rz" => b.rz(r, params[0]),
+                "u3" | "U" => b.u3(r, params[0], params[1], params[2]),
+                _ => r,
+            };
+            vec![r]
+        }
+        ("cx" | "CX" | "cz", 2) => {
+            let t = rs.pop().unwrap();
+            let c = rs.pop().unwrap();
+            let is_cz = name == "cz";
+            let t = if is_cz { b.h(t) } else { t };
+            let (c, t) = b.cnot(c, t)?;
+            let t = if is_cz { b.h(t) } else { t };
+            vec![c, t]
+        }
+        ("swap", 2) => {
+            let rb = rs.pop().unwrap();
+            let ra = rs.pop().unwrap();
+            let (ra, rb) = b.swap(ra, rb)?;
+            vec![ra, rb]
+        }
+        ("ccx", 3) => {
+            let t = rs.pop().unwrap();
+            let cr = b.merge_registers(rs).unwrap();
+            let (cr, t) = b.toffoli(cr, t)?;
+            let mut rs = match b.split_relative_index_groups(cr, [[0], [1]]) {
+                SplitManyResult::AllSelected(rs) => rs,
+                SplitManyResult::Remaining(_, _) => unreachable!(),
+            };
+            rs.push(t);
+            rs
+        }
+        _ => unreachable!(),
+    };
+    Ok(rs)
+}
+
+/// Write the circuit in `b` as an OpenQASM 2 program on a single register `q` of all the qubits
+/// in the builder. Standard gates are written by name, rotations as `rz`, and single qubit
+/// matrices as the named gate they match (up to a global phase) or otherwise as a `u3`. Each
+/// measurement writes into its own classical register `m0`, `m1`, ... in the order measurements
+/// were added. Errors naming the op for anything with no OpenQASM 2 equivalent: matrices on more
+/// than one qubit, generated matrices, stochastic measurements and ops applied on measurements.
+/// Also errors if any qubits start in a state other than |0>, as QASM registers always start in
+/// |0>.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::qasm::to_qasm;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut b = LocalBuilder::<f64>::default();
+/// let q = b.qubit();
+/// let _q = b.h(q);
+/// assert_eq!(to_qasm(&b)?, "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\nh q[0];\n");
+/// # Ok(())
+/// # }
+/// ```
+pub fn to_qasm<P: Precision>(b: &LocalBuilder<P>) -> CircuitResult<String> {
+    if b.has_initial_states() {
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::Unsupported,
+            "Cannot write qubits with initial states other than |0> as QASM",
+        ));
+    }
+    let mut qasm = format!(
+        "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[{}];\n",
+        b.n()
+    );
+    let mut measurements = 0;
+    for (pos, (indices, obj)) in b.pipeline().iter().enumerate() {
+        let unsupported = |name: &str| {
+            CircuitError::with_kind(
+                CircuitErrorKind::Unsupported,
+                format!("Cannot write the {} op at {:?} as QASM", name, pos),
+            )
+        };
+        if b.is_applied_on_measurement(pos) {
+            return Err(unsupported("conditioned"));
+        }
+        let unitary = match &obj.object {
+            BuilderCircuitObjectType::Measurement(MeasurementObject::Measurement) => {
+                let m = measurements;
+                measurements += 1;
+                writeln!(qasm, "creg m{}[{}];", m, indices.len()).unwrap();
+                indices.iter().enumerate().for_each(|(j, i)| {
+                    writeln!(qasm, "measure q[{}] -> m{}[{}];", i, m, j).unwrap()
+                });
+                continue;
+            }
+            BuilderCircuitObjectType::Measurement(MeasurementObject::StochasticMeasurement) => {
+                return Err(unsupported(obj.object.name()));
+            }
+            BuilderCircuitObjectType::Measurement(MeasurementObject::Reset) => {
+                indices
+                    .iter()
+                    .for_each(|i| writeln!(qasm, "reset q[{}];", i).unwrap());
+                continue;
+            }
+            BuilderCircuitObjectType::Unitary(unitary) => unitary,
+        };
+        let gate = match unitary {
+            UnitaryMatrixObject::X => "x".to_string(),
+            UnitaryMatrixObject::Y => "y".to_string(),
+            UnitaryMatrixObject::Z => "z".to_string(),
+            UnitaryMatrixObject::H => "h".to_string(),
+            UnitaryMatrixObject::S => "s".to_string(),
+            UnitaryMatrixObject::T => "t".to_string(),
+            UnitaryMatrixObject::Identity => "id".to_string(),
+            UnitaryMatrixObject::Barrier => {
+                let args = indices
+                    .iter()
+                    .map(|i| format!("q[{}]", i))
+                    .collect::<Vec<_>>();
+                writeln!(qasm, "barrier {};", args.join(",")).unwrap();
+                continue;
+            }
+            UnitaryMatrixObject::Rz(RotationObject::Floating(theta)) => format!("rz({})", theta),
+            UnitaryMatrixObject::Rz(RotationObject::PiRational(r)) => {
+                format!("rz(pi*{}/{})", r.numer(), r.denom())
+            }
+            // Global phases have no effect outside of controlled circuits.
+            UnitaryMatrixObject::GlobalPhase(_) => continue,
+            UnitaryMatrixObject::CNOT => {
+                writeln!(qasm, "cx q[{}],q[{}];", indices[0], indices[1]).unwrap();
+                continue;
+            }
+            UnitaryMatrixObject::SWAP => {
+                let half = indices.len() / 2;
+                (0..half).for_each(|i| {
+                    writeln!(qasm, "swap q[{}],q[{}];", indices[i], indices[half + i]).unwrap()
+                });
+                continue;
+            }
+            UnitaryMatrixObject::Generated(_) => return Err(unsupported(unitary.name())),
+            UnitaryMatrixObject::MAT(_) | UnitaryMatrixObject::Sparse(_) => {
+                if obj.n != 1 {
+                    let name = format!("{} qubit {}", obj.n, unitary.name());
+                    return Err(unsupported(&name));
+                }
+                single_qubit_gate(&unitary.matrix_data().unwrap())
+            }
+        };
+        // Single qubit gates may be broadcast over several indices.
+        indices
+            .iter()
+            .for_each(|i| writeln!(qasm, "{} q[{}];", gate, i).unwrap());
+    }
+    Ok(qasm)
+}
+
+/// Get the named gate matching the single qubit matrix `data` up to a global phase, or else the
+/// equivalent `u3` gate.
+fn single_qubit_gate<P: Precision>(data: &[Complex<P>]) -> String {
+    let (o, l) = (Complex::<P>::zero(), Complex::<P>::one());
+    let i = Complex::new(P::zero(), P::one());
+    let s = Complex::from(P::from(std::f64::consts::FRAC_1_SQRT_2).unwrap());
+    let t = Complex::from_polar(P::one(), P::from(std::f64::consts::FRAC_PI_4).unwrap());
+    let named = [
+        ("id", [l, o, o, l]),
+        ("x", [o, l, l, o]),
+        ("y", [o, -i, i, o]),
+        ("z", [l, o, o, -l]),
+        ("h", [s, s, s, -s]),
+        ("s", [l, o, o, i]),
+        ("sdg", [l, o, o, -i]),
+        ("t", [l, o, o, t]),
+        ("tdg", [l, o, o, t.conj()]),
+    ];
+    let tolerance = P::from(1e-10).unwrap();
+    // Factor out the phase of the first significant entry so the comparison ignores it.
+    let phase = |m: &[Complex<P>]| {
+        let c = m
+            .iter()
+            .find(|c| c.norm() > tolerance)
+            .cloned()
+            .unwrap_or(l);
+        c / c.norm()
+    };
+    let data_phase = phase(data);
+    let matching = named.iter().find(|(_, m)| {
+        let m_phase = phase(m);
+        m.iter()
+            .zip(data.iter())
+            .all(|(a, b)| (a * data_phase - b * m_phase).norm() < tolerance)
+    });
+    if let Some((name, _)) = matching {
+        return name.to_string();
+    }
+
+    // U3 = e^{i alpha} [[cos(theta/2), -e^{i lambda} sin(theta/2)],
+    //                   [e^{i phi} sin(theta/2), e^{i (phi + lambda)} cos(theta/2)]]
+    let theta = P::from(2).unwrap() * data[2].norm().atan2(data[0].norm());
+    let (phi, lambda) = if data[0].norm() <= tolerance {
+        (P::zero(), (-data[1]).arg() - data[2].arg())
+    } else if data[2].norm() <= tolerance {
+        (P::zero(), data[3].arg() - data[0].arg())
+    } else {
+        let alpha = data[0].arg();
+        (data[2].arg() - alpha, (-data[1]).arg() - alpha)
+    };
+    format!("u3({},{},{})", theta, phi, lambda)
+}
+
+/// Parse a parameter expression made of numbers, `pi`, parentheses and `+ - * /`.
+fn parse_expression(line: usize, s: &str) -> CircuitResult<f64> {
+    let tokens = tokenize(line, s)?;
+    let mut pos = 0;
+    let value = parse_sum(line, &tokens, &mut pos)?;
+    if pos == tokens.len() {
+        Ok(value)
+    } else {
+        Err(parse_error(
+            line,
+            format!("invalid expression `{}`", s.trim()),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Number(f64),
+    Op(char),
+}
+
+fn tokenize(line: usize, s: &str) -> CircuitResult<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '+' | '-' | '*' | '/' | '(' | ')' => tokens.push(Token::Op(c)),
+            c if c.is_ascii_alphanumeric() || c == '.' => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '.') {
+                    word.push(c);
+                }
+                let value = if word == "pi" {
+                    std::f64::consts::PI
+                } else {
+                    word.parse()
+                        .map_err(|_| parse_error(line, format!("invalid number `{}`", word)))?
+                };
+                tokens.push(Token::Number(value));
+            }
+            c => {
+                return Err(parse_error(
+                    line,
+                    format!("unexpected `{}` in expression", c),
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_sum(line: usize, tokens: &[Token], pos: &mut usize) -> CircuitResult<f64> {
+    let mut value = parse_product(line, tokens, pos)?;
+    while let Some(Token::Op(op @ ('+' | '-'))) = tokens.get(*pos) {
+        *pos += 1;
+        let rhs = parse_product(line, tokens, pos)?;
+        value = if *op == '+' { value + rhs } else { value - rhs };
+    }
+    Ok(value)
+}
+
+fn parse_product(line: usize, tokens: &[Token], pos: &mut usize) -> CircuitResult<f64> {
+    let mut value = parse_factor(line, tokens, pos)?;
+    while let Some(Token::Op(op @ ('*' | '/'))) = tokens.get(*pos) {
+        *pos += 1;
+        let rhs = parse_factor(line, tokens, pos)?;
+        value = if *op == '*' { value * rhs } else { value / rhs };
+    }
+    Ok(value)
+}
+
+fn parse_factor(line: usize, tokens: &[Token], pos: &mut usize) -> CircuitResult<f64> {
+    *pos += 1;
+    match tokens.get(*pos - 1) {
+        Some(Token::Number(x)) => Ok(*x),
+        Some(Token::Op('-')) => Ok(-parse_factor(line, tokens, pos)?),
+        Some(Token::Op('(')) => {
+            let value = parse_sum(line, tokens, pos)?;
+            if tokens.get(*pos) == Some(&Token::Op(')')) {
+                *pos += 1;
+                Ok(value)
+            } else {
+                Err(parse_error(line, "unclosed parenthesis in expression"))
+            }
+        }
+        _ => Err(parse_error(line, "incomplete expression")),
+    }
+}
+
+#[cfg(test)]
+mod qasm_tests {
+    use super::*;
+    use crate::builder::MeasurementResults;
+    use crate::builder_traits::{MeasurementBuilder, UnitaryBuilder};
+
+    #[test]
+    fn test_bell_state() -> CircuitResult<()> {
+        let qasm = r#"
+            OPENQASM 2.0;
+            include "qelib1.inc";
+            qreg q[2];
+            creg c[2];
+            h q[0];
+            cx q[0], q[1];
+            barrier q;
+            measure q -> c;
+        "#;
+        for _ in 0..10 {
+            let result = run_qasm::<f64>(qasm)?;
+            let c = result.classical_registers()[0];
+            assert!(c == 0b00 || c == 0b11);
+            // Each qubit is measured in turn, the second result is fixed by the first.
+            let ps = result
+                .measurements()
+                .iter()
+                .map(|m| match m {
+                    MeasurementResults::Single(_, p) => *p,
+                    MeasurementResults::Stochastic(_) => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(ps.len(), 2);
+            assert!((ps[0] - 0.5).abs() < 1e-10);
+            assert!((ps[1] - 1.0).abs() < 1e-10);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() {
+        let parse_error = run_qasm::<f64>("qreg q[2]; h q[2];").unwrap_err();
+        assert_eq!(parse_error.kind, CircuitErrorKind::Parse);
+        assert!(parse_error.msg.contains("line 1"));
+        let unsupported = run_qasm::<f64>("qreg q[2];\nfoo q[0];").unwrap_err();
+        assert_eq!(unsupported.kind, CircuitErrorKind::Unsupported);
+        assert!(unsupported.msg.contains("line 2"));
+    }
+
+    #[test]
+    fn test_to_qasm_bell_state() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.register(NonZeroUsize::new(2).unwrap());
+        let (q0, q1) = b.split_first_qubit(q);
+        let q0 = b.h(q0.unwrap());
+        let (q0, q1) = b.cnot(q0, q1)?;
+        let q = b.merge_two_registers(q0, q1);
+        b.barrier(&[&q]);
+        let _q = b.measure(q);
+        let expected = r#"OPENQASM 2.0;
+include "qelib1.inc";
+qreg q[2];
+h q[0];
+cx q[0],q[1];
+barrier q[0],q[1];
+creg m0[2];
+measure q[0] -> m0[0];
+measure q[1] -> m0[1];
+"#;
+        assert_eq!(to_qasm(&b)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_qasm_matrices() -> CircuitResult<()> {
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.register(NonZeroUsize::new(2).unwrap());
+        let q = b.h(q);
+        let q = b.rz_pi_by(q, 4)?;
+        let (q0, q1) = b.split_first_qubit(q);
+        let q0 = b.u3(q0.unwrap(), 0.3, 1.1, -0.4);
+        let i = Complex::new(0.0, 1.0);
+        let q1 = b.apply_matrix(q1, [-i, Complex::zero(), Complex::zero(), i])?;
+        let _q = b.merge_two_registers(q0, q1);
+        let qasm = to_qasm(&b)?;
+        assert!(qasm.contains("rz(pi*1/4) q[1];\nu3(0.3"));
+        assert!(qasm.ends_with("z q[1];\n"));
+
+        // Running the QASM gives the same state, up to a global phase.
+        let result = run_qasm::<f64>(&qasm)?;
+        crate::test_utils::assert_states_equal(
+            result.state().unwrap(),
+            &b.calculate_state().into_state().unwrap(),
+            1e-10,
+        );
+
+        let q = b.qubit();
+        let _q = b.apply_generated_matrix(q, |row, col| Complex::from((row ^ col) as f64))?;
+        let err = to_qasm(&b).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::Unsupported);
+
+        let mut b = LocalBuilder::<f64>::default();
+        let q = b.qubit_with_state(vec![Complex::zero(), Complex::one()])?;
+        let _q = b.x(q);
+        let err = to_qasm(&b).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::Unsupported);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_expression() -> CircuitResult<()> {
+        let pi = std::f64::consts::PI;
+        assert!((parse_expression(1, "-pi/2")? + pi / 2.0).abs() < 1e-12);
+        assert!((parse_expression(1, "3*(pi - 1) / 2")? - 3.0 * (pi - 1.0) / 2.0).abs() < 1e-12);
+        assert!(parse_expression(1, "pi +").is_err());
+        Ok(())
+    }
+}
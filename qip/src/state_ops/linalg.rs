@@ -0,0 +1,493 @@
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
+use crate::{Complex, Precision};
+use num_traits::{One, Zero};
+use rand::Rng;
+
+/// Get the side length of a square matrix with `len` entries, if it is one.
+fn side_length(len: usize) -> Option<usize> {
+    let side = (len as f64).sqrt().round() as usize;
+    if side * side == len {
+        Some(side)
+    } else {
+        None
+    }
+}
+
+/// Multiply the row-major square matrices `a` and `b`, giving the matrix which applies `b` and
+/// then `a`. This composes gates before applying them with `apply_vec_matrix`.
+///
+/// # Panics
+/// Panics if `a` is not square or `b` is not the same size as `a`.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::linalg::matmul;
+/// use qip::state_ops::matrix_ops::from_reals;
+///
+/// let s = std::f64::consts::FRAC_1_SQRT_2;
+/// let h = from_reals(&[s, s, s, -s]);
+/// let identity = from_reals(&[1.0, 0.0, 0.0, 1.0]);
+/// matmul(&h, &h)
+///     .iter()
+///     .zip(identity.iter())
+///     .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+/// ```
+pub fn matmul<P: Precision>(a: &[Complex<P>], b: &[Complex<P>]) -> Vec<Complex<P>> {
+    let side = side_length(a.len()).expect("Matrix must be square");
+    assert_eq!(a.len(), b.len(), "Matrices must be the same size");
+    (0..side * side)
+        .map(|i| {
+            let (row, col) = (i / side, i % side);
+            (0..side)
+                .map(|k| a[row * side + k] * b[k * side + col])
+                .sum()
+        })
+        .collect()
+}
+
+/// Get the Kronecker product of the row-major matrices `a` on `a_n` qubits and `b` on `b_n`
+/// qubits. The result acts on `a_n + b_n` qubits with `a` on the first of them, matching the
+/// big-endian order of matrices given to `apply_vec_matrix`. Errors if either matrix does not
+/// have `(2^n)^2` entries.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::linalg::kron;
+/// use qip::state_ops::matrix_ops::from_reals;
+///
+/// let x = from_reals(&[0.0, 1.0, 1.0, 0.0]);
+/// let identity = from_reals(&[1.0, 0.0, 0.0, 1.0]);
+/// let x_on_first = kron(&x, 1, &identity, 1).unwrap();
+/// assert_eq!(x_on_first.len(), 16);
+/// // Flips |00> to |10>.
+/// assert_eq!(x_on_first[0b10 * 4].re, 1.0f64);
+/// ```
+pub fn kron<P: Precision>(
+    a: &[Complex<P>],
+    a_n: usize,
+    b: &[Complex<P>],
+    b_n: usize,
+) -> CircuitResult<Vec<Complex<P>>> {
+    for (mat, n) in [(a, a_n), (b, b_n)] {
+        if mat.len() != 1 << (2 * n) {
+            let message = format!(
+                "Matrix has {:?} entries versus expected (2^{:?})^2",
+                mat.len(),
+                n
+            );
+            return Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ));
+        }
+    }
+    let (a_side, b_side) = (1 << a_n, 1 << b_n);
+    let side = a_side * b_side;
+    let data = (0..side * side)
+        .map(|i| {
+            let (row, col) = (i / side, i % side);
+            a[(row / b_side) * a_side + col / b_side] * b[(row % b_side) * b_side + col % b_side]
+        })
+        .collect();
+    Ok(data)
+}
+
+/// Get the conjugate transpose of the row-major square matrix `mat`.
+pub fn dagger<P: Precision>(mat: &[Complex<P>]) -> Vec<Complex<P>> {
+    let side = side_length(mat.len()).expect("Matrix must be square");
+    (0..side * side)
+        .map(|i| mat[(i % side) * side + i / side].conj())
+        .collect()
+}
+
+/// Check whether the row-major square matrix `mat` is Hermitian to within `tolerance`.
+pub fn is_hermitian<P: Precision>(mat: &[Complex<P>], tolerance: P) -> bool {
+    match side_length(mat.len()) {
+        None => false,
+        Some(side) => (0..side).all(|row| {
+            (row..side).all(|col| {
+                (mat[row * side + col] - mat[col * side + row].conj()).norm() <= tolerance
+            })
+        }),
+    }
+}
+
+/// Diagonalize the Hermitian row-major matrix `mat` using cyclic Jacobi rotations.
+/// Returns the real eigenvalues and the unitary `V` (row-major) whose `k`th column is the
+/// eigenvector for the `k`th eigenvalue, such that `mat = V diag(eigenvalues) V^dagger`.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::linalg::hermitian_eigh;
+/// use qip::state_ops::matrix_ops::from_reals;
+///
+/// // Pauli X has eigenvalues -1 and 1.
+/// let (mut vals, _) = hermitian_eigh(&from_reals(&[0.0, 1.0, 1.0, 0.0])).unwrap();
+/// vals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+/// assert!((vals[0] + 1.0f64).abs() < 1e-10);
+/// assert!((vals[1] - 1.0f64).abs() < 1e-10);
+/// ```
+pub fn hermitian_eigh<P: Precision>(
+    mat: &[Complex<P>],
+) -> CircuitResult<(Vec<P>, Vec<Complex<P>>)> {
+    let side = side_length(mat.len()).ok_or_else(|| {
+        CircuitError::with_kind(
+            CircuitErrorKind::MatrixSizeMismatch,
+            "Matrix must be square to diagonalize",
+        )
+    })?;
+    let tolerance = P::epsilon().sqrt();
+    if !is_hermitian(mat, tolerance) {
+        return Err(CircuitError::new("Matrix must be Hermitian to diagonalize"));
+    }
+
+    let mut a = mat.to_vec();
+    let mut v = (0..side * side)
+        .map(|i| {
+            if i / side == i % side {
+                Complex::one()
+            } else {
+                Complex::zero()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let scale = a.iter().map(|c| c.norm_sqr()).sum::<P>().sqrt();
+    let threshold = P::epsilon() * scale;
+    let max_sweeps = 100;
+    for _ in 0..max_sweeps {
+        let off_diagonal = (0..side)
+            .flat_map(|row| {
+                (0..side)
+                    .filter(move |col| *col != row)
+                    .map(move |col| (row, col))
+            })
+            .map(|(row, col)| a[row * side + col].norm_sqr())
+            .sum::<P>()
+            .sqrt();
+        if off_diagonal <= threshold {
+            break;
+        }
+        for p in 0..side {
+            for q in p + 1..side {
+                let apq = a[p * side + q];
+                if apq.norm() <= threshold / P::from(side * side).unwrap() {
+                    continue;
+                }
+                // First rotate the phase of q so that the (p, q) entry is real.
+                let phase = Complex::from_polar(P::one(), -apq.arg());
+                (0..side).for_each(|i| {
+                    a[i * side + q] *= phase;
+                    v[i * side + q] *= phase;
+                });
+                (0..side).for_each(|j| a[q * side + j] *= phase.conj());
+
+                // Then a real Jacobi rotation zeros the (p, q) entry.
+                let r = a[p * side + q].re;
+                let app = a[p * side + p].re;
+                let aqq = a[q * side + q].re;
+                let theta = P::from(0.5).unwrap() * (r + r).atan2(aqq - app);
+                let (s, c) = theta.sin_cos();
+                (0..side).for_each(|i| {
+                    let (aip, aiq) = (a[i * side + p], a[i * side + q]);
+                    a[i * side + p] = aip * c - aiq * s;
+                    a[i * side + q] = aip * s + aiq * c;
+                    let (vip, viq) = (v[i * side + p], v[i * side + q]);
+                    v[i * side + p] = vip * c - viq * s;
+                    v[i * side + q] = vip * s + viq * c;
+                });
+                (0..side).for_each(|j| {
+                    let (apj, aqj) = (a[p * side + j], a[q * side + j]);
+                    a[p * side + j] = apj * c - aqj * s;
+                    a[q * side + j] = apj * s + aqj * c;
+                });
+            }
+        }
+    }
+
+    let eigenvalues = (0..side).map(|i| a[i * side + i].re).collect();
+    Ok((eigenvalues, v))
+}
+
+/// Raise the unitary row-major matrix `mat` to a real `power`, using the principal branch for
+/// each eigenvalue. A power of `0.5` gives a square root, so for instance the square root of X.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::linalg::matrix_power;
+/// use qip::state_ops::matrix_ops::from_reals;
+///
+/// let x = from_reals(&[0.0, 1.0, 1.0, 0.0]);
+/// let x_cubed = matrix_power(&x, 3.0f64).unwrap();
+/// x_cubed.iter().zip(x.iter()).for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+/// ```
+pub fn matrix_power<P: Precision>(mat: &[Complex<P>], power: P) -> CircuitResult<Vec<Complex<P>>> {
+    let side = side_length(mat.len()).ok_or_else(|| {
+        CircuitError::with_kind(
+            CircuitErrorKind::MatrixSizeMismatch,
+            "Matrix must be square to raise to a power",
+        )
+    })?;
+    let tolerance = P::epsilon().sqrt();
+    let mat_dagger = dagger(mat);
+    let is_identity = |m: &[Complex<P>]| {
+        m.iter().enumerate().all(|(i, c)| {
+            let expected = if i / side == i % side {
+                Complex::one()
+            } else {
+                Complex::zero()
+            };
+            (c - expected).norm() <= tolerance
+        })
+    };
+    if !is_identity(&matmul(mat, &mat_dagger)) {
+        return Err(CircuitError::new(
+            "Matrix must be unitary to raise to a power",
+        ));
+    }
+
+    // The Hermitian and anti-Hermitian parts of a unitary commute, so the eigenvectors of a
+    // generic real combination of the two diagonalize the unitary itself.
+    let half = P::from(0.5).unwrap();
+    let mix = P::from(0.577_215_664_9).unwrap();
+    let h = mat
+        .iter()
+        .zip(mat_dagger.iter())
+        .map(|(u, ud)| {
+            let re = (u + ud) * half;
+            let im = (u - ud) * Complex::new(P::zero(), -half);
+            re + im * mix
+        })
+        .collect::<Vec<_>>();
+    let (_, v) = hermitian_eigh(&h)?;
+    let v_dagger = dagger(&v);
+    let d = matmul(&matmul(&v_dagger, mat), &v);
+    let off_diagonal = d
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i / side != i % side)
+        .any(|(_, c)| c.norm() > tolerance);
+    if off_diagonal {
+        return Err(CircuitError::new("Matrix could not be diagonalized"));
+    }
+
+    let d_power = (0..side * side)
+        .map(|i| {
+            if i / side == i % side {
+                Complex::from_polar(P::one(), d[i].arg() * power)
+            } else {
+                Complex::zero()
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(matmul(&matmul(&v, &d_power), &v_dagger))
+}
+
+/// Generate a Haar random unitary on `n` qubits as a row-major matrix, which can be applied with
+/// `apply_vec_matrix`. The columns of a matrix of complex gaussian entries are orthonormalized,
+/// giving the Q of its QR decomposition with a positive diagonal in R.
+///
+/// # Example
+/// ```
+/// use qip::prelude::*;
+/// use qip::rand::rngs::StdRng;
+/// use qip::rand::SeedableRng;
+/// use qip::state_ops::linalg::random_unitary;
+///
+/// # fn main() -> CircuitResult<()> {
+/// let mut rng = StdRng::seed_from_u64(1);
+/// let mut b = LocalBuilder::<f64>::default();
+/// let (q, r) = (b.qubit(), b.qubit());
+/// let r = b.merge_two_registers(q, r);
+/// let r = b.apply_vec_matrix(r, random_unitary(2, &mut rng))?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn random_unitary<P: Precision, R: Rng>(n: usize, rng: &mut R) -> Vec<Complex<P>> {
+    let side = 1 << n;
+    // Box-Muller gives two independent standard normals from two uniform samples.
+    let mut gaussian = || {
+        let radius = (-2.0 * (1.0 - rng.gen::<f64>()).ln()).sqrt();
+        let (sin, cos) = (std::f64::consts::TAU * rng.gen::<f64>()).sin_cos();
+        Complex::new(
+            P::from(radius * cos).unwrap(),
+            P::from(radius * sin).unwrap(),
+        )
+    };
+    let mut columns: Vec<Vec<Complex<P>>> = Vec::with_capacity(side);
+    (0..side).for_each(|_| {
+        let mut v = (0..side).map(|_| gaussian()).collect::<Vec<_>>();
+        columns.iter().for_each(|q| {
+            let overlap = q
+                .iter()
+                .zip(v.iter())
+                .map(|(a, b)| a.conj() * b)
+                .sum::<Complex<P>>();
+            v.iter_mut()
+                .zip(q.iter())
+                .for_each(|(b, a)| *b -= a * overlap);
+        });
+        let norm = v.iter().map(|c| c.norm_sqr()).sum::<P>().sqrt();
+        v.iter_mut().for_each(|c| *c /= norm);
+        columns.push(v);
+    });
+    (0..side * side)
+        .map(|i| columns[i % side][i / side])
+        .collect()
+}
+
+#[cfg(test)]
+mod linalg_tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::state_ops::matrix_ops::from_reals;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_eigh_reconstructs() -> CircuitResult<()> {
+        let c = |re: f64, im: f64| Complex::new(re, im);
+        let mat = vec![
+            c(2.0, 0.0),
+            c(0.5, -1.0),
+            c(0.0, 0.3),
+            c(0.5, 1.0),
+            c(-1.0, 0.0),
+            c(0.7, 0.0),
+            c(0.0, -0.3),
+            c(0.7, 0.0),
+            c(0.5, 0.0),
+        ];
+        let (vals, v) = hermitian_eigh(&mat)?;
+        let diag = (0..9)
+            .map(|i| {
+                if i / 3 == i % 3 {
+                    c(vals[i / 3], 0.0)
+                } else {
+                    c(0.0, 0.0)
+                }
+            })
+            .collect::<Vec<_>>();
+        let reconstructed = matmul(&matmul(&v, &diag), &dagger(&v));
+        reconstructed
+            .iter()
+            .zip(mat.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+        let identity = matmul(&v, &dagger(&v));
+        identity.iter().enumerate().for_each(|(i, x)| {
+            let expected = if i / 3 == i % 3 { 1.0 } else { 0.0 };
+            assert!((x - c(expected, 0.0)).norm() < 1e-10);
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul() {
+        let c = |re: f64, im: f64| Complex::new(re, im);
+        // Y = iXZ
+        let x = vec![c(0.0, 0.0), c(1.0, 0.0), c(1.0, 0.0), c(0.0, 0.0)];
+        let z = vec![c(1.0, 0.0), c(0.0, 0.0), c(0.0, 0.0), c(-1.0, 0.0)];
+        let y = vec![c(0.0, 0.0), c(0.0, -1.0), c(0.0, 1.0), c(0.0, 0.0)];
+        let ixz = matmul(&x, &z)
+            .into_iter()
+            .map(|a| a * c(0.0, 1.0))
+            .collect::<Vec<_>>();
+        assert_eq!(ixz, y);
+    }
+
+    #[test]
+    #[should_panic(expected = "Matrices must be the same size")]
+    fn test_matmul_size_mismatch() {
+        let x = from_reals::<f64>(&[0.0, 1.0, 1.0, 0.0]);
+        let xx = kron(&x, 1, &x, 1).unwrap();
+        matmul(&x, &xx);
+    }
+
+    #[test]
+    fn test_kron() -> CircuitResult<()> {
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let h = from_reals(&[s, s, s, -s]);
+        let hh = kron(&h, 1, &h, 1)?;
+        let expected = from_reals(&[
+            0.5, 0.5, 0.5, 0.5, 0.5, -0.5, 0.5, -0.5, 0.5, 0.5, -0.5, -0.5, 0.5, -0.5, -0.5, 0.5,
+        ]);
+        hh.iter()
+            .zip(expected.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+
+        // The product is the right size for a two qubit register.
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let r = b.apply_vec_matrix(r, hh)?;
+        let state = b
+            .calculate_state_with_init([(&r, 0b01)])
+            .into_state()
+            .unwrap();
+        let mut b = LocalBuilder::<f64>::default();
+        let r = b.register(NonZeroUsize::new(2).unwrap());
+        let r = b.h(r);
+        let expected = b
+            .calculate_state_with_init([(&r, 0b01)])
+            .into_state()
+            .unwrap();
+        state
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+
+        assert!(kron(&h, 2, &h, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_unitary() {
+        let mut rng = StdRng::seed_from_u64(3);
+        (0..=3).for_each(|n| {
+            let side = 1 << n;
+            let u = random_unitary::<f64, _>(n, &mut rng);
+            assert_eq!(u.len(), side * side);
+            matmul(&dagger(&u), &u)
+                .iter()
+                .enumerate()
+                .for_each(|(i, c)| {
+                    let expected = if i / side == i % side { 1.0 } else { 0.0 };
+                    assert!((c - Complex::new(expected, 0.0)).norm() < 1e-10);
+                });
+        });
+        // Different draws give different unitaries.
+        let a = random_unitary::<f64, _>(1, &mut rng);
+        let b = random_unitary::<f64, _>(1, &mut rng);
+        assert!(a.iter().zip(b.iter()).any(|(a, b)| (a - b).norm() > 1e-3));
+    }
+
+    #[test]
+    fn test_eigh_not_hermitian() {
+        let mat = vec![
+            Complex::new(0.0, 0.0),
+            Complex::new(1.0, 0.0),
+            Complex::zero(),
+            Complex::zero(),
+        ];
+        assert!(hermitian_eigh::<f64>(&mat).is_err());
+    }
+
+    #[test]
+    fn test_sqrt_x() -> CircuitResult<()> {
+        let c = |re: f64| Complex::new(re, 0.0);
+        let x = vec![c(0.0), c(1.0), c(1.0), c(0.0)];
+        let sqrt_x = matrix_power(&x, 0.5)?;
+        assert!(sqrt_x
+            .iter()
+            .zip(x.iter())
+            .any(|(a, b)| (a - b).norm() > 1e-3));
+        matmul(&sqrt_x, &sqrt_x)
+            .iter()
+            .zip(x.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+
+        let not_unitary = vec![c(1.0), c(1.0), c(0.0), c(1.0)];
+        assert!(matrix_power(&not_unitary, 0.5).is_err());
+        Ok(())
+    }
+}
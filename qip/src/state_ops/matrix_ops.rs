@@ -1,11 +1,11 @@
 /// Contains functions, structs, and enums for storing and manipulating the quantum state.
-use crate::errors::{CircuitError, CircuitResult};
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
 use crate::types::Representation;
 use crate::utils::*;
 use crate::{Complex, Precision};
-use num_traits::One;
+use num_traits::{One, Zero};
 use qip_iterators::iterators::*;
-use qip_iterators::matrix_ops::apply_op;
+use qip_iterators::matrix_ops::{apply_op, op_indices};
 use qip_iterators::utils::{flip_bits, get_bit, get_flat_index, set_bit};
 
 /// Make a Matrix MatrixOp
@@ -13,14 +13,20 @@ pub fn make_matrix_op<P>(indices: Vec<usize>, dat: Vec<P>) -> CircuitResult<Matr
     let n = indices.len();
     let expected_mat_size = 1 << (2 * n);
     if indices.is_empty() {
-        Err(CircuitError::new("Must supply at least one op index"))
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Must supply at least one op index",
+        ))
     } else if dat.len() != expected_mat_size {
         let message = format!(
             "Matrix data has {:?} entries versus expected 2^2*{:?}",
             dat.len(),
             n
         );
-        Err(CircuitError::new(message))
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::MatrixSizeMismatch,
+            message,
+        ))
     } else {
         Ok(MatrixOp::Matrix(indices, dat))
     }
@@ -37,14 +43,20 @@ pub fn make_sparse_matrix_op<P>(
     let n = indices.len();
     let expected_mat_size = 1 << n;
     if indices.is_empty() {
-        Err(CircuitError::new("Must supply at least one op index"))
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Must supply at least one op index",
+        ))
     } else if dat.len() != expected_mat_size {
         let message = format!(
             "Sparse matrix has {:?} rows versus expected 2^{:?}",
             dat.len(),
             n
         );
-        Err(CircuitError::new(message))
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::MatrixSizeMismatch,
+            message,
+        ))
     } else {
         // Each row needs at least one entry
         dat.iter().enumerate().try_for_each(|(row, v)| {
@@ -83,7 +95,10 @@ pub fn make_sparse_matrix_op<P>(
 /// Make a Swap MatrixOp
 pub fn make_swap_op<P>(a_indices: Vec<usize>, b_indices: Vec<usize>) -> CircuitResult<MatrixOp<P>> {
     if a_indices.is_empty() || b_indices.is_empty() {
-        Err(CircuitError::new("Need at least 1 swap index for a and b"))
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Need at least 1 swap index for a and b",
+        ))
     } else if a_indices.len() != b_indices.len() {
         let message = format!(
             "Swap must be performed on two sets of indices of equal length, found {:?} vs {:?}",
@@ -99,13 +114,69 @@ pub fn make_swap_op<P>(a_indices: Vec<usize>, b_indices: Vec<usize>) -> CircuitR
     }
 }
 
-/// Make a Control MatrixOp
+/// Make a Broadcast MatrixOp which applies a single qubit matrix to each of `indices`.
+pub fn make_broadcast_op<P>(indices: Vec<usize>, dat: Vec<P>) -> CircuitResult<MatrixOp<P>> {
+    if indices.is_empty() {
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Must supply at least one op index",
+        ))
+    } else if dat.len() != 4 {
+        let message = format!(
+            "Broadcast matrix data has {:?} entries versus expected 4",
+            dat.len()
+        );
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::MatrixSizeMismatch,
+            message,
+        ))
+    } else {
+        Ok(MatrixOp::Broadcast(indices, dat))
+    }
+}
+
+/// Make a Generated MatrixOp whose entries are computed by `f(row, col)` when the op is applied,
+/// rather than stored. Rows and columns are ordered the same as for `make_matrix_op`.
+pub fn make_generated_op<P, F>(indices: Vec<usize>, f: F) -> CircuitResult<MatrixOp<P>>
+where
+    F: Fn(usize, usize) -> P + Send + Sync + 'static,
+{
+    if indices.is_empty() {
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Must supply at least one op index",
+        ))
+    } else {
+        Ok(MatrixOp::new_generated(indices, f))
+    }
+}
+
+/// Make a Control MatrixOp. Errors if no control indices are given or if any of them are also
+/// acted on by `op`.
 pub fn make_control_op<P>(
     mut c_indices: Vec<usize>,
     op: MatrixOp<P>,
 ) -> CircuitResult<MatrixOp<P>> {
+    let op_indices = op_indices(&op);
+    let overlapping = c_indices
+        .iter()
+        .filter(|indx| op_indices.binary_search(indx).is_ok())
+        .cloned()
+        .collect::<Vec<_>>();
     if c_indices.is_empty() {
-        Err(CircuitError::new("Must supply at least one control index"))
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Must supply at least one control index",
+        ))
+    } else if !overlapping.is_empty() {
+        let message = format!(
+            "Control indices {:?} are also acted on by the controlled op",
+            overlapping
+        );
+        Err(CircuitError::with_kind(
+            CircuitErrorKind::OverlappingIndices,
+            message,
+        ))
     } else {
         let num_c_indices = c_indices.len();
         match op {
@@ -121,6 +192,93 @@ pub fn make_control_op<P>(
     }
 }
 
+/// Check that `op` is well formed: it acts on at least one index and no index twice, dense
+/// matrices have `2^(2n)` entries, sparse matrices `2^n` rows, broadcasts a single qubit matrix,
+/// and controlled ops list their control indices ahead of the (valid) inner op's indices.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::matrix_ops::{from_reals, validate_op};
+/// use qip_iterators::iterators::MatrixOp;
+///
+/// let op = MatrixOp::new_matrix(vec![0, 1], from_reals(&[1.0f64, 0.0, 0.0, 1.0]));
+/// assert!(validate_op(&op).is_err());
+/// ```
+pub fn validate_op<P>(op: &MatrixOp<P>) -> CircuitResult<()> {
+    let indices = op.indices();
+    if indices.is_empty() {
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::EmptyIndices,
+            "Must supply at least one op index",
+        ));
+    }
+    if let Some(index) = indices
+        .iter()
+        .enumerate()
+        .find_map(|(i, index)| indices[..i].contains(index).then_some(index))
+    {
+        let message = format!("Op acts on index {:?} more than once", index);
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::RepeatedIndices,
+            message,
+        ));
+    }
+    let n = indices.len();
+    match op {
+        MatrixOp::Matrix(_, dat) if dat.len() != 1 << (2 * n) => {
+            let message = format!(
+                "Matrix data has {:?} entries versus expected 2^2*{:?}",
+                dat.len(),
+                n
+            );
+            Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ))
+        }
+        MatrixOp::SparseMatrix(_, dat) if dat.len() != 1 << n => {
+            let message = format!(
+                "Sparse matrix has {:?} rows versus expected 2^{:?}",
+                dat.len(),
+                n
+            );
+            Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ))
+        }
+        MatrixOp::Swap(a, _) if *a == 0 || n != 2 * a => {
+            let message = format!("Swap of {:?} indices each way acts on {:?} indices", a, n);
+            Err(CircuitError::new(message))
+        }
+        MatrixOp::Broadcast(_, dat) if dat.len() != 4 => {
+            let message = format!(
+                "Broadcast matrix data has {:?} entries versus expected 4",
+                dat.len()
+            );
+            Err(CircuitError::with_kind(
+                CircuitErrorKind::MatrixSizeMismatch,
+                message,
+            ))
+        }
+        MatrixOp::Control(num_c, _, inner) => {
+            if *num_c == 0 {
+                Err(CircuitError::with_kind(
+                    CircuitErrorKind::EmptyIndices,
+                    "Must supply at least one control index",
+                ))
+            } else if indices[(*num_c).min(n)..] != *inner.indices() {
+                Err(CircuitError::new(
+                    "Control op indices must be the control indices followed by the op indices",
+                ))
+            } else {
+                validate_op(inner)
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Make a vector of vectors of rows (with `(column, value)`) built from a function
 /// `f` which takes row numbers.
 /// natural_order indicates that the lowest indexed qubit is the least significant bit in `row` and
@@ -175,6 +333,13 @@ pub fn conj_op<P: Precision>(op: MatrixOp<Complex<P>>) -> MatrixOp<Complex<P>> {
         MatrixOp::Control(c_indices, op_indices, op) => {
             MatrixOp::Control(c_indices, op_indices, Box::new(conj_op(*op)))
         }
+        MatrixOp::Broadcast(indices, mat) => {
+            let mat = mat.into_iter().map(|v| v.conj()).collect();
+            MatrixOp::Broadcast(indices, mat)
+        }
+        MatrixOp::Generated(indices, f) => {
+            MatrixOp::new_generated(indices, move |row, col| f(row, col).conj())
+        }
     }
 }
 
@@ -197,6 +362,13 @@ pub fn transpose_op<P: Precision>(op: MatrixOp<Complex<P>>) -> MatrixOp<Complex<
         MatrixOp::Control(c_indices, op_indices, op) => {
             MatrixOp::Control(c_indices, op_indices, Box::new(transpose_op(*op)))
         }
+        MatrixOp::Broadcast(indices, mut mat) => {
+            mat.swap(1, 2);
+            MatrixOp::Broadcast(indices, mat)
+        }
+        MatrixOp::Generated(indices, f) => {
+            MatrixOp::new_generated(indices, move |row, col| f(col, row))
+        }
     }
 }
 
@@ -256,10 +428,46 @@ pub fn make_op_matrix<P: Precision>(n: usize, op: &MatrixOp<Complex<P>>) -> Vec<
         .collect()
 }
 
+/// Embed the row-major matrix `small_mat` acting on `small_indices` into a row-major
+/// `2^total_n` by `2^total_n` matrix which is the identity on all other indices.
+/// Not very efficient, use only for reference and testing.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::matrix_ops::{embed_unitary, from_reals};
+///
+/// // X on the second of two qubits is I (x) X.
+/// let x = from_reals(&[0.0, 1.0, 1.0, 0.0]);
+/// let mat = embed_unitary(&x, &[1], 2);
+/// assert_eq!(mat[1].re, 1.0f64);
+/// ```
+pub fn embed_unitary<P: Precision>(
+    small_mat: &[Complex<P>],
+    small_indices: &[usize],
+    total_n: usize,
+) -> Vec<Complex<P>> {
+    let nindices = small_indices.len();
+    let small_mask: usize = small_indices
+        .iter()
+        .map(|indx| 1 << (total_n - 1 - indx))
+        .sum();
+    (0..1 << (2 * total_n))
+        .map(|i| {
+            let (row, col) = (i >> total_n, i & ((1 << total_n) - 1));
+            if row & !small_mask == col & !small_mask {
+                let (x, y) = select_matrix_coords(total_n, nindices, small_indices, row, col);
+                small_mat[x * (1 << nindices) + y]
+            } else {
+                Complex::zero()
+            }
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod state_ops_tests {
-    use qip_iterators::matrix_ops::get_index;
     use super::*;
+    use qip_iterators::matrix_ops::{commutes, fuse_diagonal, get_index, row_entries};
 
     #[test]
     fn test_get_bit() {
@@ -293,6 +501,64 @@ mod state_ops_tests {
         assert_eq!(get_index(&op, 3), 3);
     }
 
+    #[test]
+    fn test_op_indices_nested_control() {
+        let mop = MatrixOp::<Complex<f64>>::new_matrix(vec![5, 2], vec![]);
+        let inner = MatrixOp::Control(1, vec![4, 5, 2], Box::new(mop));
+        let op = MatrixOp::Control(2, vec![0, 3, 4, 5, 2], Box::new(inner));
+        assert_eq!(op_indices(&op), vec![0, 2, 3, 4, 5]);
+
+        let swap = MatrixOp::<Complex<f64>>::new_swap(vec![0], vec![2]);
+        let op = make_control_op(vec![1], swap).unwrap();
+        assert_eq!(op_indices(&op), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_commutes() {
+        let o = Complex::<f64>::one();
+        let z = Complex::<f64>::new(0.0, 0.0);
+        let x = |i: usize| MatrixOp::new_matrix(vec![i], vec![z, o, o, z]);
+        let pz = |i: usize| MatrixOp::new_matrix(vec![i], vec![o, z, z, -o]);
+        assert!(commutes(&x(0), &x(1)));
+        assert!(commutes(&pz(0), &pz(0)));
+        assert!(!commutes(&x(0), &pz(0)));
+
+        let cz = make_control_op(vec![1], pz(0)).unwrap();
+        assert!(commutes(&cz, &pz(1)));
+        assert!(!commutes(&cz, &x(1)));
+    }
+
+    #[test]
+    fn test_op_predicates() {
+        let o = Complex::<f64>::one();
+        let z = Complex::<f64>::new(0.0, 0.0);
+        let x = MatrixOp::new_matrix(vec![0], vec![z, o, o, z]);
+        let pz = MatrixOp::new_matrix(vec![0], vec![o, z, z, -o]);
+        let sparse_z = MatrixOp::new_sparse(vec![0], vec![vec![(0, o)], vec![(1, -o)]]);
+        let sparse_x = MatrixOp::new_sparse(vec![0], vec![vec![(1, o)], vec![(0, o)]]);
+        let swap = MatrixOp::<Complex<f64>>::new_swap(vec![0], vec![1]);
+        let broadcast_z = MatrixOp::new_broadcast(vec![0, 1], vec![o, z, z, -o]);
+        let broadcast_x = MatrixOp::new_broadcast(vec![0, 1], vec![z, o, o, z]);
+        let generated_z = MatrixOp::new_generated(vec![0], move |r, c| match (r, c) {
+            (0, 0) => o,
+            (1, 1) => -o,
+            _ => z,
+        });
+        let generated_x = MatrixOp::new_generated(vec![0], move |r, c| if r != c { o } else { z });
+        let cz = make_control_op(vec![1], pz.clone()).unwrap();
+        let cx = make_control_op(vec![1], x.clone()).unwrap();
+
+        let diagonal = [&pz, &sparse_z, &broadcast_z, &generated_z, &cz];
+        let not_diagonal = [&x, &sparse_x, &swap, &broadcast_x, &generated_x, &cx];
+        assert!(diagonal.iter().all(|op| op.is_diagonal()));
+        assert!(not_diagonal.iter().all(|op| !op.is_diagonal()));
+
+        assert!(cz.is_control() && cx.is_control());
+        assert!(!x.is_control() && !swap.is_control());
+        assert!(matches!(cx.inner_op(), Some(MatrixOp::Matrix(_, _))));
+        assert!(x.inner_op().is_none());
+    }
+
     #[test]
     fn test_get_index_swap() {
         let op = MatrixOp::<Complex<f64>>::new_swap(vec![0, 1], vec![2, 3]);
@@ -343,6 +609,38 @@ mod state_ops_tests {
         assert_eq!(expected, output);
     }
 
+    #[test]
+    fn test_generated_hadamard() {
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        let dense = make_matrix_op(vec![1], from_reals(&[h, h, h, -h])).unwrap();
+        let generated = make_generated_op(vec![1], move |row, col| {
+            let sign = if row & col == 1 { -1.0 } else { 1.0 };
+            Complex::new(sign * h, 0.0)
+        })
+        .unwrap();
+
+        let input = from_reals(&[0.1, 0.2, 0.3, 0.4]);
+        let mut dense_output = from_reals(&[0.0; 4]);
+        let mut generated_output = from_reals(&[0.0; 4]);
+        apply_op(2, &dense, &input, &mut dense_output, 0, 0);
+        apply_op(2, &generated, &input, &mut generated_output, 0, 0);
+        assert_eq!(dense_output, generated_output);
+
+        let mut output = from_reals(&[0.0; 4]);
+        apply_op(
+            2,
+            &invert_op(generated),
+            &generated_output,
+            &mut output,
+            0,
+            0,
+        );
+        output
+            .iter()
+            .zip(input.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+    }
+
     #[test]
     fn test_make_sparse_mat() {
         let one = Complex::<f64>::one();
@@ -375,4 +673,186 @@ mod state_ops_tests {
             assert_eq!(data, expected_dat);
         }
     }
+
+    #[test]
+    fn test_embed_unitary() {
+        let (o, l) = (0.0f64, 1.0);
+        let x = from_reals(&[o, l, l, o]);
+        #[rustfmt::skip]
+        let i_x = from_reals(&[
+            o, l, o, o,
+            l, o, o, o,
+            o, o, o, l,
+            o, o, l, o,
+        ]);
+        assert_eq!(embed_unitary(&x, &[1], 2), i_x);
+        #[rustfmt::skip]
+        let x_i = from_reals(&[
+            o, o, l, o,
+            o, o, o, l,
+            l, o, o, o,
+            o, l, o, o,
+        ]);
+        assert_eq!(embed_unitary(&x, &[0], 2), x_i);
+    }
+
+    #[test]
+    fn test_fuse_diagonal() {
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        let z = Complex::<f64>::new(0.0, 0.0);
+        let data = from_tuples(&[(h, 0.0), (0.0, h), (h, 0.0), (0.0, -h)]);
+        let mat = MatrixOp::new_matrix(vec![1], data);
+        let phase = Complex::from_polar(1.0, 0.3);
+        let diagonal = MatrixOp::new_matrix(vec![1], vec![Complex::one(), z, z, phase]);
+        let fused = fuse_diagonal(&mat, &diagonal).unwrap();
+
+        let input = from_tuples(&[(0.1, 0.2), (0.3, -0.4), (-0.5, 0.1), (0.2, 0.6)]);
+        let mut middle = vec![z; 4];
+        let mut separate = vec![z; 4];
+        apply_op(2, &mat, &input, &mut middle, 0, 0);
+        apply_op(2, &diagonal, &middle, &mut separate, 0, 0);
+        let mut together = vec![z; 4];
+        apply_op(2, &fused, &input, &mut together, 0, 0);
+        separate
+            .iter()
+            .zip(together.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+
+        let rows = vec![vec![(0, Complex::one())], vec![(1, phase)]];
+        let sparse = MatrixOp::new_sparse(vec![1], rows);
+        assert!(fuse_diagonal(&mat, &sparse).is_some());
+        let other_index = MatrixOp::new_matrix(vec![0], vec![Complex::one(), z, z, phase]);
+        assert!(fuse_diagonal(&mat, &other_index).is_none());
+        assert!(fuse_diagonal(&mat, &mat).is_none());
+    }
+
+    #[test]
+    fn test_make_control_op_overlap() -> CircuitResult<()> {
+        let x = from_reals::<f64>(&[0.0, 1.0, 1.0, 0.0]);
+        let op = make_matrix_op(vec![1], x.clone())?;
+        let controlled = make_control_op(vec![0, 2], op)?;
+        assert_eq!(controlled.indices(), &[0, 2, 1]);
+
+        let err = make_control_op(vec![0, 1], make_matrix_op(vec![1], x.clone())?).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::OverlappingIndices);
+        assert!(err.to_string().contains("[1]"));
+        // Indices of nested controls are checked too.
+        let err = make_control_op(vec![2], controlled).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::OverlappingIndices);
+        assert!(err.to_string().contains("[2]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_global_phase() -> CircuitResult<()> {
+        // A controlled e^{i phi} I is a relative phase on the control, not a global phase.
+        let phi = 0.7f64;
+        let (o, l, p) = (
+            Complex::zero(),
+            Complex::one(),
+            Complex::from_polar(1.0, phi),
+        );
+        let phase = make_matrix_op(vec![0], vec![p, o, o, p])?;
+        let controlled = make_control_op(vec![2], phase)?;
+        // Over indices [0, 2] the control is the low bit.
+        #[rustfmt::skip]
+        let cphase = make_matrix_op(vec![0, 2], vec![
+            l, o, o, o,
+            o, p, o, o,
+            o, o, l, o,
+            o, o, o, p,
+        ])?;
+        let input = (0..8)
+            .map(|i| Complex::new(i as f64 + 1.0, 0.5 - i as f64))
+            .collect::<Vec<_>>();
+        let mut output = vec![Complex::zero(); 8];
+        let mut expected = vec![Complex::zero(); 8];
+        apply_op(3, &controlled, &input, &mut output, 0, 0);
+        apply_op(3, &cphase, &input, &mut expected, 0, 0);
+        output
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_row_entries() -> CircuitResult<()> {
+        let c = |re: f64| Complex::new(re, 0.0);
+        #[rustfmt::skip]
+        let data = from_reals(&[
+            0.0, 1.0, 0.0, 0.0,
+            0.5, 0.0, 0.0, -0.5,
+            0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 0.0, 0.0,
+        ]);
+        let dense = make_matrix_op(vec![0, 1], data.clone())?;
+        let sparse_rows = (0..4)
+            .map(|row| (0..4).map(|col| (col, data[row * 4 + col])).collect())
+            .collect();
+        let sparse = MatrixOp::SparseMatrix(vec![0, 1], sparse_rows);
+        for row in 0..4 {
+            let dense_entries = row_entries(&dense, row).unwrap().collect::<Vec<_>>();
+            let sparse_entries = row_entries(&sparse, row).unwrap().collect::<Vec<_>>();
+            assert_eq!(dense_entries, sparse_entries);
+        }
+        assert_eq!(
+            row_entries(&dense, 1).unwrap().collect::<Vec<_>>(),
+            vec![(0, &c(0.5)), (3, &c(-0.5))]
+        );
+        assert_eq!(row_entries(&dense, 3).unwrap().count(), 0);
+        assert!(row_entries(&make_swap_op::<f64>(vec![0], vec![1])?, 0).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_op() -> CircuitResult<()> {
+        let one = from_reals(&[1.0f64]);
+        let x = from_reals(&[0.0f64, 1.0, 1.0, 0.0]);
+        let matrix = make_matrix_op(vec![2], x.clone())?;
+        let sparse = make_sparse_matrix_op(
+            vec![0, 1],
+            (0..4).map(|row| vec![(row, one[0])]).collect(),
+            Representation::BigEndian,
+        )?;
+        let swap = make_swap_op::<Complex<f64>>(vec![0], vec![1])?;
+        let broadcast = make_broadcast_op(vec![0, 1, 2], x.clone())?;
+        let c = one[0];
+        let generated = make_generated_op(vec![0], move |row, col| c * (row ^ col) as f64)?;
+        // A toffoli built from a control op wrapped around another.
+        let nested = MatrixOp::Control(
+            1,
+            vec![0, 1, 2],
+            Box::new(MatrixOp::Control(1, vec![1, 2], Box::new(matrix.clone()))),
+        );
+        let ops = [&matrix, &sparse, &swap, &broadcast, &generated, &nested];
+        assert_eq!(
+            ops.iter().map(|op| op.num_indices()).collect::<Vec<_>>(),
+            vec![1, 2, 2, 3, 1, 3]
+        );
+        ops.iter().try_for_each(|op| validate_op(op))?;
+
+        let invalid = [
+            MatrixOp::Matrix(vec![0, 1], x.clone()),
+            MatrixOp::Matrix(vec![1, 1], from_reals(&[0.0; 16])),
+            MatrixOp::SparseMatrix(vec![0, 1], vec![vec![(0, one[0])]]),
+            MatrixOp::Swap(1, vec![0, 1, 2]),
+            MatrixOp::Broadcast(vec![0], from_reals(&[0.0; 16])),
+            MatrixOp::Control(0, vec![2], Box::new(matrix.clone())),
+            MatrixOp::Control(1, vec![0, 1], Box::new(matrix.clone())),
+            MatrixOp::Control(
+                1,
+                vec![0, 1, 2],
+                Box::new(MatrixOp::Control(
+                    1,
+                    vec![1, 2],
+                    Box::new(MatrixOp::Matrix(vec![2], one)),
+                )),
+            ),
+        ];
+        invalid
+            .iter()
+            .for_each(|op| assert!(validate_op(op).is_err(), "{:?}", op));
+        Ok(())
+    }
 }
@@ -1,15 +1,21 @@
 #[cfg(feature = "parallel")]
 pub(crate) use rayon::prelude::*;
 
-use crate::utils::extract_bits;
+use crate::errors::{CircuitError, CircuitErrorKind, CircuitResult};
+use crate::state_ops::linalg::hermitian_eigh;
+use crate::utils::{extract_bits, KahanSum};
 use crate::{Complex, Precision};
 use num_traits::Zero;
 use qip_iterators::{into_iter, iter, iter_mut};
 use std::cmp::{max, min};
 
-/// Get total magnitude of state.
+/// Get total magnitude of state. Uses compensated summation to limit the accumulated rounding
+/// error over large states.
 pub fn prob_magnitude<P: Precision>(input: &[Complex<P>]) -> P {
-    iter!(input).map(Complex::<P>::norm_sqr).sum()
+    iter!(input)
+        .map(Complex::<P>::norm_sqr)
+        .sum::<KahanSum<P>>()
+        .value()
 }
 
 /// Calculate the probability of a given measurement. `measured` gives the bits (as a usize) which has
@@ -108,7 +114,7 @@ where
     };
 
     let r = 0usize..1 << remaining_indices.len();
-    into_iter!(r).filter_map(f).sum()
+    into_iter!(r).filter_map(f).sum::<KahanSum<P>>().value()
 }
 
 /// Get probability for each possible measurement of `indices` on `input`.
@@ -126,6 +132,25 @@ pub fn measure_probs<P: Precision>(
         .collect()
 }
 
+/// Get the basis indices of `input` whose probability exceeds `threshold`, in ascending order.
+///
+/// # Examples
+/// ```
+/// use qip::state_ops::matrix_ops::from_reals;
+/// use qip::state_ops::measurement_ops::support;
+///
+/// let input = from_reals(&[0.6, 0.0, 0.0, 0.8]);
+/// assert_eq!(support(&input, 0.01), vec![0, 3]);
+/// assert_eq!(support(&input, 0.5), vec![3]);
+/// ```
+pub fn support<P: Precision>(input: &[Complex<P>], threshold: P) -> Vec<usize> {
+    iter!(input)
+        .enumerate()
+        .filter(|(_, c)| c.norm_sqr() > threshold)
+        .map(|(i, _)| i)
+        .collect()
+}
+
 /// Sample a measurement from a state `input`. b
 /// Sample from qubits at `indices` and return bits  in order given by `indices`. See
 /// `measure_prob` for details.
@@ -268,6 +293,300 @@ pub fn measure_state<P: Precision>(
     }
 }
 
+/// A single qubit Pauli operator, used to build Pauli string observables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PauliOp {
+    /// The identity.
+    I,
+    /// Pauli X.
+    X,
+    /// Pauli Y.
+    Y,
+    /// Pauli Z.
+    Z,
+}
+
+/// Apply the Pauli string given by `terms`, each a qubit index and the operator on that qubit, to
+/// the state `input` of `n` qubits. Errors if an index is out of range or appears in more than one
+/// term.
+pub fn apply_pauli<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    terms: &[(usize, PauliOp)],
+) -> CircuitResult<Vec<Complex<P>>> {
+    if let Some((index, _)) = terms.iter().find(|(index, _)| *index >= n) {
+        let message = format!("Index {:?} is out of range for {:?} qubits", index, n);
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::IndexOutOfRange,
+            message,
+        ));
+    }
+    let mut sorted = terms.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+    sorted.sort_unstable();
+    sorted.dedup();
+    if sorted.len() != terms.len() {
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::RepeatedIndices,
+            "Indices must not be repeated",
+        ));
+    }
+    let flips = terms
+        .iter()
+        .filter(|(_, op)| matches!(op, PauliOp::X | PauliOp::Y))
+        .fold(0, |acc, (index, _)| acc ^ (1 << (n - 1 - index)));
+    // Output entry `i` comes from input entry `i ^ flips`, with a phase depending on its bits.
+    let output = into_iter!(0..input.len())
+        .map(|i| {
+            let j = i ^ flips;
+            terms.iter().fold(input[j], |acc, (index, op)| {
+                let bit = (j >> (n - 1 - index)) & 1;
+                match (op, bit) {
+                    (PauliOp::I, _) | (PauliOp::X, _) | (PauliOp::Z, 0) => acc,
+                    (PauliOp::Z, _) => -acc,
+                    // Y|0> = i|1> and Y|1> = -i|0>
+                    (PauliOp::Y, 0) => acc * Complex::i(),
+                    (PauliOp::Y, _) => -acc * Complex::i(),
+                }
+            })
+        })
+        .collect();
+    Ok(output)
+}
+
+/// Get the expectation value of the Pauli string `terms` in the state `input` of `n` qubits.
+/// Errors as with `apply_pauli`.
+pub fn expectation_pauli<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    terms: &[(usize, PauliOp)],
+) -> CircuitResult<P> {
+    let applied = apply_pauli(n, input, terms)?;
+    Ok(inner_product(input, &applied).re)
+}
+
+/// Get the variance `<O^2> - <O>^2` of the Pauli string observable `O` given by `terms` in the
+/// state `input` of `n` qubits. This estimates the shot noise when sampling `O`. Errors as with
+/// `apply_pauli`.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::matrix_ops::from_reals;
+/// use qip::state_ops::measurement_ops::{variance_pauli, PauliOp};
+///
+/// // |+> has a Z expectation of 0 and so the largest variance.
+/// let s = std::f64::consts::FRAC_1_SQRT_2;
+/// let input = from_reals(&[s, s]);
+/// let variance = variance_pauli(1, &input, &[(0, PauliOp::Z)]).unwrap();
+/// assert!((variance - 1.0).abs() < 1e-10);
+/// ```
+pub fn variance_pauli<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    terms: &[(usize, PauliOp)],
+) -> CircuitResult<P> {
+    // The observable is Hermitian so <O^2> = |O psi|^2.
+    let applied = apply_pauli(n, input, terms)?;
+    let expectation = inner_product(input, &applied).re;
+    Ok(prob_magnitude(&applied) - expectation * expectation)
+}
+
+/// Get the density matrix of the qubits `indices` of the `n` qubit state `input` with all other
+/// qubits traced out. The matrix is row-major over the little-endian values of `indices`, so the
+/// first index is the lowest bit as with measurements. Errors if `input` does not have `2^n`
+/// entries or if `indices` are out of range or repeated.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::matrix_ops::from_reals;
+/// use qip::state_ops::measurement_ops::reduced_density_matrix;
+///
+/// // |0>|1> traced down to the second qubit leaves |1><1|.
+/// let input = from_reals(&[0.0, 1.0, 0.0, 0.0]);
+/// let rho = reduced_density_matrix(2, &input, &[1]).unwrap();
+/// assert_eq!(rho, from_reals(&[0.0, 0.0, 0.0, 1.0]));
+/// assert!(reduced_density_matrix(2, &input, &[2]).is_err());
+/// ```
+pub fn reduced_density_matrix<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    indices: &[usize],
+) -> CircuitResult<Vec<Complex<P>>> {
+    check_subsystem(n, input, indices)?;
+    Ok(reduced_density_matrix_unchecked(n, input, indices))
+}
+
+/// Check that `input` has `2^n` entries and that `indices` are in range and not repeated.
+fn check_subsystem<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    indices: &[usize],
+) -> CircuitResult<()> {
+    if input.len() != 1 << n {
+        let message = format!(
+            "State has {:?} entries versus expected 2^{:?}",
+            input.len(),
+            n
+        );
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::StateSizeMismatch,
+            message,
+        ));
+    }
+    if let Some(index) = indices.iter().find(|index| **index >= n) {
+        let message = format!("Index {:?} is out of range for {:?} qubits", index, n);
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::IndexOutOfRange,
+            message,
+        ));
+    }
+    let mut sorted = indices.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    if sorted.len() != indices.len() {
+        return Err(CircuitError::with_kind(
+            CircuitErrorKind::RepeatedIndices,
+            "Indices must not be repeated",
+        ));
+    }
+    Ok(())
+}
+
+/// Get the reduced density matrix as in `reduced_density_matrix`, without checking the arguments.
+fn reduced_density_matrix_unchecked<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    indices: &[usize],
+) -> Vec<Complex<P>> {
+    let bits = indices
+        .iter()
+        .map(|index| n - 1 - index)
+        .collect::<Vec<_>>();
+    let mask: usize = bits.iter().map(|bit| 1 << bit).sum();
+    let deposit = |x: usize| -> usize {
+        bits.iter()
+            .enumerate()
+            .map(|(i, bit)| ((x >> i) & 1) << bit)
+            .sum()
+    };
+    let side = 1 << indices.len();
+    let mut rho = vec![Complex::zero(); side * side];
+    input.iter().enumerate().for_each(|(i, c)| {
+        let row = extract_bits(i, &bits);
+        (0..side).for_each(|col| {
+            let j = (i & !mask) | deposit(col);
+            rho[row * side + col] += c * input[j].conj();
+        })
+    });
+    rho
+}
+
+/// Get the purity `Tr(rho^2)` of the reduced state of the qubits `indices` in the `n` qubit state
+/// `input`. This is one for a subsystem in a pure state, down to `2^-k` for `k` qubits which are
+/// maximally entangled with the rest. Errors as `reduced_density_matrix` does.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::matrix_ops::from_reals;
+/// use qip::state_ops::measurement_ops::purity;
+///
+/// // Each half of a Bell pair is maximally mixed.
+/// let s = std::f64::consts::FRAC_1_SQRT_2;
+/// let input = from_reals(&[s, 0.0, 0.0, s]);
+/// assert!((purity(2, &input, &[0]).unwrap() - 0.5).abs() < 1e-10);
+/// ```
+pub fn purity<P: Precision>(n: usize, input: &[Complex<P>], indices: &[usize]) -> CircuitResult<P> {
+    // rho is Hermitian so Tr(rho^2) is the sum of its entries' squared magnitudes.
+    Ok(prob_magnitude(&reduced_density_matrix(n, input, indices)?))
+}
+
+/// Get the Schmidt rank of the `n` qubit state `input` split between the qubits `indices` and the
+/// rest: the number of singular values above `tolerance` when the state is reshaped into a matrix
+/// over the two parts. A rank of one means the state is a product across the bipartition. Errors
+/// as `reduced_density_matrix` does.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::matrix_ops::from_reals;
+/// use qip::state_ops::measurement_ops::schmidt_rank;
+///
+/// let s = std::f64::consts::FRAC_1_SQRT_2;
+/// let bell = from_reals(&[s, 0.0, 0.0, s]);
+/// assert_eq!(schmidt_rank(2, &bell, &[0], 1e-10).unwrap(), 2);
+/// ```
+pub fn schmidt_rank<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    indices: &[usize],
+    tolerance: P,
+) -> CircuitResult<usize> {
+    Ok(schmidt_coefficients(n, input, indices)?
+        .into_iter()
+        .filter(|v| *v > tolerance * tolerance)
+        .count())
+}
+
+/// Get the von Neumann entropy `-Tr(rho log2 rho)` in bits of the reduced state of the qubits
+/// `indices` in the `n` qubit state `input`. This is zero when the state is a product across the
+/// split, and `k` bits for `k` qubits which are maximally entangled with the rest. Errors as
+/// `reduced_density_matrix` does.
+///
+/// # Example
+/// ```
+/// use qip::state_ops::matrix_ops::from_reals;
+/// use qip::state_ops::measurement_ops::entanglement_entropy;
+///
+/// let s = std::f64::consts::FRAC_1_SQRT_2;
+/// let bell = from_reals(&[s, 0.0, 0.0, s]);
+/// assert!((entanglement_entropy(2, &bell, &[0]).unwrap() - 1.0).abs() < 1e-10);
+/// ```
+pub fn entanglement_entropy<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    indices: &[usize],
+) -> CircuitResult<P> {
+    Ok(schmidt_coefficients(n, input, indices)?
+        .into_iter()
+        .filter(|v| *v > P::zero())
+        .map(|v| -v * v.log2())
+        .sum())
+}
+
+/// Get the linear entropy `1 - Tr(rho^2)` of the reduced state of the qubits `indices` in the
+/// `n` qubit state `input`. Like `entanglement_entropy` this is zero for a product state, but it
+/// needs no diagonalization. See `purity`.
+pub fn linear_entropy<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    indices: &[usize],
+) -> CircuitResult<P> {
+    Ok(P::one() - purity(n, input, indices)?)
+}
+
+/// Get the squared Schmidt coefficients of `input` split between the qubits `indices` and the
+/// rest, as the eigenvalues of a reduced density matrix.
+fn schmidt_coefficients<P: Precision>(
+    n: usize,
+    input: &[Complex<P>],
+    indices: &[usize],
+) -> CircuitResult<Vec<P>> {
+    check_subsystem(n, input, indices)?;
+    // The eigenvalues of the reduced density matrix of either side are the same, so diagonalize
+    // whichever is smaller.
+    let complement = (0..n).filter(|i| !indices.contains(i)).collect::<Vec<_>>();
+    let smaller = if complement.len() < indices.len() {
+        &complement
+    } else {
+        indices
+    };
+    let rho = reduced_density_matrix_unchecked(n, input, smaller);
+    let (eigenvalues, _) = hermitian_eigh(&rho).expect("Reduced density matrices are Hermitian");
+    Ok(eigenvalues)
+}
+
+fn inner_product<P: Precision>(a: &[Complex<P>], b: &[Complex<P>]) -> Complex<P> {
+    iter!(a).zip(iter!(b)).map(|(a, b)| a.conj() * b).sum()
+}
+
 #[cfg(test)]
 mod measurement_tests {
     use super::*;
@@ -325,6 +644,30 @@ mod measurement_tests {
         );
     }
 
+    #[test]
+    fn test_prob_magnitude_many_small() {
+        let n = 1_000_000;
+        let amp = Complex::new((1.0 / n as f32).sqrt(), 0.0);
+        let input = vec![amp; n];
+        let naive = input
+            .iter()
+            .map(Complex::norm_sqr)
+            .fold(0.0f32, |a, b| a + b);
+        let compensated = prob_magnitude(&input);
+        assert!((compensated - 1.0).abs() < (naive - 1.0).abs());
+        assert!((compensated - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_support_ghz() {
+        let n = 4;
+        let mut amps = vec![0.0; 1 << n];
+        amps[0] = std::f64::consts::FRAC_1_SQRT_2;
+        amps[(1 << n) - 1] = std::f64::consts::FRAC_1_SQRT_2;
+        let input = from_reals(&amps);
+        assert_eq!(support(&input, 1e-6), vec![0, (1 << n) - 1]);
+    }
+
     #[test]
     fn test_measure_probs() {
         let n = 2;
@@ -333,4 +676,108 @@ mod measurement_tests {
         let p = measure_probs(n, &[m], &input, None);
         assert_eq!(p, vec![0.5, 0.5]);
     }
+
+    #[test]
+    fn test_variance_pauli() -> CircuitResult<()> {
+        let s: f64 = std::f64::consts::FRAC_1_SQRT_2;
+        let plus = from_reals(&[s, s]);
+        let zero = from_reals(&[1.0f64, 0.0]);
+        let z = [(0, PauliOp::Z)];
+        assert!((variance_pauli(1, &plus, &z)? - 1.0).abs() < 1e-10);
+        assert!(variance_pauli(1, &zero, &z)?.abs() < 1e-10);
+        assert!(variance_pauli(1, &plus, &[(0, PauliOp::X)])?.abs() < 1e-10);
+
+        // Bell state is a +1 eigenstate of XX and ZZ, and a -1 eigenstate of YY.
+        let bell = from_reals(&[s, 0.0, 0.0, s]);
+        assert!(
+            (expectation_pauli(2, &bell, &[(0, PauliOp::X), (1, PauliOp::X)])? - 1.0).abs() < 1e-10
+        );
+        assert!(
+            (expectation_pauli(2, &bell, &[(0, PauliOp::Y), (1, PauliOp::Y)])? + 1.0).abs() < 1e-10
+        );
+        assert!((expectation_pauli(2, &bell, &[(0, PauliOp::Z), (1, PauliOp::I)])?).abs() < 1e-10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_pauli_invalid_terms() {
+        let input = from_reals(&[1.0f64, 0.0, 0.0, 0.0]);
+        // X then Z on the same qubit is not a Pauli string, so repeated indices are rejected.
+        assert!(apply_pauli(2, &input, &[(0, PauliOp::X), (0, PauliOp::Z)]).is_err());
+        assert!(apply_pauli(2, &input, &[(2, PauliOp::X)]).is_err());
+        assert!(apply_pauli(2, &input, &[(0, PauliOp::X), (1, PauliOp::Z)]).is_ok());
+    }
+
+    #[test]
+    fn test_purity() -> CircuitResult<()> {
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        // |0>|+> is a product state.
+        let product = from_reals(&[s, s, 0.0, 0.0]);
+        assert!((purity(2, &product, &[0])? - 1.0).abs() < 1e-10);
+        assert!((purity(2, &product, &[1])? - 1.0).abs() < 1e-10);
+
+        let bell = from_reals(&[s, 0.0, 0.0, s]);
+        assert!((purity(2, &bell, &[1])? - 0.5).abs() < 1e-10);
+        assert!((purity(2, &bell, &[0, 1])? - 1.0).abs() < 1e-10);
+
+        let err = purity(2, &bell, &[2]).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::IndexOutOfRange);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reduced_density_matrix() -> CircuitResult<()> {
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let bell = from_reals(&[s, 0.0, 0.0, s]);
+        let mixed = from_reals(&[0.5, 0.0, 0.0, 0.5]);
+        for index in [0, 1] {
+            let rho = reduced_density_matrix(2, &bell, &[index])?;
+            rho.iter()
+                .zip(mixed.iter())
+                .for_each(|(a, b)| assert!((a - b).norm() < 1e-10));
+        }
+        assert!(reduced_density_matrix(2, &bell, &[0, 0]).is_err());
+        assert!(reduced_density_matrix(3, &bell, &[0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_entanglement_entropy() -> CircuitResult<()> {
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        let product = from_reals(&[s, s, 0.0, 0.0]);
+        assert!(entanglement_entropy(2, &product, &[0])?.abs() < 1e-10);
+        assert!(linear_entropy(2, &product, &[0])?.abs() < 1e-10);
+
+        let bell = from_reals(&[s, 0.0, 0.0, s]);
+        assert!((entanglement_entropy(2, &bell, &[0])? - 1.0).abs() < 1e-10);
+        assert!((entanglement_entropy(2, &bell, &[1])? - 1.0).abs() < 1e-10);
+        assert!((linear_entropy(2, &bell, &[0])? - 0.5).abs() < 1e-10);
+
+        let err = entanglement_entropy(2, &bell, &[2]).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::IndexOutOfRange);
+        Ok(())
+    }
+
+    #[test]
+    fn test_schmidt_rank() -> CircuitResult<()> {
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        // |0>|+>|1> is a product state across every bipartition.
+        let mut product = from_reals(&[0.0; 8]);
+        product[0b001] = Complex::new(s, 0.0);
+        product[0b011] = Complex::new(s, 0.0);
+        assert_eq!(schmidt_rank(3, &product, &[0], 1e-10)?, 1);
+        assert_eq!(schmidt_rank(3, &product, &[0, 1], 1e-10)?, 1);
+
+        // A Bell pair on qubits 0 and 2 with qubit 1 in |0>.
+        let mut bell = from_reals(&[0.0; 8]);
+        bell[0b000] = Complex::new(s, 0.0);
+        bell[0b101] = Complex::new(s, 0.0);
+        assert_eq!(schmidt_rank(3, &bell, &[0], 1e-10)?, 2);
+        assert_eq!(schmidt_rank(3, &bell, &[0, 1], 1e-10)?, 2);
+        assert_eq!(schmidt_rank(3, &bell, &[1], 1e-10)?, 1);
+
+        let err = schmidt_rank(3, &bell, &[3], 1e-10).unwrap_err();
+        assert_eq!(err.kind, CircuitErrorKind::IndexOutOfRange);
+        Ok(())
+    }
 }
@@ -1,3 +1,5 @@
+/// Dense linear algebra on small matrices.
+pub mod linalg;
 /// Operations on matrices.
 pub mod matrix_ops;
 /// Measurement operations.
@@ -0,0 +1,95 @@
+use crate::{Complex, Precision};
+use num_traits::One;
+
+/// Assert that `actual` and `expected` are the same state up to a global phase, with each entry
+/// within `tolerance`. The phase is taken from the first entry of `expected` with magnitude above
+/// `tolerance` and factored out of both states before comparing.
+///
+/// # Panics
+/// Panics listing every mismatched entry if the states differ.
+///
+/// # Example
+/// ```
+/// use qip::Complex;
+/// use qip::test_utils::assert_states_equal;
+///
+/// let s = std::f64::consts::FRAC_1_SQRT_2;
+/// let expected = [Complex::new(s, 0.0), Complex::new(s, 0.0)];
+/// let actual = [Complex::new(0.0, s), Complex::new(0.0, s)];
+/// assert_states_equal(&actual, &expected, 1e-10);
+/// ```
+pub fn assert_states_equal<P: Precision>(
+    actual: &[Complex<P>],
+    expected: &[Complex<P>],
+    tolerance: P,
+) {
+    assert_eq!(
+        actual.len(),
+        expected.len(),
+        "States have different lengths: {:?} versus {:?}",
+        actual.len(),
+        expected.len()
+    );
+    let phase = |c: Complex<P>| {
+        if c.norm() > P::zero() {
+            c / c.norm()
+        } else {
+            Complex::one()
+        }
+    };
+    let (actual_phase, expected_phase) = expected
+        .iter()
+        .position(|c| c.norm() > tolerance)
+        .map(|i| (phase(actual[i]), phase(expected[i])))
+        .unwrap_or((Complex::one(), Complex::one()));
+
+    let mismatches = actual
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, e)| (a * actual_phase.conj(), e * expected_phase.conj()))
+        .enumerate()
+        .filter(|(_, (a, e))| (a - e).norm() > tolerance)
+        .map(|(i, (a, e))| format!("\t{:?}: actual {:?}, expected {:?}", i, a, e))
+        .collect::<Vec<_>>();
+    if !mismatches.is_empty() {
+        panic!(
+            "States differ (up to global phase) at {:?} entries:\n{}",
+            mismatches.len(),
+            mismatches.join("\n")
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_utils_tests {
+    use super::*;
+    use num_traits::Zero;
+
+    fn make_state() -> Vec<Complex<f64>> {
+        let state = [
+            Complex::new(0.5, 0.1),
+            Complex::zero(),
+            Complex::new(-0.3, 0.4),
+            Complex::new(0.0, -0.2),
+        ];
+        let norm = state.iter().map(|c| c.norm_sqr()).sum::<f64>().sqrt();
+        state.iter().map(|c| c / norm).collect()
+    }
+
+    #[test]
+    fn test_global_phase_ignored() {
+        let expected = make_state();
+        let phase = Complex::from_polar(1.0, std::f64::consts::PI / 3.0);
+        let actual = expected.iter().map(|c| c * phase).collect::<Vec<_>>();
+        assert_states_equal(&actual, &expected, 1e-10);
+    }
+
+    #[test]
+    #[should_panic(expected = "States differ")]
+    fn test_relative_phase_detected() {
+        let expected = make_state();
+        let mut actual = expected.clone();
+        actual[2] = -actual[2];
+        assert_states_equal(&actual, &expected, 1e-10);
+    }
+}
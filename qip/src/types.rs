@@ -4,7 +4,7 @@ use std::iter::{Product, Sum};
 
 /// The float precision of the circuit.
 pub trait Precision:
-    Default + NumAssign + Float + Sum + Send + Sync + Display + Product + Debug
+    Default + NumAssign + Float + Sum + Send + Sync + Display + Product + Debug + 'static
 {
 }
 
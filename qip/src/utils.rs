@@ -3,6 +3,8 @@ pub(crate) use rayon::prelude::*;
 
 use qip_iterators::into_iter;
 
+use num_traits::Float;
+use std::iter::Sum;
 use std::sync::{Arc, Mutex};
 
 /// Mixes two bitstreams, `z_bits` and `o_bits`, takes one bit off the lowest position from
@@ -59,6 +61,79 @@ pub fn extract_bits(num: usize, indices: &[usize]) -> usize {
     })
 }
 
+/// Returns the parity of `num`: true if an odd number of its bits are set.
+///
+/// # Example
+///
+/// ```
+/// use qip::utils::parity;
+///
+/// assert!(parity(0b1011));
+/// assert!(!parity(0b1100));
+/// ```
+#[inline]
+pub fn parity(num: usize) -> bool {
+    num.count_ones() & 1 == 1
+}
+
+/// An accumulator for compensated (Kahan) summation, which tracks the low order bits lost when
+/// adding many small values to a large running total. Can be collected into with `sum()` from both
+/// sequential and parallel iterators.
+///
+/// # Example
+/// ```
+/// use qip::utils::KahanSum;
+///
+/// let v = vec![1e-6f32; 1_000_000];
+/// let s = v.iter().cloned().sum::<KahanSum<f32>>().value();
+/// assert!((s - 1.0).abs() < 1e-5);
+/// ```
+#[derive(Default, Debug, Clone, Copy)]
+pub struct KahanSum<P> {
+    sum: P,
+    compensation: P,
+}
+
+impl<P: Float> KahanSum<P> {
+    /// Add a value to the running total.
+    pub fn push(self, x: P) -> Self {
+        let y = x - self.compensation;
+        let sum = self.sum + y;
+        let compensation = (sum - self.sum) - y;
+        Self { sum, compensation }
+    }
+
+    /// Combine two partial sums.
+    pub fn merge(self, other: Self) -> Self {
+        self.push(other.sum).push(-other.compensation)
+    }
+
+    /// The compensated total.
+    pub fn value(&self) -> P {
+        self.sum - self.compensation
+    }
+}
+
+impl<P: Float> Sum<P> for KahanSum<P> {
+    fn sum<I: Iterator<Item = P>>(iter: I) -> Self {
+        let zero = Self {
+            sum: P::zero(),
+            compensation: P::zero(),
+        };
+        iter.fold(zero, Self::push)
+    }
+}
+
+impl<P: Float> Sum<KahanSum<P>> for KahanSum<P> {
+    fn sum<I: Iterator<Item = KahanSum<P>>>(iter: I) -> Self {
+        let zero = Self {
+            sum: P::zero(),
+            compensation: P::zero(),
+        };
+        iter.fold(zero, Self::merge)
+    }
+}
+
 /// Transpose a sparse matrix.
 pub fn transpose_sparse<T: Sync + Send>(sparse_mat: Vec<Vec<(usize, T)>>) -> Vec<Vec<(usize, T)>> {
     let sparse_len = sparse_mat.len();